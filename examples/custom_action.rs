@@ -0,0 +1,26 @@
+//! Calls an AnkiConnect action which isn't wrapped by `anki_tex::api`.
+//!
+//! Run with `cargo run --example custom_action` while anki is open.
+
+use anki_tex::api::{Client, ClientConfig};
+use color_eyre::Result;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FindCardsParams<'a> {
+    query: &'a str,
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let client = Client::new(ClientConfig::default())?;
+    let tags: Vec<String> = client.call_action("getTags", anki_tex::protocol::empty())?;
+    println!("{} tags: {}", tags.len(), tags.join(", "));
+
+    let cards: Vec<usize> = client.call_action("findCards", FindCardsParams { query: "is:due" })?;
+    println!("{} cards are due", cards.len());
+
+    Ok(())
+}
@@ -0,0 +1,405 @@
+//! Edits of files written by the user.
+//!
+//! Every feature which changes a tex file goes through [`FileEditor`], so that only
+//! the edited bytes change: line endings, the byte order mark and all other bytes
+//! stay as they were.
+
+use std::{
+    io::Write,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::eyre, Help, Result};
+
+const BOM: &str = "\u{feff}";
+
+/// Replacement of `range` of the original content
+#[derive(Debug, Clone)]
+struct Edit {
+    range: Range<usize>,
+    replacement: String,
+}
+
+/// Replaces byte ranges of a file and writes the result atomically.
+///
+/// The ranges refer to [`FileEditor::content`], e.g. spans of the parser.
+#[derive(Debug)]
+pub struct FileEditor {
+    path: PathBuf,
+    original: String,
+    /// Sorted by their start and not overlapping
+    edits: Vec<Edit>,
+}
+
+impl FileEditor {
+    /// Reads the file at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let original = std::fs::read_to_string(path)
+            .with_note(|| eyre!("while reading file {}", path.to_string_lossy()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            original,
+            edits: Vec::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The original content of the file
+    pub fn content(&self) -> &str {
+        &self.original
+    }
+
+    /// Number of edits
+    pub fn len(&self) -> usize {
+        self.edits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    /// Replaces `range` of the original content with `replacement`.
+    ///
+    /// Line breaks in `replacement` are written like the ones of the file. Fails if
+    /// the range is invalid or overlaps another edit.
+    pub fn replace(&mut self, range: Range<usize>, replacement: &str) -> Result<()> {
+        let name = self.path.to_string_lossy();
+        if range.start > range.end
+            || !self.original.is_char_boundary(range.start)
+            || !self.original.is_char_boundary(range.end)
+        {
+            return Err(eyre!("invalid edit {:?} of {}", range, name)
+                .note(format!("the file has {} bytes", self.original.len())));
+        }
+        if self.original.starts_with(BOM) && range.start < BOM.len() {
+            return Err(eyre!(
+                "edit {:?} of {} changes the byte order mark",
+                range,
+                name
+            ));
+        }
+        let idx = self
+            .edits
+            .partition_point(|edit| edit.range.start <= range.start);
+        let overlaps = |other: &Range<usize>, next: &Range<usize>| {
+            next.start < other.end || (next.start == other.start && other.is_empty())
+        };
+        if let Some(previous) = idx.checked_sub(1).map(|i| &self.edits[i].range) {
+            if overlaps(previous, &range) {
+                return Err(eyre!(
+                    "edits {:?} and {:?} of {} overlap",
+                    previous,
+                    range,
+                    name
+                ));
+            }
+        }
+        if let Some(next) = self.edits.get(idx).map(|edit| &edit.range) {
+            if overlaps(&range, next) {
+                return Err(eyre!(
+                    "edits {:?} and {:?} of {} overlap",
+                    range,
+                    next,
+                    name
+                ));
+            }
+        }
+
+        let replacement = if self.uses_crlf() {
+            replacement.replace("\r\n", "\n").replace('\n', "\r\n")
+        } else {
+            replacement.to_owned()
+        };
+        self.edits.insert(idx, Edit { range, replacement });
+        Ok(())
+    }
+
+    /// Whether the first line break of the file is `\r\n`
+    fn uses_crlf(&self) -> bool {
+        self.original
+            .find('\n')
+            .is_some_and(|i| self.original[..i].ends_with('\r'))
+    }
+
+    /// Content of the file with all edits applied
+    pub fn result(&self) -> String {
+        let mut result = String::with_capacity(self.original.len());
+        let mut pos = 0;
+        for edit in &self.edits {
+            result.push_str(&self.original[pos..edit.range.start]);
+            result.push_str(&edit.replacement);
+            pos = edit.range.end;
+        }
+        result.push_str(&self.original[pos..]);
+        result
+    }
+
+    /// Whether the file still has the content it had when it was opened.
+    pub fn is_unchanged(&self) -> Result<bool> {
+        let current = std::fs::read(&self.path)
+            .with_note(|| eyre!("while reading file {}", self.path.to_string_lossy()))?;
+        Ok(current == self.original.as_bytes())
+    }
+
+    /// Writes the result to the file, replacing it atomically, and returns the path
+    /// of the backup of the original if `backup` is set.
+    ///
+    /// Fails if the file changed since it was read.
+    pub fn write(&self, backup: bool) -> Result<Option<PathBuf>> {
+        let name = self.path.to_string_lossy();
+        if !self.is_unchanged()? {
+            return Err(
+                eyre!("{} changed while it was edited", name).suggestion("run the command again")
+            );
+        }
+
+        let backup_path = if backup {
+            let path = with_suffix(&self.path, ".bak");
+            std::fs::write(&path, &self.original)
+                .with_note(|| eyre!("while creating backup {}", path.to_string_lossy()))?;
+            Some(path)
+        } else {
+            None
+        };
+
+        // the temporary file is in the same directory, so that renaming is atomic
+        let tmp = with_suffix(&self.path, ".anki-tex.tmp");
+        let write_tmp = || -> std::io::Result<()> {
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(self.result().as_bytes())?;
+            file.set_permissions(std::fs::metadata(&self.path)?.permissions())?;
+            file.sync_all()
+        };
+        if let Err(e) = write_tmp().and_then(|()| std::fs::rename(&tmp, &self.path)) {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(eyre!(e).note(format!("while writing file {}", name)));
+        }
+        Ok(backup_path)
+    }
+}
+
+/// Detects changes of a file between parsing it and acting on its content.
+///
+/// Check [`FileGuard::is_unchanged`] right before every write-back or
+/// destructive operation based on the parsed content.
+#[derive(Debug, Clone)]
+pub struct FileGuard {
+    path: PathBuf,
+    hash: u64,
+}
+
+impl FileGuard {
+    /// `content` is the content of `path` which was parsed.
+    pub fn new(path: &Path, content: &str) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            hash: fasthash::metro::hash64(content),
+        }
+    }
+
+    /// Whether the file still has the parsed content.
+    pub fn is_unchanged(&self) -> Result<bool> {
+        let content = std::fs::read_to_string(&self.path)
+            .with_note(|| eyre!("while reading file {}", self.path.to_string_lossy()))?;
+        Ok(fasthash::metro::hash64(content) == self.hash)
+    }
+}
+
+/// `path` with `suffix` appended to the file name
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file in the temp dir with `content`, unique per test.
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("anki-tex-edit-{}-{}.tex", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn guard_unchanged_file() {
+        let path = temp_file("guard-unchanged", "a\nb\n");
+        let guard = FileGuard::new(&path, "a\nb\n");
+        assert!(guard.is_unchanged().unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn guard_detects_change_in_between() {
+        let path = temp_file("guard-changed", "a\nb\n");
+        let guard = FileGuard::new(&path, "a\nb\n");
+        std::fs::write(&path, "a\nc\n").unwrap();
+        assert!(!guard.is_unchanged().unwrap());
+        // changing it back counts as unchanged
+        std::fs::write(&path, "a\nb\n").unwrap();
+        assert!(guard.is_unchanged().unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn guard_of_removed_file_is_an_error() {
+        let path = temp_file("guard-removed", "a");
+        let guard = FileGuard::new(&path, "a");
+        std::fs::remove_file(&path).unwrap();
+        assert!(guard.is_unchanged().is_err());
+    }
+
+    #[test]
+    fn editor_refuses_write_after_change_in_between() {
+        let path = temp_file("editor-changed", "old text\n");
+        let mut editor = FileEditor::open(&path).unwrap();
+        editor.replace(0..3, "new").unwrap();
+        std::fs::write(&path, "old text, edited\n").unwrap();
+        assert!(!editor.is_unchanged().unwrap());
+        assert!(editor.write(false).is_err());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "old text, edited\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn editor_writes_unchanged_file() {
+        let path = temp_file("editor-unchanged", "old text\n");
+        let mut editor = FileEditor::open(&path).unwrap();
+        editor.replace(0..3, "new").unwrap();
+        assert!(editor.is_unchanged().unwrap());
+        assert_eq!(editor.write(false).unwrap(), None);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new text\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// `original` with the `edits` of [`FileEditor::replace`] applied, checking that
+    /// all bytes outside of the edits are unchanged.
+    fn edited(name: &str, original: &str, edits: &[(Range<usize>, &str)]) -> String {
+        let path = temp_file(name, original);
+        let mut editor = FileEditor::open(&path).unwrap();
+        for (range, replacement) in edits {
+            editor.replace(range.clone(), replacement).unwrap();
+        }
+        let result = editor.result();
+        assert_eq!(editor.write(false).unwrap(), None);
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written, result.as_bytes());
+        std::fs::remove_file(&path).unwrap();
+        assert!(!with_suffix(&path, ".anki-tex.tmp").exists());
+
+        // the bytes between the edits are kept
+        let mut edits = edits.to_vec();
+        edits.sort_by_key(|(range, _)| range.start);
+        let mut pos = 0;
+        let mut rest = result.as_str();
+        for (range, replacement) in edits {
+            let kept = &original[pos..range.start];
+            assert!(rest.starts_with(kept), "{:?} isn't kept", kept);
+            let replacement = if original.contains("\r\n") {
+                replacement.replace('\n', "\r\n")
+            } else {
+                replacement.to_owned()
+            };
+            rest = &rest[kept.len() + replacement.len()..];
+            pos = range.end;
+        }
+        assert_eq!(rest, &original[pos..]);
+        result
+    }
+
+    #[test]
+    fn untouched_bytes_are_identical() {
+        let original = "\\deck{A}  \t\n\\fields{Front}{ä}\\next\n\n% trailing  ";
+        let result = edited(
+            "untouched",
+            original,
+            &[
+                (0..8, ""),
+                (12..12, "% inserted\n"),
+                (original.len() - 2..original.len(), ""),
+            ],
+        );
+        assert_eq!(
+            result,
+            "  \t\n% inserted\n\\fields{Front}{ä}\\next\n\n% trailing"
+        );
+    }
+
+    #[test]
+    fn crlf_files_keep_their_line_endings() {
+        let original = "\\deck{A}\r\n\\fields{Front}{a}\r\n\\next\r\n";
+        let result = edited(
+            "crlf",
+            original,
+            &[
+                (10..27, "\\fields{Front}{a}\n\\fields{Back}{b}"),
+                (27..27, ""),
+            ],
+        );
+        assert_eq!(
+            result,
+            "\\deck{A}\r\n\\fields{Front}{a}\r\n\\fields{Back}{b}\r\n\\next\r\n"
+        );
+        assert!(!result.replace("\r\n", "").contains('\n'));
+    }
+
+    #[test]
+    fn byte_order_mark_is_kept() {
+        let original = "\u{feff}\\deck{A}\n";
+        let result = edited("bom", original, &[(BOM.len()..BOM.len() + 8, "\\deck{B}")]);
+        assert_eq!(result, "\u{feff}\\deck{B}\n");
+
+        let path = temp_file("bom-edit", original);
+        let mut editor = FileEditor::open(&path).unwrap();
+        assert!(editor.replace(0..BOM.len(), "").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_and_overlapping_edits_are_rejected() {
+        let path = temp_file("overlap", "abcdefä");
+        let mut editor = FileEditor::open(&path).unwrap();
+        editor.replace(2..4, "x").unwrap();
+        assert!(editor.replace(3..5, "y").is_err());
+        assert!(editor.replace(1..3, "y").is_err());
+        assert!(editor.replace(0..7, "y").is_err());
+        // touching edits don't overlap
+        editor.replace(4..5, "z").unwrap();
+        editor.replace(5..5, "<").unwrap();
+        // but edits at the same position would have no defined order
+        assert!(editor.replace(5..5, ">").is_err());
+        assert!(editor.replace(2..2, ">").is_err());
+        // out of bounds, reversed and inside of a character
+        assert!(editor.replace(5..20, "").is_err());
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 6..5;
+        assert!(editor.replace(reversed, "").is_err());
+        assert!(editor.replace(7..8, "").is_err());
+        assert_eq!(editor.len(), 3);
+        assert_eq!(editor.result(), "abxz<fä");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn backup_has_the_original_content() {
+        let path = temp_file("backup", "old\r\n");
+        let mut editor = FileEditor::open(&path).unwrap();
+        editor.replace(0..3, "new").unwrap();
+        let backup = editor.write(true).unwrap().unwrap();
+        assert_eq!(backup, with_suffix(&path, ".bak"));
+        assert_eq!(std::fs::read(&backup).unwrap(), b"old\r\n");
+        assert_eq!(std::fs::read(&path).unwrap(), b"new\r\n");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+}
@@ -1,9 +1,20 @@
 pub mod api;
+pub mod edit;
 pub mod parse_file;
-pub mod types;
+pub mod protocol;
+pub mod report;
 
-use std::collections::{HashMap, HashSet};
-use tracing::error;
+/// Old name of [`protocol`]
+#[deprecated(note = "use `anki_tex::protocol` instead")]
+pub mod types {
+    pub use crate::protocol::*;
+}
+
+use std::{
+    borrow::Cow,
+    collections::{btree_map, BTreeMap, BTreeSet, HashMap, HashSet},
+};
+use tracing::{debug, error, warn};
 
 pub use api::*;
 
@@ -14,40 +25,636 @@ pub struct Note {
     pub model: String,
     pub fields: HashMap<String, String>,
     pub tags: Vec<String>,
-    // just for error messages
-    pub question: Option<String>,
+    /// First card of stored notes, its question identifies the note in messages, see
+    /// [`Note::question_or_fields`]
+    pub first_card: Option<usize>,
+    /// Last modification time in seconds since the unix epoch, if known
+    pub mod_time: Option<i64>,
+    /// Template ordinals of the cards of the note, empty for parsed notes
+    pub card_ords: Vec<i32>,
+    /// Distinct decks of the cards of the note, starting with [`Note::deck`], empty for
+    /// parsed notes
+    ///
+    /// Single cards can be moved to other decks in anki.
+    pub card_decks: Vec<String>,
+    /// Formats of the fields which aren't [`FieldFormat::Latex`]
+    pub formats: HashMap<String, FieldFormat>,
+    /// Set by `\keep` in the file, anki-tex never changes the stored note
+    pub protected: bool,
+    /// Set by a `% anki: ...` comment in the note, empty for stored notes
+    pub options: NoteOptions,
 }
 
-impl Note {
-    pub fn question_or_fields<'a>(
-        question: &'a Option<String>,
-        fields: &'a HashMap<String, String>,
-    ) -> &'a dyn std::fmt::Debug {
-        question
-            .as_ref()
-            .map_or(fields as &dyn std::fmt::Debug, |q| {
-                q as &dyn std::fmt::Debug
-            })
+/// Options of a note set by a `% anki: {"keep": true}` or `% anki: keep=true`
+/// comment in the file
+///
+/// The values of the known options are checked by [`NoteOptions::new`], so the
+/// accessors don't fail. Unknown options are kept for [`NoteOptions::get`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NoteOptions(BTreeMap<String, serde_json::Value>);
+
+impl NoteOptions {
+    /// Names of the options read by anki-tex
+    pub const KNOWN: [&'static str; 3] = ["allow_duplicate", "format", "keep"];
+
+    /// Checks the values of the known options.
+    pub fn new(options: BTreeMap<String, serde_json::Value>) -> Result<Self, String> {
+        for (key, value) in &options {
+            match key.as_str() {
+                "allow_duplicate" | "keep" if !value.is_boolean() => {
+                    return Err(format!("`{}` must be true or false, got {}", key, value));
+                }
+                "format" => {
+                    let formats = value.as_object().ok_or_else(|| {
+                        format!("`format` must map field names to formats, got {}", value)
+                    })?;
+                    for (field, format) in formats {
+                        format
+                            .as_str()
+                            .ok_or_else(|| format!("format of field `{}` isn't a string", field))?
+                            .parse::<FieldFormat>()?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self(options))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Value of the option `key`, for options without typed accessor
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.0.get(key)
+    }
+
+    /// Options which aren't in [`NoteOptions::KNOWN`]
+    pub fn unknown(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .keys()
+            .map(String::as_str)
+            .filter(|key| !Self::KNOWN.contains(key))
+    }
+
+    /// Sets the options of `other`, replacing options with the same name.
+    pub fn merge(&mut self, other: Self) {
+        self.0.extend(other.0);
+    }
+
+    fn flag(&self, key: &str) -> bool {
+        self.get(key)
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// `allow_duplicate`: create the note even if anki has a note with the same
+    /// first field
+    pub fn allow_duplicate(&self) -> bool {
+        self.flag("allow_duplicate")
     }
+
+    /// `keep`: like `\keep`, never change the stored note
+    pub fn keep(&self) -> bool {
+        self.flag("keep")
+    }
+
+    /// `format`: formats of the fields, overriding `field_formats` of the config
+    pub fn formats(&self) -> HashMap<String, FieldFormat> {
+        self.get("format")
+            .and_then(serde_json::Value::as_object)
+            .into_iter()
+            .flatten()
+            .filter_map(|(field, format)| Some((field.clone(), format.as_str()?.parse().ok()?)))
+            .collect()
+    }
+}
+
+/// How the content of a field is sent to anki
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldFormat {
+    /// Wrapped in `[latex]` tags
+    #[default]
+    Latex,
+    /// Wrapped in `\(...\)` and rendered by MathJax
+    Mathjax,
+    /// Sent byte for byte, e.g. for data used by scripts of the model
+    Raw,
 }
 
-impl PartialEq for Note {
-    fn eq(&self, other: &Self) -> bool {
-        let matching =
-            self.deck == other.deck && self.model == other.model && self.tags == other.tags;
+impl FieldFormat {
+    /// Name as written in the config and the file
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Latex => "latex",
+            Self::Mathjax => "mathjax",
+            Self::Raw => "raw",
+        }
+    }
+
+    /// `content` in the wrapper of the format.
+    pub fn wrap(self, content: &str) -> String {
+        match self {
+            Self::Latex => format!("[latex]{}[/latex]", content),
+            Self::Mathjax => format!("\\({}\\)", content),
+            Self::Raw => content.to_owned(),
+        }
+    }
+
+    /// Content of `text` if the wrapper of the format encloses all of it,
+    /// see [`latex_wrapped`] and [`mathjax_wrapped`].
+    pub fn unwrap(self, text: &str) -> Option<&str> {
+        match self {
+            Self::Latex => latex_wrapped(text),
+            Self::Mathjax => mathjax_wrapped(text),
+            Self::Raw => None,
+        }
+    }
+
+    /// Format whose wrapper encloses all of `text`, `None` if it isn't wrapped.
+    pub fn detect(text: &str) -> Option<Self> {
+        [Self::Latex, Self::Mathjax]
+            .into_iter()
+            .find(|format| format.unwrap(text).is_some())
+    }
+}
+
+/// How display math in LaTeX fields is delimited when sent to anki
+///
+/// Some LaTeX setups of anki only work with one of `\[...\]` and `$$...$$`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MathDelimiters {
+    /// Send the delimiters as written in the file
+    #[default]
+    Keep,
+    /// Replace `\[...\]` with `$$...$$`
+    Dollars,
+    /// Replace `$$...$$` with `\[...\]`
+    Brackets,
+}
+
+/// What happens with LaTeX fields which are already wrapped in `[latex]` in the file,
+/// e.g. in notes migrated from other tools
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DoubleWrap {
+    /// Send the field as written instead of wrapping it again
+    #[default]
+    Skip,
+    /// Report the note as invalid
+    Error,
+    /// Wrap the field again, anki shows the inner markers as text
+    Force,
+}
+
+impl std::str::FromStr for FieldFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latex" => Ok(Self::Latex),
+            "mathjax" => Ok(Self::Mathjax),
+            "raw" => Ok(Self::Raw),
+            other => Err(format!(
+                "unknown field format `{}`, expected `latex`, `mathjax` or `raw`",
+                other
+            )),
+        }
+    }
+}
+
+/// Differences between a stored note and its new version, see [`Note::diff`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct NoteDiff {
+    /// Names of the fields whose content changed
+    pub fields: Vec<String>,
+    /// Tags of the new note which the stored note is missing
+    pub missing_tags: Vec<String>,
+    /// Names of the fields to clear because they aren't set in the new note,
+    /// see [`Note::cleared_fields`]
+    pub cleared: Vec<String>,
+    /// Tags of the new note which are managed inside Anki and therefore not added.
+    ///
+    /// Not set by [`Note::diff`] and not considered by [`NoteDiff::is_empty`].
+    pub protected_tags: Vec<String>,
+}
+
+impl NoteDiff {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.missing_tags.is_empty() && self.cleared.is_empty()
+    }
+}
 
-        let a_fields = self
+/// Settings of the comparison of parsed and stored notes, see [`Note::matches`]
+///
+/// They are passed to every comparison, so notes can be compared with different
+/// settings in one process.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatchOptions {
+    /// Field storing [`Note::content_hash`], it is ignored when comparing notes
+    pub hash_field: Option<String>,
+    /// Tag added to new notes by anki-tex, it is ignored when comparing notes and not
+    /// exported
+    pub generated_tag: Option<String>,
+    /// Delimiters of display math in LaTeX fields, the fields of both notes are
+    /// converted before comparing them, so notes sent with other delimiters still match
+    pub math_delimiters: MathDelimiters,
+    /// Pairs of an old and a new deck prefix, decks with the old prefix match the same
+    /// decks with the new prefix, see [`MatchOptions::canonical_deck`]
+    pub deck_equivalences: Vec<(String, String)>,
+}
+
+impl MatchOptions {
+    fn is_hash_field(&self, name: &str) -> bool {
+        self.hash_field.as_deref() == Some(name)
+    }
+
+    pub fn is_generated_tag(&self, tag: &str) -> bool {
+        self.generated_tag.as_deref() == Some(tag)
+    }
+
+    /// `deck` with the old prefix of the first matching pair of
+    /// [`MatchOptions::deck_equivalences`] replaced by the new prefix.
+    pub fn canonical_deck<'a>(&self, deck: &'a str) -> Cow<'a, str> {
+        for (old, new) in &self.deck_equivalences {
+            if let Some(rest) = deck.strip_prefix(old.as_str()) {
+                if rest.is_empty() || rest.starts_with("::") {
+                    return Cow::Owned(format!("{}{}", new, rest));
+                }
+            }
+        }
+        Cow::Borrowed(deck)
+    }
+}
+
+/// Content of `text` if one `[latex]...[/latex]` encloses all of it.
+///
+/// Markers inside of the content must be balanced, so `[latex]a[/latex] b [latex]c[/latex]`
+/// or a text mentioning `[latex]` aren't treated as wrapped.
+pub fn latex_wrapped(text: &str) -> Option<&str> {
+    wrapped(text, "[latex]", "[/latex]")
+}
+
+/// Content of `text` if one `\(...\)` encloses all of it, like [`latex_wrapped`].
+pub fn mathjax_wrapped(text: &str) -> Option<&str> {
+    wrapped(text, "\\(", "\\)")
+}
+
+fn wrapped<'a>(text: &'a str, open: &str, close: &str) -> Option<&'a str> {
+    let inner = text.trim().strip_prefix(open)?.strip_suffix(close)?;
+    let mut depth = 0usize;
+    let mut rest = inner;
+    while let Some(pos) = rest.find(open).into_iter().chain(rest.find(close)).min() {
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix(open) {
+            depth += 1;
+            rest = after;
+        } else {
+            depth = depth.checked_sub(1)?;
+            rest = &rest[close.len()..];
+        }
+    }
+    (depth == 0).then_some(inner)
+}
+
+/// How [`TagSet`] treats the case of tags
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagCase {
+    /// Keep the case as written
+    #[default]
+    Preserve,
+    /// Lowercase all tags
+    Lower,
+}
+
+/// Tags of a note without duplicates, sorted ignoring their case
+///
+/// Anki compares tags ignoring their case, so of tags differing only by case the
+/// first one is kept. Whitespace, which separates tags in anki, is replaced with `_`
+/// and quotes and control characters are removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagSet {
+    case: TagCase,
+    tags: BTreeMap<String, String>,
+}
+
+impl TagSet {
+    pub fn new(case: TagCase) -> Self {
+        Self {
+            case,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Set of `tags` with the case handling `case`
+    pub fn with_tags<S: AsRef<str>>(case: TagCase, tags: impl IntoIterator<Item = S>) -> Self {
+        let mut set = Self::new(case);
+        set.extend(tags);
+        set
+    }
+
+    /// Adds `tag` unless a tag differing only by case was added already.
+    ///
+    /// Returns whether the tag was added.
+    pub fn insert(&mut self, tag: &str) -> bool {
+        let mut sanitized = sanitize_tag(tag);
+        if sanitized != tag {
+            warn!(
+                "tag {:?} contains characters anki doesn't allow, using {:?}",
+                tag, sanitized
+            );
+        }
+        if sanitized.is_empty() {
+            return false;
+        }
+        if self.case == TagCase::Lower {
+            sanitized = sanitized.to_lowercase();
+        }
+        match self.tags.entry(sanitized.to_lowercase()) {
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(sanitized);
+                true
+            }
+            btree_map::Entry::Occupied(entry) => {
+                if *entry.get() != sanitized {
+                    debug!(
+                        "dropping tag {}, it only differs by case from {}",
+                        sanitized,
+                        entry.get()
+                    );
+                }
+                false
+            }
+        }
+    }
+
+    /// Whether `tag` or a tag differing only by case is contained.
+    pub fn contains(&self, tag: &str) -> bool {
+        self.tags.contains_key(&sanitize_tag(tag).to_lowercase())
+    }
+
+    pub fn into_vec(self) -> Vec<String> {
+        self.tags.into_values().collect()
+    }
+}
+
+impl<S: AsRef<str>> Extend<S> for TagSet {
+    fn extend<I: IntoIterator<Item = S>>(&mut self, iter: I) {
+        for tag in iter {
+            self.insert(tag.as_ref());
+        }
+    }
+}
+
+/// `tag` with the characters anki doesn't allow in tags replaced or removed, see
+/// [`TagSet`].
+fn sanitize_tag(tag: &str) -> String {
+    tag.trim()
+        .chars()
+        .filter(|c| c.is_whitespace() || (*c != '"' && !c.is_control()))
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .collect()
+}
+
+/// See [`Note::question_or_fields`]
+pub struct QuestionOrFields<'a> {
+    first_card: Option<usize>,
+    question: Option<String>,
+    fields: &'a HashMap<String, String>,
+}
+
+impl QuestionOrFields<'_> {
+    /// Fetches the question of the first card from anki, see [`api::card_question`].
+    ///
+    /// Without a card or if the question can't be fetched, the fields are shown.
+    pub fn resolve(mut self) -> Self {
+        self.question = self.first_card.and_then(api::card_question);
+        self
+    }
+}
+
+impl std::fmt::Debug for QuestionOrFields<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.question {
+            Some(question) => question.fmt(f),
+            None => self.fields.fmt(f),
+        }
+    }
+}
+
+/// Format of the tag with the generation date which is added to new notes
+pub const GENERATION_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Whether `tag` is a generation date tag, see [`GENERATION_DATE_FORMAT`].
+///
+/// Such tags differ between runs, so they are ignored when comparing notes and
+/// never added to existing notes.
+pub fn is_generation_date_tag(tag: &str) -> bool {
+    chrono::NaiveDate::parse_from_str(tag, GENERATION_DATE_FORMAT).is_ok()
+}
+
+impl Note {
+    /// Note of `model` in `deck` which isn't stored in anki, with the default formats
+    /// and options, e.g. for tests.
+    pub fn new(deck: &str, model: &str, fields: &[(&str, &str)], tags: &[&str]) -> Self {
+        Self {
+            id: None,
+            deck: deck.to_owned(),
+            model: model.to_owned(),
+            fields: fields
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            first_card: None,
+            mod_time: None,
+            card_ords: Vec::new(),
+            card_decks: Vec::new(),
+            formats: HashMap::new(),
+            protected: false,
+            options: NoteOptions::default(),
+        }
+    }
+
+    /// Identifies a note in messages by the question of `first_card` or by its
+    /// `fields`.
+    ///
+    /// The question is only fetched from anki by [`QuestionOrFields::resolve`],
+    /// formatting it shows the fields until then.
+    pub fn question_or_fields(
+        first_card: Option<usize>,
+        fields: &HashMap<String, String>,
+    ) -> QuestionOrFields<'_> {
+        QuestionOrFields {
+            first_card,
+            question: None,
+            fields,
+        }
+    }
+
+    /// Removes duplicate and invalid tags and sorts them, see [`TagSet`].
+    pub fn normalize_tags(&mut self, case: TagCase) {
+        self.tags = TagSet::with_tags(case, std::mem::take(&mut self.tags)).into_vec();
+    }
+
+    /// Adds `tags` which the note doesn't have yet, see [`TagSet`].
+    pub fn add_tags<S: AsRef<str>>(&mut self, tags: impl IntoIterator<Item = S>, case: TagCase) {
+        let mut set = TagSet::with_tags(case, &self.tags);
+        set.extend(tags);
+        self.tags = set.into_vec();
+    }
+
+    /// Whether `deck` is the deck of the note or of one of its cards, decks with
+    /// equivalent prefixes are the same, see [`MatchOptions::canonical_deck`]
+    pub fn in_deck(&self, deck: &str, options: &MatchOptions) -> bool {
+        let deck = options.canonical_deck(deck);
+        std::iter::once(&self.deck)
+            .chain(&self.card_decks)
+            .any(|d| options.canonical_deck(d) == deck)
+    }
+
+    pub fn format(&self, field: &str) -> FieldFormat {
+        self.formats.get(field).copied().unwrap_or_default()
+    }
+
+    /// Applies [`parse_file::normalize_unicode`] to all fields which aren't raw.
+    pub fn normalize_unicode(&mut self) {
+        for (name, value) in &mut self.fields {
+            if self.formats.get(name) != Some(&FieldFormat::Raw) {
+                *value = parse_file::normalize_unicode(value);
+            }
+        }
+    }
+
+    /// Normalized value of `field` for comparisons with `other`.
+    ///
+    /// Raw fields (in either note) are only trimmed. Stored notes have no formats, so
+    /// a MathJax field in either note is compared without its MathJax wrapper.
+    fn normalized(
+        &self,
+        other: &Self,
+        field: &str,
+        value: &str,
+        options: &MatchOptions,
+    ) -> MatchUnescape {
+        let format = match (self.format(field), other.format(field)) {
+            (FieldFormat::Raw, _) | (_, FieldFormat::Raw) => {
+                return MatchUnescape(value.trim().to_owned())
+            }
+            (FieldFormat::Mathjax, _) | (_, FieldFormat::Mathjax) => FieldFormat::Mathjax,
+            (FieldFormat::Latex, FieldFormat::Latex) => FieldFormat::Latex,
+        };
+        MatchUnescape::field(value, format, options.math_delimiters)
+    }
+
+    /// Hash of the model and the normalized non-empty fields except the hash field.
+    pub fn content_hash(&self, options: &MatchOptions) -> String {
+        let mut fields = self
             .fields
             .iter()
-            .filter(|(_, v)| !v.is_empty())
-            .map(|(k, v)| (MatchUnescape::from(k), MatchUnescape::from(v)))
-            .collect::<HashSet<_>>();
-        let b_fields = other
+            .filter(|(k, v)| !options.is_hash_field(k) && !v.is_empty())
+            .collect::<Vec<_>>();
+        fields.sort();
+
+        let mut content = self.model.clone();
+        for (k, v) in fields {
+            content.push('\0');
+            content.push_str(k);
+            content.push('\0');
+            content.push_str(&self.normalized(self, k, v, options).0);
+        }
+        format!("{:016x}", fasthash::metro::hash64(content))
+    }
+
+    /// Whether the normalized content of `field` is the same in both notes.
+    pub fn field_eq(&self, other: &Self, field: &str, options: &MatchOptions) -> bool {
+        let a = self.fields.get(field).map_or("", String::as_str);
+        let b = other.fields.get(field).map_or("", String::as_str);
+        self.normalized(other, field, a, options) == self.normalized(other, field, b, options)
+    }
+
+    /// Compares the normalized fields and the tags of `self` (the stored note)
+    /// with `new`.
+    ///
+    /// Only fields which are set in `new` are compared and tags which only exist on
+    /// `self` are not reported, so that changes done inside Anki are preserved.
+    pub fn diff(&self, new: &Self, options: &MatchOptions) -> NoteDiff {
+        let mut fields = new
             .fields
+            .keys()
+            .filter(|name| !self.field_eq(new, name, options))
+            .cloned()
+            .collect::<Vec<_>>();
+        fields.sort();
+
+        let stored_tags = TagSet::with_tags(TagCase::Preserve, &self.tags);
+        let missing_tags = new
+            .tags
             .iter()
-            .filter(|(_, v)| !v.is_empty())
-            .map(|(k, v)| (MatchUnescape::from(k), MatchUnescape::from(v)))
-            .collect::<HashSet<_>>();
+            .filter(|tag| !stored_tags.contains(tag) && !is_generation_date_tag(tag))
+            .cloned()
+            .collect();
+
+        NoteDiff {
+            fields,
+            missing_tags,
+            ..Default::default()
+        }
+    }
+
+    /// Names of the non-empty fields of `self` (the stored note) which aren't set
+    /// in `new`, ignoring the hash field.
+    ///
+    /// [`Note::diff`] keeps them, they are only cleared if all fields are replaced.
+    pub fn cleared_fields(&self, new: &Self, options: &MatchOptions) -> Vec<String> {
+        let mut fields = self
+            .fields
+            .iter()
+            .filter(|(name, value)| {
+                !value.is_empty() && !options.is_hash_field(name) && !new.fields.contains_key(*name)
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        fields.sort();
+        fields
+    }
+
+    /// Whether both notes have the same deck, model, tags and normalized fields
+    /// under `options`.
+    ///
+    /// Empty fields, the hash field, the generated tag and generation date tags are
+    /// ignored.
+    pub fn matches(&self, other: &Self, options: &MatchOptions) -> bool {
+        // anki ignores the case and the order of tags
+        let tags = |note: &Self| {
+            note.tags
+                .iter()
+                .filter(|tag| !options.is_generated_tag(tag) && !is_generation_date_tag(tag))
+                .map(|tag| tag.to_lowercase())
+                .collect::<BTreeSet<_>>()
+        };
+        let matching = (self.in_deck(&other.deck, options) || other.in_deck(&self.deck, options))
+            && self.model == other.model
+            && tags(self) == tags(other);
+
+        let fields = |note: &Self| {
+            note.fields
+                .iter()
+                .filter(|(k, v)| !v.is_empty() && !options.is_hash_field(k))
+                .map(|(k, v)| {
+                    (
+                        MatchUnescape::new(k, options.math_delimiters),
+                        self.normalized(other, k, v, options),
+                    )
+                })
+                .collect::<HashSet<_>>()
+        };
+        let a_fields = fields(self);
+        let b_fields = fields(other);
         let fields_match = a_fields == b_fields;
 
         if let (Some(s_id), Some(o_id)) = (self.id, other.id) {
@@ -71,17 +678,363 @@ lazy_static::lazy_static! {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct MatchUnescape(String);
 
-impl From<&String> for MatchUnescape {
-    fn from(value: &String) -> Self {
-        value.as_str().into()
+/// `value` of a field with `format` as it is compared with the fields of stored notes
+pub fn match_text(format: FieldFormat, value: &str, options: &MatchOptions) -> String {
+    match format {
+        FieldFormat::Raw => value.trim().to_owned(),
+        FieldFormat::Latex | FieldFormat::Mathjax => {
+            MatchUnescape::field(value, format, options.math_delimiters).0
+        }
     }
 }
 
-impl From<&str> for MatchUnescape {
-    fn from(s: &str) -> Self {
+impl MatchUnescape {
+    fn new(s: &str, delimiters: MathDelimiters) -> Self {
         // TODO: enable this?
         //let s = s.replace(|x: char| x.is_whitespace(), "");
         let result = UNESCAPER.replace_all(s, &[">", "<"]);
-        Self(result)
+        Self(parse_file::convert_math_delimiters(&result, delimiters))
+    }
+
+    /// [`MatchUnescape::new`] of a field with `format` without its wrapper, so fields
+    /// which were already wrapped in the file match the same fields wrapped when sent.
+    ///
+    /// Only one wrapper of `format` is removed, other wrappers are content.
+    fn field(value: &str, format: FieldFormat, delimiters: MathDelimiters) -> Self {
+        Self::new(format.unwrap(value).unwrap_or(value), delimiters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn math_delimiters_are_converted_symmetrically() {
+        let stored = Note::new("A", "Basic", &[("Front", "$$x$$ and $y$")], &[]);
+        let parsed = Note::new("A", "Basic", &[("Front", r"\[x\] and $y$")], &[]);
+        for delimiters in [MathDelimiters::Dollars, MathDelimiters::Brackets] {
+            let opts = MatchOptions {
+                math_delimiters: delimiters,
+                ..MatchOptions::default()
+            };
+            assert!(stored.matches(&parsed, &opts));
+            assert!(parsed.matches(&stored, &opts));
+            assert_eq!(stored.content_hash(&opts), parsed.content_hash(&opts));
+
+            // raw fields are compared as they are
+            let mut raw = parsed.clone();
+            raw.formats.insert(String::from("Front"), FieldFormat::Raw);
+            assert!(!stored.matches(&raw, &opts));
+        }
+        assert!(!stored.matches(&parsed, &MatchOptions::default()));
+    }
+
+    #[test]
+    fn raw_fields_are_compared_byte_exact() {
+        let opts = MatchOptions::default();
+        let stored = Note::new("A", "Basic", &[("Front", "&lt;x&gt;")], &[]);
+        let latex = Note::new("A", "Basic", &[("Front", "<x>")], &[]);
+        assert!(stored.matches(&latex, &opts));
+
+        let mut raw = latex.clone();
+        raw.formats.insert(String::from("Front"), FieldFormat::Raw);
+        assert!(!stored.matches(&raw, &opts));
+        assert!(!raw.matches(&stored, &opts));
+        assert!(!stored.field_eq(&raw, "Front", &opts));
+
+        let wrapped = Note::new("A", "Basic", &[("Front", "[latex]<x>[/latex]")], &[]);
+        assert!(wrapped.matches(&latex, &opts));
+        assert!(!wrapped.matches(&raw, &opts));
+    }
+
+    #[test]
+    fn raw_fields_are_trimmed() {
+        let opts = MatchOptions::default();
+        let mut raw = Note::new("A", "Basic", &[("Front", "{\"x\": 1}")], &[]);
+        raw.formats.insert(String::from("Front"), FieldFormat::Raw);
+        let stored = Note::new("A", "Basic", &[("Front", "\n {\"x\": 1}  \n")], &[]);
+        assert!(stored.matches(&raw, &opts));
+        let spaced = Note::new("A", "Basic", &[("Front", "{\"x\":  1}")], &[]);
+        assert!(!spaced.matches(&raw, &opts));
+        assert_eq!(raw.content_hash(&opts), {
+            let mut trimmed = stored.clone();
+            trimmed.formats = raw.formats.clone();
+            trimmed.content_hash(&opts)
+        });
+    }
+
+    #[test]
+    fn hash_field_is_ignored_when_set() {
+        let parsed = Note::new("A", "Basic", &[("Front", "a"), ("Back", "b")], &[]);
+        let stored = Note::new(
+            "A",
+            "Basic",
+            &[("Front", "a"), ("Back", "b"), ("AnkiTexHash", "0123")],
+            &[],
+        );
+        let with_hash = MatchOptions {
+            hash_field: Some(String::from("AnkiTexHash")),
+            ..Default::default()
+        };
+        assert!(stored.matches(&parsed, &with_hash));
+        assert!(!stored.matches(&parsed, &MatchOptions::default()));
+        assert!(stored.cleared_fields(&parsed, &with_hash).is_empty());
+        assert_eq!(
+            stored.cleared_fields(&parsed, &MatchOptions::default()),
+            ["AnkiTexHash"]
+        );
+    }
+
+    #[test]
+    fn content_hash_ignores_hash_field() {
+        let options = MatchOptions {
+            hash_field: Some(String::from("AnkiTexHash")),
+            ..Default::default()
+        };
+        let parsed = Note::new("A", "Basic", &[("Front", "a &lt; b")], &[]);
+        let stored = Note::new(
+            "B",
+            "Basic",
+            &[("Front", "a < b"), ("AnkiTexHash", "x")],
+            &["t"],
+        );
+        assert_eq!(parsed.content_hash(&options), stored.content_hash(&options));
+        assert_ne!(
+            parsed.content_hash(&options),
+            Note::new("A", "Basic", &[("Front", "c")], &[]).content_hash(&options)
+        );
+    }
+
+    #[test]
+    fn generated_tag_is_ignored_when_set() {
+        let parsed = Note::new("A", "Basic", &[("Front", "a")], &["x"]);
+        let stored = Note::new(
+            "A",
+            "Basic",
+            &[("Front", "a")],
+            &["x", "generated", "2024-03-12"],
+        );
+        let options = MatchOptions {
+            generated_tag: Some(String::from("generated")),
+            ..Default::default()
+        };
+        assert!(stored.matches(&parsed, &options));
+        assert!(!stored.matches(&parsed, &MatchOptions::default()));
+    }
+
+    #[test]
+    fn normalized_unicode_matches_stored_note() {
+        let mut parsed = Note::new(
+            "A",
+            "Basic",
+            &[("Front", "\u{201C}x\u{201D}"), ("Data", "\u{201C}")],
+            &[],
+        );
+        parsed
+            .formats
+            .insert(String::from("Data"), FieldFormat::Raw);
+        parsed.normalize_unicode();
+        assert_eq!(parsed.fields["Front"], "``x''");
+        // raw fields are sent byte for byte
+        assert_eq!(parsed.fields["Data"], "\u{201C}");
+
+        let stored = Note::new(
+            "A",
+            "Basic",
+            &[("Front", "``x''"), ("Data", "\u{201C}")],
+            &[],
+        );
+        assert!(stored.matches(&parsed, &MatchOptions::default()));
+        parsed.normalize_unicode();
+        assert!(stored.matches(&parsed, &MatchOptions::default()));
+    }
+
+    #[test]
+    fn only_the_wrapper_of_the_format_is_removed() {
+        let opts = MatchOptions::default();
+        let plain = Note::new("A", "Basic", &[("Front", "a")], &[]);
+        let mathjax = Note::new("A", "Basic", &[("Front", r"\(a\)")], &[]);
+        assert!(!mathjax.matches(&plain, &opts));
+        assert_eq!(mathjax.diff(&plain, &opts).fields, ["Front"]);
+
+        let mut parsed = plain.clone();
+        parsed
+            .formats
+            .insert(String::from("Front"), FieldFormat::Mathjax);
+        assert!(mathjax.matches(&parsed, &opts));
+        assert!(mathjax.diff(&parsed, &opts).is_empty());
+
+        let twice = Note::new(
+            "A",
+            "Basic",
+            &[("Front", "[latex][latex]a[/latex][/latex]")],
+            &[],
+        );
+        assert!(!twice.matches(&plain, &opts));
+        assert_eq!(
+            match_text(FieldFormat::Latex, "[latex][latex]a[/latex][/latex]", &opts),
+            "[latex]a[/latex]"
+        );
+    }
+
+    #[test]
+    fn latex_wrapped_content() {
+        assert_eq!(latex_wrapped("[latex]x[/latex]"), Some("x"));
+        assert_eq!(
+            latex_wrapped(" [latex][latex]x[/latex][/latex]\n"),
+            Some("[latex]x[/latex]")
+        );
+        assert_eq!(latex_wrapped("[latex]a[/latex] b [latex]c[/latex]"), None);
+        assert_eq!(latex_wrapped("the marker [latex] starts a field"), None);
+        assert_eq!(latex_wrapped("[latex]the marker [latex][/latex]"), None);
+        assert_eq!(latex_wrapped("[latex]x"), None);
+    }
+
+    #[test]
+    fn wrapped_fields_match_unwrapped_ones() {
+        let opts = MatchOptions::default();
+        let legacy = Note::new("A", "Basic", &[("Front", "[latex]$x$[/latex]")], &[]);
+        let new = Note::new("A", "Basic", &[("Front", "$x$")], &[]);
+        assert!(legacy.matches(&new, &opts));
+        assert!(new.matches(&legacy, &opts));
+
+        // a literal marker is content
+        let about = Note::new("A", "Basic", &[("Front", "use [latex] for $x$")], &[]);
+        let without = Note::new("A", "Basic", &[("Front", "use for $x$")], &[]);
+        assert!(!about.matches(&without, &opts));
+        assert!(about.matches(&about.clone(), &opts));
+    }
+
+    #[test]
+    fn tags_differing_by_case() {
+        let set = TagSet::with_tags(TagCase::Preserve, ["Math", "math", "MATH", "Linear"]);
+        assert_eq!(set.into_vec(), ["Linear", "Math"]);
+        let set = TagSet::with_tags(TagCase::Preserve, ["math", "Math"]);
+        assert!(set.contains("MATH"));
+        assert_eq!(set.into_vec(), ["math"]);
+        let set = TagSet::with_tags(TagCase::Lower, ["Math", "math", "Linear::Algebra"]);
+        assert_eq!(set.into_vec(), ["linear::algebra", "math"]);
+
+        let mut set = TagSet::new(TagCase::Preserve);
+        assert!(set.insert("Math"));
+        assert!(!set.insert("math"));
+        assert!(!set.insert("Math"));
+    }
+
+    #[test]
+    fn tags_are_sanitized() {
+        let set = TagSet::with_tags(
+            TagCase::Preserve,
+            [
+                "linear algebra",
+                " padded\t",
+                "\"quoted\"",
+                "bell\u{7}",
+                "",
+                "  ",
+                "\"\"",
+            ],
+        );
+        assert_eq!(
+            set.into_vec(),
+            ["bell", "linear_algebra", "padded", "quoted"]
+        );
+        // the sanitized tag is compared
+        let set = TagSet::with_tags(TagCase::Preserve, ["linear_algebra", "Linear Algebra"]);
+        assert!(set.contains("linear algebra"));
+        assert_eq!(set.into_vec(), ["linear_algebra"]);
+    }
+
+    #[test]
+    fn tags_are_sorted_deterministically() {
+        let tags = ["b", "A", "c::x", "C", "a::b", "_x", "2024-03-12"];
+        let sorted = TagSet::with_tags(TagCase::Preserve, tags).into_vec();
+        assert_eq!(sorted, ["2024-03-12", "_x", "A", "a::b", "b", "C", "c::x"]);
+        let mut reversed = tags;
+        reversed.reverse();
+        assert_eq!(
+            TagSet::with_tags(TagCase::Preserve, reversed).into_vec(),
+            sorted
+        );
+    }
+
+    #[test]
+    fn note_tags_are_normalized() {
+        let mut n = Note::new(
+            "A",
+            "Basic",
+            &[("Front", "a")],
+            &["Math", "generated", "math", "two words"],
+        );
+        n.normalize_tags(TagCase::Preserve);
+        assert_eq!(n.tags, ["generated", "Math", "two_words"]);
+        n.add_tags(["MATH", "new", "Generated"], TagCase::Preserve);
+        assert_eq!(n.tags, ["generated", "Math", "new", "two_words"]);
+        n.add_tags(["Other"], TagCase::Lower);
+        assert_eq!(n.tags, ["generated", "math", "new", "other", "two_words"]);
+    }
+
+    fn equivalences(pairs: &[(&str, &str)]) -> MatchOptions {
+        MatchOptions {
+            deck_equivalences: pairs
+                .iter()
+                .map(|(old, new)| (old.to_string(), new.to_string()))
+                .collect(),
+            ..MatchOptions::default()
+        }
+    }
+
+    #[test]
+    fn canonical_decks_of_equivalent_prefixes() {
+        let opts = equivalences(&[("Uni", "Studies::Uni"), ("Old", "New"), ("Old::A", "Other")]);
+        assert_eq!(opts.canonical_deck("Uni"), "Studies::Uni");
+        assert_eq!(
+            opts.canonical_deck("Uni::Math::Algebra"),
+            "Studies::Uni::Math::Algebra"
+        );
+        // only whole deck names are prefixes
+        assert_eq!(opts.canonical_deck("University"), "University");
+        assert_eq!(opts.canonical_deck("Studies::Uni"), "Studies::Uni");
+        assert_eq!(opts.canonical_deck("My::Uni"), "My::Uni");
+        // the first matching pair is used
+        assert_eq!(opts.canonical_deck("Old::A"), "New::A");
+        assert!(matches!(
+            opts.canonical_deck("Default"),
+            Cow::Borrowed("Default")
+        ));
+        assert_eq!(MatchOptions::default().canonical_deck("Uni"), "Uni");
+    }
+
+    #[test]
+    fn notes_in_equivalent_decks_match() {
+        let opts = equivalences(&[("Uni", "Studies::Uni")]);
+        let stored = Note::new("Uni::Math", "Basic", &[("Front", "a")], &["t"]);
+        let parsed = Note::new("Studies::Uni::Math", "Basic", &[("Front", "a")], &["t"]);
+        assert!(parsed.matches(&stored, &opts));
+        assert!(stored.matches(&parsed, &opts));
+        assert!(!parsed.matches(&stored, &MatchOptions::default()));
+
+        // both with the old prefix
+        let old = Note::new("Uni::Math", "Basic", &[("Front", "a")], &["t"]);
+        assert!(old.matches(&stored, &opts));
+
+        let other_deck = Note::new("Studies::Uni::Physics", "Basic", &[("Front", "a")], &["t"]);
+        assert!(!other_deck.matches(&stored, &opts));
+        let other_field = Note::new("Studies::Uni::Math", "Basic", &[("Front", "b")], &["t"]);
+        assert!(!other_field.matches(&stored, &opts));
+
+        // a card moved to a deck with the old prefix
+        let mut moved = Note::new("Studies::Uni::Math", "Basic", &[("Front", "a")], &["t"]);
+        moved.card_decks = vec!["Uni::Math::Hard".to_owned()];
+        assert!(moved.in_deck("Studies::Uni::Math::Hard", &opts));
+        assert!(!moved.in_deck("Uni::Physics", &opts));
+        let hard = Note::new(
+            "Studies::Uni::Math::Hard",
+            "Basic",
+            &[("Front", "a")],
+            &["t"],
+        );
+        assert!(hard.matches(&moved, &opts));
     }
 }
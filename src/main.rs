@@ -15,11 +15,14 @@ use notify::{Event, EventKind, RecursiveMode, Watcher};
 use regex::Regex;
 use serde::Deserialize;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fs::read_to_string,
+    io::IsTerminal,
     path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
 };
-use tracing::{debug, error, info, warn, Level};
+use tracing::{debug, error, info, trace, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
 #[derive(Debug, PartialEq)]
@@ -34,6 +37,19 @@ struct State {
     added_notes: Vec<Note>,
     last_main_hash: u64,
     last_custom_hash: u64,
+    /// Runs changing notes are appended to this file, see [`RunRecord`]
+    run_log: PathBuf,
+    /// Profile open in anki, see [`check_profile`]
+    profile: Option<String>,
+    /// Only parse and process the notes which changed since the last run, see
+    /// [`parse_file::parse_changed`]
+    changed_only: bool,
+    /// Decks whose notes are processed, `deck_filter` of the config unless it is
+    /// overridden by `create`
+    deck_filter: DeckFilter,
+    /// Leave out the new notes of decks exceeding their budget instead of only
+    /// warning, see `deck_budgets`
+    enforce_budgets: bool,
 }
 
 impl State {
@@ -53,15 +69,30 @@ impl State {
             .collect()
     }
 
-    fn new() -> Result<Self> {
+    fn new(config: &Config) -> Result<Self> {
         debug!("loading state");
         let models = Self::load_models()?;
+        let mut added_notes = get_notes("*", CardDetails::Decks)?;
+        if config.normalize_unicode {
+            // compare with the normalized content of the file
+            for note in &mut added_notes {
+                note.normalize_unicode();
+            }
+        }
+        check_generated_tag(config, &added_notes);
+        let deck_names = get_deck_names()?.0;
+        cache_collection(config, &deck_names, &models, &added_notes);
         Ok(Self {
-            deck_names: get_deck_names()?.0,
+            deck_names,
             models,
-            added_notes: get_notes("*")?,
+            added_notes,
             last_main_hash: 0,
             last_custom_hash: 0,
+            run_log: config.run_log.clone(),
+            profile: None,
+            changed_only: false,
+            deck_filter: config.deck_filter.clone(),
+            enforce_budgets: false,
         })
     }
 
@@ -75,20 +106,150 @@ impl State {
     }
 }
 
-fn get_notes(query: &str) -> Result<Vec<Note>> {
+/// Progress bars of long operations, only set if they should be drawn
+static PROGRESS: OnceLock<indicatif::MultiProgress> = OnceLock::new();
+
+/// Creates a progress bar with `len` steps, hidden if progress bars are disabled.
+fn progress_bar(len: usize, message: &'static str) -> indicatif::ProgressBar {
+    let Some(multi) = PROGRESS.get() else {
+        return indicatif::ProgressBar::hidden();
+    };
+    let style = indicatif::ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len} (eta {eta})")
+        .unwrap()
+        .progress_chars("=> ");
+    multi.add(
+        indicatif::ProgressBar::new(len as u64)
+            .with_style(style)
+            .with_message(message),
+    )
+}
+
+/// Log written while the UI of `browse` is shown, printed after it is closed
+static HELD_LOG: std::sync::Mutex<Option<Vec<u8>>> = std::sync::Mutex::new(None);
+
+/// Writes the log to stdout, hiding the progress bars while writing.
+struct LogWriter;
+
+impl std::io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(held) = HELD_LOG.lock().unwrap().as_mut() {
+            held.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+        let write = || std::io::stdout().write_all(buf).map(|()| buf.len());
+        match PROGRESS.get() {
+            Some(multi) => multi.suspend(write),
+            None => write(),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+/// Calls `f` with chunks of at most [`INFO_CHUNK`] ids and collects the results.
+fn chunked<T>(
+    ids: &[usize],
+    message: &'static str,
+    f: impl Fn(&[usize]) -> Result<Vec<T>>,
+) -> Result<Vec<T>> {
+    let bar = progress_bar(ids.len(), message);
+    let mut results = Vec::with_capacity(ids.len());
+    for chunk in ids.chunks(INFO_CHUNK) {
+        results.extend(f(chunk)?);
+        bar.inc(chunk.len() as u64);
+    }
+    bar.finish_and_clear();
+    Ok(results)
+}
+
+/// What [`get_notes`] fetches about the cards of the notes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CardDetails {
+    /// Only the decks, without rendering the cards.
+    /// [`Note::card_ords`] stays empty.
+    Decks,
+    /// Template ordinals of the rendered cards
+    Full,
+}
+
+/// Sets [`Note::mod_time`] of the stored `notes`.
+///
+/// [`get_notes`] doesn't fetch them, as only printing the notes needs them.
+fn add_mod_times(notes: &mut [Note]) -> Result<()> {
+    let ids = notes.iter().filter_map(|note| note.id).collect::<Vec<_>>();
+    let mod_times = chunked(&ids, "getting modification times", notes_mod_time)?
+        .into_iter()
+        .map(|m| (m.note_id, m.mod_time))
+        .collect::<HashMap<_, _>>();
+    for note in notes {
+        note.mod_time = note.id.and_then(|id| mod_times.get(&id).copied());
+    }
+    Ok(())
+}
+
+/// Deck and template ordinal of a card
+struct CardSummary {
+    deck: String,
+    ord: Option<i32>,
+}
+
+fn get_notes(query: &str, details: CardDetails) -> Result<Vec<Note>> {
     let ids = find_notes(query)?;
     info!("getting {} notes", ids.len());
-    let notes = notes_info(&ids)?;
+    get_notes_by_ids(&ids, details)
+}
+
+fn get_notes_by_ids(ids: &[usize], details: CardDetails) -> Result<Vec<Note>> {
+    let notes = chunked(ids, "getting notes", notes_info)?;
     debug!("got notes");
     let card_ids = notes
         .iter()
         .flat_map(|note_info| note_info.cards.clone())
         .collect::<Vec<_>>();
-    debug!("getting card info of {} cards", card_ids.len());
-    let cards = cards_info(&card_ids)?;
+    let mut cards = match details {
+        CardDetails::Full => {
+            debug!("getting card info of {} cards", card_ids.len());
+            chunked(&card_ids, "getting cards", cards_info)?
+                .into_iter()
+                .map(|card| {
+                    let summary = CardSummary {
+                        deck: card.deck_name,
+                        ord: Some(card.ord),
+                    };
+                    (card.card_id, summary)
+                })
+                .collect::<HashMap<_, _>>()
+        }
+        CardDetails::Decks => {
+            debug!("getting decks of {} cards", card_ids.len());
+            chunked(&card_ids, "getting decks", |chunk| {
+                Ok(get_decks(chunk)?
+                    .into_iter()
+                    .flat_map(|(deck, cards)| {
+                        cards.into_iter().map(move |card| {
+                            let summary = CardSummary {
+                                deck: deck.clone(),
+                                ord: None,
+                            };
+                            (card, summary)
+                        })
+                    })
+                    .collect())
+            })?
+            .into_iter()
+            .collect::<HashMap<_, _>>()
+        }
+    };
     debug!("got card info");
-    assert_eq!(card_ids.len(), cards.len());
-    let mut cards = cards.into_iter();
+    if cards.len() != card_ids.len() {
+        warn!(
+            "anki returned {} of {} requested cards",
+            cards.len(),
+            card_ids.len()
+        );
+    }
 
     notes
         .into_iter()
@@ -99,35 +260,339 @@ fn get_notes(query: &str) -> Result<Vec<Note>> {
                 .map(|(name, field)| (name, field.value))
                 .collect();
 
-            let mut deck_name = None;
-            let mut question = None;
-            for _ in 0..note_info.cards.len() {
-                let card = cards.next().unwrap();
-                let n = card.deck_name;
-                if let Some(name) = deck_name.as_ref() {
-                    assert_eq!(&n, name);
-                } else {
-                    deck_name = Some(n);
+            // decks of the cards with their number of cards, in the order of the cards
+            let mut decks: Vec<(String, usize)> = Vec::new();
+            let mut card_ords = Vec::new();
+            for card_id in &note_info.cards {
+                let card = cards.remove(card_id).ok_or_else(|| {
+                    eyre!(
+                        "anki returned no info of card {} of note {}",
+                        card_id,
+                        note_info.note_id
+                    )
+                    .note("the collection may have changed while the notes were fetched, try again")
+                })?;
+                match decks.iter_mut().find(|(deck, _)| *deck == card.deck) {
+                    Some((_, count)) => *count += 1,
+                    None => decks.push((card.deck, 1)),
                 }
-                question = Some(card.question);
+                card_ords.extend(card.ord);
+            }
+            if note_info.cards.is_empty() {
+                warn!(
+                    "note {} of model {} has no cards",
+                    note_info.note_id, note_info.model_name
+                );
+            }
+            // e.g. after moving single cards in anki, the note is in the deck of most
+            // cards, the first one on ties
+            let most = decks.iter().map(|(_, count)| *count).max().unwrap_or(0);
+            if let Some(idx) = decks.iter().position(|(_, count)| *count == most) {
+                let main = decks.remove(idx);
+                decks.insert(0, main);
+            }
+            let card_decks = decks.into_iter().map(|(deck, _)| deck).collect::<Vec<_>>();
+            if card_decks.len() > 1 {
+                debug!(
+                    "cards of note {} are in the decks {}, using {}",
+                    note_info.note_id,
+                    card_decks.join(", "),
+                    card_decks[0]
+                );
             }
 
             Ok(Note {
                 id: Some(note_info.note_id),
-                deck: deck_name.unwrap(),
+                deck: card_decks.first().cloned().unwrap_or_default(),
                 model: note_info.model_name,
                 fields,
                 tags: note_info.tags,
-                question,
+                first_card: note_info.cards.first().copied(),
+                mod_time: None,
+                card_ords,
+                card_decks,
+                formats: HashMap::new(),
+                protected: false,
+                options: NoteOptions::default(),
             })
         })
         .collect()
 }
 
+/// Prints `note` as fetched by [`get_notes`], with `verbose` also its cards.
+///
+/// The fields are tidied with [`tidy_field_text`] unless `normalize` is false.
+fn print_stored_note(note: &Note, verbose: bool, normalize: bool) {
+    println!("In deck '{}' with model '{}'", note.deck, note.model);
+    if note.card_decks.len() > 1 {
+        println!("Cards in the decks {}", note.card_decks.join(", "));
+    }
+    for (k, v) in &note.fields {
+        println!("[{}] {}", k, display_field(v, normalize));
+    }
+    if !note.tags.is_empty() {
+        println!("Tags: {}", note.tags.join(", "));
+    }
+    if verbose {
+        println!(
+            "Cards: {} (templates {})",
+            note.card_ords.len(),
+            note.card_ords
+                .iter()
+                .map(|ord| ord.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if let Some(modified) = note
+        .mod_time
+        .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+    {
+        println!(
+            "Modified: {}",
+            modified
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    println!("{}", "-".repeat(100));
+}
+
+/// `notes` grouped by `group_by`, sorted by the name of the group.
+///
+/// A note with several tags is in the group of each tag.
+fn group_notes(notes: &[Note], group_by: NotesGroup) -> Vec<(&str, Vec<&Note>)> {
+    let mut groups: HashMap<&str, Vec<&Note>> = HashMap::new();
+    for note in notes {
+        let keys = match group_by {
+            NotesGroup::Deck => vec![note.deck.as_str()],
+            NotesGroup::Model => vec![note.model.as_str()],
+            NotesGroup::Tag if note.tags.is_empty() => vec!["(no tags)"],
+            NotesGroup::Tag => note.tags.iter().map(String::as_str).collect(),
+        };
+        for key in keys {
+            groups.entry(key).or_default().push(note);
+        }
+    }
+
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_by(|(a, _), (b, _)| report::collate(a, b));
+    groups
+}
+
+/// Prints `notes` with [`print_stored_note`] under a heading per group.
+fn print_grouped_notes(notes: &[Note], group_by: NotesGroup, verbose: bool, normalize: bool) {
+    for (group, notes) in group_notes(notes, group_by) {
+        println!("== {} ({} notes) ==", group, notes.len());
+        for note in notes {
+            print_stored_note(note, verbose, normalize);
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref HTML_BREAK: Regex = Regex::new(r"(?i)<br\s*/?>|</div>|</p>").unwrap();
+    static ref HTML_TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+    static ref BLANK_LINES: Regex = Regex::new(r"\n[ \t]*(?:\n[ \t]*)+\n").unwrap();
+}
+
+/// Text of a field stored in anki, with the HTML removed and the LaTeX markers of
+/// anki unwrapped.
+///
+/// A `[latex]` enclosing the whole field is removed, see [`anki_tex::latex_wrapped`],
+/// `[$]` and `[$$]` become `$` and `$$`.
+fn field_text(value: &str) -> String {
+    let text = HTML_BREAK.replace_all(value, "\n");
+    let text = HTML_TAG.replace_all(&text, "");
+    let text = html_escape::decode_html_entities(&text).replace('\u{a0}', " ");
+    let text = anki_tex::latex_wrapped(&text)
+        .unwrap_or(&text)
+        .replace("[$$]", "$$")
+        .replace("[/$$]", "$$")
+        .replace("[$]", "$")
+        .replace("[/$]", "$");
+    text
+}
+
+/// Collapses runs of blank lines in `text` into one paragraph break and trims it.
+///
+/// This is only cosmetic for printing and exporting the fields, comparing notes
+/// uses its own normalization in [`Note::field_eq`].
+fn tidy_field_text(text: &str) -> String {
+    BLANK_LINES.replace_all(text, "\n\n").trim().to_owned()
+}
+
+/// [`field_text`] of `value`, tidied with [`tidy_field_text`] if `normalize` is set.
+fn display_field(value: &str, normalize: bool) -> String {
+    let text = field_text(value);
+    if normalize {
+        tidy_field_text(&text)
+    } else {
+        text
+    }
+}
+
+/// Options of `get-notes --format markdown`
+struct MarkdownOptions {
+    /// Write one file per deck into this directory instead of printing one document
+    out_dir: Option<PathBuf>,
+    /// Link each note with `anki://`
+    anki_links: bool,
+    /// Tidy the fields with [`tidy_field_text`]
+    normalize: bool,
+}
+
+/// File name of the markdown file of `deck`, without characters which aren't
+/// allowed in file names on common systems.
+fn markdown_file_name(deck: &str) -> String {
+    let name = deck
+        .split("::")
+        .map(|part| {
+            part.chars()
+                .map(|c| {
+                    if c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.') {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>()
+                .trim_matches(|c: char| c == ' ' || c == '.')
+                .to_owned()
+        })
+        .collect::<Vec<_>>()
+        .join(" - ");
+    if name.trim_matches(|c| c == ' ' || c == '-').is_empty() {
+        String::from("deck")
+    } else {
+        name
+    }
+}
+
+/// Formats `notes` as Markdown, with headings of level `level` for the notes.
+///
+/// The fields are sorted like in their model, `\[`/`\]` and `\(`/`\)` become the
+/// `$$` and `$` understood by Obsidian, see [`parse_file::dollar_math`].
+fn notes_markdown(
+    notes: &[&Note],
+    field_order: &HashMap<String, Vec<String>>,
+    level: usize,
+    options: &MarkdownOptions,
+) -> String {
+    use std::fmt::Write;
+
+    let heading = "#".repeat(level);
+    let mut out = String::new();
+    for note in notes {
+        let id = note.id.map_or(String::from("?"), |id| id.to_string());
+        let _ = writeln!(out, "{} Note {}\n", heading, id);
+        let _ = writeln!(out, "- id: {}", id);
+        let _ = writeln!(out, "- model: {}", note.model);
+        if !note.tags.is_empty() {
+            let _ = writeln!(out, "- tags: {}", note.tags.join(", "));
+        }
+        if let (true, Some(id)) = (options.anki_links, note.id) {
+            let _ = writeln!(
+                out,
+                "- link: [open in anki](anki://x-callback-url/search?query=nid:{})",
+                id
+            );
+        }
+        out.push('\n');
+
+        let mut names = note.fields.keys().collect::<Vec<_>>();
+        let order = field_order.get(&note.model);
+        names.sort_by_key(|name| {
+            (
+                order.and_then(|order| order.iter().position(|n| n == *name)),
+                name.as_str(),
+            )
+        });
+        // fields missing in the model are sorted last
+        names.sort_by_key(|name| order.is_none_or(|order| !order.contains(name)));
+        for name in names {
+            let text =
+                parse_file::dollar_math(&display_field(&note.fields[name], options.normalize));
+            if text.trim().is_empty() {
+                continue;
+            }
+            let _ = writeln!(out, "{}# {}\n\n{}\n", heading, name, text);
+        }
+    }
+    out
+}
+
+/// Writes `notes` as Markdown, one section per deck, see [`MarkdownOptions`].
+fn export_markdown(notes: &[Note], options: &MarkdownOptions) -> Result<()> {
+    let mut decks: BTreeMap<&str, Vec<&Note>> = BTreeMap::new();
+    for note in notes {
+        decks.entry(&note.deck).or_default().push(note);
+    }
+    for notes in decks.values_mut() {
+        notes.sort_by_key(|note| note.id);
+    }
+    let mut decks = decks.into_iter().collect::<Vec<_>>();
+    decks.sort_by(|(a, _), (b, _)| report::collate(a, b));
+
+    let models = notes
+        .iter()
+        .map(|note| note.model.as_str())
+        .collect::<BTreeSet<_>>();
+    let field_order = get_model_field_names_multi(models.iter().copied())?
+        .into_iter()
+        .zip(models)
+        .map(|(fields, model)| (model.to_owned(), fields.0))
+        .collect::<HashMap<_, _>>();
+
+    let Some(out_dir) = &options.out_dir else {
+        for (deck, notes) in &decks {
+            println!("## {}\n", deck);
+            print!("{}", notes_markdown(notes, &field_order, 3, options));
+        }
+        return Ok(());
+    };
+
+    std::fs::create_dir_all(out_dir)
+        .with_note(|| eyre!("while creating directory {}", out_dir.to_string_lossy()))?;
+    let mut used_names = HashSet::new();
+    for (deck, notes) in &decks {
+        let base = markdown_file_name(deck);
+        // decks may only differ in removed characters
+        let name = (1..)
+            .map(|i| match i {
+                1 => format!("{}.md", base),
+                i => format!("{} ({}).md", base, i),
+            })
+            .find(|name| used_names.insert(name.to_lowercase()))
+            .expect("infinite iterator");
+        let path = out_dir.join(&name);
+        let content = format!(
+            "# {}\n\n{}",
+            deck,
+            notes_markdown(notes, &field_order, 2, options)
+        );
+        std::fs::write(&path, content)
+            .with_note(|| eyre!("while writing file {}", path.to_string_lossy()))?;
+        debug!("wrote {} notes of deck {} to {}", notes.len(), deck, name);
+    }
+    info!(
+        "exported {} notes of {} decks to {}",
+        notes.len(),
+        decks.len(),
+        out_dir.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Prefix of the tags added by `tag_source_file`
+const SOURCE_TAG_PREFIX: &str = "source::";
+
 struct FilePaths {
     main: PathBuf,
     anki: PathBuf,
     custom: PathBuf,
+    /// Directory the source tags are relative to
+    root: PathBuf,
 }
 
 impl FilePaths {
@@ -137,63 +602,331 @@ impl FilePaths {
             .ok_or_else(|| eyre!("{} has no parent", main.to_string_lossy()))?;
         let anki = parent.join("ankitex.sty");
         let custom = parent.join("custom.sty");
+        let root = if main.is_dir() {
+            main.clone()
+        } else {
+            parent.to_owned()
+        };
 
-        Ok(Self { main, anki, custom })
+        Ok(Self {
+            main,
+            anki,
+            custom,
+            root,
+        })
+    }
+
+    /// Directory of the main file, which LaTeX resolves the files included by it in
+    fn dir(&self) -> &Path {
+        self.main.parent().unwrap_or(Path::new(""))
+    }
+
+    /// Tag derived from the path of `main` relative to `root`,
+    /// e.g. `source::src::chapter1` for `src/chapter1.tex`.
+    fn source_tag(&self) -> String {
+        source_tag(&self.root, &self.main)
     }
 }
 
-fn create_template(config: &Config, paths: &FilePaths, force: bool) -> Result<()> {
-    use std::io::Write;
+/// Path of the main file, from the `--file` of the subcommand, the global `--path`
+/// or `anki.tex`, the latter two relative to `path` of the config.
+fn main_path(file: Option<&Path>, path: Option<&Path>, config_dir: Option<&Path>) -> PathBuf {
+    let (child, source) = match (file, path) {
+        (Some(file), _) => {
+            debug!("using main file {} from `--file`", file.to_string_lossy());
+            return file.to_owned();
+        }
+        (None, Some(path)) => (path, "`--path`"),
+        (None, None) => (Path::new("anki.tex"), "the default"),
+    };
+    match config_dir {
+        Some(dir) if child.is_relative() => {
+            let main = dir.join(child);
+            debug!(
+                "using main file {} from {} in `path` of the config",
+                main.to_string_lossy(),
+                source
+            );
+            main
+        }
+        _ => {
+            debug!(
+                "using main file {} from {}",
+                child.to_string_lossy(),
+                source
+            );
+            child.to_owned()
+        }
+    }
+}
+
+fn source_tag(root: &Path, file: &Path) -> String {
+    let relative = file.strip_prefix(root).unwrap_or(file).with_extension("");
+    let slug = relative
+        .components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(part) => {
+                Some(part.to_string_lossy().replace(char::is_whitespace, "_"))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("::");
+    format!("{}{}", SOURCE_TAG_PREFIX, slug)
+}
+
+/// Warns about stored notes whose source tag doesn't belong to any of `files`,
+/// e.g. because the file was renamed or deleted. Notes with `keep_tag` are skipped.
+fn warn_orphaned_sources(config: &Config, state: &State, root: &Path, files: &[PathBuf]) {
+    let known = files
+        .iter()
+        .map(|file| source_tag(root, file))
+        .collect::<HashSet<_>>();
+
+    let mut orphans: BTreeMap<&str, Vec<&Note>> = BTreeMap::new();
+    for note in state
+        .added_notes
+        .iter()
+        .filter(|note| !config.is_kept(note))
+    {
+        for tag in &note.tags {
+            if tag.starts_with(SOURCE_TAG_PREFIX) && !known.contains(tag) {
+                orphans.entry(tag).or_default().push(note);
+            }
+        }
+    }
+
+    let mut orphans = orphans.into_iter().collect::<Vec<_>>();
+    orphans.sort_by(|(a, _), (b, _)| report::collate(a, b));
+    for (tag, notes) in orphans {
+        warn!(
+            "{} notes are tagged with {} but there is no such file anymore",
+            notes.len(),
+            tag
+        );
+        for note in notes {
+            info!(
+                "  in deck {}: {:?}",
+                note.deck,
+                Note::question_or_fields(note.first_card, &note.fields).resolve()
+            );
+        }
+    }
+}
+
+/// Content of the main file created by `template`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum TemplateVariant {
+    /// Only the header and the footer
+    #[default]
+    Minimal,
+    /// An example note of each syntax with comments
+    Standard,
+    /// Several realistic notes in two decks
+    Examples,
+}
+
+impl TemplateVariant {
+    /// Content between the header and the footer
+    fn body(self) -> &'static str {
+        match self {
+            Self::Minimal => "\n% Add your content here\n\n",
+            Self::Standard => parse_file::STANDARD_TEMPLATE_BODY,
+            Self::Examples => parse_file::EXAMPLES_TEMPLATE_BODY,
+        }
+    }
+}
+
+/// Contents of the files written by `template`
+struct TemplateFiles {
+    main: String,
+    anki: String,
+    custom: String,
+}
+
+impl TemplateFiles {
+    fn new(parse: &parse_file::ParseOptions, ankitex: &str, variant: TemplateVariant) -> Self {
+        Self {
+            main: [
+                parse.frame.header.as_str(),
+                variant.body(),
+                parse.frame.footer.as_str(),
+            ]
+            .concat(),
+            anki: ankitex.to_owned(),
+            custom: parse_file::CUSTOM_TEMPLATE.to_owned(),
+        }
+    }
+
+    /// Checks that the files of `variant` are accepted when reading them again, so the
+    /// header and footer of the config still fit the shipped templates.
+    ///
+    /// The main file of [`TemplateVariant::Minimal`] must have no notes, the warning
+    /// about the missing notes is expected for it.
+    fn validate(
+        parse: &parse_file::ParseOptions,
+        ankitex: &str,
+        variant: TemplateVariant,
+    ) -> Result<()> {
+        let template = Self::new(parse, ankitex, variant);
+        parse_file::check_ankitex_content("ankitex.sty", &template.anki, ankitex).into_result()?;
+        parse_file::check_custom_content("custom.sty", &template.custom).into_result()?;
+        let outcome = parse_file::parse_content(template.main, parse)
+            .with_note(|| eyre!("while parsing the {:?} template", variant))?;
+        if let Some(warning) = outcome.warnings.iter().find(|warning| {
+            !(variant == TemplateVariant::Minimal
+                && warning.class() == parse_file::WarningClass::NoNotes)
+        }) {
+            return Err(eyre!(
+                "the {:?} template gives the warning: {}",
+                variant,
+                warning
+            ));
+        }
+        if variant == TemplateVariant::Minimal && !outcome.notes.is_empty() {
+            return Err(eyre!(
+                "the {:?} template has {} notes instead of none",
+                variant,
+                outcome.notes.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Options of `template`
+struct TemplateOptions {
+    /// Overwrite existing files
+    force: bool,
+    /// Content of the main file, `template_variant` of the config if not set
+    variant: Option<TemplateVariant>,
+    /// Only print which files would be written
+    dry_run: bool,
+    /// Create missing parent directories of the files
+    create_dirs: bool,
+    /// Allow files outside of `path` of the config
+    allow_outside_root: bool,
+}
+
+/// `path` as absolute path without `.` and `..`, with the symlinks of its existing
+/// ancestors resolved.
+fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    let mut existing = resolved.as_path();
+    while !existing.exists() {
+        let Some(parent) = existing.parent() else {
+            return Ok(resolved);
+        };
+        existing = parent;
+    }
+    let canonical = existing.canonicalize()?;
+    let rest = resolved.strip_prefix(existing)?;
+    Ok(match rest.as_os_str().is_empty() {
+        true => canonical,
+        false => canonical.join(rest),
+    })
+}
 
-    let main = [
-        parse_file::HEADER,
-        "\n% Add your content here\n\n",
-        parse_file::FOOTER,
-    ];
-    let anki = [parse_file::ANKITEX];
-    let custom = [parse_file::CUSTOM_TEMPLATE];
-
-    let files: &[(&Path, &[&str])] = &[
-        (&paths.main, &main),
-        (&paths.anki, &anki),
-        (&paths.custom, &custom),
-    ];
-    for (filepath, content) in files {
+/// Writes the template files to `paths`, see [`TemplateOptions`].
+///
+/// All files are checked before the first one is written.
+fn create_template(config: &Config, paths: &FilePaths, options: &TemplateOptions) -> Result<()> {
+    let template = TemplateFiles::new(
+        &config.parse,
+        &config.ankitex,
+        options.variant.unwrap_or(config.template_variant),
+    );
+    let root = config.path.as_deref().map(resolve_path).transpose()?;
+    let mut files = Vec::new();
+    let mut missing_dirs = BTreeSet::new();
+    for (filepath, content) in [
+        (&paths.main, template.main),
+        (&paths.anki, template.anki),
+        (&paths.custom, template.custom),
+    ] {
         if config.is_ignored(&filepath.to_string_lossy()) {
             return Err(eyre!("template file is excluded"));
         }
-
-        if filepath.is_file() {
-            if force {
-                warn!("overwriting file {}", filepath.to_string_lossy());
-            } else {
+        let resolved = resolve_path(filepath)?;
+        if let Some(root) = root.as_ref().filter(|_| !options.allow_outside_root) {
+            if !resolved.starts_with(root) {
                 return Err(eyre!(
-                    "file {} already exists. Use `--force` to overwrite",
-                    filepath.to_string_lossy()
-                ));
+                    "{} is outside of {} set as `path` in the config",
+                    resolved.to_string_lossy(),
+                    root.to_string_lossy()
+                )
+                .suggestion("check `--file` and `--path` or use `--allow-outside-root`"));
             }
         }
-        if filepath.is_dir() {
+        if resolved.is_dir() {
             return Err(eyre!(
                 "Cannot create file {}. There is a folder with the same name",
-                filepath.to_string_lossy()
+                resolved.to_string_lossy()
+            ));
+        }
+        let exists = resolved.is_file();
+        if exists && !options.force {
+            return Err(eyre!(
+                "file {} already exists. Use `--force` to overwrite",
+                resolved.to_string_lossy()
             ));
         }
-        let mut file = std::fs::File::create(filepath)?;
-        for c in *content {
-            file.write_all(c.as_bytes())?;
+        let dir = resolved.parent().unwrap_or(Path::new("/")).to_owned();
+        if !dir.is_dir() {
+            if !options.create_dirs {
+                return Err(eyre!("directory {} doesn't exist", dir.to_string_lossy())
+                    .suggestion("create it or use `--create-dirs`"));
+            }
+            missing_dirs.insert(dir);
+        }
+        files.push((resolved, content, exists));
+    }
+    let anki = &files[1].0;
+
+    if options.dry_run {
+        for dir in &missing_dirs {
+            println!("would create directory {}", dir.to_string_lossy());
+        }
+        for (path, _, exists) in &files {
+            let action = if *exists { "overwrite" } else { "create" };
+            println!("would {} {}", action, path.to_string_lossy());
+        }
+        println!("would mark {} as readonly", anki.to_string_lossy());
+        return Ok(());
+    }
+
+    for dir in &missing_dirs {
+        info!("creating directory {}", dir.to_string_lossy());
+        std::fs::create_dir_all(dir)
+            .with_note(|| eyre!("while creating directory {}", dir.to_string_lossy()))?;
+    }
+    for (path, content, exists) in &files {
+        if *exists {
+            warn!("overwriting file {}", path.to_string_lossy());
         }
+        std::fs::write(path, content)
+            .with_note(|| eyre!("while writing file {}", path.to_string_lossy()))?;
     }
 
     {
         debug!("marking `ankitex.sty` as readonly");
-        if let Ok(m) = std::fs::metadata(&paths.anki) {
+        if let Ok(m) = std::fs::metadata(anki) {
             let mut perms = m.permissions();
             perms.set_readonly(true);
-            if let Err(e) = std::fs::set_permissions(&paths.anki, perms) {
+            if let Err(e) = std::fs::set_permissions(anki, perms) {
                 info!(
                     "failed to mark {} as readonly: {}",
-                    paths.anki.to_string_lossy(),
+                    anki.to_string_lossy(),
                     e
                 );
             }
@@ -203,522 +936,9204 @@ fn create_template(config: &Config, paths: &FilePaths, force: bool) -> Result<()
     Ok(())
 }
 
-fn fmt_content(content: &String) -> String {
-    format!(
-        "[latex]{}[/latex]",
-        content //.replace("\\]", "$$").replace("\\[", "$$")
+/// `content` of a field as it is sent to anki.
+///
+/// Fields which are already wrapped in the wrapper of their format are only wrapped
+/// again with `double_wrap = "force"`.
+fn fmt_content(config: &Config, format: FieldFormat, content: &str) -> String {
+    wrap_content(
+        format,
+        content,
+        config.double_wrap,
+        config.matching.math_delimiters,
     )
 }
 
-fn update_change(state: &mut State, config: &Config, paths: &FilePaths) -> Result<()> {
-    if config.is_ignored(&paths.main.to_string_lossy()) {
-        return Ok(());
-    }
-    if paths.main.is_dir() {
-        debug!(
-            "{} is a directory. Updating children instead",
-            paths.main.to_string_lossy()
-        );
-        let children = std::fs::read_dir(&paths.main).with_note(|| {
-            eyre!(
-                "while collecting children of {}",
-                paths.main.to_string_lossy()
-            )
-        })?;
-        for read_dir in children {
-            // TODO is this correct or should the anki and custom path be changed as well?
-            let new_paths = FilePaths {
-                main: read_dir?.path(),
-                anki: paths.anki.clone(),
-                custom: paths.custom.clone(),
+/// [`fmt_content`] with the settings of the config passed explicitly
+fn wrap_content(
+    format: FieldFormat,
+    content: &str,
+    double_wrap: DoubleWrap,
+    math_delimiters: anki_tex::MathDelimiters,
+) -> String {
+    match format {
+        FieldFormat::Latex | FieldFormat::Mathjax => {
+            let content = match format.unwrap(content) {
+                Some(inner) if double_wrap != DoubleWrap::Force => inner,
+                _ => content,
             };
-            update_change(state, config, &new_paths)?;
+            format.wrap(&parse_file::convert_math_delimiters(
+                content,
+                math_delimiters,
+            ))
         }
+        FieldFormat::Raw => content.to_owned(),
+    }
+}
 
-        return Ok(());
+/// Inverse of [`fmt_content`]
+fn unfmt_content(format: FieldFormat, content: &str) -> &str {
+    format.unwrap(content).unwrap_or(content)
+}
+
+fn to_api_note(note: &Note) -> anki_tex::api::Note {
+    anki_tex::api::Note {
+        deck_name: note.deck.clone(),
+        model_name: note.model.clone(),
+        fields: note.fields.clone(),
+        tags: note.tags.clone(),
+        options: note
+            .options
+            .allow_duplicate()
+            .then_some(anki_tex::api::AddNoteOptions {
+                allow_duplicate: true,
+            }),
     }
-    let main_content = read_to_string(&paths.main)
-        .with_note(|| eyre!("while reading file {}", paths.main.to_string_lossy()))?;
+}
 
-    parse_file::check_ankitex_template(&paths.anki)?;
+/// Environment variable with the path of the config file, used instead of the
+/// config directory of the user
+const CONFIG_ENV: &str = "ANKI_TEX_CONFIG";
+/// Environment variable with the directory of the cache and the run log
+const CACHE_DIR_ENV: &str = "ANKI_TEX_CACHE_DIR";
 
-    // TODO do something with paths.custom. E.g. check that it is correctly set as template
+/// Default of `generated_tag`
+const DEFAULT_GENERATED_TAG: &str = "generated";
+/// Default of `keep_tag`
+const DEFAULT_KEEP_TAG: &str = "anki-tex::keep";
 
-    let new_main_hash = fasthash::metro::hash64(&main_content);
-    let new_custom_hash = fasthash::metro::hash64(&main_content);
-    if new_main_hash != state.last_main_hash {
-        state.last_main_hash = new_main_hash;
-    } else if new_custom_hash != state.last_custom_hash {
-        state.last_custom_hash = new_custom_hash;
-    } else {
-        debug!("nothing changed");
-        return Ok(());
+/// Warns if `generated_tag` changed since the last run and stored notes still
+/// have the old tag. The old tag is the default if no run was recorded.
+fn check_generated_tag(config: &Config, notes: &[Note]) {
+    if !config.add_generated {
+        return;
     }
-    info!("updating changes from {}", paths.main.to_string_lossy());
-    state.reload()?;
-
-    let mut note_decks: HashMap<String, (Vec<_>, Vec<_>)> = HashMap::new();
+    let cached = CachedState::load(&config.cache_file).generated_tag;
+    let old = cached
+        .clone()
+        .unwrap_or_else(|| String::from(DEFAULT_GENERATED_TAG));
+    if old != config.generated_tag {
+        let count = notes.iter().filter(|n| n.tags.contains(&old)).count();
+        if count != 0 {
+            warn!(
+                "`generated_tag` changed from `{}` to `{}`, but {} notes in anki still have the tag `{}`",
+                old, config.generated_tag, count, old
+            );
+            info!(
+                "rename the tag in anki's browser if the notes should have the new tag, e.g. by searching for `tag:{}`",
+                old
+            );
+        }
+    }
+    if cached.as_ref() != Some(&config.generated_tag) {
+        let saved = CachedState::update(&config.cache_file, |cache| {
+            cache.generated_tag = Some(config.generated_tag.clone());
+        });
+        if let Err(e) = saved {
+            warn!("failed to save the generated tag: {:#}", e);
+        }
+    }
+}
 
-    debug!("parsing file {}", paths.main.to_string_lossy());
-    let notes = parse_file::get_content(main_content)?;
-    debug!("finished parsing file");
+/// Tag with the generation date added to new notes, set by `generation_date` of the
+/// config or `--generation-date`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum GenerationDate {
+    /// The current date
+    #[default]
+    Auto,
+    /// No tag
+    None,
+    /// A fixed date, e.g. when backfilling old notes
+    Date(chrono::NaiveDate),
+}
 
-    debug!("checking notes");
-    for mut note in notes {
-        let Some(model) = state.models.get(&note.model) else {
-            error!("create note with invalid model name {}", note.model);
-            return Ok(());
+impl GenerationDate {
+    /// The tag, formatted with [`anki_tex::GENERATION_DATE_FORMAT`]
+    fn tag(self) -> Option<String> {
+        let date = match self {
+            Self::Auto => chrono::Local::now().date_naive(),
+            Self::None => return None,
+            Self::Date(date) => date,
         };
-        for field_name in note.fields.keys() {
-            if !model.field_names.contains(field_name) {
-                error!(
-                    "model {} does not contain field `{}`",
-                    note.model, field_name
-                );
-                info!("field names: {}", model.field_names.join(", "));
-                return Ok(());
-            }
-        }
+        Some(date.format(anki_tex::GENERATION_DATE_FORMAT).to_string())
+    }
+}
 
-        if config.add_generated {
-            note.tags.push(String::from("generated"));
-        }
+impl std::str::FromStr for GenerationDate {
+    type Err = String;
 
-        if let Some(date) = &config.add_generation_date {
-            note.tags.push(date.clone());
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "none" => Ok(Self::None),
+            date => chrono::NaiveDate::parse_from_str(date, anki_tex::GENERATION_DATE_FORMAT)
+                .map(Self::Date)
+                .map_err(|e| {
+                    format!(
+                        "invalid generation date `{}` ({}), expected `auto`, `none` or a date like `2024-03-01` (YYYY-MM-DD)",
+                        date, e
+                    )
+                }),
         }
+    }
+}
 
-        if state.added_notes.contains(&note) {
-            continue;
-        }
+impl TryFrom<String> for GenerationDate {
+    type Error = String;
 
-        for field in note.fields.values_mut() {
-            *field = fmt_content(field);
-        }
-        let api_note = anki_tex::api::Note {
-            deck_name: note.deck.clone(),
-            model_name: note.model.clone(),
-            fields: note.fields.clone(),
-            tags: note.tags.clone(),
-        };
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
 
-        let (notes, api_notes) = note_decks.entry(note.deck.clone()).or_default();
-        notes.push(note);
-        api_notes.push(api_note);
+/// Adds the tags configured to be added to every note and returns them.
+fn add_auto_tags(config: &Config, note: &mut Note) -> Vec<String> {
+    let mut tags = Vec::new();
+    if config.add_generated {
+        tags.push(config.generated_tag.clone());
     }
-    debug!("checked notes");
+    if let Some(date) = config.generation_date.tag() {
+        tags.push(date);
+    }
+    // tags which the note already has aren't added again
+    let existing = TagSet::with_tags(config.tag_case, &note.tags);
+    tags.retain(|tag| !existing.contains(tag));
+    note.add_tags(&tags, config.tag_case);
+    tags
+}
 
-    let mut global_added_notes = 0;
+/// Prints the notes of the file after all transformations which are applied before
+/// sending them to anki.
+fn show_notes(
+    config: &Config,
+    paths: &FilePaths,
+    index: Option<usize>,
+    grep: Option<&str>,
+) -> Result<()> {
+    let main_content = read_to_string(&paths.main)
+        .with_note(|| eyre!("while reading file {}", paths.main.to_string_lossy()))?;
+    let outcome = parse_file::parse_content(main_content, &config.parse)?;
+    config.handle_parse_warnings(&outcome.warnings)?;
+    let mut notes = outcome.notes;
+    config.select_decks(&mut notes, &paths.main);
+    let model_sources = config.select_models(&mut notes)?;
 
-    for (deck, (notes, api_notes)) in note_decks {
-        // TODO id
-        if !state.deck_names.contains(&deck) {
-            error!("create note with invalid deck name {}", deck);
-            info!("create all decks in the file with `anki-tex create-all-decks`");
-            return Ok(());
+    let models = match State::load_models() {
+        Ok(models) => Some(models),
+        Err(e) => {
+            warn!("can't load models from anki, models and fields are unvalidated");
+            debug!("{:?}", e);
+            None
         }
-        info!("creating {} notes in deck {}", notes.len(), deck);
+    };
 
-        let mut duplicates = 0;
-        let mut added_notes = 0;
-        let ids = add_notes(&api_notes)?;
-        for (id, mut note) in ids.into_iter().zip(notes) {
-            if id.is_none() {
-                duplicates += 1;
-                debug!(
-                    "Duplicate! Note in deck {} with fields {:?} already existed",
-                    &note.deck,
-                    Note::question_or_fields(&note.question, &note.fields),
-                );
-            } else {
-                added_notes += 1;
-                debug!(
-                    "created note in deck {} with fields {:?}",
-                    &note.deck,
-                    Note::question_or_fields(&note.question, &note.fields),
-                );
+    let mut shown = 0;
+    for (i, (mut note, model_source)) in notes.into_iter().zip(model_sources).enumerate() {
+        let number = i + 1;
+        if index.is_some_and(|index| index != number) {
+            continue;
+        }
+        if let Some(grep) = grep {
+            if !note.fields.values().any(|v| v.contains(grep)) {
+                continue;
             }
-            note.id = id;
-            state.added_notes.push(note);
         }
+        shown += 1;
 
-        if duplicates != 0 {
-            info!(
-                "Duplicates! {} Notes in deck {} already existed",
-                duplicates, deck
-            );
+        check_field_sizes(config, &note)?;
+        if config.normalize_unicode {
+            note.normalize_unicode();
         }
-        if added_notes != 0 {
-            info!("added {} new notes in deck {}", added_notes, deck);
-            global_added_notes += added_notes;
+        note.normalize_tags(config.tag_case);
+        let mut auto_tags = add_auto_tags(config, &mut note);
+        if config.tag_source_file {
+            auto_tags.push(paths.source_tag());
+            note.add_tags([paths.source_tag()], config.tag_case);
         }
-    }
-
-    if global_added_notes == 0 {
-        info!("nothing to do :)");
-    } else {
-        info!("added {} new notes", global_added_notes);
-    }
-
-    Ok(())
-}
-
-fn watch(config: &Config, paths: &FilePaths) -> Result<()> {
-    let mut state = State::new()?;
-    update_change(&mut state, config, paths)?;
 
-    let (tx, rx) = std::sync::mpsc::channel();
+        let model = models.as_ref().map(|models| models.get(&note.model));
+        println!("Note #{}", number);
+        println!("Deck: {}", note.deck);
+        let status = match model {
+            None => Some("unvalidated"),
+            Some(None) => Some("does not exist"),
+            Some(Some(_)) => None,
+        };
+        let details = status
+            .map(str::to_owned)
+            .into_iter()
+            .chain(model_source)
+            .collect::<Vec<_>>();
+        if details.is_empty() {
+            println!("Model: {}", note.model);
+        } else {
+            println!("Model: {} ({})", note.model, details.join(", "));
+        }
+        let tags = note
+            .tags
+            .iter()
+            .map(|tag| {
+                if auto_tags.contains(tag) {
+                    format!("{} (auto)", tag)
+                } else {
+                    tag.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        println!("Tags: {}", tags.join(", "));
 
-    let mut watcher = notify::recommended_watcher(tx)?;
-    watcher.watch(&paths.main, RecursiveMode::Recursive)?;
-    watcher.watch(&paths.custom, RecursiveMode::NonRecursive)?;
+        let mut field_names = note.fields.keys().collect::<Vec<_>>();
+        match model {
+            Some(Some(model)) => field_names.sort_by_key(|name| {
+                model
+                    .field_names
+                    .iter()
+                    .position(|n| n == *name)
+                    .unwrap_or(usize::MAX)
+            }),
+            _ => field_names.sort(),
+        }
+        for name in field_names {
+            let status = match model {
+                None => " (unvalidated)",
+                Some(Some(model)) if model.field_names.contains(name) => "",
+                Some(_) => " (not in model)",
+            };
+            let format = note.format(name);
+            println!("[{}]{} format {}", name, status, format.name());
+            println!("{}", fmt_content(config, format, &note.fields[name]));
+        }
 
-    info!("You can exit with Ctrl+C");
-    for res in rx {
-        let event: Event = res?;
-        match event.kind {
-            EventKind::Access(_) => {}
-            EventKind::Create(_) => error!("file was created but should have existed before"),
-            // TODO finer
-            EventKind::Modify(_) => {
-                if let Err(e) = update_change(&mut state, config, paths) {
-                    error!("{:#?}", e);
-                }
-            }
-            EventKind::Any | EventKind::Other => {
-                error!("unknown file watcher event: {:?}", event);
-            }
-            EventKind::Remove(_) => {
-                // TODO is this necessary?
-                watcher.watch(&paths.main, RecursiveMode::Recursive)?;
-                watcher.watch(&paths.custom, RecursiveMode::NonRecursive)?;
-                if !paths.main.is_file() {
-                    error!("file was removed.")
-                } else if let Err(e) = update_change(&mut state, config, paths) {
-                    error!("{}", e);
-                }
+        let hash_field = config.matching.hash_field.as_ref().filter(|hash_field| {
+            matches!(model, Some(Some(model)) if model.field_names.contains(hash_field))
+        });
+        match (hash_field, model) {
+            (Some(hash_field), _) => {
+                println!(
+                    "Matching key: {}:{}",
+                    hash_field,
+                    note.content_hash(&config.matching)
+                )
             }
+            (None, Some(Some(model))) => match model.field_names.first() {
+                Some(first) => println!(
+                    "Matching key: {} with {} = {:?}",
+                    note.model,
+                    first,
+                    note.fields
+                        .get(first)
+                        .map(|value| fmt_content(config, note.format(first), value))
+                        .unwrap_or_default()
+                ),
+                None => println!("Matching key: none (model has no fields)"),
+            },
+            (None, _) => println!("Matching key: unvalidated"),
         }
+        println!("{}", "-".repeat(100));
     }
 
-    info!("Exiting");
+    println!("showed {} notes", shown);
 
     Ok(())
 }
 
-/// Create Anki notes from file
-#[derive(clap::Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Path of the file to read from.
-    ///
-    /// If no value is given and no config file exists `anki.tex` will be used.
-    #[arg(short, long)]
-    path: Option<PathBuf>,
-
-    /// Log Level
-    #[arg(long, default_value = "info")]
-    log_level: Level,
-    /// Use short log output
-    #[arg(long)]
-    short_log: bool,
-    /// Add a tag with the value `generated` for each new note.
-    #[arg(long, default_value = "true")]
-    add_generated: bool,
-    /// Add a tag with the value `generated@$date` for each new note.
-    #[arg(long, default_value = "true")]
-    add_generation_date: bool,
-
-    #[command(subcommand)]
-    subcommand: Commands,
+/// What `create` would do with a note shown by `browse`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BrowseStatus {
+    New,
+    Update,
+    /// Differs from the stored note, but is protected by `\keep` or `keep_tag`
+    Protected,
+    Unchanged,
+    Error(String),
 }
 
-#[derive(Debug, clap::Subcommand)]
-enum Commands {
-    /// Save the template files (`anki.tex`, `ankitex.sty` and `custom.sty`) to the project directory.
-    Template {
-        /// Whether to overwrite the file if it exists
-        #[arg(short, long)]
-        force: bool,
-    },
-    /// Watch for changes and create new notes
-    Watch,
-    /// Create new notes
-    #[clap(visible_alias = "c")]
-    Create,
-    /// Create all decks in the file if they don't exist already
-    CreateAllDecks,
-    /// Get all deck names
-    GetDecks,
-    /// Get all model names
-    GetModels,
-    /// Get all Notes for the given query
-    GetNotes {
-        /// See https://docs.ankiweb.net/searching.html
-        #[arg(default_value = "*")]
-        query: String,
-    },
-    /// Render all latex
-    #[clap(visible_alias = "r")]
-    Render,
-    /// Sync all notes to ankiweb
-    #[clap(visible_alias = "s")]
-    Sync,
-    /// Create, render and sync all notes to ankiweb
-    Crs,
+impl BrowseStatus {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::New => "new",
+            Self::Update => "update",
+            Self::Protected => "protected",
+            Self::Unchanged => "unchanged",
+            Self::Error(_) => "error",
+        }
+    }
 }
 
-#[derive(Debug)]
-struct RegexString {
-    re: Regex,
-    re_str: String,
+/// A note of the file in `browse`
+struct BrowseEntry {
+    /// The note as parsed from the file
+    note: Note,
+    status: BrowseStatus,
+    diagnostics: Vec<String>,
+    marked: bool,
 }
 
-impl<'de> Deserialize<'de> for RegexString {
-    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        use serde::de::Error;
-        let re_str: String = serde::Deserialize::deserialize(deserializer)?;
-
-        let re = Regex::new(&re_str).map_err(D::Error::custom)?;
-
-        Ok(Self { re, re_str })
-    }
+/// State of the terminal UI of `browse`
+struct Browser {
+    entries: Vec<BrowseEntry>,
+    /// Notes to create as they are sent to anki by their index in the file
+    new: HashMap<usize, Note>,
+    table: ratatui::widgets::TableState,
+    /// Whether the selected note is shown instead of the list
+    detail: bool,
+    /// Result of the last action, shown in the status line
+    message: String,
 }
 
-struct Config {
-    path: Option<PathBuf>,
-    file_include: Vec<RegexString>,
-    file_exclude: Vec<RegexString>,
-    add_generated: bool,
-    add_generation_date: Option<String>,
-}
+impl Browser {
+    /// Parses the file and computes the status of the notes like `create --dry-run`.
+    fn load(state: &mut State, config: &Config, paths: &FilePaths) -> Result<Self> {
+        let content = read_to_string(&paths.main)
+            .with_note(|| eyre!("while reading file {}", paths.main.to_string_lossy()))?;
+        let outcome = parse_file::parse_content(content, &config.parse)?;
+        config.handle_parse_warnings(&outcome.warnings)?;
+        let mut notes = outcome.notes;
+        config.select_decks(&mut notes, &paths.main);
+        config.select_models(&mut notes)?;
+        state.reload()?;
+        let plan = plan_notes(state, config, paths, notes.clone(), |i| i, false)?;
 
-impl Config {
-    fn load(add_generated: bool, add_generation_date: Option<String>) -> Result<Self> {
-        #[derive(Default, serde::Deserialize)]
-        struct ExternalConfig {
-            path: Option<PathBuf>,
-            #[serde(default)]
-            file_include: Vec<RegexString>,
-            #[serde(default)]
-            file_exclude: Vec<RegexString>,
+        let mut entries = notes
+            .into_iter()
+            .map(|note| BrowseEntry {
+                note,
+                status: BrowseStatus::Unchanged,
+                diagnostics: Vec::new(),
+                marked: false,
+            })
+            .collect::<Vec<_>>();
+        for diagnostic in &plan.diagnostics {
+            if let Some(entry) = diagnostic.note_index.and_then(|i| entries.get_mut(i)) {
+                let severity = match diagnostic.severity {
+                    report::Severity::Error => "error",
+                    report::Severity::Warning => "warning",
+                };
+                entry
+                    .diagnostics
+                    .push(format!("{}: {}", severity, diagnostic.message));
+            }
         }
-
-        let project_dirs = directories_next::ProjectDirs::from("", "akida", "anki-tex")
-            .expect("no valid home directory path could be found");
-        let config_dir = project_dirs.config_dir();
-        if !config_dir.is_dir() {
-            std::fs::create_dir_all(config_dir)?;
+        for &i in &plan.invalid {
+            let message = entries[i].diagnostics.first().cloned().unwrap_or_default();
+            entries[i].status = BrowseStatus::Error(message);
+        }
+        for (i, ..) in &plan.updates {
+            entries[*i].status = BrowseStatus::Update;
+        }
+        for (i, ..) in &plan.protected {
+            entries[*i].status = BrowseStatus::Protected;
+        }
+        let mut new = HashMap::new();
+        for (i, note) in plan.new {
+            entries[i].status = if state.deck_names.contains(&note.deck) {
+                BrowseStatus::New
+            } else {
+                BrowseStatus::Error(format!("deck {} does not exist", note.deck))
+            };
+            new.insert(i, note);
         }
-        let config_path = config_dir.join("config.toml");
-
-        let config: ExternalConfig = if !config_path.is_file() {
-            info!(
-                "no config file found. You can create one at {}",
-                config_path.to_string_lossy()
-            );
-            Default::default()
-        } else {
-            let config_text = read_to_string(&config_path).with_note(|| {
-                eyre!(
-                    "while reading config file from {}",
-                    config_path.to_string_lossy()
-                )
-            })?;
-            toml::from_str(&config_text).with_note(|| {
-                eyre!(
-                    "while parsing config file from {}",
-                    config_path.to_string_lossy()
-                )
-            })?
-        };
 
         Ok(Self {
-            path: config.path,
-            file_include: config.file_include,
-            file_exclude: config.file_exclude,
-            add_generated,
-            add_generation_date,
+            entries,
+            new,
+            table: ratatui::widgets::TableState::default().with_selected(Some(0)),
+            detail: false,
+            message: String::new(),
         })
     }
 
-    fn is_ignored(&self, path: &str) -> bool {
-        if !self.file_include.iter().any(|r| r.re.is_match(path)) {
-            info!(
-                "ignoring {} because it is not included (regex={})",
-                path,
-                self.file_include
-                    .iter()
-                    .map(|r| format!("\"{}\"", r.re_str))
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            );
-            return true;
-        }
-        for RegexString { re, re_str } in &self.file_exclude {
-            if re.is_match(path) {
-                info!(
-                    "ignoring {} because it is excluded (regex={})",
-                    path, re_str
-                );
-                return true;
+    /// Handles key presses until the user quits.
+    fn run(
+        &mut self,
+        terminal: &mut ratatui::DefaultTerminal,
+        state: &mut State,
+        config: &Config,
+        paths: &FilePaths,
+    ) -> Result<()> {
+        use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+
+        loop {
+            terminal.draw(|frame| self.draw(frame, state))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Esc if !self.detail => return Ok(()),
+                KeyCode::Esc => self.detail = false,
+                KeyCode::Enter => self.detail = !self.detail,
+                KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+                KeyCode::Char(' ') => {
+                    if let Some(entry) = self.table.selected().and_then(|i| self.entries.get_mut(i))
+                    {
+                        if entry.status == BrowseStatus::New {
+                            entry.marked = !entry.marked;
+                        } else {
+                            self.message = String::from("only new notes can be created");
+                        }
+                    }
+                }
+                KeyCode::Char('a') => {
+                    let all = self
+                        .entries
+                        .iter()
+                        .filter(|e| e.status == BrowseStatus::New)
+                        .all(|e| e.marked);
+                    for entry in &mut self.entries {
+                        entry.marked = !all && entry.status == BrowseStatus::New;
+                    }
+                }
+                KeyCode::Char('c') => self.create_marked(state, config, paths)?,
+                _ => {}
             }
         }
-        false
     }
-}
 
-fn create_all_decks(paths: &FilePaths) -> Result<()> {
-    let main_content = read_to_string(&paths.main)
-        .with_note(|| eyre!("while reading file {}", paths.main.to_string_lossy()))?;
-
-    debug!("parsing file for used decks");
-    let used_decks = parse_file::get_used_decks(main_content)?;
-
-    let used_decks = used_decks
-        .into_iter()
-        .flat_map(|full| {
-            let mut decks = Vec::new();
-            let mut prefix = String::new();
+    /// Creates the marked notes and reloads the file.
+    fn create_marked(
+        &mut self,
+        state: &mut State,
+        config: &Config,
+        paths: &FilePaths,
+    ) -> Result<()> {
+        let mut notes = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.marked)
+            .filter_map(|(i, _)| Some((i, self.new.get(&i)?.clone())))
+            .collect::<Vec<_>>();
+        if notes.is_empty() {
+            self.message = String::from("no notes marked, mark new notes with space");
+            return Ok(());
+        }
 
-            for part in full.split("::") {
-                if !prefix.is_empty() {
-                    prefix.push_str("::");
-                }
-                prefix.push_str(part);
-                decks.push(prefix.clone());
-            }
+        let order = config.creation_order.apply(&mut notes);
+        info!("creating {} notes in {} order", notes.len(), order);
+        let mut summary = report::RunSummary::default();
+        create_notes(state, paths, notes, &mut summary);
 
-            decks
-        })
-        .collect::<Vec<_>>();
+        let selected = self.table.selected();
+        *self = Self::load(state, config, paths)?;
+        self.table.select(selected);
+        let totals = summary.totals();
+        self.message = format!(
+            "created {} notes ({} duplicates, {} errors)",
+            totals.created, totals.duplicates, totals.errors
+        );
+        Ok(())
+    }
 
-    debug!("collecting available decks from anki");
-    let available_decks: HashSet<_> = get_deck_names()?.0.into_iter().collect();
+    fn draw(&mut self, frame: &mut ratatui::Frame, state: &State) {
+        use ratatui::{
+            layout::{Constraint, Layout},
+            style::{Modifier, Style},
+            text::Line,
+            widgets::{Block, Paragraph, Row, Table, Wrap},
+        };
 
-    let mut created: HashSet<String> = HashSet::new();
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let help = if self.detail {
+            "enter/esc back  q quit"
+        } else {
+            "↑/↓ move  enter details  space mark  a mark all new  c create marked  q quit"
+        };
+        let status_line = if self.message.is_empty() {
+            help.to_owned()
+        } else {
+            format!("{} | {}", self.message, help)
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
 
-    for deck in used_decks {
-        if available_decks.contains(&deck) || created.contains(&deck) {
-            continue;
-        }
-        if api::create_deck(&deck)?.is_some() {
-            info!("created deck {}", deck);
+        let selected = self.table.selected().and_then(|i| self.entries.get(i));
+        if let (true, Some(entry)) = (self.detail, selected) {
+            let i = self.table.selected().unwrap_or_default();
+            let mut lines = vec![
+                Line::from(format!("Status: {}", entry.status.label())),
+                Line::from(format!("Deck: {}", entry.note.deck)),
+                Line::from(format!("Model: {}", entry.note.model)),
+                Line::from(format!("Tags: {}", entry.note.tags.join(", "))),
+            ];
+            for name in browse_field_order(state, &entry.note) {
+                lines.push(Line::from(""));
+                lines.push(
+                    Line::from(format!("[{}]", name))
+                        .style(Style::new().add_modifier(Modifier::BOLD)),
+                );
+                lines.extend(
+                    entry.note.fields[name]
+                        .lines()
+                        .map(|l| Line::from(l.to_owned())),
+                );
+            }
+            if !entry.diagnostics.is_empty() {
+                lines.push(Line::from(""));
+                lines.extend(entry.diagnostics.iter().map(|d| Line::from(d.clone())));
+            }
+            let detail = Paragraph::new(lines)
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title(format!("Note #{}", i + 1)));
+            frame.render_widget(detail, main);
+            return;
         }
-        assert!(created.insert(deck));
-    }
 
-    if created.is_empty() {
-        info!("All decks were already created")
+        let rows = self.entries.iter().enumerate().map(|(i, entry)| {
+            let preview = browse_field_order(state, &entry.note)
+                .first()
+                .map(|name| {
+                    entry.note.fields[*name]
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .unwrap_or_default();
+            Row::new([
+                if entry.marked { "*" } else { "" }.to_owned(),
+                (i + 1).to_string(),
+                entry.status.label().to_owned(),
+                entry.note.deck.clone(),
+                entry.note.model.clone(),
+                preview,
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(1),
+                Constraint::Length(5),
+                Constraint::Length(9),
+                Constraint::Percentage(25),
+                Constraint::Percentage(15),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(
+            Row::new(["", "#", "Status", "Deck", "Model", "First field"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(format!(
+            "{} notes, {} marked",
+            self.entries.len(),
+            self.entries.iter().filter(|e| e.marked).count()
+        )));
+        frame.render_stateful_widget(table, main, &mut self.table);
     }
-
-    Ok(())
 }
 
-fn main() -> Result<()> {
-    color_eyre::install()?;
+/// Field names of the note in the order of its model, unknown fields sorted at the end.
+fn browse_field_order<'a>(state: &State, note: &'a Note) -> Vec<&'a String> {
+    let mut names = note.fields.keys().collect::<Vec<_>>();
+    names.sort();
+    if let Some(model) = state.models.get(&note.model) {
+        names.sort_by_key(|name| {
+            model
+                .field_names
+                .iter()
+                .position(|n| n == *name)
+                .unwrap_or(usize::MAX)
+        });
+    }
+    names
+}
 
-    let args = Args::parse();
+/// Shows the notes of the file in a terminal UI and creates the notes marked by the user.
+fn browse(config: &Config, paths: &FilePaths) -> Result<()> {
+    if !std::io::stdout().is_terminal() {
+        return Err(
+            eyre!("`browse` needs a terminal, but stdout is not a terminal")
+                .suggestion("use `diff` to see what `create` would do"),
+        );
+    }
+    if paths.main.is_dir() {
+        return Err(eyre!(
+            "`browse` shows a single file, but {} is a directory",
+            paths.main.to_string_lossy()
+        ));
+    }
 
-    let builder = FmtSubscriber::builder().with_max_level(args.log_level);
+    let mut state = State::new(config)?;
+    let mut browser = Browser::load(&mut state, config, paths)?;
 
-    if args.short_log {
-        let subscriber = builder.without_time().compact().finish();
-        tracing::subscriber::set_global_default(subscriber)?;
-    } else {
-        tracing::subscriber::set_global_default(builder.finish())?;
+    // the log would be drawn over the UI
+    *HELD_LOG.lock().unwrap() = Some(Vec::new());
+    // also restores the terminal on panics
+    let mut terminal = ratatui::init();
+    let result = browser.run(&mut terminal, &mut state, config, paths);
+    ratatui::restore();
+    if let Some(log) = HELD_LOG.lock().unwrap().take() {
+        use std::io::Write;
+        std::io::stdout().write_all(&log)?;
     }
+    result
+}
 
-    let config = Config::load(
-        args.add_generated,
-        args.add_generation_date
-            .then(|| format!("{}", chrono::Local::now().format("%Y-%m-%d"))),
-    )?;
-
-    let child = args.path.unwrap_or_else(|| "anki.tex".into());
-    let main_path = if let Some(parent) = &config.path {
-        if child.is_relative() {
-            parent.join(child)
+/// Differences of `stored` and its new version `note`.
+///
+/// Fields missing in `note` are kept in anki unless `replace_all_fields` is set,
+/// then they are cleared.
+fn note_diff(
+    stored: &Note,
+    note: &Note,
+    replace_all_fields: bool,
+    matching: &MatchOptions,
+) -> NoteDiff {
+    NoteDiff {
+        cleared: if replace_all_fields {
+            stored.cleared_fields(note, matching)
         } else {
-            child
-        }
+            Vec::new()
+        },
+        ..stored.diff(note, matching)
+    }
+}
+
+/// The fields sent to update a stored note to `note`, the changed and the cleared
+/// fields of `diff`.
+fn updated_fields(note: &Note, diff: &NoteDiff) -> HashMap<String, String> {
+    diff.fields
+        .iter()
+        .map(|name| (name.clone(), note.fields[name].clone()))
+        .chain(
+            diff.cleared
+                .iter()
+                .map(|name| (name.clone(), String::new())),
+        )
+        .collect()
+}
+
+/// Sends the changes `diff` of the stored note `stored` to its new version `note` to
+/// anki and applies them to `stored`. Returns the id of the note.
+///
+/// Nothing is sent for an empty diff. Tags are only added, so the generation date
+/// tag of `stored` is kept and never replaced by the one of the current run.
+fn apply_note_update(
+    stored: &mut Note,
+    note: &Note,
+    diff: &NoteDiff,
+    tag_case: TagCase,
+) -> Result<usize> {
+    let id = stored
+        .id
+        .ok_or_else(|| eyre!("can't update note without id in deck {}", note.deck))?;
+    if diff.is_empty() {
+        return Ok(id);
+    }
+    let fields = updated_fields(note, diff);
+    debug!(
+        "updating note {} (fields: {:?}, cleared fields: {:?}, new tags: {:?})",
+        id, diff.fields, diff.cleared, diff.missing_tags
+    );
+    let mut tags = stored.tags.clone();
+    let res = if diff.missing_tags.is_empty() {
+        update_note_fields(id, &fields)
     } else {
-        child
+        tags = TagSet::with_tags(tag_case, tags.iter().chain(&diff.missing_tags)).into_vec();
+        update_note(id, &fields, &tags)
     };
+    if let Err(e) = res {
+        return Err(e.wrap_err(format!("failed to update note {}", id)));
+    }
+    stored.tags = tags;
+    stored.fields.extend(fields);
+    Ok(id)
+}
 
-    let paths = FilePaths::from_main(main_path)?;
+/// Adds the fields of `field_names` missing in the new note `note` as empty fields,
+/// so new notes always have all fields of their model.
+fn complete_fields(note: &mut Note, field_names: &[String]) {
+    for name in field_names {
+        note.fields.entry(name.clone()).or_default();
+    }
+}
+
+/// Finds the stored note which Anki would consider `note` a duplicate of,
+/// i.e. a note of the same model with the same content in the first field.
+fn find_stored_note(
+    stored: &[Note],
+    model: &Model,
+    note: &Note,
+    options: &MatchOptions,
+) -> Option<usize> {
+    let first_field = model.field_names.first()?;
+    if !note.fields.contains_key(first_field) {
+        return None;
+    }
+    stored
+        .iter()
+        .position(|s| s.model == note.model && s.field_eq(note, first_field, options))
+}
 
-    // drop args so it can't be used later on
-    let Args { subcommand, .. } = args;
+lazy_static::lazy_static! {
+    /// `{{name}}` or `{{name:argument}}` in a field, `\{\{` is left as is
+    static ref PLACEHOLDER: Regex = Regex::new(r"\{\{([a-z]+)(?::([^{}]*))?\}\}").unwrap();
+}
 
-    match subcommand {
-        Commands::Template { force } => create_template(&config, &paths, force)?,
-        Commands::Watch => watch(&config, &paths)?,
-        Commands::Create => {
-            let mut state = State::new()?;
-            update_change(&mut state, &config, &paths)?;
+/// Stands for a placeholder while the text around it is normalized
+const PLACEHOLDER_MARK: char = '\u{E000}';
+
+/// Returns the first placeholder of `note` which isn't one of `{{today}}`, `{{file}}`,
+/// `{{deck}}` and `{{counter:<name>}}`, with its field.
+fn unknown_placeholder(note: &Note) -> Option<(&str, &str)> {
+    let mut fields = note.fields.iter().collect::<Vec<_>>();
+    fields.sort();
+    fields.into_iter().find_map(|(name, value)| {
+        PLACEHOLDER
+            .captures_iter(value)
+            .find(|cap| {
+                !matches!(
+                    (&cap[1], cap.get(2).map(|arg| arg.as_str())),
+                    ("today" | "file" | "deck", None) | ("counter", Some(_))
+                ) || cap.get(2).is_some_and(|arg| arg.as_str().is_empty())
+            })
+            .map(|cap| (name.as_str(), cap.get(0).unwrap().as_str()))
+    })
+}
+
+/// Which fields of a note are empty or whitespace, see [`blank_fields`]
+#[derive(Debug, PartialEq, Eq)]
+enum BlankFields<'a> {
+    None,
+    /// All fields, the note would be a blank card
+    All,
+    /// The first field of the model but not all fields, anki may reject the note
+    First(&'a str),
+}
+
+/// Checks the fields of `note` for [`BlankFields`], the field names are compared
+/// with the first field of `model` like [`match_field_names`] does
+fn blank_fields<'a>(model: &'a Model, note: &Note, case: FieldNameCase) -> BlankFields<'a> {
+    if note.fields.values().all(|value| value.trim().is_empty()) {
+        return BlankFields::All;
+    }
+    let Some(first_field) = model.field_names.first() else {
+        return BlankFields::None;
+    };
+    let first_value = match case {
+        FieldNameCase::Ignore => note
+            .fields
+            .iter()
+            .find(|(name, _)| name.to_lowercase() == first_field.to_lowercase())
+            .map(|(_, value)| value),
+        _ => note.fields.get(first_field),
+    };
+    if first_value.is_none_or(|value| value.trim().is_empty()) {
+        BlankFields::First(first_field)
+    } else {
+        BlankFields::None
+    }
+}
+
+/// Values of the placeholders of new notes, see [`expand_placeholders`]
+struct Placeholders {
+    today: String,
+    file: String,
+    /// Last values of the counters
+    counters: BTreeMap<String, u64>,
+    /// Held while counters are used, so concurrent runs don't use the same values.
+    /// `None` before the first counter and if the counters are only previewed.
+    lock: Option<CacheLock>,
+    /// Save the used counter values in the cache
+    reserve: bool,
+    cache_file: PathBuf,
+}
+
+impl Placeholders {
+    fn new(paths: &FilePaths, cache_file: &Path, reserve: bool) -> Self {
+        let file = paths.main.strip_prefix(&paths.root).unwrap_or(&paths.main);
+        Self {
+            today: chrono::Local::now()
+                .format(GENERATION_DATE_FORMAT)
+                .to_string(),
+            file: file.to_string_lossy().into_owned(),
+            counters: BTreeMap::new(),
+            lock: None,
+            reserve,
+            cache_file: cache_file.to_owned(),
         }
-        Commands::GetDecks => {
-            let names = get_deck_names()?;
-            println!("All deck names: \n {}", names.0.join("\n "))
+    }
+
+    /// Next value of the counter `name`
+    fn next_counter(&mut self, name: &str) -> Result<u64> {
+        if self.reserve && self.lock.is_none() {
+            self.lock = Some(CacheLock::acquire(&self.cache_file)?);
+            self.counters = CachedState::load(&self.cache_file).counters;
+        } else if !self.reserve && self.counters.is_empty() {
+            self.counters = CachedState::load(&self.cache_file).counters;
         }
-        Commands::GetModels => {
-            let names = get_model_names()?;
-            println!("All model names: \n {}", names.0.join("\n "))
+        let counter = self.counters.entry(name.to_owned()).or_default();
+        *counter += 1;
+        Ok(*counter)
+    }
+
+    /// Saves the used counter values and releases the lock.
+    fn finish(&mut self) -> Result<()> {
+        let Some(lock) = self.lock.take() else {
+            return Ok(());
+        };
+        let mut cache = CachedState::load(&self.cache_file);
+        cache.counters = std::mem::take(&mut self.counters);
+        cache.save(&self.cache_file)?;
+        drop(lock);
+        Ok(())
+    }
+}
+
+/// Replaces the placeholders in the fields of `note` (a note of the file).
+///
+/// If a stored note matches `note` with any values of the placeholders, its values
+/// are kept, so a later run doesn't change the note. Otherwise `{{today}}` is the
+/// current date, `{{file}}` the path of the file, `{{deck}}` the deck of the note and
+/// `{{counter:<name>}}` the next value of the counter, which is kept in the cache.
+/// `\{\{` in raw fields becomes `{{`, LaTeX shows it as braces anyway.
+fn expand_placeholders(
+    added_notes: &[Note],
+    matching: &MatchOptions,
+    model: &Model,
+    note: &mut Note,
+    values: &mut Placeholders,
+) -> Result<()> {
+    let templates = note
+        .fields
+        .iter()
+        .filter(|(_, value)| PLACEHOLDER.is_match(value))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<BTreeMap<_, _>>();
+    let unescape = |note: &mut Note| {
+        for (name, value) in &mut note.fields {
+            if note.formats.get(name) == Some(&FieldFormat::Raw) && value.contains(r"\{\{") {
+                *value = value.replace(r"\{\{", "{{");
+            }
         }
-        Commands::GetNotes { query } => {
-            let notes = get_notes(&query)?;
-            let notes_len = notes.len();
+    };
+    if templates.is_empty() {
+        unescape(note);
+        return Ok(());
+    }
 
-            for note in notes {
-                println!("In deck '{}' with model '{}'", note.deck, note.model);
-                for (k, v) in note.fields {
-                    let v = v.replace("[latex]", "");
-                    let v = v.replace("[/latex]", "");
-                    println!("[{}] {}", k, v);
-                }
-                if !note.tags.is_empty() {
-                    println!("Tags: {}", note.tags.join(", "));
-                }
-                println!("{}", "-".repeat(100));
+    // the fields with placeholders as they are compared with stored notes
+    let patterns = templates
+        .iter()
+        .map(|(name, template)| {
+            let marked = PLACEHOLDER.replace_all(template, PLACEHOLDER_MARK.to_string());
+            let text = anki_tex::match_text(note.format(name), &marked, matching);
+            let parts = text
+                .split(PLACEHOLDER_MARK)
+                .map(regex::escape)
+                .collect::<Vec<_>>();
+            let pattern = Regex::new(&format!("(?s)^{}$", parts.join("(.*?)")))?;
+            Ok((name.clone(), pattern))
+        })
+        .collect::<Result<BTreeMap<_, _>>>()?;
+    let captures = |stored: &Note| {
+        patterns
+            .iter()
+            .map(|(name, pattern)| {
+                let value = stored.fields.get(name)?;
+                let text = anki_tex::match_text(note.format(name), value, matching);
+                let cap = pattern.captures(&text)?;
+                let values = cap
+                    .iter()
+                    .skip(1)
+                    .map(|m| m.map_or("", |m| m.as_str()).to_owned())
+                    .collect::<Vec<_>>();
+                Some((name.clone(), values))
+            })
+            .collect::<Option<BTreeMap<_, _>>>()
+    };
+    let first_field = model.field_names.first();
+    let stored = match first_field.filter(|first| !templates.contains_key(*first)) {
+        Some(_) => find_stored_note(added_notes, model, note, matching)
+            .and_then(|idx| captures(&added_notes[idx])),
+        None => added_notes
+            .iter()
+            .filter(|stored| stored.model == note.model)
+            .filter(|stored| {
+                note.fields
+                    .keys()
+                    .filter(|name| !templates.contains_key(*name))
+                    .all(|name| stored.field_eq(note, name, matching))
+            })
+            .find_map(captures),
+    };
+
+    for (name, template) in templates {
+        let mut stored_values = stored
+            .as_ref()
+            .and_then(|stored| stored.get(&name))
+            .map(|values| values.iter());
+        let mut error = None;
+        let value = PLACEHOLDER.replace_all(&template, |cap: &regex::Captures| {
+            if let Some(value) = stored_values.as_mut().and_then(Iterator::next) {
+                return value.clone();
+            }
+            match (&cap[1], cap.get(2)) {
+                ("today", _) => values.today.clone(),
+                ("file", _) => values.file.clone(),
+                ("deck", _) => note.deck.clone(),
+                (_, Some(counter)) => match values.next_counter(counter.as_str()) {
+                    Ok(value) => value.to_string(),
+                    Err(e) => {
+                        error.get_or_insert(e);
+                        String::new()
+                    }
+                },
+                _ => unreachable!("checked by `unknown_placeholder`"),
             }
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        let value = value.into_owned();
+        note.fields.insert(name, value);
+    }
+    unescape(note);
+    Ok(())
+}
 
-            println!("fetched {} notes in total", notes_len);
+lazy_static::lazy_static! {
+    static ref BASE64_RUN: Regex = Regex::new(r"[A-Za-z0-9+/]{200,}={0,2}").unwrap();
+}
+
+/// Rejects fields which are too large to be handled well by anki
+/// and warns about fields which are suspiciously large.
+///
+/// Returns the warnings as diagnostics.
+fn check_field_sizes(config: &Config, note: &Note) -> Result<Vec<report::Diagnostic>> {
+    let mut warnings = Vec::new();
+    for (name, value) in &note.fields {
+        let size = value.len();
+        if size <= config.warn_field_bytes {
+            continue;
         }
-        Commands::Render => {
-            info!("rendering all latex");
-            if render_all_latex()? {
-                println!("Success");
+        let binary =
+            (value.contains("data:") && value.contains(";base64,")) || BASE64_RUN.is_match(value);
+
+        if size > config.max_field_bytes {
+            let err = color_eyre::Report::new(report::Diagnostic::new(
+                report::Severity::Error,
+                report::Code::FieldTooLarge,
+                format!(
+                    "field `{}` of note in deck {} is too large ({} bytes, max {} bytes)",
+                    name, note.deck, size, config.max_field_bytes,
+                ),
+            ))
+            .with_note(|| {
+                format!(
+                    "note fields: {:?}",
+                    Note::question_or_fields(note.first_card, &note.fields).resolve()
+                )
+            })
+            .note("you can change the limit with `max_field_bytes` in the config");
+            return Err(if binary {
+                err.suggestion("the field seems to contain binary data. Add the file to anki's media folder and reference it instead")
             } else {
-                println!("Error :(");
+                err
+            });
+        }
+
+        let message = format!(
+            "field `{}` of note in deck {} is large ({} bytes)",
+            name, note.deck, size
+        );
+        warn!("{}", message);
+        if binary {
+            info!("the field seems to contain binary data. Add the file to anki's media folder and reference it instead");
+        }
+        warnings.push(report::Diagnostic::new(
+            report::Severity::Warning,
+            report::Code::LargeField,
+            message,
+        ));
+    }
+
+    Ok(warnings)
+}
+
+/// Creates and updates the notes of the file in anki.
+///
+/// With `dry_run` nothing is changed and the summary contains the changes which would be made.
+fn update_change(
+    state: &mut State,
+    config: &Config,
+    paths: &FilePaths,
+    dry_run: bool,
+) -> Result<report::RunSummary> {
+    let mut summary = report::RunSummary {
+        profile: state.profile.clone(),
+        ..Default::default()
+    };
+
+    if config.is_ignored(&paths.main.to_string_lossy()) {
+        return Ok(summary);
+    }
+    if paths.main.is_dir() {
+        debug!(
+            "{} is a directory. Updating children instead",
+            paths.main.to_string_lossy()
+        );
+        let children = config.children(&paths.main)?;
+        for child in &children {
+            // TODO is this correct or should the anki and custom path be changed as well?
+            let new_paths = FilePaths {
+                main: child.clone(),
+                anki: paths.anki.clone(),
+                custom: paths.custom.clone(),
+                root: paths.root.clone(),
+            };
+            summary.merge(update_change(state, config, &new_paths, dry_run)?);
+        }
+        if config.tag_source_file {
+            warn_orphaned_sources(config, state, &paths.root, &children);
+        }
+
+        return Ok(summary);
+    }
+    let main_content = read_to_string(&paths.main)
+        .with_note(|| eyre!("while reading file {}", paths.main.to_string_lossy()))?;
+
+    parse_file::check_ankitex_template(&paths.anki, &config.ankitex)?.into_result()?;
+    parse_file::check_preamble(&main_content, &config.parse.frame, paths.dir())?;
+    let guard = edit::FileGuard::new(&paths.main, &main_content);
+    // checked before each step changing anki, e.g. the file may change while the
+    // confirmation is pending or while the notes are updated
+    let changed_since_parse = |state: &mut State| -> Result<bool> {
+        if dry_run || guard.is_unchanged()? {
+            return Ok(false);
+        }
+        info!(
+            "{} changed, skipping changes to anki this round",
+            paths.main.to_string_lossy()
+        );
+        // make sure the next event triggers an update
+        state.last_main_hash = 0;
+        Ok(true)
+    };
+
+    match parse_file::check_custom_template(&paths.custom) {
+        Ok(custom) => {
+            for finding in &custom.findings {
+                warn!("{}: {}", custom.name, finding);
+                let diagnostic = report::Diagnostic::new(
+                    report::Severity::Warning,
+                    report::Code::InvalidStyFile,
+                    finding.to_string(),
+                )
+                .file(custom.name.clone());
+                summary.diagnostics.push(match finding.line() {
+                    Some(line) => diagnostic.position(line, None),
+                    None => diagnostic,
+                });
+            }
+        }
+        Err(e) => warn!("can't check {}: {}", paths.custom.to_string_lossy(), e),
+    }
+
+    let new_main_hash = fasthash::metro::hash64(&main_content);
+    let new_custom_hash = fasthash::metro::hash64(&main_content);
+    if new_main_hash != state.last_main_hash {
+        state.last_main_hash = new_main_hash;
+    } else if new_custom_hash != state.last_custom_hash {
+        state.last_custom_hash = new_custom_hash;
+    } else {
+        debug!("nothing changed");
+        return Ok(summary);
+    }
+    info!("updating changes from {}", paths.main.to_string_lossy());
+    state.reload()?;
+
+    debug!("parsing file {}", paths.main.to_string_lossy());
+    let key = std::path::absolute(&paths.main)?
+        .to_string_lossy()
+        .into_owned();
+    let cache = state
+        .changed_only
+        .then(|| CachedState::load(&config.cache_file));
+    let previous = cache
+        .as_ref()
+        .and_then(|cache| cache.parsed_files.get(&key))
+        .filter(|parsed| parsed.config == config.parse_hash)
+        .map(|parsed| &parsed.index);
+    let changed = parse_file::parse_changed(main_content, &config.parse, previous)?;
+    config.handle_parse_warnings(&changed.outcome.warnings)?;
+    if changed.skipped != 0 {
+        info!(
+            "skipping {} notes which didn't change since the last run",
+            changed.skipped
+        );
+    }
+    let first_note = changed.first_note;
+    let mut parsed = state.changed_only.then_some((key, changed.index));
+    let mut notes = changed.outcome.notes;
+    config.select_decks(&mut notes, &paths.main);
+    let kept = state.deck_filter.apply(&mut notes, &mut summary);
+    if !summary.filtered.is_empty() {
+        // the left out notes have to be processed by the next run
+        parsed = None;
+    }
+    let index = |i: usize| first_note + kept[i];
+    config.select_models_by(&mut notes, index)?;
+    debug!("finished parsing file");
+
+    let plan = plan_notes(state, config, paths, notes, index, !dry_run)?;
+    summary.diagnostics.extend(plan.diagnostics);
+    if !plan.invalid.is_empty() {
+        return Ok(summary);
+    }
+    for (_, note) in &plan.unchanged {
+        summary.deck_mut(&note.deck).unchanged += 1;
+    }
+
+    if changed_since_parse(state)? {
+        return Ok(summary);
+    }
+
+    if !dry_run {
+        confirm_new_notes(config, paths, &plan.new)?;
+        if !plan.new.is_empty() && changed_since_parse(state)? {
+            return Ok(summary);
+        }
+    }
+
+    for (_, _, diff, note) in plan.protected {
+        let question = Note::question_or_fields(note.first_card, &note.fields).resolve();
+        if dry_run {
+            info!(
+                "protected, differs: fields {:?} and tags {:?} of note {:?}",
+                diff.fields, diff.missing_tags, question
+            );
+        } else {
+            info!("protected, skipping note {:?}", question);
+        }
+        summary.deck_mut(&note.deck).protected += 1;
+    }
+
+    let mut update_record = RunRecord {
+        file: Some(paths.main.to_string_lossy().into_owned()),
+        profile: state.profile.clone(),
+        ..RunRecord::new()
+    };
+    if !dry_run {
+        let ids = plan
+            .updates
+            .iter()
+            .filter_map(|(_, idx, _, _)| state.added_notes[*idx].id)
+            .collect::<Vec<_>>();
+        update_record.backup = backup_notes(config, &update_record.id, &ids)?;
+    }
+    for (_, idx, diff, note) in plan.updates {
+        let deck_summary = summary.deck_mut(&note.deck);
+        if dry_run {
+            info!(
+                "would update fields {:?}, clear fields {:?} and add tags {:?} of note {:?}",
+                diff.fields,
+                diff.cleared,
+                diff.missing_tags,
+                Note::question_or_fields(note.first_card, &note.fields).resolve(),
+            );
+            let kept = state.added_notes[idx]
+                .tags
+                .iter()
+                .filter(|tag| config.protected_tags.find_match(tag).is_some())
+                .collect::<Vec<_>>();
+            if !diff.protected_tags.is_empty() || !kept.is_empty() {
+                info!(
+                    "  protected tags (excluded from the comparison): {:?} in the file are not \
+                     added, {:?} in anki are kept",
+                    diff.protected_tags, kept
+                );
             }
+            deck_summary.updated += 1;
+            continue;
         }
-        Commands::CreateAllDecks => {
-            create_all_decks(&paths)?;
+
+        match apply_note_update(&mut state.added_notes[idx], &note, &diff, config.tag_case) {
+            Ok(id) => {
+                deck_summary.updated += 1;
+                update_record.updated.push(id);
+            }
+            Err(e) => {
+                error!("{:#}", e);
+                deck_summary.errors += 1;
+            }
         }
-        Commands::Sync => {
-            info!("syncing all notes");
-            sync()?;
-            println!("Success");
+    }
+    if !update_record.updated.is_empty() {
+        if let Err(e) = update_record.append(&state.run_log) {
+            warn!("failed to log the updated notes: {:#}", e);
         }
-        Commands::Crs => {
-            // TODO remove duplication
-            let mut state = State::new()?;
-            update_change(&mut state, &config, &paths)?;
-            info!("rendering all latex");
-            if render_all_latex()? {
-                println!("Success");
-            } else {
-                println!("Error :(");
+    }
+
+    let mut new_notes = Vec::new();
+    let mut missing_decks = HashSet::new();
+    for (i, note) in plan.new {
+        // TODO id
+        if !state.deck_names.contains(&note.deck) {
+            if missing_decks.insert(note.deck.clone()) {
+                error!("create note with invalid deck name {}", note.deck);
+                info!("create all decks in the file with `anki-tex create-all-decks`");
             }
-            info!("syncing all notes");
-            sync()?;
-            println!("Success");
+            summary.deck_mut(&note.deck).errors += 1;
+            continue;
+        }
+        new_notes.push((i, note));
+    }
+    let over_budget = config
+        .deck_budgets
+        .check(new_notes.iter().map(|(_, note)| note))?;
+    for deck in &over_budget {
+        warn!(
+            "deck {} would have {} notes, more than its budget of {} (currently {})",
+            deck.deck, deck.after, deck.budget, deck.current
+        );
+    }
+    if state.enforce_budgets && !over_budget.is_empty() {
+        new_notes.retain(|(_, note)| {
+            let Some(deck) = over_budget.iter().find(|deck| {
+                note.deck == deck.deck
+                    || note.deck.starts_with(&format!(
+                        "{}{}",
+                        deck.deck,
+                        parse_file::ANKI_DECK_SEPARATOR
+                    ))
+            }) else {
+                return true;
+            };
+            *summary
+                .filtered
+                .entry(format!("budget of {}", deck.deck))
+                .or_default() += 1;
+            false
+        });
+        // the left out notes have to be created by a later run
+        parsed = None;
+    }
+    if new_notes.is_empty() {
+        if let Some((key, index)) = parsed.take() {
+            save_parsed(config, key, index, &summary);
         }
+        return Ok(summary);
     }
 
-    Ok(())
+    let order = config.creation_order.apply(&mut new_notes);
+    summary.order = Some(order.clone());
+    if dry_run {
+        info!("would create {} notes in {} order", new_notes.len(), order);
+        for (_, note) in new_notes {
+            summary.deck_mut(&note.deck).created += 1;
+        }
+        return Ok(summary);
+    }
+    if changed_since_parse(state)? {
+        return Ok(summary);
+    }
+    info!("creating {} notes in {} order", new_notes.len(), order);
+
+    create_notes(state, paths, new_notes, &mut summary);
+    if let Some((key, index)) = parsed {
+        save_parsed(config, key, index, &summary);
+    }
+
+    Ok(summary)
+}
+
+/// What `create` does with the notes of a file, see [`plan_notes`]
+///
+/// The first `usize` of each entry is the index of the note in the file.
+#[derive(Debug, Default)]
+struct NotePlan {
+    /// Notes which are already in anki
+    unchanged: Vec<(usize, Note)>,
+    /// Stored notes to update with their index in `added_notes` and the changes
+    updates: Vec<(usize, usize, NoteDiff, Note)>,
+    /// Like `updates`, but the notes are protected by `\keep` or `keep_tag` and
+    /// aren't changed
+    protected: Vec<(usize, usize, NoteDiff, Note)>,
+    /// Notes to create, in file order
+    new: Vec<(usize, Note)>,
+    /// Notes with a model or field which doesn't exist in anki
+    invalid: Vec<usize>,
+    /// Problems of the notes, with the file and note index set
+    diagnostics: Vec<report::Diagnostic>,
+}
+
+/// Matches the field names of `note` with the fields of `model` as set by `case`,
+/// renaming them to the names of the model with [`FieldNameCase::Fix`]. Returns
+/// the first field which doesn't exist in the model.
+///
+/// Fields of models with fields differing only by case are matched strictly, the
+/// models are warned about once and added to `ambiguous`.
+fn match_field_names(
+    note: &mut Note,
+    model: &Model,
+    case: FieldNameCase,
+    ambiguous: &mut HashSet<String>,
+) -> Option<String> {
+    let strict = case == FieldNameCase::Strict || {
+        let lowercase = model
+            .field_names
+            .iter()
+            .map(|name| name.to_lowercase())
+            .collect::<HashSet<_>>();
+        lowercase.len() != model.field_names.len()
+    };
+    if strict && case != FieldNameCase::Strict && ambiguous.insert(note.model.clone()) {
+        warn!(
+            "model {} has fields which differ only by case, matching their names strictly",
+            note.model
+        );
+    }
+
+    let mut renames = Vec::new();
+    for name in note.fields.keys() {
+        if model.field_names.contains(name) {
+            continue;
+        }
+        let canonical = model
+            .field_names
+            .iter()
+            .find(|field| field.to_lowercase() == name.to_lowercase())
+            .filter(|field| !strict && !note.fields.contains_key(*field));
+        match canonical {
+            Some(canonical) => renames.push((name.clone(), canonical.clone())),
+            None => return Some(name.clone()),
+        }
+    }
+    if case == FieldNameCase::Fix {
+        for (name, canonical) in renames {
+            debug!(
+                "using field `{}` of model {} for `{}`",
+                canonical, note.model, name
+            );
+            if let Some(value) = note.fields.remove(&name) {
+                note.fields.insert(canonical.clone(), value);
+            }
+            if let Some(format) = note.formats.remove(&name) {
+                note.formats.insert(canonical, format);
+            }
+        }
+    }
+    None
+}
+
+/// Validates the parsed notes of `paths.main` and compares them with the notes
+/// stored in anki.
+///
+/// The notes are normalized, tagged and formatted like they are sent to anki.
+/// `index(i)` is the index in the file of the note `i` of `notes`, used in the
+/// diagnostics and messages.
+///
+/// With `reserve`, the counters used by placeholders are saved, see
+/// [`expand_placeholders`].
+fn plan_notes(
+    state: &State,
+    config: &Config,
+    paths: &FilePaths,
+    notes: Vec<Note>,
+    index: impl Fn(usize) -> usize,
+    reserve: bool,
+) -> Result<NotePlan> {
+    debug!("checking notes");
+    let mut plan = NotePlan::default();
+    let mut placeholders = Placeholders::new(paths, &config.cache_file, reserve);
+    let mut checked_notes = Vec::new();
+    let mut models_without_hash = HashSet::new();
+    let mut stale_models = HashSet::new();
+    let mut models_without_fields = HashSet::new();
+    let mut ambiguous_models = HashSet::new();
+    // protected tags removed from the notes, by index
+    let mut protected_tags = HashMap::new();
+    let file = paths.main.to_string_lossy();
+    for (i, mut note) in notes.into_iter().enumerate() {
+        let diagnostic = |severity, code, message| {
+            report::Diagnostic::new(severity, code, message)
+                .file(file.clone())
+                .note_index(Some(index(i)))
+        };
+        let Some(model) = state.models.get(&note.model) else {
+            error!("create note with invalid model name {}", note.model);
+            plan.diagnostics.push(diagnostic(
+                report::Severity::Error,
+                report::Code::UnknownModel,
+                format!("model {} does not exist", note.model),
+            ));
+            plan.invalid.push(i);
+            continue;
+        };
+        if model.field_names.is_empty() {
+            if models_without_fields.insert(note.model.clone()) {
+                error!("model {} has no fields", note.model);
+                info!("add fields to the model in anki or choose another model");
+            }
+            plan.diagnostics.push(diagnostic(
+                report::Severity::Error,
+                report::Code::ModelWithoutFields,
+                format!("model {} has no fields", note.model),
+            ));
+            plan.invalid.push(i);
+            continue;
+        }
+        if let Some(field_name) = match_field_names(
+            &mut note,
+            model,
+            config.field_name_case,
+            &mut ambiguous_models,
+        ) {
+            error!(
+                "model {} does not contain field `{}`",
+                note.model, field_name
+            );
+            info!("field names: {}", model.field_names.join(", "));
+            plan.diagnostics.push(diagnostic(
+                report::Severity::Error,
+                report::Code::UnknownField,
+                format!(
+                    "model {} does not contain field `{}`",
+                    note.model, field_name
+                ),
+            ));
+            plan.invalid.push(i);
+            continue;
+        }
+        let blank = blank_fields(model, &note, config.field_name_case);
+        if blank == BlankFields::All {
+            error!(
+                "all fields of note #{} of {} in deck {} are empty",
+                index(i) + 1,
+                file,
+                note.deck
+            );
+            plan.diagnostics.push(diagnostic(
+                report::Severity::Error,
+                report::Code::BlankNote,
+                String::from("all fields of the note are empty"),
+            ));
+            plan.invalid.push(i);
+            continue;
+        }
+        if let BlankFields::First(first_field) = blank {
+            warn!(
+                "the first field `{}` of note #{} of {} in deck {} is empty, anki may reject the note",
+                first_field,
+                index(i) + 1,
+                file,
+                note.deck
+            );
+            plan.diagnostics.push(diagnostic(
+                report::Severity::Warning,
+                report::Code::EmptyFirstField,
+                format!("the first field `{}` is empty", first_field),
+            ));
+        }
+        if config.double_wrap == DoubleWrap::Error {
+            if let Some((name, format)) = note.fields.iter().find_map(|(name, value)| {
+                let format = note.format(name);
+                format.unwrap(value).map(|_| (name, format))
+            }) {
+                error!(
+                    "field `{}` of note {:?} is already wrapped in {}",
+                    name,
+                    Note::question_or_fields(note.first_card, &note.fields).resolve(),
+                    format.wrap("...")
+                );
+                plan.diagnostics.push(diagnostic(
+                    report::Severity::Error,
+                    report::Code::DoubleWrap,
+                    format!(
+                        "field `{}` is already wrapped in {}",
+                        name,
+                        format.wrap("...")
+                    ),
+                ));
+                plan.invalid.push(i);
+                continue;
+            }
+        }
+        for warning in check_field_sizes(config, &note)? {
+            plan.diagnostics
+                .push(warning.file(file.clone()).note_index(Some(index(i))));
+        }
+        let unknown = config
+            .field_placeholders
+            .then(|| unknown_placeholder(&note))
+            .flatten();
+        if let Some((field, placeholder)) = unknown {
+            error!(
+                "unknown placeholder `{}` in field `{}` of note {:?}",
+                placeholder,
+                field,
+                Note::question_or_fields(note.first_card, &note.fields).resolve()
+            );
+            plan.diagnostics.push(diagnostic(
+                report::Severity::Error,
+                report::Code::UnknownPlaceholder,
+                format!("unknown placeholder `{}` in field `{}`", placeholder, field),
+            ));
+            plan.invalid.push(i);
+            continue;
+        }
+        if config.normalize_unicode {
+            note.normalize_unicode();
+        }
+        if config.field_placeholders {
+            expand_placeholders(
+                &state.added_notes,
+                &config.matching,
+                model,
+                &mut note,
+                &mut placeholders,
+            )?;
+        }
+
+        note.normalize_tags(config.tag_case);
+        add_auto_tags(config, &mut note);
+
+        let (protected, tags) = std::mem::take(&mut note.tags)
+            .into_iter()
+            .partition::<Vec<_>, _>(|tag| config.protected_tags.find_match(tag).is_some());
+        note.tags = tags;
+        if !protected.is_empty() {
+            warn!(
+                "not adding protected tags {:?} to note {:?}, they are managed inside anki",
+                protected,
+                Note::question_or_fields(note.first_card, &note.fields).resolve(),
+            );
+            plan.diagnostics.push(diagnostic(
+                report::Severity::Warning,
+                report::Code::ProtectedTag,
+                format!("protected tags {} are not added", protected.join(", ")),
+            ));
+            protected_tags.insert(i, protected);
+        }
+
+        if let Some(stored) = state
+            .added_notes
+            .iter()
+            .find(|stored| stored.matches(&note, &config.matching))
+        {
+            warn_stale_wrapper(&mut stale_models, stored, &note);
+            plan.unchanged.push((i, note));
+            continue;
+        }
+
+        let hash = match &config.matching.hash_field {
+            Some(hash_field) if model.field_names.contains(hash_field) => {
+                Some(note.content_hash(&config.matching))
+            }
+            Some(hash_field) => {
+                if models_without_hash.insert(note.model.clone()) {
+                    warn!(
+                        "model {} has no field `{}`, matching notes by their content instead",
+                        note.model, hash_field
+                    );
+                }
+                None
+            }
+            None => None,
+        };
+
+        for (name, field) in &mut note.fields {
+            *field = fmt_content(
+                config,
+                note.formats.get(name).copied().unwrap_or_default(),
+                field,
+            );
+        }
+        if let (Some(hash_field), Some(hash)) = (&config.matching.hash_field, &hash) {
+            note.fields.insert(hash_field.clone(), hash.clone());
+        }
+
+        checked_notes.push((i, note, hash));
+    }
+
+    let hash_matches = if let Some(hash_field) = &config.matching.hash_field {
+        let queries = checked_notes
+            .iter()
+            .filter_map(|(_, _, hash)| hash.as_ref())
+            .map(|hash| format!("\"{}:{}\"", hash_field, hash))
+            .collect::<Vec<_>>();
+        if queries.is_empty() {
+            HashMap::new()
+        } else {
+            debug!("looking up {} notes by their hash", queries.len());
+            checked_notes
+                .iter()
+                .filter_map(|(_, _, hash)| hash.clone())
+                .zip(find_notes_multi(&queries)?)
+                .filter_map(|(hash, ids)| Some((hash, *ids.first()?)))
+                .collect::<HashMap<_, _>>()
+        }
+    } else {
+        HashMap::new()
+    };
+
+    for (i, note, hash) in checked_notes {
+        let model = &state.models[&note.model];
+        let hash_match = hash
+            .and_then(|hash| hash_matches.get(&hash))
+            .and_then(|id| state.added_notes.iter().position(|n| n.id == Some(*id)));
+
+        let diff = |stored: &Note| NoteDiff {
+            protected_tags: protected_tags.get(&i).cloned().unwrap_or_default(),
+            ..note_diff(stored, &note, config.replace_all_fields, &config.matching)
+        };
+        let found = match hash_match {
+            Some(_) => None,
+            None => find_stored_note(&state.added_notes, model, &note, &config.matching),
+        };
+        if let Some(idx) = hash_match.or(found) {
+            warn_stale_wrapper(&mut stale_models, &state.added_notes[idx], &note);
+        }
+        if let Some(idx) = hash_match {
+            // the content is the same, only the tags may have changed
+            let diff = NoteDiff {
+                fields: Vec::new(),
+                ..diff(&state.added_notes[idx])
+            };
+            if diff.is_empty() {
+                debug!(
+                    "unchanged (same hash), skipping note in deck {} with fields {:?}",
+                    &note.deck,
+                    Note::question_or_fields(note.first_card, &note.fields),
+                );
+                plan.unchanged.push((i, note));
+            } else if note.protected || config.is_kept(&state.added_notes[idx]) {
+                plan.protected.push((i, idx, diff, note));
+            } else {
+                plan.updates.push((i, idx, diff, note));
+            }
+            continue;
+        }
+
+        if let Some(idx) = found {
+            let diff = diff(&state.added_notes[idx]);
+            if diff.is_empty() {
+                debug!(
+                    "unchanged, skipping note in deck {} with fields {:?}",
+                    &note.deck,
+                    Note::question_or_fields(note.first_card, &note.fields),
+                );
+                plan.unchanged.push((i, note));
+                continue;
+            }
+            if note.protected || config.is_kept(&state.added_notes[idx]) {
+                plan.protected.push((i, idx, diff, note));
+                continue;
+            }
+            if state.added_notes[idx].id.is_some() {
+                plan.updates.push((i, idx, diff, note));
+                continue;
+            }
+        }
+        let mut note = note;
+        complete_fields(&mut note, &model.field_names);
+        if config.tag_source_file {
+            // only added to new notes so it doesn't take part in the comparison
+            note.add_tags([paths.source_tag()], config.tag_case);
+        }
+        plan.new.push((i, note));
+    }
+    placeholders.finish()?;
+    debug!("checked notes");
+
+    Ok(plan)
+}
+
+/// Warns once per model if a field of `stored` is wrapped for another format than
+/// the one of the field in `new`, e.g. after changing `field_formats` of the config.
+///
+/// The notes still match, as the wrappers are ignored when comparing them, but the
+/// stored notes keep their old wrapper until they are migrated.
+fn warn_stale_wrapper(stale_models: &mut HashSet<String>, stored: &Note, new: &Note) {
+    if stale_models.contains(&new.model) {
+        return;
+    }
+    let stale = new.fields.keys().find_map(|name| {
+        let format = new.format(name);
+        let stored_format = FieldFormat::detect(stored.fields.get(name)?)?;
+        (format != FieldFormat::Raw && stored_format != format).then_some((stored_format, format))
+    });
+    if let Some((from, to)) = stale {
+        warn!(
+            "notes of model {} are stored with {} fields, but the fields are {} now",
+            new.model,
+            from.name(),
+            to.name()
+        );
+        info!(
+            "run `anki-tex migrate-format --model {:?} --from {} --to {}` to rewrite them",
+            new.model,
+            from.name(),
+            to.name()
+        );
+        stale_models.insert(new.model.clone());
+    }
+}
+
+/// Creates the notes in batches of [`ADD_NOTES_BATCH`] in the given order and adds
+/// them to `state`.
+fn create_notes(
+    state: &mut State,
+    paths: &FilePaths,
+    new_notes: Vec<(usize, Note)>,
+    summary: &mut report::RunSummary,
+) {
+    let mut created_ids = Vec::new();
+    let bar = progress_bar(new_notes.len(), "creating notes");
+    let mut new_notes = new_notes.into_iter().peekable();
+    while new_notes.peek().is_some() {
+        // chunks are created one after another so the order is kept
+        let batch = new_notes.by_ref().take(ADD_NOTES_BATCH).collect::<Vec<_>>();
+        bar.inc(batch.len() as u64);
+        let notes = addable_notes(state, paths, batch, summary);
+        if notes.is_empty() {
+            continue;
+        }
+        let api_notes = notes.iter().map(to_api_note).collect::<Vec<_>>();
+
+        let ids = match add_notes(&api_notes) {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("failed to create {} notes: {}", notes.len(), e);
+                for note in notes {
+                    summary.deck_mut(&note.deck).errors += 1;
+                }
+                continue;
+            }
+        };
+        for (id, mut note) in ids.into_iter().zip(notes) {
+            let deck_summary = summary.deck_mut(&note.deck);
+            if id.is_none() {
+                deck_summary.duplicates += 1;
+                debug!(
+                    "Duplicate! Note in deck {} with fields {:?} already existed",
+                    &note.deck,
+                    Note::question_or_fields(note.first_card, &note.fields),
+                );
+            } else {
+                deck_summary.created += 1;
+                debug!(
+                    "created note in deck {} with fields {:?}",
+                    &note.deck,
+                    Note::question_or_fields(note.first_card, &note.fields),
+                );
+            }
+            created_ids.extend(id);
+            note.id = id;
+            state.added_notes.push(note);
+        }
+    }
+    bar.finish_and_clear();
+
+    if created_ids.is_empty() {
+        return;
+    }
+    let record = RunRecord {
+        file: Some(paths.main.to_string_lossy().into_owned()),
+        created: created_ids,
+        profile: state.profile.clone(),
+        ..RunRecord::new()
+    };
+    match record.append(&state.run_log) {
+        Ok(()) => info!(
+            "logged as run `{}`, revert it with `anki-tex undo`",
+            record.id
+        ),
+        Err(e) => warn!("failed to log the created notes: {:#}", e),
+    }
+}
+
+/// Asks anki which notes of `batch` can be created and returns them.
+///
+/// The others are logged with the reason given by anki and counted in `summary`.
+/// Duplicates are remembered in `state` like duplicates found while creating notes.
+/// If anki can't check the notes, all of them are returned.
+fn addable_notes(
+    state: &mut State,
+    paths: &FilePaths,
+    batch: Vec<(usize, Note)>,
+    summary: &mut report::RunSummary,
+) -> Vec<Note> {
+    let api_notes = batch
+        .iter()
+        .map(|(_, note)| to_api_note(note))
+        .collect::<Vec<_>>();
+    let checks = match can_add_notes_with_error_detail(&api_notes) {
+        Ok(checks) => checks,
+        Err(e) => {
+            debug!("falling back to `canAddNotes`: {:#}", e);
+            match can_add_notes(&api_notes) {
+                // without details, rejected notes are assumed to be duplicates like
+                // notes which `addNotes` doesn't create
+                Ok(can_add) => can_add
+                    .into_iter()
+                    .map(|can_add| api::CanAddNote {
+                        can_add,
+                        error: (!can_add).then(|| String::from("duplicate")),
+                    })
+                    .collect(),
+                Err(e) => {
+                    warn!("can't check which notes can be created: {:#}", e);
+                    return batch.into_iter().map(|(_, note)| note).collect();
+                }
+            }
+        }
+    };
+
+    let mut addable = Vec::new();
+    for ((i, note), check) in batch.into_iter().zip(checks) {
+        if check.can_add {
+            addable.push(note);
+            continue;
+        }
+        let reason = check
+            .error
+            .unwrap_or_else(|| String::from("no reason given"));
+        let deck_summary = summary.deck_mut(&note.deck);
+        if reason.contains("duplicate") {
+            deck_summary.duplicates += 1;
+            debug!(
+                "Duplicate! Note #{} of {} in deck {} with fields {:?} already existed",
+                i + 1,
+                paths.main.to_string_lossy(),
+                &note.deck,
+                Note::question_or_fields(note.first_card, &note.fields),
+            );
+            state.added_notes.push(note);
+        } else {
+            error!(
+                "anki can't create note #{} of {} in deck {}: {}",
+                i + 1,
+                paths.main.to_string_lossy(),
+                note.deck,
+                reason
+            );
+            deck_summary.errors += 1;
+        }
+    }
+    addable
+}
+
+/// Number of notes shown when asking whether many notes should be created
+const NEW_NOTES_SAMPLE: usize = 5;
+
+/// Asks before creating more than `max_new_notes_per_run` notes.
+///
+/// Fails if the user declines or stdin isn't a terminal.
+fn confirm_new_notes(config: &Config, paths: &FilePaths, notes: &[(usize, Note)]) -> Result<()> {
+    let Some(max) = config.max_new_notes_per_run else {
+        return Ok(());
+    };
+    if notes.len() <= max {
+        return Ok(());
+    }
+
+    warn!(
+        "{} would create {} new notes, more than `max_new_notes_per_run` ({})",
+        paths.main.to_string_lossy(),
+        notes.len(),
+        max
+    );
+    for (i, note) in notes.iter().take(NEW_NOTES_SAMPLE) {
+        warn!(
+            "  #{} in deck {}: {:?}",
+            i + 1,
+            note.deck,
+            Note::question_or_fields(note.first_card, &note.fields).resolve()
+        );
+    }
+    if notes.len() > NEW_NOTES_SAMPLE {
+        warn!("  and {} more", notes.len() - NEW_NOTES_SAMPLE);
+    }
+
+    if !std::io::stdin().is_terminal() {
+        return Err(
+            eyre!("not creating {} notes without confirmation", notes.len())
+                .suggestion("pass `--yes` or raise `max_new_notes_per_run` in the config"),
+        );
+    }
+    if !confirm(&format!("create {} notes?", notes.len()))? {
+        return Err(eyre!("creating {} notes was cancelled", notes.len()));
+    }
+    Ok(())
+}
+
+/// Maximum number of notes created with one request
+const ADD_NOTES_BATCH: usize = 500;
+/// Number of notes or cards requested at once when getting their infos
+const INFO_CHUNK: usize = 1000;
+
+/// Order in which new notes are sent to anki.
+///
+/// Anki shows new cards roughly in the order they were created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum CreationOrder {
+    /// The order of the file
+    #[default]
+    File,
+    /// The reversed order of the file
+    Reverse,
+    /// Random order, reproducible with `--seed`
+    Shuffled,
+}
+
+/// [`CreationOrder`] with the seed used for shuffling
+#[derive(Debug, Clone, Copy)]
+struct NoteOrder {
+    order: CreationOrder,
+    seed: Option<u64>,
+}
+
+impl NoteOrder {
+    /// Reorders `notes` and returns a description of the used order.
+    fn apply<T>(&self, notes: &mut [T]) -> String {
+        use rand::{seq::SliceRandom, SeedableRng};
+
+        match self.order {
+            CreationOrder::File => String::from("file"),
+            CreationOrder::Reverse => {
+                notes.reverse();
+                String::from("reverse")
+            }
+            CreationOrder::Shuffled => {
+                let seed = self.seed.unwrap_or_else(rand::random);
+                let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+                notes.shuffle(&mut rng);
+                format!("shuffled (seed {})", seed)
+            }
+        }
+    }
+}
+
+/// Prints the summary of a run of `create` or `diff`.
+/// Opens `profile` in anki if set and checks that the open profile is the expected
+/// one, i.e. `profile` or `expected_profile` of the config.
+///
+/// Returns the open profile, `None` if AnkiConnect can't report it. Called before
+/// anything is changed.
+fn check_profile(
+    config: &Config,
+    profile: Option<&str>,
+    ignore_check: bool,
+) -> Result<Option<String>> {
+    if let Some(profile) = profile {
+        info!("opening profile `{}`", profile);
+        api::load_profile(profile)
+            .map_err(|e| explain_unsupported(e, "loadProfile"))
+            .with_note(|| eyre!("while opening profile `{}`", profile))?;
+    }
+    let expected = profile.or(config.expected_profile.as_deref());
+
+    let active = match api::get_active_profile() {
+        Ok(active) => active,
+        Err(e) if api::is_unsupported_action(&e) => {
+            if let (Some(expected), false) = (expected, ignore_check) {
+                return Err(eyre!(
+                    "can't check that the profile `{}` is open in anki",
+                    expected
+                )
+                .note("this version of AnkiConnect doesn't support `getActiveProfile`")
+                .suggestion("update AnkiConnect or run with `--ignore-profile-check`"));
+            }
+            debug!("AnkiConnect doesn't report the open profile");
+            return Ok(None);
+        }
+        Err(e) => return Err(e.wrap_err("failed to get the profile open in anki")),
+    };
+    info!("anki profile: {}", active);
+
+    if let Some(expected) = expected.filter(|expected| *expected != active) {
+        if ignore_check {
+            warn!(
+                "anki has the profile `{}` open instead of `{}`, continuing because of `--ignore-profile-check`",
+                active, expected
+            );
+        } else {
+            return Err(eyre!(
+                "anki has the profile `{}` open, but `{}` is expected",
+                active,
+                expected
+            )
+            .note("nothing was changed")
+            .suggestion(format!(
+                "switch to the profile in anki, run with `--profile {}` to open it automatically or with `--ignore-profile-check` to use `{}` anyway",
+                expected, active
+            )));
+        }
+    }
+    Ok(Some(active))
+}
+
+/// Number and duration of all requests sent to anki so far, `None` if there were none
+fn request_totals() -> Option<report::RequestTotals> {
+    let timings = api::timings();
+    (!timings.is_empty()).then(|| report::RequestTotals {
+        requests: timings.values().map(|t| t.count).sum(),
+        total_ms: timings
+            .values()
+            .map(|t| t.total)
+            .sum::<Duration>()
+            .as_millis() as u64,
+    })
+}
+
+/// Prints the number and latency of the requests sent to anki by their action, to
+/// stderr with `--output json`.
+fn print_timings(output: OutputFormat) {
+    let timings = api::timings();
+    if timings.is_empty() {
+        return;
+    }
+    let ms = |d: Duration| format!("{:.1}", d.as_secs_f64() * 1000.0);
+    let mut table = report::Table::new(&["action", "requests", "total ms", "mean ms", "max ms"]);
+    for column in 1..=4 {
+        table = table.align(column, report::Align::Right);
+    }
+    let mut totals = api::ActionTiming::default();
+    for (action, timing) in &timings {
+        table.row(vec![
+            action.clone(),
+            timing.count.to_string(),
+            ms(timing.total),
+            ms(timing.total / timing.count as u32),
+            ms(timing.max),
+        ]);
+        totals.count += timing.count;
+        totals.total += timing.total;
+        totals.max = totals.max.max(timing.max);
+    }
+    table.totals(vec![
+        String::from("total"),
+        totals.count.to_string(),
+        ms(totals.total),
+        ms(totals.total / totals.count as u32),
+        ms(totals.max),
+    ]);
+    match output {
+        OutputFormat::Text => print!("{}", table.render(report::use_color())),
+        OutputFormat::Json => eprint!("{}", table.render(false)),
+    }
+}
+
+fn print_summary(summary: &report::RunSummary, output: OutputFormat) -> Result<()> {
+    let summary = &report::RunSummary {
+        requests: request_totals(),
+        ..summary.clone()
+    };
+    warn_unmatched_decks(summary);
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(summary)?),
+        OutputFormat::Text => {
+            if !summary.has_changes() && summary.totals().errors == 0 {
+                info!("nothing to do :)");
+            }
+            if !summary.is_empty() {
+                print!("{}", summary.to_table().render(report::use_color()));
+            }
+            if let Some(order) = &summary.order {
+                println!("new notes were sent in {} order", order);
+            }
+            if !summary.filtered.is_empty() {
+                println!(
+                    "left out {} notes: {}",
+                    summary.filtered.values().sum::<usize>(),
+                    summary
+                        .filtered
+                        .iter()
+                        .map(|(filter, count)| format!("{} by {}", count, filter))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            if let Some(profile) = &summary.profile {
+                println!("anki profile: {}", profile);
+            }
+            if let Some(totals) = &summary.requests {
+                println!(
+                    "{} requests to anki in {} ms",
+                    totals.requests, totals.total_ms
+                );
+            }
+            for (step, status) in &summary.steps {
+                println!("{}: {}", step, status);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Version of the format of [`CachedState`], increased when fields change in an
+/// incompatible way. Caches of other versions are rebuilt from scratch.
+const CACHE_SCHEMA_VERSION: u32 = 2;
+
+/// Data kept between runs, saved as JSON in `cache_file` of the config
+#[derive(Debug, Default, serde::Serialize, Deserialize)]
+struct CachedState {
+    /// [`CACHE_SCHEMA_VERSION`] of the anki-tex which wrote the cache
+    #[serde(default)]
+    schema_version: u32,
+    /// Time of the last successful sync in seconds since the unix epoch
+    last_sync: Option<i64>,
+    /// Decks in anki when the state was last loaded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deck_names: Vec<String>,
+    /// Ids of the generated notes when the state was last loaded by their
+    /// [`Note::content_hash`], notes with the same content share a hash
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    managed_notes: BTreeMap<String, Vec<usize>>,
+    /// Hash of `custom.sty` of the last successful `create`
+    custom_sty_hash: Option<String>,
+    /// `generated_tag` of the last run, see [`check_generated_tag`]
+    generated_tag: Option<String>,
+    /// Cards buried by `focus` which weren't unburied with `focus --undo` yet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    focused_cards: Vec<usize>,
+    /// [`files_hash`] of the last successful `create` by the absolute path of the
+    /// main file, see `create --changed-only`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    file_hashes: BTreeMap<String, String>,
+    /// Models and their fields when anki was last asked for them, see `get-models --offline`
+    model_fields: Option<CachedModels>,
+    /// Parse results of the last successful run with `--changed-only` by the absolute
+    /// path of the main file
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    parsed_files: BTreeMap<String, CachedParse>,
+    /// Last values of the counters of `{{counter:<name>}}`, see [`expand_placeholders`]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    counters: BTreeMap<String, u64>,
+}
+
+/// How long to wait for the lock of another run, see [`CacheLock`]
+const CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Locks older than this were left by a crashed run
+const CACHE_LOCK_STALE: Duration = Duration::from_secs(60);
+
+/// Lock file next to the cache, held while values which have to be unique between
+/// concurrent runs are taken from it, like the counters of placeholders
+struct CacheLock(PathBuf);
+
+impl CacheLock {
+    fn acquire(cache_file: &Path) -> Result<Self> {
+        let path = cache_file.with_extension("lock");
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let start = std::time::Instant::now();
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => return Ok(Self(path)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    return Err(e)
+                        .with_note(|| eyre!("while creating lock {}", path.to_string_lossy()))
+                }
+            }
+            let stale = std::fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .is_ok_and(|time| time.elapsed().unwrap_or_default() > CACHE_LOCK_STALE);
+            if stale {
+                warn!("removing stale lock {}", path.to_string_lossy());
+                // another run may have removed it first
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            if start.elapsed() > CACHE_LOCK_TIMEOUT {
+                return Err(
+                    eyre!("the cache is locked by another run of anki-tex").note(format!(
+                        "remove {} if no other run is active",
+                        path.to_string_lossy()
+                    )),
+                );
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("failed to remove lock {}: {}", self.0.to_string_lossy(), e);
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, Deserialize)]
+struct CachedParse {
+    /// [`Config::parse_hash`] of the run, other settings may parse the file differently
+    config: String,
+    index: parse_file::ParseIndex,
+}
+
+/// Saves the parse result `index` of the main file `key` for the next run with
+/// `--changed-only`, unless notes failed and have to be processed again.
+fn save_parsed(
+    config: &Config,
+    key: String,
+    index: parse_file::ParseIndex,
+    summary: &report::RunSummary,
+) {
+    if summary.has_errors() {
+        return;
+    }
+    let saved = CachedState::update(&config.cache_file, |cache| {
+        cache.parsed_files.insert(
+            key,
+            CachedParse {
+                config: config.parse_hash.clone(),
+                index,
+            },
+        );
+    });
+    if let Err(e) = saved {
+        warn!("failed to save the parsed notes in the cache: {:#}", e);
+    }
+}
+
+#[derive(Debug, serde::Serialize, Deserialize)]
+struct CachedModels {
+    /// Time the models were fetched in seconds since the unix epoch
+    time: i64,
+    models: BTreeMap<String, Vec<String>>,
+}
+
+impl CachedModels {
+    fn new(models: &HashMap<String, Model>) -> Self {
+        Self {
+            time: chrono::Utc::now().timestamp(),
+            models: models
+                .iter()
+                .map(|(name, model)| (name.clone(), model.field_names.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Saves the fields of `models` in the cache for `get-models --offline`.
+fn cache_models(config: &Config, models: &HashMap<String, Model>) {
+    let saved = CachedState::update(&config.cache_file, |cache| {
+        cache.model_fields = Some(CachedModels::new(models));
+    });
+    if let Err(e) = saved {
+        warn!("failed to save the models in the cache: {:#}", e);
+    }
+}
+
+/// Saves the decks, models and generated notes of the collection in the cache.
+fn cache_collection(
+    config: &Config,
+    deck_names: &[String],
+    models: &HashMap<String, Model>,
+    notes: &[Note],
+) {
+    let mut managed_notes = BTreeMap::<String, Vec<usize>>::new();
+    for note in notes {
+        if let (Some(id), true) = (note.id, note.tags.contains(&config.generated_tag)) {
+            managed_notes
+                .entry(note.content_hash(&config.matching))
+                .or_default()
+                .push(id);
+        }
+    }
+    let saved = CachedState::update(&config.cache_file, |cache| {
+        cache.deck_names = deck_names.to_vec();
+        cache.model_fields = Some(CachedModels::new(models));
+        cache.managed_notes = managed_notes;
+    });
+    if let Err(e) = saved {
+        warn!("failed to save the collection in the cache: {:#}", e);
+    }
+}
+
+/// Options of `get-models`
+struct ModelsOptions {
+    /// Print the fields of the models
+    fields: bool,
+    /// Only print this model
+    model: Option<String>,
+    /// Only print the models used by the file
+    used: bool,
+    /// Use the models cached by the last run
+    offline: bool,
+}
+
+/// Prints the models of anki or of the cache, see [`ModelsOptions`].
+fn get_models(
+    config: &Config,
+    paths: &FilePaths,
+    options: &ModelsOptions,
+    output: OutputFormat,
+) -> Result<()> {
+    let mut models = if options.offline {
+        let cached = CachedState::load(&config.cache_file)
+            .model_fields
+            .ok_or_else(|| {
+                eyre!("no models are cached")
+                    .suggestion("run `anki-tex get-models` once while anki is running")
+            })?;
+        let time = fmt_timestamp(cached.time);
+        match output {
+            OutputFormat::Text => println!("cached at {}", time),
+            OutputFormat::Json => eprintln!("cached at {}", time),
+        }
+        cached.models
+    } else {
+        let models = State::load_models()?;
+        cache_models(config, &models);
+        models
+            .into_iter()
+            .map(|(name, model)| (name, model.field_names))
+            .collect()
+    };
+
+    if let Some(model) = &options.model {
+        if !models.contains_key(model) {
+            return Err(eyre!("model {} does not exist", model));
+        }
+        models.retain(|name, _| name == model);
+    }
+    if options.used {
+        let mut used = BTreeSet::new();
+        used_models(config, paths, &mut used)?;
+        models.retain(|name, _| used.contains(name));
+    }
+
+    let mut names = models.keys().collect::<Vec<_>>();
+    names.sort_by(|a, b| report::collate(a, b));
+    match (output, options.fields) {
+        (OutputFormat::Json, true) => println!("{}", serde_json::to_string_pretty(&models)?),
+        (OutputFormat::Json, false) => println!("{}", serde_json::to_string_pretty(&names)?),
+        (OutputFormat::Text, true) => {
+            for name in names {
+                println!("{}", name);
+                for field in &models[name] {
+                    println!("  {}", field);
+                }
+            }
+        }
+        (OutputFormat::Text, false) => {
+            let names = names.into_iter().map(String::as_str).collect::<Vec<_>>();
+            println!("All model names: \n {}", names.join("\n "))
+        }
+    }
+    Ok(())
+}
+
+/// Part of [`CachedState`] which is read before the rest, so that caches of other
+/// versions are recognized even if their fields can't be read
+#[derive(Deserialize)]
+struct CacheVersion {
+    #[serde(default)]
+    schema_version: u32,
+}
+
+impl CachedState {
+    fn empty() -> Self {
+        Self {
+            schema_version: CACHE_SCHEMA_VERSION,
+            ..Self::default()
+        }
+    }
+
+    /// Loads the cache, or an empty cache if it is missing, invalid or of another
+    /// version. Falls back to the TOML cache of older versions of anki-tex.
+    fn load(path: &Path) -> Self {
+        let text = match read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::load_legacy(path),
+            Err(e) => {
+                warn!(
+                    "rebuilding unreadable cache {}: {}",
+                    path.to_string_lossy(),
+                    e
+                );
+                return Self::empty();
+            }
+        };
+        let loaded = serde_json::from_str::<CacheVersion>(&text).and_then(|version| {
+            if version.schema_version == CACHE_SCHEMA_VERSION {
+                serde_json::from_str::<Self>(&text).map(Some)
+            } else {
+                warn!(
+                    "rebuilding cache {} of version {}, the current version is {}",
+                    path.to_string_lossy(),
+                    version.schema_version,
+                    CACHE_SCHEMA_VERSION
+                );
+                Ok(None)
+            }
+        });
+        match loaded {
+            Ok(cache) => cache.unwrap_or_else(Self::empty),
+            Err(e) => {
+                warn!("rebuilding invalid cache {}: {}", path.to_string_lossy(), e);
+                Self::empty()
+            }
+        }
+    }
+
+    /// Path of the TOML cache written by older versions of anki-tex
+    fn legacy_path(path: &Path) -> PathBuf {
+        path.with_extension("toml")
+    }
+
+    fn load_legacy(path: &Path) -> Self {
+        let legacy = Self::legacy_path(path);
+        let Ok(text) = read_to_string(&legacy) else {
+            return Self::empty();
+        };
+        match toml::from_str::<Self>(&text) {
+            Ok(cache) => {
+                info!(
+                    "moving cache {} to {}",
+                    legacy.to_string_lossy(),
+                    path.to_string_lossy()
+                );
+                Self {
+                    schema_version: CACHE_SCHEMA_VERSION,
+                    ..cache
+                }
+            }
+            Err(e) => {
+                warn!("ignoring invalid cache {}: {}", legacy.to_string_lossy(), e);
+                Self::empty()
+            }
+        }
+    }
+
+    /// Loads the cache, changes it with `change` and saves it while holding the
+    /// [`CacheLock`], so concurrent runs don't undo each other's changes, e.g. reserved
+    /// counters.
+    fn update(path: &Path, change: impl FnOnce(&mut Self)) -> Result<()> {
+        let _lock = CacheLock::acquire(path)?;
+        let mut cache = Self::load(path);
+        change(&mut cache);
+        cache.save(path)
+    }
+
+    /// Writes the cache, use [`CachedState::update`] unless the [`CacheLock`] is held.
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // concurrent runs write to their own temporary file in the same directory,
+        // so that renaming is atomic
+        let tmp = edit::with_suffix(path, &format!(".{}.tmp", std::process::id()));
+        let result = std::fs::write(&tmp, serde_json::to_string(self)?)
+            .and_then(|()| std::fs::rename(&tmp, path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        result.with_note(|| eyre!("while writing cache {}", path.to_string_lossy()))
+    }
+}
+
+/// Formats a time in seconds since the unix epoch in the local timezone.
+fn fmt_timestamp(time: i64) -> String {
+    chrono::DateTime::from_timestamp(time, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// Prints the path, the age and the number of entries of the cache.
+fn show_cache(config: &Config, output: OutputFormat) -> Result<()> {
+    let path = &config.cache_file;
+    let age = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .map(|time| time.elapsed().unwrap_or_default().as_secs());
+    let cache = CachedState::load(path);
+    let models = cache.model_fields.as_ref().map_or(0, |m| m.models.len());
+    let notes = cache.managed_notes.values().map(Vec::len).sum::<usize>();
+    match output {
+        OutputFormat::Text => {
+            println!("path: {}", path.to_string_lossy());
+            match age {
+                Some(age) => println!("age: {}s", age),
+                None => println!("age: not saved yet"),
+            }
+            println!("schema version: {}", cache.schema_version);
+            println!("decks: {}", cache.deck_names.len());
+            println!("models: {}", models);
+            println!("generated notes: {}", notes);
+            println!(
+                "last sync: {}",
+                cache
+                    .last_sync
+                    .map_or_else(|| String::from("never"), fmt_timestamp)
+            );
+            println!(
+                "custom.sty hash: {}",
+                cache.custom_sty_hash.as_deref().unwrap_or("none")
+            );
+            println!("parsed files: {}", cache.parsed_files.len());
+            println!("counters: {}", cache.counters.len());
+            println!("focused cards: {}", cache.focused_cards.len());
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "path": path,
+                "age_secs": age,
+                "schema_version": cache.schema_version,
+                "decks": cache.deck_names.len(),
+                "models": models,
+                "generated_notes": notes,
+                "last_sync": cache.last_sync,
+                "custom_sty_hash": cache.custom_sty_hash,
+                "parsed_files": cache.parsed_files.len(),
+                "counters": cache.counters.len(),
+                "focused_cards": cache.focused_cards.len(),
+            }))?
+        ),
+    }
+    Ok(())
+}
+
+/// Removes the cache and the cache of older versions of anki-tex.
+fn clear_cache(config: &Config) -> Result<()> {
+    let _lock = CacheLock::acquire(&config.cache_file)?;
+    let mut removed = false;
+    for path in [
+        config.cache_file.clone(),
+        CachedState::legacy_path(&config.cache_file),
+    ] {
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                info!("removed {}", path.to_string_lossy());
+                removed = true;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_note(|| eyre!("while removing {}", path.to_string_lossy()))
+            }
+        }
+    }
+    if !removed {
+        info!("the cache is already empty");
+    }
+    Ok(())
+}
+
+/// Hash of the content of the files read when updating `paths`, with all children if
+/// the main path is a directory.
+fn files_hash(config: &Config, paths: &FilePaths) -> Result<String> {
+    fn add(config: &Config, path: &Path, content: &mut Vec<u8>) -> Result<()> {
+        if path.is_dir() {
+            for child in config.children(path)? {
+                add(config, &child, content)?;
+            }
+            return Ok(());
+        }
+        content.extend(path.to_string_lossy().as_bytes());
+        content.push(0);
+        // missing files are reported by the run itself
+        content.extend(std::fs::read(path).unwrap_or_default());
+        content.push(0);
+        Ok(())
+    }
+
+    let mut content = Vec::new();
+    for path in [&paths.main, &paths.anki, &paths.custom] {
+        add(config, path, &mut content)?;
+    }
+    Ok(format!("{:016x}", fasthash::metro::hash64(content)))
+}
+
+/// Runs `on_change_cmd` after `summary` changed notes of `paths`, with the main file
+/// and the numbers of created and updated notes in the environment.
+///
+/// The output of the command is logged, failures don't stop `watch`.
+fn run_on_change_cmd(command: &[String], paths: &FilePaths, summary: &report::RunSummary) {
+    let Some((program, args)) = command.split_first() else {
+        return;
+    };
+    let totals = summary.totals();
+    debug!("running {} {:?}", program, args);
+    let output = match std::process::Command::new(program)
+        .args(args)
+        .env("ANKI_TEX_FILE", &paths.main)
+        .env("ANKI_TEX_CREATED", totals.created.to_string())
+        .env("ANKI_TEX_UPDATED", totals.updated.to_string())
+        .stdin(std::process::Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            error!("can't run `{}` of `on_change_cmd`: {}", program, e);
+            return;
+        }
+    };
+    for line in String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .chain(String::from_utf8_lossy(&output.stderr).lines())
+    {
+        info!("{}: {}", program, line);
+    }
+    if !output.status.success() {
+        error!("`on_change_cmd` failed with {}", output.status);
+    }
+}
+
+/// A run which created, updated or deleted notes, stored as one line of the run log.
+///
+/// The log is the source of truth for `undo`.
+#[derive(Debug, serde::Serialize, Deserialize)]
+struct RunRecord {
+    /// Unique id of the run, starting with its local time
+    id: String,
+    /// Seconds since the unix epoch when the run finished changing notes
+    time: i64,
+    /// Arguments of the invocation
+    args: Vec<String>,
+    /// File the notes were created from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    created: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    updated: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deleted: Vec<usize>,
+    /// Backup of the updated and deleted notes as they were before the run, see
+    /// [`backup_notes`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backup: Option<PathBuf>,
+    /// Id of the run reverted by this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    undoes: Option<String>,
+    /// Profile open in anki during the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+}
+
+impl RunRecord {
+    fn new() -> Self {
+        Self {
+            id: format!(
+                "{}-{:04x}",
+                chrono::Local::now().format("%Y%m%d-%H%M%S"),
+                rand::random::<u16>()
+            ),
+            time: chrono::Utc::now().timestamp(),
+            args: std::env::args().skip(1).collect(),
+            file: None,
+            created: Vec::new(),
+            updated: Vec::new(),
+            deleted: Vec::new(),
+            backup: None,
+            undoes: None,
+            profile: None,
+        }
+    }
+
+    /// Appends the run to the log at `path`.
+    fn append(&self, path: &Path) -> Result<()> {
+        use std::io::Write;
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_note(|| eyre!("while opening run log {}", path.to_string_lossy()))?;
+        writeln!(file, "{}", serde_json::to_string(self)?)
+            .with_note(|| eyre!("while writing run log {}", path.to_string_lossy()))
+    }
+
+    /// Reads all runs of the log at `path`, skipping invalid lines.
+    fn load_all(path: &Path) -> Result<Vec<Self>> {
+        if !path.is_file() {
+            return Ok(Vec::new());
+        }
+        let text = read_to_string(path)
+            .with_note(|| eyre!("while reading run log {}", path.to_string_lossy()))?;
+        Ok(text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .filter_map(|(i, line)| match serde_json::from_str(line) {
+                Ok(run) => Some(run),
+                Err(e) => {
+                    warn!(
+                        "ignoring invalid line {} of run log {}: {}",
+                        i + 1,
+                        path.to_string_lossy(),
+                        e
+                    );
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// Deletes the notes created by the run `id`, or by the last run which wasn't
+/// undone yet.
+///
+/// Notes modified in anki after the run are kept.
+fn undo(config: &Config, id: Option<&str>, yes: bool) -> Result<()> {
+    let runs = RunRecord::load_all(&config.run_log)?;
+    let is_undone = |run: &RunRecord| runs.iter().any(|r| r.undoes.as_ref() == Some(&run.id));
+    let run = match id {
+        Some(id) => {
+            let run = runs
+                .iter()
+                .find(|run| run.id == id)
+                .ok_or_else(|| eyre!("no run with id `{}` in the run log", id))
+                .with_note(|| format!("the run log is {}", config.run_log.to_string_lossy()))?;
+            if is_undone(run) {
+                return Err(eyre!("run `{}` was already undone", id));
+            }
+            run
+        }
+        None => runs
+            .iter()
+            .rev()
+            .find(|run| !run.created.is_empty() && !is_undone(run))
+            .ok_or_else(|| eyre!("no run which created notes to undo"))?,
+    };
+    if run.created.is_empty() {
+        return Err(eyre!("run `{}` didn't create notes", run.id));
+    }
+
+    // notes may have been deleted in anki since
+    let query = format!(
+        "nid:{}",
+        run.created
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    let mut existing = find_notes(&query)?;
+    let gone = run.created.len() - existing.len();
+    if let Some(keep_tag) = &config.keep_tag {
+        let kept = find_notes(&format!("{} \"tag:{}\"", query, keep_tag))?;
+        if !kept.is_empty() {
+            info!("keeping {} notes with the tag {}", kept.len(), keep_tag);
+            existing.retain(|id| !kept.contains(id));
+        }
+    }
+    let unmodified = |ids: &[usize]| -> Result<Vec<usize>> {
+        let mut unmodified = Vec::new();
+        for chunk in ids.chunks(INFO_CHUNK) {
+            for note in notes_mod_time(chunk)? {
+                if note.mod_time > run.time {
+                    warn!(
+                        "keeping note {}, it was modified in anki after the run",
+                        note.note_id
+                    );
+                } else {
+                    unmodified.push(note.note_id);
+                }
+            }
+        }
+        Ok(unmodified)
+    };
+    let mut delete = unmodified(&existing)?;
+    if gone > 0 {
+        info!("{} notes of the run don't exist anymore", gone);
+    }
+    if delete.is_empty() {
+        println!("no notes of run `{}` to delete", run.id);
+        return Ok(());
+    }
+
+    println!(
+        "run `{}` (anki-tex {}) created {} notes{}",
+        run.id,
+        run.args.join(" "),
+        run.created.len(),
+        run.file
+            .as_ref()
+            .map_or(String::new(), |file| format!(" from {}", file))
+    );
+    let preview = notes_info(&delete[..delete.len().min(NEW_NOTES_SAMPLE)])?;
+    for note in &preview {
+        let first = note
+            .fields
+            .values()
+            .min_by_key(|field| field.order)
+            .map_or("", |field| field.value.as_str());
+        println!(
+            "  {} ({}): {}",
+            note.note_id,
+            note.model_name,
+            first.chars().take(60).collect::<String>()
+        );
+    }
+    if delete.len() > preview.len() {
+        println!("  and {} more", delete.len() - preview.len());
+    }
+    if !yes {
+        if !std::io::stdin().is_terminal() {
+            return Err(
+                eyre!("not deleting {} notes without confirmation", delete.len())
+                    .suggestion("pass `--yes` to delete them"),
+            );
+        }
+        if !confirm(&format!("delete {} notes?", delete.len()))? {
+            return Err(eyre!("deleting {} notes was cancelled", delete.len()));
+        }
+        // the notes may have been edited while the confirmation was pending
+        delete = unmodified(&delete)?;
+        if delete.is_empty() {
+            println!("no notes of run `{}` to delete", run.id);
+            return Ok(());
+        }
+    }
+
+    let mut record = RunRecord {
+        undoes: Some(run.id.clone()),
+        ..RunRecord::new()
+    };
+    record.backup = backup_notes(config, &record.id, &delete)?;
+    delete_notes(&delete)?;
+    record.deleted = delete;
+    record.append(&config.run_log)?;
+    println!(
+        "deleted {} notes, the undo was logged as run `{}`",
+        record.deleted.len(),
+        record.id
+    );
+    Ok(())
+}
+
+/// Notes as they were before a run changed them, written by [`backup_notes`]
+#[derive(Debug, serde::Serialize, Deserialize)]
+struct NoteBackup {
+    /// Id of the run, see [`RunRecord`]
+    run: String,
+    /// Seconds since the unix epoch when the backup was written
+    time: i64,
+    notes: Vec<BackedUpNote>,
+}
+
+#[derive(Debug, serde::Serialize, Deserialize)]
+struct BackedUpNote {
+    id: usize,
+    model: String,
+    deck: String,
+    fields: BTreeMap<String, String>,
+    tags: Vec<String>,
+}
+
+/// Saves the notes `ids` in a new backup of the run `run` before they are changed
+/// and removes the oldest backups exceeding `keep_backups`.
+///
+/// Returns the path of the backup, or `None` if there are no notes, backups are
+/// disabled or there are more than `max_backup_notes` notes.
+fn backup_notes(config: &Config, run: &str, ids: &[usize]) -> Result<Option<PathBuf>> {
+    if ids.is_empty() || config.keep_backups == 0 {
+        return Ok(None);
+    }
+    if ids.len() > config.max_backup_notes {
+        warn!(
+            "not backing up {} notes, more than `max_backup_notes` ({})",
+            ids.len(),
+            config.max_backup_notes
+        );
+        return Ok(None);
+    }
+    let notes = get_notes_by_ids(ids, CardDetails::Decks)?
+        .into_iter()
+        .filter_map(|note| {
+            Some(BackedUpNote {
+                id: note.id?,
+                model: note.model,
+                deck: note.deck,
+                fields: note.fields.into_iter().collect(),
+                tags: note.tags,
+            })
+        })
+        .collect();
+    let backup = NoteBackup {
+        run: run.to_owned(),
+        time: chrono::Utc::now().timestamp(),
+        notes,
+    };
+
+    let dir = &config.backup_dir;
+    std::fs::create_dir_all(dir)
+        .with_note(|| eyre!("while creating directory {}", dir.to_string_lossy()))?;
+    let path = dir.join(format!("{}.json", run));
+    std::fs::write(&path, serde_json::to_string_pretty(&backup)?)
+        .with_note(|| eyre!("while writing backup {}", path.to_string_lossy()))?;
+    info!(
+        "backed up {} notes to {}",
+        backup.notes.len(),
+        path.to_string_lossy()
+    );
+
+    // the names start with the time of the run
+    let mut backups = std::fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect::<Vec<_>>();
+    backups.sort();
+    let old = backups.len().saturating_sub(config.keep_backups);
+    for old in &backups[..old] {
+        debug!("removing old backup {}", old.to_string_lossy());
+        if let Err(e) = std::fs::remove_file(old) {
+            warn!(
+                "failed to remove old backup {}: {}",
+                old.to_string_lossy(),
+                e
+            );
+        }
+    }
+    Ok(Some(path))
+}
+
+/// Restores the fields and tags of the notes in the backup at `path`.
+///
+/// Deleted notes are created again, they get new ids.
+fn restore(config: &Config, path: &Path, yes: bool) -> Result<()> {
+    let text = read_to_string(path)
+        .with_note(|| eyre!("while reading backup {}", path.to_string_lossy()))?;
+    let backup = serde_json::from_str::<NoteBackup>(&text)
+        .with_note(|| eyre!("while reading backup {}", path.to_string_lossy()))?;
+    if backup.notes.is_empty() {
+        println!("the backup contains no notes");
+        return Ok(());
+    }
+    let query = format!(
+        "nid:{}",
+        backup
+            .notes
+            .iter()
+            .map(|note| note.id.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    let existing = find_notes(&query)?;
+    let deleted = backup.notes.len() - existing.len();
+    println!(
+        "backup of run `{}` contains {} notes",
+        backup.run,
+        backup.notes.len()
+    );
+    if deleted > 0 {
+        println!(
+            "{} of them were deleted, they are created again with new ids",
+            deleted
+        );
+    }
+    if !yes {
+        if !std::io::stdin().is_terminal() {
+            return Err(eyre!(
+                "not restoring {} notes without confirmation",
+                backup.notes.len()
+            )
+            .suggestion("pass `--yes` to restore them"));
+        }
+        if !confirm(&format!("restore {} notes?", backup.notes.len()))? {
+            return Err(eyre!(
+                "restoring {} notes was cancelled",
+                backup.notes.len()
+            ));
+        }
+    }
+
+    let mut record = RunRecord::new();
+    record.backup = backup_notes(config, &record.id, &existing)?;
+    let existing = existing.into_iter().collect::<HashSet<_>>();
+    let mut failed = 0;
+    for note in backup.notes {
+        let fields = note.fields.into_iter().collect::<HashMap<_, _>>();
+        if existing.contains(&note.id) {
+            match update_note(note.id, &fields, &note.tags) {
+                Ok(()) => record.updated.push(note.id),
+                Err(e) => {
+                    error!("failed to restore note {}: {}", note.id, e);
+                    failed += 1;
+                }
+            }
+            continue;
+        }
+        let api_note = anki_tex::api::Note {
+            deck_name: note.deck,
+            model_name: note.model,
+            fields,
+            tags: note.tags,
+            options: None,
+        };
+        match add_note(&api_note) {
+            Ok(Some(id)) => {
+                info!("created deleted note {} again as note {}", note.id, id);
+                record.created.push(id);
+            }
+            Ok(None) => {
+                error!(
+                    "can't create deleted note {} again, it is a duplicate",
+                    note.id
+                );
+                failed += 1;
+            }
+            Err(e) => {
+                error!("failed to create deleted note {} again: {}", note.id, e);
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "restored {} notes and created {} deleted notes again",
+        record.updated.len(),
+        record.created.len()
+    );
+    if !record.updated.is_empty() || !record.created.is_empty() {
+        record.append(&config.run_log)?;
+        info!("logged as run `{}`", record.id);
+    }
+    if failed > 0 {
+        return Err(eyre!("couldn't restore {} notes", failed));
+    }
+    Ok(())
+}
+
+/// Number of cards `focus` buries without `--yes`
+const FOCUS_MAX_CARDS: usize = 500;
+
+/// Buries the due cards which don't match `query` and remembers them in the cache for
+/// [`unfocus`].
+fn focus(config: &Config, query: &str, yes: bool) -> Result<()> {
+    let keep = find_cards(query)?.into_iter().collect::<HashSet<_>>();
+    if keep.is_empty() {
+        return Err(eyre!("no cards match `{}`", query)
+            .note("focusing on nothing would bury all due cards")
+            .suggestion("try the query in the browser of anki"));
+    }
+    let cards = find_cards("is:due -is:suspended -is:buried")?
+        .into_iter()
+        .filter(|card| !keep.contains(card))
+        .collect::<Vec<_>>();
+    if cards.is_empty() {
+        println!("nothing to bury, all due cards match `{}`", query);
+        return Ok(());
+    }
+
+    let mut decks = get_decks(&cards)?.into_iter().collect::<Vec<_>>();
+    decks.sort_by(|(a, _), (b, _)| report::collate(a, b));
+    let mut table = report::Table::new(&["deck", "buried"]).align(1, report::Align::Right);
+    for (deck, cards) in &decks {
+        table.row(vec![deck.clone(), cards.len().to_string()]);
+    }
+    table.totals(vec![String::from("total"), cards.len().to_string()]);
+    print!("{}", table.render(report::use_color()));
+
+    if cards.len() > FOCUS_MAX_CARDS && !yes {
+        return Err(eyre!(
+            "not burying {} cards without confirmation, more than {}",
+            cards.len(),
+            FOCUS_MAX_CARDS
+        )
+        .note(format!("{} cards match `{}`", keep.len(), query))
+        .suggestion("pass `--yes` to bury them"));
+    }
+
+    for chunk in cards.chunks(INFO_CHUNK) {
+        bury(chunk)?;
+    }
+    let saved = CachedState::update(&config.cache_file, |cache| {
+        cache.focused_cards.extend(&cards);
+        cache.focused_cards.sort_unstable();
+        cache.focused_cards.dedup();
+    });
+    if let Err(e) = saved {
+        warn!(
+            "failed to remember the buried cards, `focus --undo` won't find them: {:#}",
+            e
+        );
+    }
+    println!(
+        "buried {} cards until tomorrow, unbury them with `anki-tex focus --undo`",
+        cards.len()
+    );
+    Ok(())
+}
+
+/// Unburies the cards buried by [`focus`].
+fn unfocus(config: &Config) -> Result<()> {
+    let focused = CachedState::load(&config.cache_file).focused_cards;
+    if focused.is_empty() {
+        println!("no cards were buried by `focus`");
+        return Ok(());
+    }
+    let mut unburied = 0;
+    for chunk in focused.chunks(INFO_CHUNK) {
+        unburied += unbury(chunk)?;
+    }
+    let already = focused.len() - unburied;
+    if already > 0 {
+        info!(
+            "{} cards were already unburied, e.g. by anki on a new day",
+            already
+        );
+    }
+    // cards buried by a concurrent `focus` are kept
+    CachedState::update(&config.cache_file, |cache| {
+        cache
+            .focused_cards
+            .retain(|card| focused.binary_search(card).is_err());
+    })?;
+    println!("unburied {} cards", unburied);
+    Ok(())
+}
+
+/// Syncs with ankiweb unless the last sync was less than `min_sync_interval_secs` ago
+/// and `force` isn't set. Returns the status for the summary.
+fn sync_throttled(config: &Config, force: bool) -> Result<String> {
+    let now = chrono::Utc::now().timestamp();
+    if let Some(last_sync) = CachedState::load(&config.cache_file).last_sync {
+        let elapsed = now - last_sync;
+        if !force && (0..config.min_sync_interval_secs).contains(&elapsed) {
+            info!("skipping sync (last sync {}s ago)", elapsed);
+            return Ok(format!("skipped (last sync {}s ago)", elapsed));
+        }
+    }
+
+    info!("syncing all notes");
+    sync()?;
+    let saved = CachedState::update(&config.cache_file, |cache| cache.last_sync = Some(now));
+    if let Err(e) = saved {
+        warn!("failed to save time of the sync: {:#}", e);
+    }
+    Ok(String::from("done"))
+}
+
+const RENDER_ADDON_MISSING: &str =
+    "rendering is not available, `renderAllLatex` is provided by a separate add-on which isn't installed";
+
+/// Renders all latex. Returns `None` if the add-on providing `renderAllLatex` is missing.
+fn render_latex() -> Result<Option<bool>> {
+    info!("rendering all latex");
+    match render_all_latex() {
+        Ok(success) => Ok(Some(success)),
+        Err(e) if api::is_unsupported_action(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the LaTeX images referenced by the HTML of a rendered card.
+///
+/// Anki renders LaTeX to `<img class=latex src="latex-<hash>.png">`, newer versions
+/// also add more classes or write svg files, so an image counts as LaTeX image if
+/// its class or its file name starts with `latex`.
+fn latex_images(html: &str) -> Vec<&str> {
+    let attribute = |tag: &'_ str, name: &str| -> Option<(usize, usize)> {
+        // e.g. not the `src` of `data-src`
+        let pattern = format!("{}=", name);
+        let start = tag
+            .match_indices(&pattern)
+            .map(|(i, _)| i)
+            .find(|&i| tag[..i].ends_with(char::is_whitespace))?
+            + pattern.len();
+        let value = &tag[start..];
+        Some(match value.as_bytes().first()? {
+            quote @ (b'"' | b'\'') => {
+                let len = value[1..].find(*quote as char)?;
+                (start + 1, start + 1 + len)
+            }
+            _ => {
+                let len = value
+                    .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                    .unwrap_or(value.len());
+                (start, start + len)
+            }
+        })
+    };
+    let mut images = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("<img") {
+        let tag = &rest[start..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        rest = &rest[start + tag.len()..];
+        let Some((src_start, src_end)) = attribute(tag, "src") else {
+            continue;
+        };
+        let src = &tag[src_start..src_end];
+        let is_latex_class = attribute(tag, "class")
+            .is_some_and(|(start, end)| tag[start..end].split(' ').any(|c| c.starts_with("latex")));
+        if is_latex_class || src.starts_with("latex-") {
+            images.push(src);
+        }
+    }
+    images
+}
+
+/// A note whose rendered LaTeX images are missing in the media folder
+struct MissingRender {
+    note: api::NoteInfo,
+    files: BTreeSet<String>,
+}
+
+/// Checks that the LaTeX images of the cards of the notes matching `query` exist
+/// in the media folder of anki.
+///
+/// Returns the number of checked notes and the notes with missing images.
+fn verify_render(query: &str) -> Result<(usize, Vec<MissingRender>)> {
+    let ids = api::find_notes(query)?;
+    let notes = api::notes_info(&ids)?;
+    let cards = notes
+        .iter()
+        .flat_map(|note| note.cards.iter().copied())
+        .collect::<Vec<_>>();
+    // only the cards render the LaTeX, the fields just contain the source
+    let cards = api::cards_info(&cards)?;
+    let media = api::get_media_files_names("latex-*")?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    debug!("found {} LaTeX images in the media folder", media.len());
+
+    let mut missing: HashMap<usize, BTreeSet<String>> = HashMap::new();
+    for card in &cards {
+        for image in latex_images(&card.question)
+            .into_iter()
+            .chain(latex_images(&card.answer))
+        {
+            if !media.contains(image) {
+                missing
+                    .entry(card.note)
+                    .or_default()
+                    .insert(image.to_owned());
+            }
+        }
+    }
+    let checked = notes.len();
+    let missing = notes
+        .into_iter()
+        .filter_map(|note| {
+            let files = missing.remove(&note.note_id)?;
+            Some(MissingRender { note, files })
+        })
+        .collect();
+    Ok((checked, missing))
+}
+
+/// Prints the notes of [`verify_render`] and fails if images are missing.
+fn report_missing_renders(checked: usize, missing: &[MissingRender]) -> Result<()> {
+    for MissingRender { note, files } in missing {
+        println!(
+            "note {} ({}) is missing rendered LaTeX:",
+            note.note_id, note.model_name
+        );
+        for file in files {
+            println!("  {}", file);
+        }
+        let mut fields = note.fields.iter().collect::<Vec<_>>();
+        fields.sort_by_key(|(_, field)| field.order);
+        for (name, field) in fields {
+            let value = field.value.split_whitespace().collect::<Vec<_>>().join(" ");
+            let excerpt = value.chars().take(60).collect::<String>();
+            let more = if excerpt.len() < value.len() {
+                "..."
+            } else {
+                ""
+            };
+            println!("  [{}] {}{}", name, excerpt, more);
+        }
+    }
+    if missing.is_empty() {
+        println!("verified the rendered LaTeX of {} notes", checked);
+        return Ok(());
+    }
+    Err(eyre!(
+        "{} of {} notes are missing rendered LaTeX images",
+        missing.len(),
+        checked
+    )
+    .suggestion("fix the LaTeX of the notes and render again, anki shows the errors when reviewing the cards on the desktop"))
+}
+
+/// Time without file events after which `watch` checks whether the file changed anyway
+const MISSED_EVENTS_CHECK: Duration = Duration::from_secs(60);
+
+/// Directories whose files are ignored by `watch` if `watch_ignore_dirs` isn't set
+const DEFAULT_WATCH_IGNORE_DIRS: [&str; 3] = [".git", "build", "_minted-*"];
+
+/// Which changed files `watch` reacts to
+struct WatchFilter {
+    /// Extensions of the relevant files, without the dot
+    extensions: Vec<String>,
+    /// Globs of directory names whose files are never relevant
+    ignore_dirs: GlobList,
+}
+
+impl WatchFilter {
+    /// Whether a change of `path` may change the notes.
+    ///
+    /// The main file and the custom style file are always relevant, the ignored
+    /// directories are matched relative to the root of the project.
+    fn is_relevant(&self, path: &Path, paths: &FilePaths) -> bool {
+        let same_file =
+            |other: &Path| path == other || other.canonicalize().is_ok_and(|other| path == other);
+        if same_file(&paths.main) || same_file(&paths.custom) {
+            return true;
+        }
+        let has_extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| self.extensions.iter().any(|e| e == ext));
+        if !has_extension {
+            return false;
+        }
+        let relative = path
+            .strip_prefix(&paths.root)
+            .ok()
+            .or_else(|| path.strip_prefix(paths.root.canonicalize().ok()?).ok())
+            .unwrap_or(path);
+        let dirs = relative.parent().into_iter().flat_map(Path::components);
+        !dirs
+            .filter_map(|dir| dir.as_os_str().to_str())
+            .any(|dir| self.ignore_dirs.find_match(dir).is_some())
+    }
+
+    /// Whether `event` is about a relevant file, filtered events are logged at
+    /// trace level.
+    fn is_relevant_event(&self, event: &Event, paths: &FilePaths) -> bool {
+        // some backends don't report the paths
+        let relevant =
+            event.paths.is_empty() || event.paths.iter().any(|p| self.is_relevant(p, paths));
+        if !relevant {
+            trace!("ignoring file event {:?} of {:?}", event.kind, event.paths);
+        }
+        relevant
+    }
+}
+
+/// Drains all events which arrived while an update was running.
+///
+/// Returns whether one of them requires another update, errors are logged.
+fn drain_pending_events(
+    rx: &std::sync::mpsc::Receiver<notify::Result<Event>>,
+    filter: &WatchFilter,
+    paths: &FilePaths,
+) -> bool {
+    let mut rerun = false;
+    for res in rx.try_iter() {
+        match res {
+            Ok(event) => {
+                rerun |= matches!(event.kind, EventKind::Modify(_) | EventKind::Remove(_))
+                    && filter.is_relevant_event(&event, paths)
+            }
+            Err(e) => error!("file watcher error: {}", e),
+        }
+    }
+    rerun
+}
+
+/// Watches the files for changes and updates the notes.
+///
+/// With `poll`, the files are checked for changes in this interval instead of
+/// relying on events of the OS.
+///
+/// `on_change_cmd` is run after each update which changed notes, see
+/// [`run_on_change_cmd`]. With `changed_only` only the changed notes are processed,
+/// see [`State::changed_only`]. `profile` is the profile open in anki, see
+/// [`check_profile`].
+fn watch(
+    config: &Config,
+    paths: &FilePaths,
+    output: OutputFormat,
+    poll: Option<Duration>,
+    on_change_cmd: Option<&[String]>,
+    changed_only: bool,
+    profile: Option<String>,
+) -> Result<()> {
+    let on_change = |summary: &report::RunSummary| {
+        if let (Some(command), true) = (on_change_cmd, summary.has_changes()) {
+            run_on_change_cmd(command, paths, summary);
+        }
+    };
+
+    let mut state = State::new(config)?;
+    state.profile = profile;
+    state.changed_only = changed_only;
+    let summary = update_change(&mut state, config, paths, false)?;
+    print_summary(&summary, output)?;
+    on_change(&summary);
+
+    let update = |state: &mut State| match update_change(state, config, paths, false) {
+        Ok(mut summary) if !summary.is_empty() => {
+            if config.auto_sync && summary.has_changes() {
+                let status = sync_throttled(config, false).unwrap_or_else(|e| {
+                    error!("failed to sync: {:#}", e);
+                    String::from("failed")
+                });
+                summary.steps.push(("sync".into(), status));
+            }
+            let printed = print_summary(&summary, output);
+            on_change(&summary);
+            printed
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("{:#?}", e);
+            Ok(())
+        }
+    };
+
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let mut watcher: Box<dyn Watcher> = match poll {
+        Some(interval) => {
+            info!("checking for changes every {:?}", interval);
+            // only the modification times are compared, so polling stays cheap
+            Box::new(notify::PollWatcher::new(
+                tx,
+                notify::Config::default().with_poll_interval(interval),
+            )?)
+        }
+        None => Box::new(notify::recommended_watcher(tx)?),
+    };
+    watcher.watch(&paths.main, RecursiveMode::Recursive)?;
+    watcher.watch(&paths.custom, RecursiveMode::NonRecursive)?;
+
+    let modified = || {
+        std::fs::metadata(&paths.main)
+            .and_then(|m| m.modified())
+            .ok()
+    };
+    // modification time of the main file when the last event arrived
+    let mut last_seen = modified();
+    let mut hinted = false;
+
+    info!("You can exit with Ctrl+C");
+    loop {
+        let res = match rx.recv_timeout(MISSED_EVENTS_CHECK) {
+            Ok(res) => res,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                // e.g. inotify doesn't report changes on network filesystems
+                if poll.is_none() && !hinted && modified() != last_seen {
+                    warn!(
+                        "{} changed, but no file events were received",
+                        paths.main.to_string_lossy()
+                    );
+                    info!("if the file is on a network filesystem like SSHFS or NFS, use `anki-tex watch --poll`");
+                    hinted = true;
+                }
+                continue;
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        last_seen = modified();
+        let event: Event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                error!("file watcher error: {}", e);
+                continue;
+            }
+        };
+        if !matches!(event.kind, EventKind::Access(_))
+            && !config.watch_filter.is_relevant_event(&event, paths)
+        {
+            continue;
+        }
+        match event.kind {
+            EventKind::Access(_) => {}
+            EventKind::Create(_) => error!("file was created but should have existed before"),
+            // TODO finer
+            EventKind::Modify(_) => {
+                update(&mut state)?;
+                if drain_pending_events(&rx, &config.watch_filter, paths) {
+                    debug!("files changed during the update, updating once more");
+                    update(&mut state)?;
+                }
+            }
+            EventKind::Any | EventKind::Other => {
+                error!("unknown file watcher event: {:?}", event);
+            }
+            EventKind::Remove(_) => {
+                // TODO is this necessary?
+                // editors may replace the file, which ends watching it
+                for (path, mode) in [
+                    (&paths.main, RecursiveMode::Recursive),
+                    (&paths.custom, RecursiveMode::NonRecursive),
+                ] {
+                    if let Err(e) = watcher.watch(path, mode) {
+                        error!("can't watch {}: {}", path.to_string_lossy(), e);
+                    }
+                }
+                if !paths.main.is_file() {
+                    error!("file was removed.")
+                } else {
+                    update(&mut state)?;
+                }
+            }
+        }
+    }
+
+    info!("Exiting");
+
+    Ok(())
+}
+
+/// Definition of a model as stored by `anki-tex models export`
+#[derive(Debug, PartialEq, serde::Serialize, Deserialize)]
+struct ModelSchema {
+    fields: Vec<String>,
+    css: String,
+    templates: BTreeMap<String, CardTemplate>,
+}
+
+#[derive(Debug, Default, serde::Serialize, Deserialize)]
+struct ModelsFile {
+    models: BTreeMap<String, ModelSchema>,
+}
+
+fn fetch_model_schema(model_name: &str) -> Result<ModelSchema> {
+    Ok(ModelSchema {
+        fields: get_model_field_names(model_name)?.0,
+        css: get_model_styling(model_name)?.css,
+        templates: get_model_templates(model_name)?,
+    })
+}
+
+fn export_models(config: &Config, paths: &FilePaths, out: &Path) -> Result<()> {
+    let main_content = read_to_string(&paths.main)
+        .with_note(|| eyre!("while reading file {}", paths.main.to_string_lossy()))?;
+
+    debug!("parsing file for used models");
+    let used_models = parse_file::get_used_models(main_content, &config.parse)?;
+    let available_models = get_model_names()?.0;
+
+    let mut file = ModelsFile::default();
+    for model in used_models {
+        if !available_models.contains(&model) {
+            return Err(eyre!("model {} does not exist in anki", model));
+        }
+        debug!("fetching definition of model {}", model);
+        let schema = fetch_model_schema(&model)?;
+        file.models.insert(model, schema);
+    }
+
+    let text = toml::to_string_pretty(&file)?;
+    std::fs::write(out, text)
+        .with_note(|| eyre!("while writing file {}", out.to_string_lossy()))?;
+    info!(
+        "exported {} models to {}",
+        file.models.len(),
+        out.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+/// Prints a line based diff between `old` and `new`.
+fn print_diff(old: &str, new: &str) {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // longest common subsequence of the lines
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            println!("    + {}", new[j]);
+            j += 1;
+        } else {
+            println!("    - {}", old[i]);
+            i += 1;
+        }
+    }
+}
+
+/// Returns the notes matching `query` which have no cards.
+fn get_empty_notes(query: &str) -> Result<Vec<Note>> {
+    Ok(get_notes(query, CardDetails::Full)?
+        .into_iter()
+        .filter(|note| note.card_ords.is_empty())
+        .collect())
+}
+
+fn cleanup_empty_notes(config: &Config) -> Result<()> {
+    let empty_notes = get_empty_notes("*")?;
+    if empty_notes.is_empty() {
+        info!("there are no notes without cards");
+        return Ok(());
+    }
+
+    for note in &empty_notes {
+        println!(
+            "note {} of model {} with fields {:?}",
+            note.id.unwrap_or_default(),
+            note.model,
+            note.fields
+        );
+    }
+    if !confirm(&format!(
+        "remove these {} notes without cards?",
+        empty_notes.len()
+    ))? {
+        info!("aborted, nothing was removed");
+        return Ok(());
+    }
+    // `removeEmptyNotes` removes all empty notes, not only the confirmed ones
+    let ids = |notes: &[Note]| notes.iter().map(|n| n.id).collect::<BTreeSet<_>>();
+    if ids(&get_empty_notes("*")?) != ids(&empty_notes) {
+        return Err(
+            eyre!("the notes without cards changed, nothing was removed")
+                .suggestion("run the command again"),
+        );
+    }
+    let mut record = RunRecord::new();
+    record.deleted = empty_notes.iter().filter_map(|note| note.id).collect();
+    record.backup = backup_notes(config, &record.id, &record.deleted)?;
+    remove_empty_notes()?;
+    record.append(&config.run_log)?;
+    info!("removed {} notes without cards", empty_notes.len());
+
+    Ok(())
+}
+
+/// Rewrites the wrappers of the fields of the generated notes of `model` matching
+/// `query` from `from` to `to`, e.g. after changing the format of the fields in the
+/// config.
+///
+/// Only fields wrapped in the wrapper of `from` are changed. With `dry_run` the
+/// changes are only printed.
+fn migrate_format(
+    config: &Config,
+    model: &str,
+    from: FieldFormat,
+    to: FieldFormat,
+    query: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    if from == FieldFormat::Raw || to == FieldFormat::Raw {
+        return Err(eyre!("raw fields have no wrapper to migrate")
+            .suggestion("use `--from` and `--to` with `latex` or `mathjax`"));
+    }
+    if from == to {
+        return Err(eyre!("`--from` and `--to` are both {}", from.name()));
+    }
+
+    let mut search = format!("tag:{} \"note:{}\"", config.generated_tag, model);
+    if let Some(query) = query {
+        search.push_str(&format!(" ({})", query));
+    }
+    let ids = api::find_notes(&search)?;
+    let notes = chunked(&ids, "fetching notes", api::notes_info)?;
+
+    let mut updates = Vec::new();
+    let mut fields_changed = 0;
+    for note in &notes {
+        let mut fields = note.fields.iter().collect::<Vec<_>>();
+        fields.sort_by_key(|(_, field)| field.order);
+        let changed = fields
+            .into_iter()
+            .filter_map(|(name, field)| {
+                let inner = from.unwrap(&field.value)?;
+                Some((name.clone(), to.wrap(inner)))
+            })
+            .collect::<Vec<_>>();
+        if changed.is_empty() {
+            continue;
+        }
+
+        println!("note {}:", note.note_id);
+        for (name, value) in &changed {
+            println!("  field {}:", name);
+            print_diff(&note.fields[name].value, value);
+        }
+        fields_changed += changed.len();
+        updates.push((note.note_id, changed.into_iter().collect::<HashMap<_, _>>()));
+    }
+
+    let skipped = notes.len() - updates.len();
+    if dry_run {
+        println!(
+            "would migrate {} fields of {} notes from {} to {}, {} notes have no {} fields",
+            fields_changed,
+            updates.len(),
+            from.name(),
+            to.name(),
+            skipped,
+            from.name()
+        );
+        return Ok(());
+    }
+
+    let mut record = RunRecord::new();
+    let ids = updates.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    record.backup = backup_notes(config, &record.id, &ids)?;
+    let bar = progress_bar(updates.len(), "updating notes");
+    let mut failed = 0;
+    for chunk in updates.chunks(INFO_CHUNK) {
+        let results = api::update_note_fields_multi(chunk)?;
+        for ((id, _), result) in chunk.iter().zip(results) {
+            match result {
+                Ok(()) => record.updated.push(*id),
+                Err(e) => {
+                    error!("couldn't update note {}: {}", id, e);
+                    failed += 1;
+                }
+            }
+        }
+        bar.inc(chunk.len() as u64);
+    }
+    bar.finish_and_clear();
+    if !record.updated.is_empty() {
+        record.append(&config.run_log)?;
+    }
+
+    println!(
+        "migrated {} fields of {} notes from {} to {}, {} notes have no {} fields",
+        fields_changed,
+        updates.len() - failed,
+        from.name(),
+        to.name(),
+        skipped,
+        from.name()
+    );
+    if failed > 0 {
+        return Err(eyre!("couldn't update {} notes", failed));
+    }
+    if !config
+        .parse
+        .field_formats
+        .get(model)
+        .is_some_and(|formats| formats.values().any(|format| *format == to))
+    {
+        info!(
+            "set the format of the fields of model {} to `{}` in `field_formats` of the config",
+            model,
+            to.name()
+        );
+    }
+    Ok(())
+}
+
+/// Formats line numbers for messages, e.g. `3, 7, 12`.
+fn fmt_lines(lines: impl IntoIterator<Item = usize>) -> String {
+    lines
+        .into_iter()
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parses the file, reports lints and removes redundant commands if `fix` is set.
+///
+/// With `compile`, the file is also compiled with `latex_command`.
+fn check_file(
+    config: &Config,
+    paths: &FilePaths,
+    fix: bool,
+    compile: bool,
+    backup: bool,
+) -> Result<()> {
+    if paths.main.is_dir() {
+        for child in config.children(&paths.main)? {
+            let new_paths = FilePaths {
+                main: child,
+                anki: paths.anki.clone(),
+                custom: paths.custom.clone(),
+                root: paths.root.clone(),
+            };
+            check_file(config, &new_paths, fix, compile, backup)?;
+        }
+        return Ok(());
+    }
+
+    lint_file(config, paths, fix, backup)?;
+    if compile {
+        compile_file(config, paths)?;
+    }
+    Ok(())
+}
+
+/// Reports the commands in the fields of the notes in the file or, if it is a
+/// directory, its children which are neither standard LaTeX nor defined in
+/// `custom.sty`, `ankitex.sty` or `known_macros`.
+///
+/// They are warnings, or errors with `strict`. Files which can't be parsed are
+/// skipped, `check` reports them already.
+fn check_macros(
+    config: &Config,
+    paths: &FilePaths,
+    strict: bool,
+    diagnostics: &mut Vec<report::Diagnostic>,
+) -> Result<()> {
+    if paths.main.is_dir() {
+        for child in config.children(&paths.main)? {
+            let new_paths = FilePaths {
+                main: child,
+                anki: paths.anki.clone(),
+                custom: paths.custom.clone(),
+                root: paths.root.clone(),
+            };
+            check_macros(config, &new_paths, strict, diagnostics)?;
+        }
+        return Ok(());
+    }
+
+    let mut known = config.known_macros.clone();
+    for sty in [&paths.custom, &paths.anki] {
+        if !sty.is_file() {
+            debug!(
+                "{} doesn't exist, no macros are defined by it",
+                sty.to_string_lossy()
+            );
+            continue;
+        }
+        let content = read_to_string(sty)
+            .with_note(|| eyre!("while reading file {}", sty.to_string_lossy()))?;
+        known.extend(parse_file::defined_macros(&content));
+    }
+
+    let file = paths.main.to_string_lossy();
+    let content = read_to_string(&paths.main).with_note(|| eyre!("while reading file {}", file))?;
+    let notes = match parse_file::parse_content(content, &config.parse) {
+        Ok(outcome) => outcome.notes,
+        Err(e) => {
+            debug!(
+                "skipping the macros of {}, it can't be parsed: {:#}",
+                file, e
+            );
+            return Ok(());
+        }
+    };
+    let severity = if strict {
+        report::Severity::Error
+    } else {
+        report::Severity::Warning
+    };
+    for (i, note) in notes.iter().enumerate() {
+        let mut fields = note.fields.iter().collect::<Vec<_>>();
+        fields.sort();
+        for (name, value) in fields {
+            if note.format(name) == FieldFormat::Raw {
+                continue;
+            }
+            for command in parse_file::unknown_macros(value, &known) {
+                diagnostics.push(
+                    report::Diagnostic::new(
+                        severity,
+                        report::Code::UnknownMacro,
+                        format!("unknown command `\\{}` in field `{}`", command, name),
+                    )
+                    .file(file.clone())
+                    .note_index(Some(i)),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Selectors of the rules in `css` with normalized whitespace
+fn css_selectors(css: &str) -> BTreeSet<String> {
+    lazy_static::lazy_static! {
+        static ref CSS_COMMENT: Regex = Regex::new(r"(?s)/\*.*?\*/").unwrap();
+    }
+    let css = CSS_COMMENT.replace_all(css, "");
+    let mut selectors = BTreeSet::new();
+    let mut start = 0;
+    for (i, c) in css.char_indices() {
+        match c {
+            '{' => {
+                selectors.extend(
+                    css[start..i]
+                        .split(',')
+                        .map(|s| s.split_whitespace().collect::<Vec<_>>().join(" "))
+                        .filter(|s| !s.is_empty()),
+                );
+                start = i + 1;
+            }
+            '}' | ';' => start = i + 1,
+            _ => {}
+        }
+    }
+    selectors
+}
+
+/// Rules of [`parse_file::MODEL_CSS`] without a rule for their selector in `css`
+fn missing_model_css(css: &str) -> Vec<(&'static str, &'static str)> {
+    let selectors = css_selectors(css);
+    parse_file::MODEL_CSS
+        .iter()
+        .filter(|(selector, _)| !selectors.contains(*selector))
+        .copied()
+        .collect()
+}
+
+/// Snippet with the rules to paste into the styling of a model
+fn model_css_snippet(missing: &[(&str, &str)]) -> String {
+    missing
+        .iter()
+        .map(|(_, rule)| *rule)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Collects the models used by the notes of the file or, if it is a directory, its
+/// children.
+fn used_models(config: &Config, paths: &FilePaths, models: &mut BTreeSet<String>) -> Result<()> {
+    if paths.main.is_dir() {
+        for child in config.children(&paths.main)? {
+            let new_paths = FilePaths {
+                main: child,
+                anki: paths.anki.clone(),
+                custom: paths.custom.clone(),
+                root: paths.root.clone(),
+            };
+            used_models(config, &new_paths, models)?;
+        }
+        return Ok(());
+    }
+
+    let content = read_to_string(&paths.main)
+        .with_note(|| eyre!("while reading file {}", paths.main.to_string_lossy()))?;
+    let mut notes = parse_file::parse_content(content, &config.parse)?.notes;
+    config.select_decks(&mut notes, &paths.main);
+    config.select_models(&mut notes)?;
+    models.extend(notes.into_iter().map(|note| note.model));
+    Ok(())
+}
+
+/// Checks the styling of the models for the rules of [`parse_file::MODEL_CSS`], prints
+/// the missing ones and appends them after confirmation if `fix` is set.
+fn check_model_styling(models: &BTreeSet<String>, fix: bool) -> Result<()> {
+    let mut unfixed = 0;
+    for model in models {
+        let css = get_model_styling(model)
+            .with_note(|| eyre!("while getting the styling of model {}", model))?
+            .css;
+        let missing = missing_model_css(&css);
+        if missing.is_empty() {
+            debug!("styling of model {} has all rules", model);
+            continue;
+        }
+        let selectors = missing.iter().map(|(s, _)| *s).collect::<Vec<_>>();
+        warn!(
+            "the styling of model {} has no rules for {}",
+            model,
+            selectors.join(", ")
+        );
+        let snippet = model_css_snippet(&missing);
+        println!("add to the styling of model {}:\n{}\n", model, snippet);
+        if fix && confirm(&format!("append the rules to the styling of {}?", model))? {
+            update_model_styling(model, &format!("{}\n\n{}\n", css.trim_end(), snippet))
+                .with_note(|| eyre!("while updating the styling of model {}", model))?;
+            info!("updated the styling of model {}", model);
+        } else {
+            unfixed += 1;
+        }
+    }
+    if unfixed != 0 && !fix {
+        info!("run `anki-tex check --online --fix-styling` to append the rules");
+    }
+    Ok(())
+}
+
+/// Collects the problems `check` finds in the file or, if it is a directory, its children.
+///
+/// Errors in the file are returned as diagnostics, other errors like unreadable files fail.
+fn check_diagnostics(
+    config: &Config,
+    paths: &FilePaths,
+    compile: bool,
+    diagnostics: &mut Vec<report::Diagnostic>,
+) -> Result<()> {
+    use report::{Code, Diagnostic, Severity};
+
+    if paths.main.is_dir() {
+        for child in config.children(&paths.main)? {
+            let new_paths = FilePaths {
+                main: child,
+                anki: paths.anki.clone(),
+                custom: paths.custom.clone(),
+                root: paths.root.clone(),
+            };
+            check_diagnostics(config, &new_paths, compile, diagnostics)?;
+        }
+        return Ok(());
+    }
+
+    let file = paths.main.to_string_lossy();
+    let content = read_to_string(&paths.main).with_note(|| eyre!("while reading file {}", file))?;
+    let frame = &config.parse.frame;
+    let error = |e: color_eyre::Report| {
+        if let Some(diagnostic) = e.chain().find_map(|e| e.downcast_ref::<Diagnostic>()) {
+            return diagnostic.clone().file(file.clone());
+        }
+        let Some(parse_error) = parse_file::find_parse_error(&e) else {
+            return Diagnostic::new(Severity::Error, Code::Other, e.to_string()).file(file.clone());
+        };
+        let diagnostic =
+            Diagnostic::new(Severity::Error, parse_error.code, parse_error.to_string())
+                .file(file.clone())
+                .note_index(parse_error.note_index);
+        match parse_error.pos {
+            Some(pos) => {
+                let (line, column) = parse_file::file_position(&content, pos);
+                diagnostic.position(line, Some(column))
+            }
+            None => diagnostic,
+        }
+    };
+
+    if let Err(e) = parse_file::check_preamble(&content, frame, paths.dir()) {
+        diagnostics.push(error(e));
+    }
+    match parse_file::parse_content(content.clone(), &config.parse) {
+        Err(e) => diagnostics.push(error(e)),
+        Ok(outcome) => {
+            for warning in &outcome.warnings {
+                let severity = match config
+                    .warnings_by_class
+                    .get(&warning.class())
+                    .copied()
+                    .unwrap_or(config.warnings)
+                {
+                    WarningAction::Print => Severity::Warning,
+                    WarningAction::Error => Severity::Error,
+                    WarningAction::Ignore => continue,
+                };
+                let diagnostic = Diagnostic::new(severity, warning.code(), warning.to_string())
+                    .file(file.clone());
+                diagnostics.push(match warning.line() {
+                    Some(line) => diagnostic.position(line, None),
+                    None => diagnostic,
+                });
+            }
+
+            let mut notes = outcome.notes;
+            config.select_decks(&mut notes, &paths.main);
+            match config.select_models(&mut notes) {
+                Err(e) => diagnostics.push(error(e)),
+                Ok(_) => {
+                    for (i, note) in notes.iter().enumerate() {
+                        match check_field_sizes(config, note) {
+                            Ok(warnings) => diagnostics.extend(
+                                warnings
+                                    .into_iter()
+                                    .map(|w| w.file(file.clone()).note_index(Some(i))),
+                            ),
+                            Err(e) => diagnostics.push(error(e).note_index(Some(i))),
+                        }
+                    }
+                }
+            }
+
+            let lint = parse_file::lint_content(&content, &config.parse)?;
+            for redundant in &lint.redundant {
+                let line_start = content[..redundant.span.start]
+                    .rfind('\n')
+                    .map_or(0, |i| i + 1);
+                let column = content[line_start..redundant.span.start].chars().count() + 1;
+                diagnostics.push(
+                    Diagnostic::new(
+                        Severity::Warning,
+                        Code::RedundantCommand,
+                        format!(
+                            "redundant \\{} command, {} is already set",
+                            redundant.command, redundant.value
+                        ),
+                    )
+                    .file(file.clone())
+                    .position(redundant.line, Some(column)),
+                );
+            }
+            for &line in &lint.untagged {
+                diagnostics.push(
+                    Diagnostic::new(Severity::Warning, Code::UntaggedNote, "note without tags")
+                        .file(file.clone())
+                        .position(line, None),
+                );
+            }
+        }
+    }
+
+    if compile {
+        match run_latex(config, paths)? {
+            None => warn!(
+                "skipped compiling {}: `{}` is not installed",
+                file, config.latex_command[0]
+            ),
+            Some((success, errors, _)) => {
+                if !success && errors.is_empty() {
+                    diagnostics.push(
+                        Diagnostic::new(
+                            Severity::Error,
+                            Code::LatexError,
+                            format!("{} failed", config.latex_command[0]),
+                        )
+                        .file(file.clone()),
+                    );
+                }
+                diagnostics.extend(errors.into_iter().map(|e| {
+                    Diagnostic::new(Severity::Error, Code::LatexError, e.message)
+                        .file(e.file)
+                        .position(e.line, None)
+                }));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A LaTeX error with the position given in the log by `-file-line-error`
+#[derive(Debug, PartialEq, Eq)]
+struct LatexError {
+    file: String,
+    line: usize,
+    message: String,
+}
+
+/// Finds the errors in the log of a LaTeX run with `-file-line-error`,
+/// i.e. lines like `./anki.tex:12: Undefined control sequence.`
+fn parse_latex_errors(log: &str) -> Vec<LatexError> {
+    lazy_static::lazy_static! {
+        static ref ERROR: Regex = Regex::new(r"^(.+?\.(?:tex|sty|cls)):(\d+): (.*)$").unwrap();
+    }
+
+    log.lines()
+        .filter_map(|line| {
+            let cap = ERROR.captures(line)?;
+            Some(LatexError {
+                file: cap[1].to_owned(),
+                line: cap[2].parse().ok()?,
+                message: cap[3].to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Compiles the file with `latex_command` in a temporary directory together with
+/// `ankitex.sty` and `custom.sty` and reports the errors of LaTeX.
+///
+/// If the command isn't installed the check is skipped.
+fn compile_file(config: &Config, paths: &FilePaths) -> Result<()> {
+    let path = paths.main.to_string_lossy();
+    let Some((success, errors, log)) = run_latex(config, paths)? else {
+        warn!(
+            "skipped compiling {}: `{}` is not installed",
+            path, config.latex_command[0]
+        );
+        return Ok(());
+    };
+
+    if success && errors.is_empty() {
+        println!("{}: compiles with {}", path, config.latex_command[0]);
+        return Ok(());
+    }
+    for error in &errors {
+        println!("{}:{}: {}", error.file, error.line, error.message);
+    }
+    let mut report = eyre!("{} failed to compile with {} errors", path, errors.len());
+    if errors.is_empty() {
+        let tail = log.lines().rev().take(20).collect::<Vec<_>>();
+        report = report.note(format!(
+            "end of the log:\n{}",
+            tail.into_iter().rev().collect::<Vec<_>>().join("\n")
+        ));
+    }
+    Err(report)
+}
+
+/// Compiles the file with `latex_command` in a temporary directory.
+///
+/// Returns whether the command succeeded, the errors with the paths of the original
+/// files and the log or `None` if the command isn't installed.
+fn run_latex(
+    config: &Config,
+    paths: &FilePaths,
+) -> Result<Option<(bool, Vec<LatexError>, String)>> {
+    let Some(file_name) = paths.main.file_name() else {
+        return Err(eyre!("{} is not a file", paths.main.to_string_lossy()));
+    };
+    let dir = std::env::temp_dir().join(format!("anki-tex-compile-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)
+        .with_note(|| eyre!("while creating directory {}", dir.to_string_lossy()))?;
+    let result = compile_in(config, paths, &dir, file_name);
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        warn!("can't remove {}: {}", dir.to_string_lossy(), e);
+    }
+
+    // the files are copied to the temporary directory, so the log uses their names
+    Ok(result?.map(|(success, mut errors, log)| {
+        for error in &mut errors {
+            let name = Path::new(&error.file).file_name();
+            if let Some(original) = [&paths.main, &paths.anki, &paths.custom]
+                .into_iter()
+                .find(|p| p.file_name() == name)
+            {
+                error.file = original.to_string_lossy().into_owned();
+            }
+        }
+        (success, errors, log)
+    }))
+}
+
+/// Runs `latex_command` in `dir` on a copy of the files of `paths`.
+///
+/// Returns whether the command succeeded, the errors and the log or `None` if the
+/// command isn't installed.
+fn compile_in(
+    config: &Config,
+    paths: &FilePaths,
+    dir: &Path,
+    file_name: &std::ffi::OsStr,
+) -> Result<Option<(bool, Vec<LatexError>, String)>> {
+    for file in [&paths.main, &paths.anki, &paths.custom] {
+        if let Some(name) = file.file_name().filter(|_| file.is_file()) {
+            std::fs::copy(file, dir.join(name))
+                .with_note(|| eyre!("while copying {}", file.to_string_lossy()))?;
+        }
+    }
+
+    let (command, args) = config.latex_command.split_first().unwrap();
+    debug!("running {} {:?} on {:?}", command, args, file_name);
+    let output = match std::process::Command::new(command)
+        .args(args)
+        .arg(file_name)
+        .current_dir(dir)
+        .stdin(std::process::Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(eyre!(e).wrap_err(format!("can't run `{}`", command))),
+    };
+
+    let log_file = dir.join(Path::new(file_name).with_extension("log"));
+    let log = read_to_string(log_file)
+        .unwrap_or_else(|_| String::from_utf8_lossy(&output.stdout).into_owned());
+    Ok(Some((
+        output.status.success(),
+        parse_latex_errors(&log),
+        log,
+    )))
+}
+
+/// Parses the file, reports lints and removes redundant commands if `fix` is set.
+fn lint_file(config: &Config, paths: &FilePaths, fix: bool, backup: bool) -> Result<()> {
+    let path = paths.main.to_string_lossy();
+    let mut editor = edit::FileEditor::open(&paths.main)?;
+    let content = editor.content().to_owned();
+    let outcome = parse_file::parse_content(content.clone(), &config.parse)?;
+    parse_file::check_preamble(&content, &config.parse.frame, paths.dir())?;
+    config.handle_parse_warnings(&outcome.warnings)?;
+    let lint = parse_file::lint_content(&content, &config.parse)?;
+
+    if lint.is_empty() {
+        println!("{}: {} notes, no problems found", path, lint.notes);
+        return Ok(());
+    }
+    println!("{}: {} notes", path, lint.notes);
+    for command in ["deck", "model"] {
+        let lines = lint
+            .redundant
+            .iter()
+            .filter(|r| r.command == command)
+            .map(|r| r.line)
+            .collect::<Vec<_>>();
+        if !lines.is_empty() {
+            println!(
+                "  {} redundant \\{} commands, the value is already set (lines {})",
+                lines.len(),
+                command,
+                fmt_lines(lines)
+            );
+        }
+    }
+    if !lint.common_tags.is_empty() {
+        println!("  tags set on every note: {}", lint.common_tags.join(", "));
+    }
+    if !lint.untagged.is_empty() {
+        println!(
+            "  {} notes without tags (ending at lines {})",
+            lint.untagged.len(),
+            fmt_lines(lint.untagged.iter().copied())
+        );
+    }
+
+    if !fix || lint.redundant.is_empty() {
+        return Ok(());
+    }
+    if !confirm(&format!(
+        "remove {} redundant commands from {}?",
+        lint.redundant.len(),
+        path
+    ))? {
+        return Ok(());
+    }
+
+    for removal in lint.removals(&content) {
+        editor.replace(removal, "")?;
+    }
+    let fixed = editor.result();
+    let fixed_outcome = parse_file::parse_content(fixed.clone(), &config.parse)
+        .with_note(|| "while parsing the fixed file")?;
+    let same = outcome.notes.len() == fixed_outcome.notes.len()
+        && outcome
+            .notes
+            .iter()
+            .zip(&fixed_outcome.notes)
+            .all(|(a, b)| {
+                a.deck == b.deck && a.model == b.model && a.fields == b.fields && a.tags == b.tags
+            });
+    if !same {
+        return Err(eyre!(
+            "removing the redundant commands would change the notes, {} was not changed",
+            path
+        )
+        .note("please report this as a bug"));
+    }
+
+    let backup = editor.write(backup)?;
+    info!(
+        "removed {} redundant commands{}",
+        lint.redundant.len(),
+        backup_note(backup)
+    );
+
+    Ok(())
+}
+
+/// Describes where the old version of a rewritten file was saved.
+fn backup_note(backup: Option<PathBuf>) -> String {
+    match backup {
+        Some(backup) => format!(", the old file was saved as {}", backup.to_string_lossy()),
+        None => String::new(),
+    }
+}
+
+/// Replaces the name of the field `old` of notes with model `model` by `new` in
+/// `path` or, if it is a directory, its children.
+///
+/// The result is parsed again and has to give the same notes with the field renamed.
+fn field_rewrites(
+    config: &Config,
+    path: &Path,
+    model: &str,
+    old: &str,
+    new: &str,
+    rewrites: &mut Vec<edit::FileEditor>,
+) -> Result<()> {
+    if path.is_dir() {
+        for child in config.children(path)? {
+            field_rewrites(config, &child, model, old, new, rewrites)?;
+        }
+        return Ok(());
+    }
+
+    let name = path.to_string_lossy();
+    let mut editor = edit::FileEditor::open(path)?;
+    let content = editor.content().to_owned();
+    let outcome = parse_file::parse_content(content.clone(), &config.parse)?;
+    let found = parse_file::field_name_spans(&content, &config.parse, model, old)?;
+    if !found.fixed.is_empty() {
+        return Err(eyre!(
+            "{} sets the field `{}` in a way which can't be rewritten (lines {})",
+            name,
+            old,
+            fmt_lines(found.fixed)
+        )
+        .note("custom commands, shorthands and note templates can't be rewritten, neither can `\\shared` fields which are also used by notes of other models")
+        .suggestion("change them to use the new field name and run the command again"));
+    }
+    if found.spans.is_empty() {
+        return Ok(());
+    }
+
+    for span in found.spans {
+        editor.replace(span, new)?;
+    }
+    let rewritten = editor.result();
+
+    let expected = outcome.notes.into_iter().map(|mut note| {
+        if note.model == model {
+            if let Some(value) = note.fields.remove(old) {
+                note.fields.insert(new.to_owned(), value);
+            }
+        }
+        note
+    });
+    let rewritten_outcome = parse_file::parse_content(rewritten.clone(), &config.parse)
+        .with_note(|| eyre!("while parsing the rewritten file {}", name))?;
+    let same = expected.len() == rewritten_outcome.notes.len()
+        && expected.zip(&rewritten_outcome.notes).all(|(a, b)| {
+            a.deck == b.deck && a.model == b.model && a.fields == b.fields && a.tags == b.tags
+        });
+    if !same {
+        return Err(eyre!(
+            "renaming the field would change other parts of the notes in {}",
+            name
+        )
+        .note("please report this as a bug"));
+    }
+
+    rewrites.push(editor);
+    Ok(())
+}
+
+/// Renames the field `old` of `model` in anki and, with `rewrite`, in the files.
+///
+/// The files are checked before anything is changed.
+fn rename_field(
+    config: &Config,
+    paths: &FilePaths,
+    model: &str,
+    old: &str,
+    new: &str,
+    rewrite: bool,
+    backup: bool,
+) -> Result<()> {
+    let fields = api::get_model_field_names(model)
+        .with_note(|| eyre!("while getting the fields of model `{}`", model))?
+        .0;
+    if !fields.iter().any(|f| f == old) {
+        return Err(eyre!("model `{}` has no field `{}`", model, old)
+            .note(format!("fields of the model: {}", fields.join(", "))));
+    }
+    if fields.iter().any(|f| f == new) {
+        return Err(eyre!("model `{}` already has a field `{}`", model, new));
+    }
+
+    let mut rewrites = Vec::new();
+    if rewrite {
+        field_rewrites(config, &paths.main, model, old, new, &mut rewrites)?;
+    }
+    for editor in &rewrites {
+        if !editor.is_unchanged()? {
+            return Err(eyre!(
+                "{} changed while it was checked, nothing was renamed",
+                editor.path().to_string_lossy()
+            ));
+        }
+    }
+
+    api::model_field_rename(model, old, new)
+        .map_err(|e| explain_unsupported(e, "modelFieldRename"))?;
+    info!("renamed field `{}` of model `{}` to `{}`", old, model, new);
+
+    if !rewrite {
+        println!(
+            "the files still use `{}`, update them or run the command with `--rewrite`",
+            old
+        );
+        return Ok(());
+    }
+    if rewrites.is_empty() {
+        println!("the field `{}` isn't used in the files", old);
+    }
+    for editor in rewrites {
+        let path = editor.path().to_string_lossy();
+        println!("{}:", path);
+        print_diff(editor.content(), &editor.result());
+        if !confirm(&format!("rename {} occurrences in {}?", editor.len(), path))? {
+            continue;
+        }
+        if !editor.is_unchanged()? {
+            warn!("{} changed, rename `{}` in it by hand", path, old);
+            continue;
+        }
+        let backup = editor.write(backup)?;
+        info!(
+            "renamed {} occurrences in {}{}",
+            editor.len(),
+            path,
+            backup_note(backup)
+        );
+    }
+
+    Ok(())
+}
+
+const REPORT_CSS: &str = "body { font-family: sans-serif; margin: 2em auto; max-width: 60em; }
+table.decks { border-collapse: collapse; margin-bottom: 2em; }
+table.decks th, table.decks td { border: 1px solid #ccc; padding: 0.3em 0.8em; }
+table.decks td.count { text-align: right; }";
+
+/// Adds a hint to errors of actions which older versions of AnkiConnect don't know.
+fn explain_unsupported(e: color_eyre::Report, action: &str) -> color_eyre::Report {
+    if api::is_unsupported_action(&e) {
+        e.note(format!(
+            "the installed version of AnkiConnect doesn't support `{}`",
+            action
+        ))
+        .suggestion("update the AnkiConnect add-on")
+    } else {
+        e
+    }
+}
+
+/// How `progress` groups the notes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ProgressGroup {
+    /// The deck of the first card of the note
+    Deck,
+    /// The tag with the date the note was created, see `--generation-date`
+    Date,
+}
+
+/// Parses a duration of `recent --since` with the unit `h` (hours) or `d` (days).
+fn parse_since(text: &str) -> std::result::Result<Duration, String> {
+    let (number, hours) = if let Some(number) = text.strip_suffix('h') {
+        (number, 1)
+    } else if let Some(number) = text.strip_suffix('d') {
+        (number, 24)
+    } else {
+        return Err(format!(
+            "`{}` has no unit, expected e.g. `12h` or `7d`",
+            text
+        ));
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|e| format!("invalid number `{}`: {}", number, e))?;
+    number
+        .checked_mul(hours * 3600)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("`{}` is too long", text))
+}
+
+/// A card answered with again in the reviews of `recent`
+#[derive(Debug, serde::Serialize)]
+struct FailedCard {
+    card_id: usize,
+    note_id: usize,
+    first_field: String,
+    /// Time of the last again in milliseconds since the unix epoch
+    last_failed: i64,
+    /// Number of agains in the reviews
+    again: usize,
+    /// Lapses of the card in total
+    lapses: i32,
+    /// All reviews of the card since the cutoff, as returned by anki
+    reviews: Vec<api::Review>,
+}
+
+/// Cards of notes with `generated_tag` in `deck` which were answered with again in the
+/// reviews after `start_id`, the most recently failed first.
+fn failed_cards(deck: &str, start_id: i64, generated_tag: &str) -> Result<Vec<FailedCard>> {
+    let reviews = if api::get_latest_review_id(deck)? > start_id {
+        api::card_reviews(deck, start_id)?
+    } else {
+        Vec::new()
+    };
+    debug!("got {} reviews after {}", reviews.len(), start_id);
+    let mut by_card: HashMap<usize, Vec<api::Review>> = HashMap::new();
+    for review in reviews {
+        by_card.entry(review.card_id()).or_default().push(review);
+    }
+    let card_ids = by_card
+        .iter()
+        .filter(|(_, reviews)| reviews.iter().any(api::Review::is_again))
+        .map(|(card, _)| *card)
+        .collect::<Vec<_>>();
+
+    let cards = chunked(&card_ids, "getting cards", cards_info)?;
+    let note_ids = cards
+        .iter()
+        .map(|card| card.note)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let notes = chunked(&note_ids, "getting notes", notes_info)?
+        .into_iter()
+        .filter(|note| note.tags.iter().any(|tag| tag == generated_tag))
+        .map(|note| (note.note_id, note))
+        .collect::<HashMap<_, _>>();
+
+    let mut failed = cards
+        .into_iter()
+        .filter_map(|card| {
+            let note = notes.get(&card.note)?;
+            let first_field = note
+                .fields
+                .values()
+                .min_by_key(|field| field.order)
+                .map(|field| tidy_field_text(&field_text(&field.value)))
+                .unwrap_or_default();
+            let reviews = by_card.remove(&card.card_id).unwrap_or_default();
+            let agains = reviews.iter().filter(|review| review.is_again());
+            Some(FailedCard {
+                card_id: card.card_id,
+                note_id: card.note,
+                first_field,
+                last_failed: agains
+                    .clone()
+                    .map(api::Review::time_ms)
+                    .max()
+                    .unwrap_or_default(),
+                again: agains.count(),
+                lapses: card.lapses,
+                reviews,
+            })
+        })
+        .collect::<Vec<_>>();
+    failed.sort_by_key(|card| std::cmp::Reverse((card.last_failed, card.card_id)));
+    Ok(failed)
+}
+
+/// Lists the cards of generated notes in `deck` which were answered with again in
+/// the last `since`.
+fn recent(config: &Config, deck: &str, since: Duration, output: OutputFormat) -> Result<()> {
+    let cutoff = chrono::Local::now() - since;
+    let cutoff_text = cutoff.format("%Y-%m-%d %H:%M");
+    let failed = failed_cards(deck, cutoff.timestamp_millis(), &config.generated_tag)?;
+    debug!("{} cards failed since {}", failed.len(), cutoff_text);
+
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&failed)?),
+        OutputFormat::Text if failed.is_empty() => println!(
+            "no cards of generated notes in {} were answered with again since {}",
+            deck, cutoff_text
+        ),
+        OutputFormat::Text => {
+            let mut table = report::Table::new(&["failed", "card", "front", "again", "lapses"])
+                .align(1, report::Align::Right)
+                .align(3, report::Align::Right)
+                .align(4, report::Align::Right);
+            for card in &failed {
+                let failed_at = chrono::DateTime::from_timestamp_millis(card.last_failed)
+                    .map(|time| {
+                        time.with_timezone(&chrono::Local)
+                            .format("%Y-%m-%d %H:%M")
+                            .to_string()
+                    })
+                    .unwrap_or_default();
+                let front = card.first_field.lines().next().unwrap_or_default();
+                table.row(vec![
+                    failed_at,
+                    card.card_id.to_string(),
+                    front.chars().take(60).collect(),
+                    card.again.to_string(),
+                    card.lapses.to_string(),
+                ]);
+            }
+            print!("{}", table.render(report::use_color()));
+        }
+    }
+    Ok(())
+}
+
+/// Prints the learning progress of the cards of the generated notes matching `query`.
+fn progress(
+    config: &Config,
+    query: Option<&str>,
+    group_by: ProgressGroup,
+    output: OutputFormat,
+) -> Result<()> {
+    let search = match query {
+        Some(query) => format!("\"tag:{}\" ({})", config.generated_tag, query),
+        None => format!("\"tag:{}\"", config.generated_tag),
+    };
+    let note_ids = find_notes(&search)?;
+    if note_ids.is_empty() {
+        return Err(eyre!("no notes match `{}`", search)
+            .note("only notes with `generated_tag` are included")
+            .suggestion("try the query in the browser of anki"));
+    }
+    let notes = chunked(&note_ids, "getting notes", notes_info)?;
+    let card_ids = notes
+        .iter()
+        .flat_map(|note| note.cards.iter().copied())
+        .collect::<Vec<_>>();
+    let cards = chunked(&card_ids, "getting cards", cards_info)?
+        .into_iter()
+        .map(|card| (card.card_id, card))
+        .collect::<HashMap<_, _>>();
+    let due = find_cards(&format!("{} is:due", search))?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut groups = BTreeMap::new();
+    for note in &notes {
+        let note_cards = note
+            .cards
+            .iter()
+            .filter_map(|id| cards.get(id))
+            .collect::<Vec<_>>();
+        let group = match group_by {
+            ProgressGroup::Deck => note_cards
+                .first()
+                .map_or_else(|| String::from("(no cards)"), |card| card.deck_name.clone()),
+            ProgressGroup::Date => note
+                .tags
+                .iter()
+                .find(|tag| anki_tex::is_generation_date_tag(tag))
+                .cloned()
+                .unwrap_or_else(|| String::from("(no date)")),
+        };
+        let progress = groups
+            .entry(group.clone())
+            .or_insert_with(|| report::Progress::new(group));
+        progress.notes += 1;
+        for card in note_cards {
+            progress.add_card(card, due.contains(&card.card_id));
+        }
+    }
+    let mut groups = groups.into_values().collect::<Vec<_>>();
+    groups.sort_by(|a, b| report::collate(&a.group, &b.group));
+
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&groups)?),
+        OutputFormat::Text => print!(
+            "{}",
+            report::progress_table(&groups).render(report::use_color())
+        ),
+    }
+    Ok(())
+}
+
+/// Saves the statistics of anki as standalone HTML file.
+fn write_report(out: &Path, deck_prefix: Option<&str>, open: bool) -> Result<()> {
+    api::ping().map_err(|e| {
+        e.wrap_err(format!("can't reach anki at {}", api::url()))
+            .suggestion("start anki and make sure the AnkiConnect add-on is installed")
+    })?;
+
+    let stats = api::get_collection_stats_html(true)
+        .map_err(|e| explain_unsupported(e, "getCollectionStatsHTML"))?;
+
+    let deck_table = match deck_prefix {
+        Some(prefix) => {
+            let sub_prefix = format!("{}::", prefix);
+            let decks = get_deck_names()?
+                .0
+                .into_iter()
+                .filter(|deck| deck == prefix || deck.starts_with(&sub_prefix))
+                .collect::<Vec<_>>();
+            if decks.is_empty() {
+                return Err(eyre!("there are no decks under {}", prefix)
+                    .suggestion("list the decks with `anki-tex get-decks`"));
+            }
+            let deck_stats =
+                api::get_deck_stats(&decks).map_err(|e| explain_unsupported(e, "getDeckStats"))?;
+
+            let mut table = String::from(
+                "<table class=\"decks\">\n<tr><th>Deck</th><th>New</th><th>Learning</th><th>Review</th><th>Total</th></tr>\n",
+            );
+            for deck in deck_stats {
+                table.push_str(&format!(
+                    "<tr><td>{}</td><td class=\"count\">{}</td><td class=\"count\">{}</td><td class=\"count\">{}</td><td class=\"count\">{}</td></tr>\n",
+                    html_escape::encode_text(&deck.name),
+                    deck.new_count,
+                    deck.learn_count,
+                    deck.review_count,
+                    deck.total_in_deck
+                ));
+            }
+            table.push_str("</table>\n");
+            table
+        }
+        None => String::new(),
+    };
+
+    let title = format!("Anki report {}", chrono::Local::now().format("%Y-%m-%d"));
+    let html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\n{css}\n</style>\n</head>\n<body>\n<h1>{title}</h1>\n{deck_table}{stats}\n</body>\n</html>\n",
+        title = title,
+        css = REPORT_CSS,
+        deck_table = deck_table,
+        stats = stats
+    );
+    std::fs::write(out, html)
+        .with_note(|| eyre!("while writing report to {}", out.to_string_lossy()))?;
+    info!("saved report to {}", out.to_string_lossy());
+
+    if open {
+        open::that(out).with_note(|| eyre!("while opening {}", out.to_string_lossy()))?;
+    }
+
+    Ok(())
+}
+
+/// Prints how `b` differs from `a`.
+fn print_note_diff(number: usize, a: &Note, b: &Note, options: &MatchOptions) {
+    println!("Note #{} differs", number);
+    if a.deck != b.deck {
+        println!("  deck: {:?} != {:?}", a.deck, b.deck);
+    }
+    if a.model != b.model {
+        println!("  model: {:?} != {:?}", a.model, b.model);
+    }
+    if a.tags != b.tags {
+        println!("  tags: {:?} != {:?}", a.tags, b.tags);
+    }
+    let names = a
+        .fields
+        .keys()
+        .chain(b.fields.keys())
+        .collect::<BTreeSet<_>>();
+    for name in names {
+        if !a.field_eq(b, name, options) {
+            println!(
+                "  field {}: {:?} != {:?}",
+                name,
+                a.fields.get(name),
+                b.fields.get(name)
+            );
+        }
+    }
+}
+
+/// Sends the notes of the file through the request format of anki and the tex
+/// formatter and checks that parsing the result gives the same notes again.
+fn roundtrip(config: &Config, paths: &FilePaths) -> Result<()> {
+    let content = read_to_string(&paths.main)
+        .with_note(|| eyre!("while reading file {}", paths.main.to_string_lossy()))?;
+    let mut notes = parse_file::get_content(content, &config.parse)?;
+    if config.normalize_unicode {
+        for note in &mut notes {
+            note.normalize_unicode();
+        }
+    }
+
+    // the notes as they are sent to anki
+    let requests = notes
+        .iter()
+        .map(|note| {
+            let mut note = note.clone();
+            for (name, field) in &mut note.fields {
+                *field = fmt_content(
+                    config,
+                    note.formats.get(name).copied().unwrap_or_default(),
+                    field,
+                );
+            }
+            serde_json::to_value(to_api_note(&note))
+        })
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    // anki doesn't know the formats, so they are taken from the original notes
+    let sent = requests
+        .into_iter()
+        .zip(&notes)
+        .map(|(request, original)| {
+            let note: anki_tex::api::Note = serde_json::from_value(request)?;
+            Ok(Note {
+                id: None,
+                deck: note.deck_name,
+                model: note.model_name,
+                fields: note
+                    .fields
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = unfmt_content(original.format(name), value);
+                        (name.clone(), value.to_owned())
+                    })
+                    .collect(),
+                tags: note.tags,
+                first_card: None,
+                mod_time: None,
+                card_ords: Vec::new(),
+                card_decks: Vec::new(),
+                formats: original.formats.clone(),
+                protected: original.protected,
+                options: original.options.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let formatted = parse_file::format_notes(&sent, &config.parse, &config.matching)?;
+    debug!("formatted notes:\n{}", formatted);
+    let mut reparsed = parse_file::get_content(formatted, &config.parse)
+        .with_note(|| "while parsing the formatted notes")?;
+    if config.normalize_unicode {
+        for note in &mut reparsed {
+            note.normalize_unicode();
+        }
+    }
+
+    let mut differences = 0;
+    for (i, (a, b)) in notes.iter().zip(&reparsed).enumerate() {
+        if !a.matches(b, &config.matching) {
+            differences += 1;
+            print_note_diff(i + 1, a, b, &config.matching);
+        }
+    }
+    if notes.len() != reparsed.len() {
+        return Err(eyre!(
+            "the file has {} notes, but {} notes were parsed after the roundtrip",
+            notes.len(),
+            reparsed.len()
+        ));
+    }
+    if differences != 0 {
+        return Err(eyre!(
+            "{} of {} notes changed during the roundtrip",
+            differences,
+            notes.len()
+        ));
+    }
+
+    println!("all {} notes survived the roundtrip", notes.len());
+    Ok(())
+}
+
+/// Asks the user a yes/no question on the terminal. Defaults to no.
+fn confirm(question: &str) -> Result<bool> {
+    use std::io::Write;
+
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+enum ModelChange<'a> {
+    Create(&'a ModelSchema),
+    Update {
+        templates: Option<&'a BTreeMap<String, CardTemplate>>,
+        css: Option<&'a str>,
+    },
+}
+
+fn apply_models(path: &Path) -> Result<()> {
+    let text = read_to_string(path)
+        .with_note(|| eyre!("while reading file {}", path.to_string_lossy()))?;
+    let file: ModelsFile = toml::from_str(&text)
+        .with_note(|| eyre!("while parsing file {}", path.to_string_lossy()))?;
+
+    let available_models = get_model_names()?.0;
+
+    let mut changes = Vec::new();
+    for (name, schema) in &file.models {
+        if !available_models.contains(name) {
+            println!("create model {}", name);
+            println!("  fields: {}", schema.fields.join(", "));
+            println!(
+                "  templates: {}",
+                schema
+                    .templates
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            changes.push((name, ModelChange::Create(schema)));
+            continue;
+        }
+
+        let current = fetch_model_schema(name)?;
+        if current.fields != schema.fields {
+            warn!(
+                "fields of model {} differ ({} in anki, {} in {}) but can't be changed by `models apply`",
+                name,
+                current.fields.join(", "),
+                schema.fields.join(", "),
+                path.to_string_lossy()
+            );
+        }
+        let templates = (current.templates != schema.templates).then_some(&schema.templates);
+        let css = (current.css != schema.css).then_some(schema.css.as_str());
+        if templates.is_none() && css.is_none() {
+            debug!("model {} is up to date", name);
+            continue;
+        }
+
+        println!("update model {}", name);
+        if templates.is_some() {
+            let empty = CardTemplate {
+                front: String::new(),
+                back: String::new(),
+            };
+            let template_names = current
+                .templates
+                .keys()
+                .chain(schema.templates.keys())
+                .collect::<BTreeSet<_>>();
+            for template_name in template_names {
+                let old = current.templates.get(template_name).unwrap_or(&empty);
+                let new = schema.templates.get(template_name).unwrap_or(&empty);
+                if old.front != new.front {
+                    println!("  template {} (front):", template_name);
+                    print_diff(&old.front, &new.front);
+                }
+                if old.back != new.back {
+                    println!("  template {} (back):", template_name);
+                    print_diff(&old.back, &new.back);
+                }
+            }
+        }
+        if css.is_some() {
+            println!("  styling:");
+            print_diff(&current.css, &schema.css);
+        }
+        changes.push((name, ModelChange::Update { templates, css }));
+    }
+
+    if changes.is_empty() {
+        info!("all models are up to date");
+        return Ok(());
+    }
+    if !confirm(&format!("apply changes to {} models?", changes.len()))? {
+        info!("aborted, nothing was changed");
+        return Ok(());
+    }
+
+    for (name, change) in changes {
+        match change {
+            ModelChange::Create(schema) => {
+                create_model(name, &schema.fields, &schema.css, &schema.templates)?;
+                info!("created model {}", name);
+            }
+            ModelChange::Update { templates, css } => {
+                if let Some(templates) = templates {
+                    update_model_templates(name, templates)?;
+                }
+                if let Some(css) = css {
+                    update_model_styling(name, css)?;
+                }
+                info!("updated model {}", name);
+                if templates.is_some() {
+                    let empty_notes = get_empty_notes(&format!("\"note:{}\"", name))?;
+                    if !empty_notes.is_empty() {
+                        warn!(
+                            "{} notes of model {} have no cards after the template change",
+                            empty_notes.len(),
+                            name
+                        );
+                        info!("remove them with `anki-tex cleanup --empty-notes`");
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Create Anki notes from file
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path of the file to read from.
+    ///
+    /// If no value is given and no config file exists `anki.tex` will be used.
+    #[arg(short, long)]
+    path: Option<PathBuf>,
+
+    /// Log Level
+    #[arg(long, default_value = "info")]
+    log_level: Level,
+    /// Use short log output
+    #[arg(long)]
+    short_log: bool,
+    /// Write all raw requests to and responses from anki to FILE or to the log.
+    ///
+    /// Can also be enabled with the environment variable `ANKI_TEX_TRACE=1`.
+    /// Without FILE, the trace is logged at debug level with the target `anki_tex::api::wire`.
+    #[arg(long, value_name = "FILE", num_args = 0..=1)]
+    trace_api: Option<Option<PathBuf>>,
+    /// Format of the summary printed by `create`, `diff` and `watch`, the results of `doctor`
+    /// and the problems found by `check`
+    #[arg(long, value_enum, default_value = "text")]
+    output: OutputFormat,
+    /// Add the tag `generated_tag` (`generated` by default) to each new note.
+    ///
+    /// Overrides `add_generated` of the config.
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    add_generated: Option<bool>,
+    /// Date of the tag added to each new note: `auto` for the current date, `none`
+    /// for no tag or a date like `2024-03-01`, e.g. when backfilling old notes.
+    ///
+    /// Overrides `generation_date` of the config, which is `auto` by default.
+    #[arg(long, value_name = "auto|none|YYYY-MM-DD")]
+    generation_date: Option<GenerationDate>,
+    /// If a field is set multiple times in one note, use the last value instead of failing.
+    ///
+    /// Overrides `on_duplicate_field` of the config.
+    #[arg(long)]
+    prefer_last: bool,
+    /// Clear the fields of updated notes which aren't set in the file.
+    ///
+    /// New notes always get all fields of their model, the ones missing in the file
+    /// are empty. When updating a note, only the fields set in the file are changed
+    /// by default, so fields filled in anki (e.g. `Extra`) are kept.
+    #[arg(long)]
+    replace_all_fields: bool,
+    /// Don't keep the old version of files rewritten by `check --fix` and
+    /// `fields rename --rewrite` as `.bak`
+    #[arg(long)]
+    no_backup: bool,
+    /// Order in which new notes are created.
+    ///
+    /// Overrides `creation_order` of the config.
+    #[arg(long, value_enum)]
+    order: Option<CreationOrder>,
+    /// Seed for `--order shuffled`. A random seed is used if none is given.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Don't show progress bars.
+    ///
+    /// They are also hidden if stderr isn't a terminal, with `--output json` and
+    /// with log levels below info.
+    #[arg(long)]
+    no_progress: bool,
+    /// Open this profile in anki before changing notes.
+    ///
+    /// Overrides `expected_profile` of the config.
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+    /// Change notes even if anki has another profile open than `expected_profile`
+    #[arg(long)]
+    ignore_profile_check: bool,
+    /// Print the number and latency of the requests to anki by their action at the end
+    /// of the run, also done with log level debug
+    #[arg(long)]
+    timings: bool,
+    /// Create notes without asking, even if there are more than `max_new_notes_per_run`,
+    /// and delete the notes of `undo` without asking.
+    #[arg(short, long)]
+    yes: bool,
+
+    #[command(subcommand)]
+    subcommand: Commands,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// Aligned table, colored if stdout is a terminal
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum NotesFormat {
+    /// The fields of every note, separated by lines
+    Text,
+    /// Markdown with one section per deck and note, e.g. for Obsidian
+    Markdown,
+}
+
+/// Override of the main file for one subcommand
+#[derive(Debug, clap::Args)]
+struct FileArg {
+    /// Read FILE instead of the file given by `--path` or the config, relative to the
+    /// current directory. `ankitex.sty` and `custom.sty` are taken from its directory.
+    #[arg(long, value_name = "FILE")]
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum NotesGroup {
+    Deck,
+    Model,
+    Tag,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Commands {
+    /// Save the template files (`anki.tex`, `ankitex.sty` and `custom.sty`) to the project directory.
+    Template {
+        #[command(flatten)]
+        file: FileArg,
+        /// Whether to overwrite the file if it exists
+        #[arg(short, long)]
+        force: bool,
+        /// Content of the main file, overrides `template_variant` of the config
+        #[arg(long, value_enum)]
+        variant: Option<TemplateVariant>,
+        /// Only print the absolute paths of the files which would be written
+        #[arg(long)]
+        dry_run: bool,
+        /// Create missing directories of the files
+        #[arg(long)]
+        create_dirs: bool,
+        /// Allow writing files outside of `path` of the config
+        #[arg(long)]
+        allow_outside_root: bool,
+    },
+    /// Watch for changes and create new notes
+    Watch {
+        #[command(flatten)]
+        file: FileArg,
+        /// Check the files for changes every SECS seconds instead of waiting for
+        /// events, e.g. on network filesystems like SSHFS which don't report changes.
+        ///
+        /// Overrides `watch_poll_secs` of the config.
+        #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "2")]
+        poll: Option<u64>,
+        /// Run CMD after each update which created or updated notes, with the
+        /// environment variables `ANKI_TEX_FILE`, `ANKI_TEX_CREATED` and `ANKI_TEX_UPDATED`.
+        ///
+        /// The arguments are separated by spaces. Overrides `on_change_cmd` of the config.
+        #[arg(long, value_name = "CMD", value_delimiter = ' ')]
+        on_change_cmd: Option<Vec<String>>,
+        /// Only parse and process the notes which changed since the last update.
+        /// Enabled by default, notes changed inside anki are only updated after
+        /// `--changed-only false` or `create`.
+        #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+        changed_only: Option<bool>,
+    },
+    /// Create new notes
+    #[clap(visible_alias = "c")]
+    Create {
+        #[command(flatten)]
+        file: FileArg,
+        /// Only print the summary if notes were created or updated, e.g. when run
+        /// as the hook of `latexmk -pvc`
+        #[arg(long)]
+        quiet: bool,
+        /// Skip the run if the files didn't change since the last successful run and
+        /// otherwise only parse and process the notes which changed
+        #[arg(long)]
+        changed_only: bool,
+        /// Only process the notes in decks matching this glob and their subdecks, e.g.
+        /// `Uni::Analysis::Ch3*`. `*` matches within one level and `**` any number of
+        /// levels. Can be repeated and replaces `only_decks` of the config
+        #[arg(long, value_name = "GLOB")]
+        only_deck: Vec<String>,
+        /// Skip the notes in decks matching this glob and their subdecks, like
+        /// `--only-deck`. Can be repeated and replaces `skip_decks` of the config
+        #[arg(long, value_name = "GLOB")]
+        skip_deck: Vec<String>,
+        /// Don't create the new notes of decks which would exceed their budget in
+        /// `deck_budgets` of the config, instead of only warning
+        #[arg(long)]
+        enforce_budgets: bool,
+    },
+    /// Show which notes would be created or updated without changing anything
+    Diff {
+        #[command(flatten)]
+        file: FileArg,
+    },
+    /// Browse the notes of the file in a terminal UI and create selected notes
+    Browse {
+        #[command(flatten)]
+        file: FileArg,
+    },
+    /// Show the notes of the file as they would be sent to anki
+    Show {
+        #[command(flatten)]
+        file: FileArg,
+        /// Only show the note with this number (starting at 1)
+        #[arg(long, conflicts_with = "grep")]
+        index: Option<usize>,
+        /// Only show notes with a field containing this text
+        #[arg(long)]
+        grep: Option<String>,
+    },
+    /// Create all decks in the file if they don't exist already
+    CreateAllDecks {
+        #[command(flatten)]
+        file: FileArg,
+    },
+    /// Get all deck names
+    GetDecks,
+    /// Get all model names
+    GetModels {
+        /// Also print the fields of the models in their order
+        #[arg(long)]
+        fields: bool,
+        /// Only print this model
+        #[arg(long, value_name = "NAME")]
+        model: Option<String>,
+        /// Only print the models used by the file
+        #[arg(long)]
+        used: bool,
+        /// Answer from the models cached by the last run instead of asking anki
+        #[arg(long)]
+        offline: bool,
+        #[command(flatten)]
+        file: FileArg,
+    },
+    /// Get all Notes for the given query
+    GetNotes {
+        /// See https://docs.ankiweb.net/searching.html
+        #[arg(default_value = "*")]
+        query: String,
+        /// Also show the cards of the notes, which needs all cards to be rendered
+        #[arg(short, long)]
+        verbose: bool,
+        /// Get and print the notes in chunks instead of all at once, to bound the
+        /// memory usage
+        #[arg(long)]
+        stream: bool,
+        /// Format of the notes
+        #[arg(long, value_enum, default_value = "text")]
+        format: NotesFormat,
+        /// Write one Markdown file per deck into DIR, instead of printing all decks
+        #[arg(long, value_name = "DIR")]
+        out_dir: Option<PathBuf>,
+        /// Add `anki://` links opening the notes to the Markdown
+        #[arg(long)]
+        anki_links: bool,
+        /// Print the fields as stored, without collapsing blank lines and trimming
+        #[arg(long)]
+        no_normalize: bool,
+        /// Print the notes under a heading per deck, model or tag with the number of
+        /// notes, notes with several tags are printed under each of them
+        #[arg(long, value_enum, conflicts_with = "stream")]
+        group_by: Option<NotesGroup>,
+    },
+    /// Render all latex
+    #[clap(visible_alias = "r")]
+    Render {
+        /// Check afterwards that the images of the notes exist in the media folder
+        #[arg(long)]
+        verify: bool,
+        /// Notes checked by `--verify`, by default the notes changed in the last day
+        #[arg(long, requires = "verify")]
+        query: Option<String>,
+    },
+    /// Sync all notes to ankiweb
+    #[clap(visible_alias = "s")]
+    Sync,
+    /// Create, render and sync all notes to ankiweb
+    Crs {
+        #[command(flatten)]
+        file: FileArg,
+        /// Fail if the add-on for rendering is missing instead of skipping the rendering
+        #[arg(long)]
+        require_render: bool,
+        /// Sync even if the last sync was less than `min_sync_interval_secs` ago
+        #[arg(long)]
+        force_sync: bool,
+    },
+    /// Delete the notes created by the last run, or by the run with the given id
+    ///
+    /// Notes which were modified in anki after the run are kept.
+    Undo {
+        /// Id of the run as printed when the notes were created
+        #[arg(long)]
+        run: Option<String>,
+    },
+    /// Restore the fields and tags of notes from a backup written before they were
+    /// changed
+    ///
+    /// The backups are in the `backups` directory next to the cache. Deleted notes are
+    /// created again with new ids.
+    Restore {
+        /// Backup file, named after the run which changed the notes
+        backup: PathBuf,
+    },
+    /// Bury the cards due today which don't match QUERY, e.g. to study the notes of
+    /// one file before an exam
+    ///
+    /// Anki unburies the cards the next day. Asks for `--yes` before burying more than
+    /// 500 cards.
+    Focus {
+        /// Search of the cards to study, e.g. `added:1 tag:anki-tex::source::chapter1`
+        #[arg(required_unless_present = "undo")]
+        query: Option<String>,
+        /// Unbury the cards buried by `focus` instead
+        #[arg(long, conflicts_with = "query")]
+        undo: bool,
+    },
+    /// Show or clear the data kept between runs
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Remove broken notes from the collection
+    Cleanup {
+        /// Remove notes which have no cards, e.g. after a template change
+        #[arg(long)]
+        empty_notes: bool,
+    },
+    /// Rewrite the wrappers of the fields of generated notes after changing their
+    /// format, e.g. from `[latex]...[/latex]` to `\(...\)`
+    ///
+    /// Only fields wrapped in the wrapper of `--from` are changed.
+    MigrateFormat {
+        /// Model of the notes
+        #[arg(long)]
+        model: String,
+        /// Current format of the fields, `latex` or `mathjax`
+        #[arg(long)]
+        from: FieldFormat,
+        /// New format of the fields, `latex` or `mathjax`
+        #[arg(long)]
+        to: FieldFormat,
+        /// Search restricting the notes, e.g. `deck:Analysis`
+        query: Option<String>,
+        /// Only print the changes
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Move the cards of the deck OLD and its subdecks to the same decks below NEW,
+    /// e.g. after changing the deck prefix in the files
+    ///
+    /// Until then, `deck_equivalences` lets the notes in the old decks match the notes
+    /// of the files. anki-tex doesn't edit the config, so the command fails after
+    /// moving the cards if the pair of OLD and NEW is still in `deck_equivalences`,
+    /// remove it by hand.
+    MigrateDeckPrefix {
+        old: String,
+        new: String,
+        /// Only print the decks which would be moved
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Export or apply the definitions of the used models
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+    /// Check the setup and print hints how to fix problems
+    Doctor {
+        #[command(flatten)]
+        file: FileArg,
+    },
+    /// Save the statistics of anki as HTML file
+    Report {
+        /// File to write the report to
+        #[arg(long, default_value = "report.html")]
+        out: PathBuf,
+        /// Add a table with the statistics of the decks starting with this prefix
+        #[arg(long)]
+        deck_prefix: Option<String>,
+        /// Open the report in the browser
+        #[arg(long)]
+        open: bool,
+    },
+    /// Show how well the cards of the generated notes matching QUERY are learned
+    Progress {
+        /// Search of the notes, e.g. `tag:anki-tex::source::chapter5`, all generated
+        /// notes by default
+        query: Option<String>,
+        /// Group the notes by their deck or by the date they were created
+        #[arg(long, value_enum, default_value = "deck")]
+        group_by: ProgressGroup,
+    },
+    /// List the cards of generated notes in DECK which were answered with again
+    /// recently, the most recent first
+    Recent {
+        /// Deck of the reviews, including its subdecks
+        deck: String,
+        /// Only include reviews of the last DURATION, e.g. `12h` or `7d`
+        #[arg(long, value_name = "DURATION", default_value = "24h", value_parser = parse_since)]
+        since: Duration,
+    },
+    /// Check that formatting the parsed notes as tex and parsing them again gives the same notes
+    #[command(hide = true)]
+    Roundtrip {
+        #[command(flatten)]
+        file: FileArg,
+    },
+    /// Parse the file and report redundant commands and notes without tags
+    Check {
+        #[command(flatten)]
+        file: FileArg,
+        /// Remove redundant `\deck` and `\model` commands, the old file is kept as `.bak`
+        /// unless `--no-backup` is set
+        #[arg(long)]
+        fix: bool,
+        /// Also compile the file with `latex_command`, skipped if LaTeX isn't installed
+        #[arg(long)]
+        compile: bool,
+        /// Also check that the styling of the used models has the CSS anki-tex relies
+        /// on, needs anki
+        #[arg(long)]
+        online: bool,
+        /// Append the missing CSS to the styling of the models after confirmation
+        #[arg(long, requires = "online")]
+        fix_styling: bool,
+        /// Also warn about commands in fields which are neither standard LaTeX nor
+        /// defined in `custom.sty`, `ankitex.sty` or `known_macros` of the config
+        #[arg(long)]
+        macros: bool,
+        /// Fail if `--macros` finds unknown commands
+        #[arg(long, requires = "macros")]
+        strict: bool,
+    },
+    /// Change the fields of a model
+    Fields {
+        #[command(subcommand)]
+        command: FieldsCommand,
+    },
+}
+
+impl Commands {
+    /// The `--file` of the subcommand, if it reads the main file and has one
+    fn file(&self) -> Option<&Path> {
+        let file = match self {
+            Self::Template { file, .. }
+            | Self::Watch { file, .. }
+            | Self::Create { file, .. }
+            | Self::Diff { file }
+            | Self::Browse { file }
+            | Self::Show { file, .. }
+            | Self::CreateAllDecks { file }
+            | Self::Crs { file, .. }
+            | Self::Doctor { file }
+            | Self::GetModels { file, .. }
+            | Self::Roundtrip { file }
+            | Self::Check { file, .. }
+            | Self::Models {
+                command: ModelsCommand::Export { file, .. },
+            }
+            | Self::Fields {
+                command: FieldsCommand::Rename { file, .. },
+            } => file,
+            _ => return None,
+        };
+        file.file.as_deref()
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum FieldsCommand {
+    /// Rename a field in anki and optionally in the files
+    Rename {
+        #[command(flatten)]
+        file: FileArg,
+        model: String,
+        old: String,
+        new: String,
+        /// Also replace the field name in `\fields` and field environments, the old
+        /// files are kept as `.bak` unless `--no-backup` is set
+        #[arg(long)]
+        rewrite: bool,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum CacheCommand {
+    /// Print the path, the age and a summary of the cache
+    Show,
+    /// Remove the cache, it is rebuilt by the next runs
+    ///
+    /// The counters of placeholders start again and `focus --undo` forgets the buried
+    /// cards.
+    Clear,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum ModelsCommand {
+    /// Save fields, templates and styling of all models used in the file
+    Export {
+        #[command(flatten)]
+        file: FileArg,
+        /// File to write the model definitions to
+        #[arg(long, default_value = "models.toml")]
+        out: PathBuf,
+    },
+    /// Create missing models and update templates and styling of existing ones
+    Apply {
+        /// File with the model definitions, as written by `models export`
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug)]
+struct RegexString {
+    re: Regex,
+    re_str: String,
+}
+
+impl<'de> Deserialize<'de> for RegexString {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let re_str: String = serde::Deserialize::deserialize(deserializer)?;
+
+        let re = Regex::new(&re_str).map_err(D::Error::custom)?;
+
+        Ok(Self { re, re_str })
+    }
+}
+
+/// A list of globs matched at once
+struct GlobList {
+    set: globset::GlobSet,
+    globs: Vec<String>,
+}
+
+impl GlobList {
+    fn new(globs: Vec<String>) -> Result<Self> {
+        let mut builder = globset::GlobSetBuilder::new();
+        for glob in &globs {
+            builder.add(
+                globset::GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()
+                    .with_note(|| eyre!("while parsing glob `{}`", glob))?,
+            );
+        }
+        Ok(Self {
+            set: builder.build()?,
+            globs,
+        })
+    }
+
+    /// Returns the first glob matching `path`.
+    fn find_match(&self, path: &str) -> Option<&str> {
+        self.set
+            .matches(path)
+            .first()
+            .map(|&i| self.globs[i].as_str())
+    }
+}
+
+/// Models of notes without `\model` by the glob patterns of their decks
+#[derive(Debug, Default)]
+struct DeckModels {
+    set: globset::GlobSet,
+    /// Pattern and model of each glob in `set`
+    entries: Vec<(String, String)>,
+}
+
+impl DeckModels {
+    /// A pattern without glob characters matches the deck and its subdecks.
+    fn new(deck_models: BTreeMap<String, String>) -> Result<Self> {
+        let mut builder = globset::GlobSetBuilder::new();
+        let mut entries = Vec::new();
+        for (pattern, model) in deck_models {
+            let globs = if pattern.contains(['*', '?', '[', '{']) {
+                vec![pattern.clone()]
+            } else {
+                vec![pattern.clone(), format!("{}::*", pattern)]
+            };
+            for glob in globs {
+                builder.add(
+                    globset::Glob::new(&glob)
+                        .with_note(|| eyre!("while parsing deck pattern `{}`", pattern))?,
+                );
+                entries.push((pattern.clone(), model.clone()));
+            }
+        }
+        Ok(Self {
+            set: builder.build()?,
+            entries,
+        })
+    }
+
+    /// Returns the pattern and model of the entry matching `deck`.
+    ///
+    /// If multiple patterns match, the one with the longest literal prefix wins,
+    /// then the longer pattern and then the pattern sorting first.
+    fn find(&self, deck: &str) -> Option<(&str, &str)> {
+        let literal_prefix =
+            |pattern: &str| pattern.find(['*', '?', '[', '{']).unwrap_or(pattern.len());
+        self.set
+            .matches(deck)
+            .into_iter()
+            .map(|i| &self.entries[i])
+            .min_by_key(|(pattern, _)| {
+                (
+                    std::cmp::Reverse(literal_prefix(pattern)),
+                    std::cmp::Reverse(pattern.len()),
+                    pattern.as_str(),
+                )
+            })
+            .map(|(pattern, model)| (pattern.as_str(), model.as_str()))
+    }
+}
+
+/// Glob patterns of decks matched at once
+///
+/// `*` matches within one level of the `::` hierarchy and `**` any number of levels.
+/// The subdecks of matching decks match as well.
+#[derive(Debug, Clone, Default)]
+struct DeckGlobs {
+    set: globset::GlobSet,
+    patterns: Vec<String>,
+    /// Index in `patterns` of each glob in `set`
+    entries: Vec<usize>,
+}
+
+impl DeckGlobs {
+    fn new(patterns: Vec<String>) -> Result<Self> {
+        let mut builder = globset::GlobSetBuilder::new();
+        let mut entries = Vec::new();
+        for (i, pattern) in patterns.iter().enumerate() {
+            // `::` is mapped to `/`, so `*` doesn't match across the separator
+            let path = Self::path(pattern);
+            for glob in [path.clone(), format!("{}/**", path)] {
+                builder.add(
+                    globset::GlobBuilder::new(&glob)
+                        .literal_separator(true)
+                        .build()
+                        .with_note(|| eyre!("while parsing deck pattern `{}`", pattern))?,
+                );
+                entries.push(i);
+            }
+        }
+        Ok(Self {
+            set: builder.build()?,
+            patterns,
+            entries,
+        })
+    }
+
+    fn path(deck: &str) -> String {
+        deck.replace('/', "\u{2215}")
+            .replace(parse_file::ANKI_DECK_SEPARATOR, "/")
+    }
+
+    /// Returns the indices of the patterns matching `deck`.
+    fn matches(&self, deck: &str) -> BTreeSet<usize> {
+        self.set
+            .matches(Self::path(deck))
+            .into_iter()
+            .map(|i| self.entries[i])
+            .collect()
+    }
+}
+
+/// Decks whose notes are processed, see `only_decks` and `skip_decks`
+#[derive(Debug, Clone, Default)]
+struct DeckFilter {
+    /// Only notes in these decks are processed, unless it's empty
+    only: DeckGlobs,
+    skip: DeckGlobs,
+}
+
+impl DeckFilter {
+    fn new(only: Vec<String>, skip: Vec<String>) -> Result<Self> {
+        Ok(Self {
+            only: DeckGlobs::new(only)?,
+            skip: DeckGlobs::new(skip)?,
+        })
+    }
+
+    /// Replaces the patterns of `self` with the non-empty lists of the arguments.
+    fn with_overrides(&self, only: Vec<String>, skip: Vec<String>) -> Result<Self> {
+        let or_current = |patterns: Vec<String>, current: &DeckGlobs| {
+            if patterns.is_empty() {
+                current.patterns.clone()
+            } else {
+                patterns
+            }
+        };
+        Self::new(or_current(only, &self.only), or_current(skip, &self.skip))
+    }
+
+    /// Removes the notes of excluded decks from `notes` and counts them by the filter
+    /// in `summary`.
+    ///
+    /// Returns the index before the removal of each remaining note.
+    fn apply(&self, notes: &mut Vec<Note>, summary: &mut report::RunSummary) -> Vec<usize> {
+        for pattern in &self.only.patterns {
+            summary
+                .only_deck_matches
+                .entry(pattern.clone())
+                .or_default();
+        }
+        let mut kept = Vec::with_capacity(notes.len());
+        let mut i = 0;
+        notes.retain(|note| {
+            let only = self.only.matches(&note.deck);
+            for &pattern in &only {
+                *summary
+                    .only_deck_matches
+                    .get_mut(&self.only.patterns[pattern])
+                    .unwrap() += 1;
+            }
+            let filter = if !self.only.patterns.is_empty() && only.is_empty() {
+                Some(String::from("only-deck"))
+            } else {
+                self.skip
+                    .matches(&note.deck)
+                    .first()
+                    .map(|&pattern| format!("skip-deck {}", self.skip.patterns[pattern]))
+            };
+            let keep = match filter {
+                Some(filter) => {
+                    debug!("leaving out a note in deck {} by {}", note.deck, filter);
+                    *summary.filtered.entry(filter).or_default() += 1;
+                    false
+                }
+                None => {
+                    kept.push(i);
+                    true
+                }
+            };
+            i += 1;
+            keep
+        });
+        kept
+    }
+}
+
+/// Maximum number of notes of decks, see `deck_budgets`
+#[derive(Debug, Clone, Default)]
+struct DeckBudgets {
+    globs: DeckGlobs,
+    /// Budget of each pattern of `globs`
+    budgets: Vec<usize>,
+}
+
+/// A deck which would have more notes than its budget after a run
+#[derive(Debug)]
+struct OverBudget {
+    deck: String,
+    budget: usize,
+    /// Notes in the deck and its subdecks in anki
+    current: usize,
+    /// Notes after creating the new notes
+    after: usize,
+}
+
+impl DeckBudgets {
+    fn new(budgets: BTreeMap<String, usize>) -> Result<Self> {
+        let (patterns, budgets) = budgets.into_iter().unzip();
+        Ok(Self {
+            globs: DeckGlobs::new(patterns)?,
+            budgets,
+        })
+    }
+
+    /// Smallest budget of the patterns matching `deck`
+    fn budget(&self, deck: &str) -> Option<usize> {
+        self.globs
+            .matches(deck)
+            .into_iter()
+            .map(|pattern| self.budgets[pattern])
+            .min()
+    }
+
+    /// Returns the decks which would exceed their budget by creating `notes`.
+    ///
+    /// Like in anki, the notes of the subdecks count for a deck, so the parents of
+    /// the decks of the notes are checked as well.
+    fn check<'a>(&self, notes: impl IntoIterator<Item = &'a Note>) -> Result<Vec<OverBudget>> {
+        if self.budgets.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut new_notes: BTreeMap<String, usize> = BTreeMap::new();
+        for note in notes {
+            let mut deck = String::new();
+            for part in note.deck.split(parse_file::ANKI_DECK_SEPARATOR) {
+                if !deck.is_empty() {
+                    deck.push_str(parse_file::ANKI_DECK_SEPARATOR);
+                }
+                deck.push_str(part);
+                *new_notes.entry(deck.clone()).or_default() += 1;
+            }
+        }
+        let budgeted = new_notes
+            .into_iter()
+            .filter_map(|(deck, new)| Some((self.budget(&deck)?, deck, new)))
+            .collect::<Vec<_>>();
+        if budgeted.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let queries = budgeted
+            .iter()
+            .map(|(_, deck, _)| deck_query(deck))
+            .collect::<Vec<_>>();
+        debug!(
+            "counting the notes of {} decks with a budget",
+            queries.len()
+        );
+        let counts = find_notes_multi(&queries)?;
+        Ok(budgeted
+            .into_iter()
+            .zip(counts)
+            .map(|((budget, deck, new), ids)| OverBudget {
+                deck,
+                budget,
+                current: ids.len(),
+                after: ids.len() + new,
+            })
+            .filter(|deck| deck.after > deck.budget)
+            .collect())
+    }
+}
+
+/// Search for the notes in `deck` and its subdecks, with the wildcards of anki
+/// escaped.
+fn deck_query(deck: &str) -> String {
+    let mut escaped = String::with_capacity(deck.len());
+    for c in deck.chars() {
+        if matches!(c, '\\' | '"' | '*' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    format!("\"deck:{}\"", escaped)
+}
+
+/// Moves the cards of `old` and its subdecks to the same decks below `new`.
+///
+/// Fails after moving the cards if `old` and `new` are still a pair of
+/// `deck_equivalences`, which has to be removed from the config by hand.
+fn migrate_deck_prefix(config: &Config, old: &str, new: &str, dry_run: bool) -> Result<()> {
+    if old == new {
+        return Err(eyre!("the old and the new prefix are both `{}`", old));
+    }
+    let equivalent = config
+        .matching
+        .deck_equivalences
+        .iter()
+        .any(|(o, n)| o == old && n == new);
+    let cards = find_cards(&deck_query(old))?;
+    // (old deck, new deck) -> cards
+    let mut moves = BTreeMap::<(String, String), Vec<usize>>::new();
+    for chunk in cards.chunks(INFO_CHUNK) {
+        for (deck, cards) in get_decks(chunk)? {
+            let Some(rest) = deck
+                .strip_prefix(old)
+                .filter(|rest| rest.is_empty() || rest.starts_with("::"))
+            else {
+                continue;
+            };
+            let new_deck = format!("{}{}", new, rest);
+            moves.entry((deck, new_deck)).or_default().extend(cards);
+        }
+    }
+    if moves.is_empty() {
+        println!("no cards in deck {} or its subdecks", old);
+        return Ok(());
+    }
+
+    for ((from, to), cards) in &moves {
+        println!("{} -> {}: {} cards", from, to, cards.len());
+    }
+    let total = moves.values().map(Vec::len).sum::<usize>();
+    if dry_run {
+        println!("would move {} cards to {} decks", total, moves.len());
+        if equivalent {
+            println!(
+                "remove [\"{}\", \"{}\"] from `deck_equivalences` by hand after moving them",
+                old, new
+            );
+        }
+        return Ok(());
+    }
+    let mut record = RunRecord::new();
+    // the notes with cards in `old` or its subdecks, i.e. of the moved cards
+    record.updated = find_notes(&deck_query(old))?;
+    record.backup = backup_notes(config, &record.id, &record.updated)?;
+    let bar = progress_bar(total, "moving cards");
+    for ((_, to), cards) in &moves {
+        for chunk in cards.chunks(INFO_CHUNK) {
+            api::change_deck(chunk, to)?;
+            bar.inc(chunk.len() as u64);
+        }
+    }
+    bar.finish_and_clear();
+    record.append(&config.run_log)?;
+    println!("moved {} cards to {} decks", total, moves.len());
+    if equivalent {
+        return Err(eyre!(
+            "[\"{}\", \"{}\"] is still in `deck_equivalences` of the config",
+            old,
+            new
+        )
+        .note("the cards were moved, anki-tex doesn't edit the config")
+        .suggestion("remove the pair from `deck_equivalences` by hand"));
+    }
+    Ok(())
+}
+
+/// Warns about `only-deck` patterns which didn't match the deck of any note, they
+/// are probably misspelled.
+fn warn_unmatched_decks(summary: &report::RunSummary) {
+    for (pattern, _) in summary
+        .only_deck_matches
+        .iter()
+        .filter(|(_, count)| **count == 0)
+    {
+        warn!(
+            "the only-deck pattern `{}` matches no deck of the processed notes, is it misspelled?",
+            pattern
+        );
+    }
+}
+
+/// Template of the deck of notes without `\deck`, see [`DeckTemplate::deck`]
+#[derive(Debug)]
+struct DeckTemplate(String);
+
+impl DeckTemplate {
+    const PLACEHOLDERS: [&'static str; 3] = ["stem", "stem_title", "dir"];
+
+    fn new(template: String) -> Result<Self> {
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                return Err(eyre!(
+                    "unclosed placeholder in `deck_from_path` `{}`",
+                    template
+                ));
+            };
+            let name = &rest[start + 1..start + end];
+            if !Self::PLACEHOLDERS.contains(&name) {
+                return Err(
+                    eyre!("unknown placeholder `{{{}}}` in `deck_from_path`", name).note(format!(
+                        "available placeholders: {}",
+                        Self::PLACEHOLDERS.map(|p| format!("{{{}}}", p)).join(", ")
+                    )),
+                );
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(Self(template))
+    }
+
+    /// Replaces the placeholders by parts of `path`:
+    /// - `{stem}`: the file name without extension, e.g. `ch03-integration`
+    /// - `{stem_title}`: the stem with `-` and `_` replaced by spaces and the first
+    ///   letter of each word in upper case, e.g. `Ch03 Integration`
+    /// - `{dir}`: the name of the directory containing the file
+    fn deck(&self, path: &Path) -> String {
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let dir = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|d| d.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.0
+            .replace("{stem}", &stem)
+            .replace("{stem_title}", &title_case(&stem))
+            .replace("{dir}", &dir)
+    }
+}
+
+/// Replaces `-` and `_` by spaces and converts the first letter of each word to upper case.
+fn title_case(s: &str) -> String {
+    s.split(['-', '_', ' '])
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first.to_uppercase().chain(chars).collect::<String>()
+            })
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Model used for notes without `\model` which have the fields `fields`
+#[derive(Debug, Clone, Deserialize)]
+struct ModelSelection {
+    fields: Vec<String>,
+    model: String,
+}
+
+/// How field names of the file are matched with the fields of the model, see
+/// [`match_field_names`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FieldNameCase {
+    /// Field names have to match exactly
+    #[default]
+    Strict,
+    /// Field names are matched case-insensitively and renamed to the names of the model
+    Fix,
+    /// Field names are matched case-insensitively and kept as written, anki matches
+    /// the fields of new notes case-insensitively, but not of updated notes
+    Ignore,
+}
+
+/// Prints, ignores or fails on the warnings, with the action of `by_class` for the
+/// class of the warning or else `default`.
+fn handle_parse_warnings(
+    default: WarningAction,
+    by_class: &HashMap<parse_file::WarningClass, WarningAction>,
+    warnings: &[parse_file::ParseWarning],
+) -> Result<()> {
+    let mut errors = Vec::new();
+    for warning in warnings {
+        let action = by_class.get(&warning.class()).copied().unwrap_or(default);
+        match action {
+            WarningAction::Print => warn!("{}", warning),
+            WarningAction::Error => errors.push(warning),
+            WarningAction::Ignore => debug!("ignoring warning: {}", warning),
+        }
+    }
+
+    match errors.as_slice() {
+        [] => Ok(()),
+        [error] => Err(eyre!("{}", error)),
+        [first, rest @ ..] => Err(rest.iter().fold(eyre!("{}", first), |err, warning| {
+            err.note(warning.to_string())
+        })),
+    }
+}
+
+/// How to handle warnings while parsing a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WarningAction {
+    #[default]
+    Print,
+    Error,
+    Ignore,
+}
+
+struct Config {
+    path: Option<PathBuf>,
+    file_include: Vec<RegexString>,
+    file_exclude: Vec<RegexString>,
+    file_include_globs: GlobList,
+    /// Tags managed inside anki, which are never added or removed
+    protected_tags: GlobList,
+    /// Tag protecting stored notes from changes like `\keep`, `None` if disabled
+    keep_tag: Option<String>,
+    /// Commands which `check --macros` doesn't report, without the backslash
+    known_macros: BTreeSet<String>,
+    file_exclude_globs: GlobList,
+    respect_gitignore: bool,
+    custom_commands: Vec<parse_file::CustomCommand>,
+    parse: parse_file::ParseOptions,
+    /// Clear fields of updated notes which aren't set in the file
+    replace_all_fields: bool,
+    add_generated: bool,
+    /// Tag added to new notes with `add_generated`
+    generated_tag: String,
+    generation_date: GenerationDate,
+    max_field_bytes: usize,
+    warn_field_bytes: usize,
+    /// How parsed notes are compared with stored notes
+    matching: MatchOptions,
+    double_wrap: DoubleWrap,
+    tag_case: TagCase,
+    normalize_unicode: bool,
+    /// Expand `{{today}}`, `{{file}}`, `{{deck}}` and `{{counter:<name>}}` in the
+    /// fields of new notes, see [`expand_placeholders`]. Off by default, as LaTeX like
+    /// `\frac{{a}}{b}` looks the same.
+    field_placeholders: bool,
+    field_name_case: FieldNameCase,
+    warnings: WarningAction,
+    warnings_by_class: HashMap<parse_file::WarningClass, WarningAction>,
+    creation_order: NoteOrder,
+    template_variant: TemplateVariant,
+    collation: report::Collation,
+    tag_source_file: bool,
+    /// Expected content of `ankitex.sty`
+    ankitex: String,
+    min_sync_interval_secs: i64,
+    /// Interval of `watch` checking the files for changes, instead of file events
+    watch_poll_secs: Option<u64>,
+    watch_filter: WatchFilter,
+    /// Profile which has to be open in anki before notes are changed
+    expected_profile: Option<String>,
+    auto_sync: bool,
+    cache_file: PathBuf,
+    /// See [`RunRecord`]
+    run_log: PathBuf,
+    /// Directory of the backups written by [`backup_notes`]
+    backup_dir: PathBuf,
+    /// Number of backups kept, `0` disables backups
+    keep_backups: usize,
+    /// Changes to more notes at once aren't backed up
+    max_backup_notes: usize,
+    model_selection: Vec<ModelSelection>,
+    deck_models: DeckModels,
+    deck_filter: DeckFilter,
+    deck_budgets: DeckBudgets,
+    /// Command and arguments used by `check --compile`, the file is appended
+    latex_command: Vec<String>,
+    /// Command and arguments run by `watch` after notes were changed
+    on_change_cmd: Option<Vec<String>>,
+    /// Creating more notes at once needs a confirmation, `None` with `--yes`
+    max_new_notes_per_run: Option<usize>,
+    deck_from_path: Option<DeckTemplate>,
+    /// Hash of the config file and the arguments changing how files are parsed
+    parse_hash: String,
+}
+
+/// Chooses the model of `notes` without `\model` with `deck_models` or
+/// `model_selection`, see [`Config::select_models`].
+///
+/// `index(i)` is the index in the file of the note `i` of `notes`.
+fn select_models(
+    deck_models: &DeckModels,
+    model_selection: &[ModelSelection],
+    notes: &mut [Note],
+    index: impl Fn(usize) -> usize,
+) -> Result<Vec<Option<String>>> {
+    let mut sources = Vec::with_capacity(notes.len());
+    for (i, note) in notes.iter_mut().enumerate() {
+        let i = index(i);
+        if !note.model.is_empty() {
+            sources.push(None);
+            continue;
+        }
+        if let Some((pattern, model)) = deck_models.find(&note.deck) {
+            debug!(
+                "selected model {} for note #{} in deck {} by deck mapping `{}`",
+                model,
+                i + 1,
+                note.deck,
+                pattern
+            );
+            note.model = model.to_owned();
+            sources.push(Some(format!("deck mapping `{}`", pattern)));
+            continue;
+        }
+        if model_selection.is_empty() {
+            return Err(no_model(
+                i,
+                format!(
+                    "no entry of `deck_models` matches the deck {} of note #{} without `\\model`",
+                    note.deck,
+                    i + 1
+                ),
+            )
+            .suggestion("add the deck to `deck_models` or set the model with `\\model`"));
+        }
+        let fields = note.fields.keys().cloned().collect::<BTreeSet<_>>();
+        let describe = |selection: &&ModelSelection| {
+            format!("{} ({})", selection.model, selection.fields.join(", "))
+        };
+
+        let exact = model_selection
+            .iter()
+            .filter(|s| s.fields.iter().cloned().collect::<BTreeSet<_>>() == fields)
+            .collect::<Vec<_>>();
+        let candidates = if exact.is_empty() {
+            model_selection
+                .iter()
+                .filter(|s| fields.iter().all(|f| s.fields.contains(f)))
+                .collect::<Vec<_>>()
+        } else {
+            exact
+        };
+
+        let fields = fields.into_iter().collect::<Vec<_>>().join(", ");
+        match candidates.as_slice() {
+            [selection] => {
+                debug!(
+                    "selected model {} for note #{} with fields {}",
+                    selection.model,
+                    i + 1,
+                    fields
+                );
+                note.model = selection.model.clone();
+                sources.push(Some(String::from("selected by fields")));
+            }
+            [] => {
+                return Err(no_model(
+                    i,
+                    format!(
+                        "no model matches the fields {} of note #{} without `\\model`",
+                        fields,
+                        i + 1
+                    ),
+                )
+                .note(format!(
+                    "configured models: {}",
+                    model_selection
+                        .iter()
+                        .map(|s| describe(&s))
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                ))
+                .suggestion("add the fields to `model_selection` or set the model with `\\model`"))
+            }
+            _ => {
+                return Err(no_model(
+                    i,
+                    format!(
+                        "multiple models match the fields {} of note #{} without `\\model`",
+                        fields,
+                        i + 1
+                    ),
+                )
+                .note(format!(
+                    "candidates: {}",
+                    candidates
+                        .iter()
+                        .map(describe)
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                ))
+                .suggestion("set the model with `\\model`"))
+            }
+        }
+    }
+    Ok(sources)
+}
+
+/// Returns the path of the config file and the cache directory.
+///
+/// `config_env` and `cache_env` are the values of [`CONFIG_ENV`] and
+/// [`CACHE_DIR_ENV`], without them the directories of `project_dirs` are used or,
+/// without a home directory, the current directory.
+fn config_locations(
+    config_env: Option<std::ffi::OsString>,
+    cache_env: Option<std::ffi::OsString>,
+    project_dirs: Option<directories_next::ProjectDirs>,
+) -> Result<(PathBuf, PathBuf)> {
+    if project_dirs.is_none() {
+        warn!(
+            "no valid home directory path could be found, using the current directory for \
+             the config and cache unless `{}` and `{}` are set",
+            CONFIG_ENV, CACHE_DIR_ENV
+        );
+    }
+    let config_path = match config_env {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            if !path.is_file() {
+                return Err(eyre!(
+                    "the config file {} set by `{}` doesn't exist",
+                    path.to_string_lossy(),
+                    CONFIG_ENV
+                ));
+            }
+            path
+        }
+        None => match &project_dirs {
+            Some(project_dirs) => {
+                let config_dir = project_dirs.config_dir();
+                if !config_dir.is_dir() {
+                    std::fs::create_dir_all(config_dir)?;
+                }
+                config_dir.join("config.toml")
+            }
+            None => PathBuf::from("anki-tex.toml"),
+        },
+    };
+    let cache_dir = match (cache_env, &project_dirs) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(project_dirs)) => project_dirs.cache_dir().to_owned(),
+        (None, None) => PathBuf::from(".anki-tex-cache"),
+    };
+    Ok((config_path, cache_dir))
+}
+
+/// Error of [`select_models`] for the note with index `index`
+fn no_model(index: usize, message: String) -> color_eyre::Report {
+    color_eyre::Report::new(
+        report::Diagnostic::new(report::Severity::Error, report::Code::NoModel, message)
+            .note_index(Some(index)),
+    )
+}
+
+impl Config {
+    fn load(
+        add_generated: Option<bool>,
+        generation_date: Option<GenerationDate>,
+        prefer_last: bool,
+        replace_all_fields: bool,
+        order: Option<CreationOrder>,
+        seed: Option<u64>,
+        yes: bool,
+    ) -> Result<Self> {
+        #[derive(Default, serde::Deserialize)]
+        struct ExternalConfig {
+            path: Option<PathBuf>,
+            #[serde(default)]
+            file_include: Vec<RegexString>,
+            #[serde(default)]
+            file_exclude: Vec<RegexString>,
+            #[serde(default)]
+            file_include_globs: Vec<String>,
+            #[serde(default)]
+            protected_tags: Vec<String>,
+            keep_tag: Option<String>,
+            #[serde(default)]
+            known_macros: Vec<String>,
+            #[serde(default)]
+            math_delimiters: anki_tex::MathDelimiters,
+            #[serde(default)]
+            double_wrap: DoubleWrap,
+            #[serde(default)]
+            tag_case: TagCase,
+            #[serde(default)]
+            file_exclude_globs: Vec<String>,
+            #[serde(default)]
+            respect_gitignore: bool,
+            #[serde(default)]
+            custom_commands: Vec<parse_file::CustomCommand>,
+            #[serde(default)]
+            on_duplicate_field: parse_file::OnDuplicateField,
+            #[serde(default)]
+            on_mid_note_switch: parse_file::OnMidNoteSwitch,
+            max_requests_per_second: Option<f64>,
+            max_field_bytes: Option<usize>,
+            warn_field_bytes: Option<usize>,
+            hash_field: Option<String>,
+            #[serde(default)]
+            normalize_unicode: bool,
+            #[serde(default)]
+            field_placeholders: bool,
+            #[serde(default)]
+            field_name_case: FieldNameCase,
+            #[serde(default)]
+            warnings: WarningAction,
+            #[serde(default)]
+            warnings_by_class: HashMap<parse_file::WarningClass, WarningAction>,
+            #[serde(default)]
+            creation_order: CreationOrder,
+            #[serde(default)]
+            template_variant: TemplateVariant,
+            #[serde(default)]
+            collation: report::Collation,
+            #[serde(default)]
+            tag_source_file: bool,
+            header: Option<String>,
+            header_file: Option<PathBuf>,
+            footer: Option<String>,
+            footer_file: Option<PathBuf>,
+            ankitex_file: Option<PathBuf>,
+            #[serde(default)]
+            anki_connect: api::ClientConfig,
+            /// model -> field -> format
+            #[serde(default)]
+            field_formats: HashMap<String, HashMap<String, FieldFormat>>,
+            #[serde(default)]
+            note_templates: Vec<parse_file::NoteTemplate>,
+            #[serde(default)]
+            min_sync_interval_secs: u32,
+            watch_poll_secs: Option<u64>,
+            watch_extensions: Option<Vec<String>>,
+            watch_ignore_dirs: Option<Vec<String>>,
+            expected_profile: Option<String>,
+            #[serde(default)]
+            auto_sync: bool,
+            #[serde(default)]
+            model_selection: Vec<ModelSelection>,
+            #[serde(default)]
+            deck_models: BTreeMap<String, String>,
+            #[serde(default)]
+            only_decks: Vec<String>,
+            #[serde(default)]
+            skip_decks: Vec<String>,
+            /// deck pattern -> maximum number of notes
+            #[serde(default)]
+            deck_budgets: BTreeMap<String, usize>,
+            /// `[old prefix, new prefix]`, removed by hand after `migrate-deck-prefix`
+            #[serde(default)]
+            deck_equivalences: Vec<[String; 2]>,
+            latex_command: Option<Vec<String>>,
+            on_change_cmd: Option<Vec<String>>,
+            deck_separator: Option<String>,
+            max_new_notes_per_run: Option<usize>,
+            keep_backups: Option<usize>,
+            max_backup_notes: Option<usize>,
+            add_generated: Option<bool>,
+            generated_tag: Option<String>,
+            generation_date: Option<GenerationDate>,
+            deck_from_path: Option<String>,
+            #[serde(default)]
+            notelist: parse_file::NoteListFields,
+            #[serde(default)]
+            implicit_fields: bool,
+            implicit_field_names: Option<[String; 2]>,
+        }
+
+        let (config_path, cache_dir) = config_locations(
+            std::env::var_os(CONFIG_ENV),
+            std::env::var_os(CACHE_DIR_ENV),
+            directories_next::ProjectDirs::from("", "akida", "anki-tex"),
+        )?;
+        let config_dir = config_path.parent().unwrap_or(Path::new("")).to_owned();
+        debug!(
+            "using config file {} and cache directory {}",
+            config_path.to_string_lossy(),
+            cache_dir.to_string_lossy()
+        );
+
+        let mut config_text = String::new();
+        let config: ExternalConfig = if !config_path.is_file() {
+            info!(
+                "no config file found. You can create one at {}",
+                config_path.to_string_lossy()
+            );
+            Default::default()
+        } else {
+            config_text = read_to_string(&config_path).with_note(|| {
+                eyre!(
+                    "while reading config file from {}",
+                    config_path.to_string_lossy()
+                )
+            })?;
+            toml::from_str(&config_text).with_note(|| {
+                eyre!(
+                    "while parsing config file from {}",
+                    config_path.to_string_lossy()
+                )
+            })?
+        };
+
+        api::configure_client(config.anki_connect).with_note(|| {
+            eyre!(
+                "while loading `anki_connect` from {}",
+                config_path.to_string_lossy()
+            )
+        })?;
+
+        if let Some(rate) = config.max_requests_per_second {
+            if rate.is_nan() || rate < api::MIN_REQUESTS_PER_SECOND {
+                return Err(eyre!(
+                    "max_requests_per_second must be at least {}, got {}",
+                    api::MIN_REQUESTS_PER_SECOND,
+                    rate
+                ));
+            }
+            api::set_rate_limit(Some(rate));
+        }
+
+        let generated_tag = config
+            .generated_tag
+            .unwrap_or_else(|| String::from(DEFAULT_GENERATED_TAG));
+        if generated_tag.is_empty() || generated_tag.contains(char::is_whitespace) {
+            return Err(eyre!(
+                "`generated_tag` must be a tag without whitespace, got `{}`",
+                generated_tag
+            ));
+        }
+        // an empty tag disables it
+        let keep_tag = match config.keep_tag {
+            None => Some(DEFAULT_KEEP_TAG.to_owned()),
+            Some(tag) if tag.is_empty() => None,
+            Some(tag) if tag.contains(char::is_whitespace) => {
+                return Err(eyre!(
+                    "`keep_tag` must be a tag without whitespace, got `{}`",
+                    tag
+                ))
+            }
+            Some(tag) => Some(tag),
+        };
+        let deck_equivalences = config
+            .deck_equivalences
+            .into_iter()
+            .map(|[old, new]| {
+                if old.is_empty() || new.is_empty() || old == new {
+                    return Err(eyre!(
+                        "`deck_equivalences` must contain pairs of different deck prefixes, \
+                         got [\"{}\", \"{}\"]",
+                        old,
+                        new
+                    ));
+                }
+                Ok((old, new))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // relative paths are resolved from the directory of the config file
+        let read_config_file = |path: &Path| {
+            let path = config_dir.join(path);
+            read_to_string(&path)
+                .with_note(|| eyre!("while reading file {}", path.to_string_lossy()))
+        };
+        let snippet =
+            |key: &str, inline: Option<String>, file: Option<&PathBuf>| match (inline, file) {
+                (Some(_), Some(_)) => Err(eyre!("`{}` and `{}_file` are both set", key, key)
+                    .suggestion("remove one of them from the config")),
+                (Some(inline), None) => Ok(Some(inline)),
+                (None, Some(file)) => read_config_file(file).map(Some),
+                (None, None) => Ok(None),
+            };
+        let default_frame = parse_file::DocumentFrame::default();
+        // the file is trimmed before comparing it with header and footer
+        let frame = parse_file::DocumentFrame {
+            header: snippet("header", config.header, config.header_file.as_ref())?
+                .map_or(default_frame.header, |h| h.trim_start().to_owned()),
+            footer: snippet("footer", config.footer, config.footer_file.as_ref())?
+                .map_or(default_frame.footer, |f| f.trim_end().to_owned()),
+        };
+        let ankitex = match &config.ankitex_file {
+            Some(file) => read_config_file(file)?,
+            None => parse_file::ANKITEX.to_owned(),
+        };
+        if config.on_change_cmd.as_ref().is_some_and(Vec::is_empty) {
+            return Err(eyre!("`on_change_cmd` must contain at least the command"));
+        }
+        let deck_separator = match config.deck_separator {
+            Some(separator) if separator.trim().is_empty() => {
+                return Err(eyre!("`deck_separator` must not be empty or whitespace"))
+            }
+            Some(separator) if separator == parse_file::ANKI_DECK_SEPARATOR => None,
+            separator => separator,
+        };
+        let latex_command = match config.latex_command {
+            Some(command) if command.is_empty() => {
+                return Err(eyre!("`latex_command` must contain at least the command"))
+            }
+            Some(command) => command,
+            None => [
+                "pdflatex",
+                "-interaction=nonstopmode",
+                "-halt-on-error",
+                "-file-line-error",
+            ]
+            .map(String::from)
+            .to_vec(),
+        };
+
+        let commands =
+            parse_file::CommandTable::new(&config.custom_commands, &config.note_templates)
+                .with_note(|| {
+                    eyre!(
+                        "while loading custom commands and note templates from {}",
+                        config_path.to_string_lossy()
+                    )
+                })?;
+
+        let parse_hash = format!(
+            "{:016x}",
+            fasthash::metro::hash64(format!("{}\0{}", config_text, prefer_last))
+        );
+        Ok(Self {
+            parse_hash,
+            path: config.path,
+            file_include: config.file_include,
+            file_exclude: config.file_exclude,
+            file_include_globs: GlobList::new(config.file_include_globs)?,
+            protected_tags: GlobList::new(config.protected_tags)
+                .with_note(|| "while loading `protected_tags`")?,
+            keep_tag,
+            known_macros: config
+                .known_macros
+                .iter()
+                .map(|name| name.trim_start_matches('\\').to_owned())
+                .collect(),
+            file_exclude_globs: GlobList::new(config.file_exclude_globs)?,
+            respect_gitignore: config.respect_gitignore,
+            custom_commands: config.custom_commands,
+            parse: parse_file::ParseOptions {
+                commands,
+                on_duplicate_field: if prefer_last {
+                    parse_file::OnDuplicateField::Last
+                } else {
+                    config.on_duplicate_field
+                },
+                on_mid_note_switch: config.on_mid_note_switch,
+                frame,
+                field_formats: config.field_formats,
+                allow_missing_model: !config.model_selection.is_empty()
+                    || !config.deck_models.is_empty(),
+                allow_missing_deck: config.deck_from_path.is_some(),
+                note_list: config.notelist,
+                deck_separator,
+                implicit_fields: {
+                    let mut implicit = parse_file::ImplicitFields {
+                        enabled: config.implicit_fields,
+                        ..Default::default()
+                    };
+                    if let Some([first, second]) = config.implicit_field_names {
+                        (implicit.first, implicit.second) = (first, second);
+                    }
+                    implicit
+                },
+            },
+            replace_all_fields,
+            add_generated: add_generated.or(config.add_generated).unwrap_or(true),
+            generated_tag: generated_tag.clone(),
+            generation_date: generation_date
+                .or(config.generation_date)
+                .unwrap_or_default(),
+            max_field_bytes: config.max_field_bytes.unwrap_or(100 * 1024),
+            warn_field_bytes: config.warn_field_bytes.unwrap_or(20 * 1024),
+            matching: MatchOptions {
+                hash_field: config.hash_field,
+                generated_tag: Some(generated_tag),
+                math_delimiters: config.math_delimiters,
+                deck_equivalences,
+            },
+            double_wrap: config.double_wrap,
+            tag_case: config.tag_case,
+            normalize_unicode: config.normalize_unicode,
+            field_placeholders: config.field_placeholders,
+            field_name_case: config.field_name_case,
+            warnings: config.warnings,
+            warnings_by_class: config.warnings_by_class,
+            creation_order: NoteOrder {
+                order: order.unwrap_or(config.creation_order),
+                seed,
+            },
+            template_variant: config.template_variant,
+            collation: config.collation,
+            tag_source_file: config.tag_source_file,
+            ankitex,
+            min_sync_interval_secs: config.min_sync_interval_secs.into(),
+            watch_poll_secs: config.watch_poll_secs,
+            expected_profile: config.expected_profile,
+            watch_filter: WatchFilter {
+                extensions: config
+                    .watch_extensions
+                    .unwrap_or_else(|| vec![String::from("tex")])
+                    .into_iter()
+                    .map(|ext| ext.trim_start_matches('.').to_owned())
+                    .collect(),
+                ignore_dirs: GlobList::new(
+                    config
+                        .watch_ignore_dirs
+                        .unwrap_or_else(|| DEFAULT_WATCH_IGNORE_DIRS.map(String::from).to_vec()),
+                )
+                .with_note(|| "while loading `watch_ignore_dirs`")?,
+            },
+            auto_sync: config.auto_sync,
+            cache_file: cache_dir.join("state.json"),
+            run_log: cache_dir.join("runs.jsonl"),
+            backup_dir: cache_dir.join("backups"),
+            keep_backups: config.keep_backups.unwrap_or(20),
+            max_backup_notes: config.max_backup_notes.unwrap_or(10_000),
+            model_selection: config.model_selection,
+            deck_models: DeckModels::new(config.deck_models)?,
+            deck_filter: DeckFilter::new(config.only_decks, config.skip_decks)
+                .with_note(|| "while loading `only_decks` and `skip_decks`")?,
+            deck_budgets: DeckBudgets::new(config.deck_budgets)
+                .with_note(|| "while loading `deck_budgets`")?,
+            latex_command,
+            on_change_cmd: config.on_change_cmd,
+            // 0 disables the limit
+            max_new_notes_per_run: match config.max_new_notes_per_run.unwrap_or(200) {
+                _ if yes => None,
+                0 => None,
+                max => Some(max),
+            },
+            deck_from_path: config.deck_from_path.map(DeckTemplate::new).transpose()?,
+        })
+    }
+
+    /// Checks the custom commands against their definitions in `custom.sty`.
+    fn check_custom_commands(&self, custom: &Path) -> Result<()> {
+        if self.custom_commands.is_empty() || !custom.is_file() {
+            return Ok(());
+        }
+        let content = read_to_string(custom)
+            .with_note(|| eyre!("while reading file {}", custom.to_string_lossy()))?;
+        parse_file::check_custom_command_args(&content, &self.custom_commands)
+    }
+
+    /// Sets the deck of notes without `\deck` to the one derived from `path` with
+    /// `deck_from_path`.
+    fn select_decks(&self, notes: &mut [Note], path: &Path) {
+        let Some(template) = &self.deck_from_path else {
+            return;
+        };
+        let mut missing = notes.iter_mut().filter(|n| n.deck.is_empty()).peekable();
+        if missing.peek().is_none() {
+            return;
+        }
+        let deck = template.deck(path);
+        info!(
+            "using deck {} derived from the path {} for notes without `\\deck`",
+            deck,
+            path.to_string_lossy()
+        );
+        for note in missing {
+            note.deck = deck.clone();
+        }
+    }
+
+    /// Chooses the model of notes without `\model` by their deck or their field names.
+    ///
+    /// A matching entry of `deck_models` wins. Otherwise a configured field set equal
+    /// to the fields of the note is used or the note must match exactly one configured
+    /// set containing all its fields.
+    ///
+    /// Returns how the model of each note was chosen, `None` for an explicit `\model`.
+    fn select_models(&self, notes: &mut [Note]) -> Result<Vec<Option<String>>> {
+        self.select_models_by(notes, |i| i)
+    }
+
+    /// Like [`Config::select_models`] for `notes` whose index in the file is
+    /// `index(i)` for the note `i` of `notes`.
+    fn select_models_by(
+        &self,
+        notes: &mut [Note],
+        index: impl Fn(usize) -> usize,
+    ) -> Result<Vec<Option<String>>> {
+        select_models(&self.deck_models, &self.model_selection, notes, index)
+    }
+
+    /// Prints, ignores or fails on the warnings as configured in `warnings` and `warnings_by_class`.
+    fn handle_parse_warnings(&self, warnings: &[parse_file::ParseWarning]) -> Result<()> {
+        handle_parse_warnings(self.warnings, &self.warnings_by_class, warnings)
+    }
+
+    /// Returns the entries of the directory `dir`.
+    ///
+    /// With `respect_gitignore`, entries ignored by `.gitignore` files are skipped.
+    fn children(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let note = || eyre!("while collecting children of {}", dir.to_string_lossy());
+        if !self.respect_gitignore {
+            return std::fs::read_dir(dir)
+                .with_note(note)?
+                .map(|entry| Ok(entry?.path()))
+                .collect();
+        }
+
+        ignore::WalkBuilder::new(dir)
+            .standard_filters(false)
+            .git_ignore(true)
+            .git_exclude(true)
+            .require_git(false)
+            .max_depth(Some(1))
+            .build()
+            // the first entry is `dir` itself
+            .skip(1)
+            .map(|entry| Ok(entry.with_note(note)?.into_path()))
+            .collect()
+    }
+
+    /// Whether the stored note has `keep_tag`, anki compares tags case insensitively
+    fn is_kept(&self, stored: &Note) -> bool {
+        self.keep_tag
+            .as_ref()
+            .is_some_and(|keep| stored.tags.iter().any(|tag| tag.eq_ignore_ascii_case(keep)))
+    }
+
+    /// Whether `path` should be skipped.
+    ///
+    /// Excludes (regexes and globs) take precedence over includes.
+    fn is_ignored(&self, path: &str) -> bool {
+        for RegexString { re, re_str } in &self.file_exclude {
+            if re.is_match(path) {
+                info!(
+                    "ignoring {} because it is excluded (regex={})",
+                    path, re_str
+                );
+                return true;
+            }
+        }
+        if let Some(glob) = self.file_exclude_globs.find_match(path) {
+            info!("ignoring {} because it is excluded (glob={})", path, glob);
+            return true;
+        }
+        if !self.file_include.iter().any(|r| r.re.is_match(path))
+            && self.file_include_globs.find_match(path).is_none()
+        {
+            info!(
+                "ignoring {} because it is not included (regex={}, glob={})",
+                path,
+                self.file_include
+                    .iter()
+                    .map(|r| format!("\"{}\"", r.re_str))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                self.file_include_globs
+                    .globs
+                    .iter()
+                    .map(|g| format!("\"{}\"", g))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return true;
+        }
+        false
+    }
+}
+
+/// Collects the decks used in `path` or, if it is a directory, its children.
+fn collect_used_decks(config: &Config, path: &Path, decks: &mut Vec<String>) -> Result<()> {
+    if config.is_ignored(&path.to_string_lossy()) {
+        return Ok(());
+    }
+    if path.is_dir() {
+        for child in config.children(path)? {
+            collect_used_decks(config, &child, decks)?;
+        }
+        return Ok(());
+    }
+
+    let content = read_to_string(path)
+        .with_note(|| eyre!("while reading file {}", path.to_string_lossy()))?;
+    debug!("parsing file {} for used decks", path.to_string_lossy());
+    decks.extend(parse_file::get_used_decks(content.clone(), &config.parse)?);
+    if config.deck_from_path.is_some() {
+        let mut notes = parse_file::get_content(content, &config.parse)?;
+        if notes.iter().any(|n| n.deck.is_empty()) {
+            config.select_decks(&mut notes, path);
+            decks.extend(notes.into_iter().map(|n| n.deck));
+        }
+    }
+    Ok(())
+}
+
+/// Creates all decks used in `paths` which don't exist yet, parents first.
+///
+/// Returns the decks which were actually created.
+fn create_all_decks(config: &Config, paths: &FilePaths) -> Result<Vec<String>> {
+    let mut used_decks = Vec::new();
+    collect_used_decks(config, &paths.main, &mut used_decks)?;
+
+    debug!("collecting available decks from anki");
+    let available_decks: HashSet<_> = get_deck_names()?.0.into_iter().collect();
+
+    let mut missing: Vec<String> = Vec::new();
+    for full in used_decks {
+        let mut prefix = String::new();
+
+        for part in full.split("::") {
+            if !prefix.is_empty() {
+                prefix.push_str("::");
+            }
+            prefix.push_str(part);
+            if !available_decks.contains(&prefix) && !missing.contains(&prefix) {
+                missing.push(prefix.clone());
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        info!("All decks were already created");
+        return Ok(Vec::new());
+    }
+
+    let names = missing.iter().map(String::as_str).collect::<Vec<_>>();
+    let results = api::create_decks_multi(&names)
+        .with_note(|| format!("while creating {} decks", missing.len()))?;
+
+    let mut created = Vec::new();
+    let mut failed = 0;
+    for (deck, result) in missing.into_iter().zip(results) {
+        match result {
+            Ok(Some(_)) => {
+                info!("created deck {}", deck);
+                created.push(deck);
+            }
+            Ok(None) => debug!("deck {} wasn't created", deck),
+            Err(e) => {
+                error!("couldn't create deck {}: {}", deck, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(eyre!("couldn't create {} decks", failed)).with_note(|| {
+            format!(
+                "{} other decks were created: {}",
+                created.len(),
+                created.join(", ")
+            )
+        });
+    }
+    Ok(created)
+}
+
+/// Compares `ankitex.sty` (`required`) with its template or checks the structure of
+/// `custom.sty`, see [`parse_file::StyReport`].
+fn check_sty_file(name: &str, path: &Path, template: &str, required: bool) -> report::Check {
+    use report::{Check, CheckStatus};
+
+    let content = match read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            return Check::new(
+                name,
+                CheckStatus::Fail,
+                format!("can't read {}: {}", path.to_string_lossy(), e),
+            )
+            .hint("create the template files with `anki-tex template`")
+        }
+    };
+    let path_name = path.to_string_lossy();
+    let sty = if required {
+        parse_file::check_ankitex_content(&path_name, &content, template)
+    } else {
+        parse_file::check_custom_content(&path_name, &content)
+    };
+    if sty.is_ok() {
+        let status = match content == template {
+            true => "matches template",
+            false => "customized",
+        };
+        return Check::new(name, CheckStatus::Pass, status);
+    }
+
+    let mut message = sty
+        .findings
+        .iter()
+        .filter(|finding| **finding != parse_file::StyFinding::Modified)
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if required {
+        message.insert(0, format!("{} differs from the template", path_name));
+    }
+    let hint = match required {
+        false => "fix custom.sty, anki's LaTeX fails with it",
+        true => "this file shouldn't be edited, regenerate it with `anki-tex template --force` in an empty directory",
+    };
+    Check::new(name, CheckStatus::Warn, message.join("; ")).hint(hint)
+}
+
+/// Runs a series of checks of the setup and prints the results.
+///
+/// Returns an error if any check failed.
+fn doctor(
+    config: std::result::Result<&Config, &color_eyre::Report>,
+    paths: &FilePaths,
+    output: OutputFormat,
+) -> Result<()> {
+    use report::{Check, CheckStatus};
+
+    let mut checks = Vec::new();
+
+    let config = match config {
+        Ok(config) => {
+            checks.push(Check::new("config", CheckStatus::Pass, "valid"));
+            Some(config)
+        }
+        Err(e) => {
+            checks.push(
+                Check::new("config", CheckStatus::Fail, format!("{:#}", e))
+                    .hint("fix the config file or move it away to use the defaults"),
+            );
+            None
+        }
+    };
+    if let Some(config) = config {
+        checks.push(match config.check_custom_commands(&paths.custom) {
+            Ok(()) => Check::new("custom commands", CheckStatus::Pass, "valid"),
+            Err(e) => Check::new("custom commands", CheckStatus::Fail, format!("{:#}", e))
+                .hint("align `custom_commands` of the config with the definitions in custom.sty"),
+        });
+        let templates = [
+            TemplateVariant::Minimal,
+            TemplateVariant::Standard,
+            TemplateVariant::Examples,
+        ]
+        .into_iter()
+        .try_for_each(|variant| TemplateFiles::validate(&config.parse, &config.ankitex, variant));
+        checks.push(match templates {
+            Ok(()) => Check::new("templates", CheckStatus::Pass, "valid"),
+            Err(e) => Check::new("templates", CheckStatus::Fail, format!("{:#}", e))
+                .hint("align `header` and `footer` of the config with the template files"),
+        });
+    }
+
+    if let Some(tls) = api::check_tls() {
+        checks.push(match tls {
+            Ok(()) => Check::new("tls", CheckStatus::Pass, "handshake succeeded"),
+            Err(e) => Check::new("tls", CheckStatus::Fail, format!("{:#}", e)).hint(
+                "check `tls_client_cert`, `tls_client_key` and `tls_ca_cert` of `anki_connect` in the config",
+            ),
+        });
+    }
+
+    let reachable = match api::ping() {
+        Ok(()) => {
+            checks.push(Check::new(
+                "anki",
+                CheckStatus::Pass,
+                format!("reachable at {}", api::url()),
+            ));
+            true
+        }
+        Err(e) => {
+            checks.push(
+                Check::new(
+                    "anki",
+                    CheckStatus::Fail,
+                    format!("can't reach {}: {}", api::url(), e),
+                )
+                .hint("start anki and make sure the AnkiConnect add-on is installed and listens on this address"),
+            );
+            false
+        }
+    };
+
+    let mut usable = reachable;
+    if reachable {
+        checks.push(match api::version() {
+            Ok(version) if version >= api::MIN_VERSION => Check::new(
+                "ankiconnect version",
+                CheckStatus::Pass,
+                version.to_string(),
+            ),
+            Ok(version) => {
+                usable = false;
+                Check::new(
+                    "ankiconnect version",
+                    CheckStatus::Fail,
+                    format!("{} is older than the required {}", version, api::MIN_VERSION),
+                )
+                .hint("update the AnkiConnect add-on")
+            }
+            Err(e) => {
+                usable = false;
+                Check::new("ankiconnect version", CheckStatus::Fail, format!("{:#}", e))
+                    .hint("make sure the AnkiConnect add-on is installed and not another program listens on this address")
+            }
+        });
+        checks.push(match api::request_permission() {
+            Ok(permission) if !permission.is_granted() => {
+                usable = false;
+                Check::new("permission", CheckStatus::Fail, "denied")
+                    .hint("allow access in the AnkiConnect popup or add the origin to `webCorsOriginList`")
+            }
+            Ok(permission) if permission.require_apikey => Check::new(
+                "permission",
+                CheckStatus::Warn,
+                "granted, but AnkiConnect requires an api key",
+            )
+            .hint("anki-tex doesn't send an api key, remove `apiKey` from the AnkiConnect config"),
+            Ok(_) => Check::new("permission", CheckStatus::Pass, "granted"),
+            Err(e) => {
+                usable = false;
+                Check::new("permission", CheckStatus::Fail, format!("{:#}", e))
+            }
+        });
+    } else {
+        for name in ["ankiconnect version", "permission"] {
+            checks.push(Check::new(name, CheckStatus::Skip, "anki is not reachable"));
+        }
+    }
+
+    checks.push(check_sty_file(
+        "ankitex.sty",
+        &paths.anki,
+        config.map_or(parse_file::ANKITEX, |c| &c.ankitex),
+        true,
+    ));
+    checks.push(check_sty_file(
+        "custom.sty",
+        &paths.custom,
+        parse_file::CUSTOM_TEMPLATE,
+        false,
+    ));
+
+    let main_content = match read_to_string(&paths.main) {
+        Ok(content) => {
+            checks.push(Check::new(
+                "main file",
+                CheckStatus::Pass,
+                format!("{} exists", paths.main.to_string_lossy()),
+            ));
+            Some(content)
+        }
+        Err(e) => {
+            checks.push(
+                Check::new(
+                    "main file",
+                    CheckStatus::Fail,
+                    format!("can't read {}: {}", paths.main.to_string_lossy(), e),
+                )
+                .hint("pass the file with `--path`, set `path` in the config or create it with `anki-tex template`"),
+            );
+            None
+        }
+    };
+
+    let notes = match (main_content, config) {
+        (Some(content), Some(config)) => match parse_file::parse_content(content, &config.parse) {
+            Ok(outcome) => {
+                let status = if outcome.warnings.is_empty() {
+                    CheckStatus::Pass
+                } else {
+                    CheckStatus::Warn
+                };
+                checks.push(Check::new(
+                    "parse",
+                    status,
+                    format!(
+                        "{} notes, {} warnings",
+                        outcome.notes.len(),
+                        outcome.warnings.len()
+                    ),
+                ));
+                Some(outcome.notes)
+            }
+            Err(e) => {
+                checks.push(Check::new("parse", CheckStatus::Fail, format!("{:#}", e)));
+                None
+            }
+        },
+        (None, _) => {
+            checks.push(Check::new(
+                "parse",
+                CheckStatus::Skip,
+                "main file is missing",
+            ));
+            None
+        }
+        (_, None) => {
+            checks.push(Check::new("parse", CheckStatus::Skip, "config is invalid"));
+            None
+        }
+    };
+
+    match notes {
+        Some(notes) if usable => {
+            let used_decks: BTreeSet<_> = notes.iter().map(|n| n.deck.as_str()).collect();
+            checks.push(match get_deck_names() {
+                Ok(available) => {
+                    let missing = used_decks
+                        .iter()
+                        .filter(|deck| !available.0.iter().any(|a| a == *deck))
+                        .copied()
+                        .collect::<Vec<_>>();
+                    if missing.is_empty() {
+                        Check::new(
+                            "decks",
+                            CheckStatus::Pass,
+                            format!("{} used, all exist", used_decks.len()),
+                        )
+                    } else {
+                        Check::new(
+                            "decks",
+                            CheckStatus::Fail,
+                            format!("missing {}", missing.join(", ")),
+                        )
+                        .hint("create them with `anki-tex create-all-decks`")
+                    }
+                }
+                Err(e) => Check::new("decks", CheckStatus::Fail, format!("{:#}", e)),
+            });
+
+            let used_models: BTreeSet<_> = notes.iter().map(|n| n.model.as_str()).collect();
+            checks.push(match get_model_names() {
+                Ok(available) => {
+                    let missing = used_models
+                        .iter()
+                        .filter(|model| !available.0.iter().any(|a| a == *model))
+                        .copied()
+                        .collect::<Vec<_>>();
+                    if missing.is_empty() {
+                        Check::new(
+                            "models",
+                            CheckStatus::Pass,
+                            format!("{} used, all exist", used_models.len()),
+                        )
+                    } else {
+                        Check::new(
+                            "models",
+                            CheckStatus::Fail,
+                            format!("missing {}", missing.join(", ")),
+                        )
+                        .hint("create them in anki or with `anki-tex models apply`")
+                    }
+                }
+                Err(e) => Check::new("models", CheckStatus::Fail, format!("{:#}", e)),
+            });
+
+            let mut lacking = Vec::new();
+            let mut selectors = BTreeSet::new();
+            let mut error = None;
+            for model in &used_models {
+                match get_model_styling(model) {
+                    Ok(styling) => {
+                        let missing = missing_model_css(&styling.css);
+                        if !missing.is_empty() {
+                            lacking.push(*model);
+                            selectors.extend(missing.into_iter().map(|(s, _)| s));
+                        }
+                    }
+                    // missing models are reported by the models check
+                    Err(e) => error = error.or(Some(format!("{}: {:#}", model, e))),
+                }
+            }
+            checks.push(if !lacking.is_empty() {
+                Check::new(
+                    "model styling",
+                    CheckStatus::Warn,
+                    format!(
+                        "styling of {} has no rules for {}",
+                        lacking.join(", "),
+                        selectors.into_iter().collect::<Vec<_>>().join(", ")
+                    ),
+                )
+                .hint("print the rules with `anki-tex check --online` or append them with `--fix-styling`")
+            } else if let Some(error) = error {
+                Check::new("model styling", CheckStatus::Skip, error)
+            } else {
+                Check::new(
+                    "model styling",
+                    CheckStatus::Pass,
+                    format!("{} used, all have the rules", used_models.len()),
+                )
+            });
+        }
+        Some(_) => {
+            for name in ["decks", "models", "model styling"] {
+                checks.push(Check::new(name, CheckStatus::Skip, "anki is not usable"));
+            }
+        }
+        None => {
+            for name in ["decks", "models", "model styling"] {
+                checks.push(Check::new(
+                    name,
+                    CheckStatus::Skip,
+                    "file could not be parsed",
+                ));
+            }
+        }
+    }
+
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&checks)?),
+        OutputFormat::Text => print!("{}", report::render_checks(&checks, report::use_color())),
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == CheckStatus::Fail)
+        .count();
+    if failed != 0 {
+        return Err(eyre!("{} of {} checks failed", failed, checks.len()));
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let args = Args::parse();
+
+    if !args.no_progress
+        && std::io::stderr().is_terminal()
+        && args.output == OutputFormat::Text
+        && args.log_level >= Level::INFO
+        && !matches!(
+            args.subcommand,
+            Commands::Browse { .. } | Commands::Create { quiet: true, .. }
+        )
+    {
+        let _ = PROGRESS.set(indicatif::MultiProgress::new());
+    }
+
+    let builder = FmtSubscriber::builder()
+        .with_max_level(args.log_level)
+        .with_writer(|| LogWriter);
+
+    if args.short_log {
+        let subscriber = builder.without_time().compact().finish();
+        tracing::subscriber::set_global_default(subscriber)?;
+    } else {
+        tracing::subscriber::set_global_default(builder.finish())?;
+    }
+
+    match &args.trace_api {
+        Some(file) => api::enable_tracing(file.as_deref())?,
+        None if std::env::var_os("ANKI_TEX_TRACE").is_some_and(|v| v == "1") => {
+            api::enable_tracing(None)?
+        }
+        None => {}
+    }
+
+    let config = Config::load(
+        args.add_generated,
+        args.generation_date,
+        args.prefer_last,
+        args.replace_all_fields,
+        args.order,
+        args.seed,
+        args.yes,
+    );
+
+    let main_path = main_path(
+        args.subcommand.file(),
+        args.path.as_deref(),
+        config.as_ref().ok().and_then(|c| c.path.as_deref()),
+    );
+    let paths = FilePaths::from_main(main_path)?;
+    // doctor reports an invalid config instead of failing
+    if let Commands::Doctor { .. } = args.subcommand {
+        return doctor(config.as_ref(), &paths, args.output);
+    }
+    let config = config?;
+    config.check_custom_commands(&paths.custom)?;
+    report::set_collation(config.collation);
+
+    // the subcommands only use these fields
+    let Args {
+        subcommand,
+        output,
+        no_backup,
+        yes,
+        profile,
+        ignore_profile_check,
+        timings,
+        log_level,
+        ..
+    } = args;
+    let check_profile = || check_profile(&config, profile.as_deref(), ignore_profile_check);
+
+    match subcommand {
+        Commands::Template {
+            force,
+            variant,
+            dry_run,
+            create_dirs,
+            allow_outside_root,
+            ..
+        } => {
+            let options = TemplateOptions {
+                force,
+                variant,
+                dry_run,
+                create_dirs,
+                allow_outside_root,
+            };
+            create_template(&config, &paths, &options)?
+        }
+        Commands::Watch {
+            poll,
+            on_change_cmd,
+            changed_only,
+            ..
+        } => {
+            let poll = poll.or(config.watch_poll_secs).filter(|secs| *secs > 0);
+            if on_change_cmd.as_ref().is_some_and(Vec::is_empty) {
+                return Err(eyre!("`--on-change-cmd` must contain at least the command"));
+            }
+            let on_change_cmd = on_change_cmd.or_else(|| config.on_change_cmd.clone());
+            let profile = check_profile()?;
+            watch(
+                &config,
+                &paths,
+                output,
+                poll.map(Duration::from_secs),
+                on_change_cmd.as_deref(),
+                changed_only.unwrap_or(true),
+                profile,
+            )?
+        }
+        Commands::Create {
+            quiet,
+            changed_only,
+            only_deck,
+            skip_deck,
+            enforce_budgets,
+            ..
+        } => {
+            let deck_filter = config.deck_filter.with_overrides(only_deck, skip_deck)?;
+            let hash = files_hash(&config, &paths)?;
+            let main = std::path::absolute(&paths.main)?
+                .to_string_lossy()
+                .into_owned();
+            if changed_only
+                && CachedState::load(&config.cache_file).file_hashes.get(&main) == Some(&hash)
+            {
+                debug!("{} didn't change since the last run", main);
+                if !quiet {
+                    println!("{} didn't change since the last run, nothing to do", main);
+                }
+                return Ok(());
+            }
+            let profile = check_profile()?;
+            let mut state = State::new(&config)?;
+            state.profile = profile;
+            state.changed_only = changed_only;
+            state.deck_filter = deck_filter;
+            state.enforce_budgets = enforce_budgets;
+            let summary = update_change(&mut state, &config, &paths, false)?;
+            if !quiet || summary.has_changes() {
+                print_summary(&summary, output)?;
+            } else {
+                warn_unmatched_decks(&summary);
+            }
+            // failed and left out notes are processed by the next run
+            if !summary.has_errors() && summary.filtered.is_empty() {
+                let custom_sty_hash = std::fs::read(&paths.custom)
+                    .ok()
+                    .map(|content| format!("{:016x}", fasthash::metro::hash64(content)));
+                // loaded again, the run saves the parsed notes in the cache
+                CachedState::update(&config.cache_file, |cache| {
+                    cache.file_hashes.insert(main, hash);
+                    cache.custom_sty_hash = custom_sty_hash;
+                })?;
+            }
+        }
+        Commands::Diff { .. } => {
+            let mut state = State::new(&config)?;
+            let summary = update_change(&mut state, &config, &paths, true)?;
+            print_summary(&summary, output)?;
+        }
+        Commands::Show { index, grep, .. } => show_notes(&config, &paths, index, grep.as_deref())?,
+        Commands::Browse { .. } => {
+            check_profile()?;
+            browse(&config, &paths)?
+        }
+        Commands::GetDecks => {
+            let mut names = get_deck_names()?.0;
+            names.sort_by(|a, b| report::collate(a, b));
+            println!("All deck names: \n {}", names.join("\n "))
+        }
+        Commands::GetModels {
+            fields,
+            model,
+            used,
+            offline,
+            ..
+        } => {
+            let options = ModelsOptions {
+                fields,
+                model,
+                used,
+                offline,
+            };
+            get_models(&config, &paths, &options, output)?
+        }
+        Commands::GetNotes {
+            query,
+            verbose,
+            stream,
+            format: NotesFormat::Markdown,
+            out_dir,
+            anki_links,
+            no_normalize,
+            group_by,
+        } => {
+            if stream || verbose || group_by.is_some() {
+                return Err(eyre!(
+                    "`--stream`, `--verbose` and `--group-by` can't be used with `--format markdown`"
+                ));
+            }
+            let notes = get_notes(&query, CardDetails::Decks)?;
+            export_markdown(
+                &notes,
+                &MarkdownOptions {
+                    out_dir,
+                    anki_links,
+                    normalize: !no_normalize,
+                },
+            )?;
+        }
+        Commands::GetNotes {
+            query,
+            verbose,
+            stream,
+            out_dir,
+            anki_links,
+            no_normalize,
+            group_by,
+            ..
+        } => {
+            if out_dir.is_some() || anki_links {
+                return Err(eyre!(
+                    "`--out-dir` and `--anki-links` need `--format markdown`"
+                ));
+            }
+            let details = if verbose {
+                CardDetails::Full
+            } else {
+                CardDetails::Decks
+            };
+            let notes_len = if stream {
+                let ids = find_notes(&query)?;
+                info!("getting {} notes in chunks of {}", ids.len(), INFO_CHUNK);
+                let bar = progress_bar(ids.len(), "streaming notes");
+                for chunk in ids.chunks(INFO_CHUNK) {
+                    let mut notes = get_notes_by_ids(chunk, details)?;
+                    add_mod_times(&mut notes)?;
+                    // the bars are hidden while printing, so the notes aren't mixed
+                    // with them
+                    bar.suspend(|| {
+                        for note in &notes {
+                            print_stored_note(note, verbose, !no_normalize);
+                        }
+                    });
+                    bar.inc(chunk.len() as u64);
+                }
+                bar.finish_and_clear();
+                ids.len()
+            } else {
+                let mut notes = get_notes(&query, details)?;
+                add_mod_times(&mut notes)?;
+                match group_by {
+                    Some(group_by) => print_grouped_notes(&notes, group_by, verbose, !no_normalize),
+                    None => {
+                        for note in &notes {
+                            print_stored_note(note, verbose, !no_normalize);
+                        }
+                    }
+                }
+                notes.len()
+            };
+
+            println!("fetched {} notes in total", notes_len);
+        }
+        Commands::Render { verify, query } => {
+            let start = std::time::Instant::now();
+            match render_latex()? {
+                Some(true) => println!("Success, rendered in {:.1?}", start.elapsed()),
+                Some(false) => println!("Error :("),
+                None => {
+                    return Err(eyre!(RENDER_ADDON_MISSING)
+                        .suggestion("install the add-on providing `renderAllLatex` in anki"))
+                }
+            }
+            if verify {
+                let query =
+                    query.unwrap_or_else(|| format!("tag:{} edited:1", config.generated_tag));
+                let (checked, missing) = verify_render(&query)?;
+                report_missing_renders(checked, &missing)?;
+            }
+        }
+        Commands::CreateAllDecks { .. } => {
+            let created = create_all_decks(&config, &paths)?;
+            if !created.is_empty() {
+                info!("created {} decks", created.len());
+            }
+        }
+        Commands::Sync => {
+            sync_throttled(&config, true)?;
+            println!("Success");
+        }
+        Commands::Crs {
+            require_render,
+            force_sync,
+            ..
+        } => {
+            // TODO remove duplication
+            let profile = check_profile()?;
+            let mut state = State::new(&config)?;
+            state.profile = profile;
+            let mut summary = update_change(&mut state, &config, &paths, false)?;
+            let render = match render_latex()? {
+                Some(true) => "done",
+                Some(false) => "failed",
+                None if require_render => {
+                    print_summary(&summary, output)?;
+                    return Err(eyre!(RENDER_ADDON_MISSING)
+                        .suggestion("install the add-on or run without `--require-render`"));
+                }
+                None => {
+                    warn!("{}, continuing without rendering", RENDER_ADDON_MISSING);
+                    "skipped (add-on missing)"
+                }
+            };
+            summary.steps.push(("render".into(), render.into()));
+            let synced = sync_throttled(&config, force_sync);
+            let status = match &synced {
+                Ok(status) => status.clone(),
+                Err(_) => String::from("failed"),
+            };
+            summary.steps.push(("sync".into(), status));
+            print_summary(&summary, output)?;
+            synced?;
+        }
+        Commands::Undo { run } => undo(&config, run.as_deref(), yes)?,
+        Commands::Restore { backup } => restore(&config, &backup, yes)?,
+        Commands::Focus { query, undo } => match query {
+            Some(query) if !undo => focus(&config, &query, yes)?,
+            _ => unfocus(&config)?,
+        },
+        Commands::Cache { command } => match command {
+            CacheCommand::Show => show_cache(&config, output)?,
+            CacheCommand::Clear => clear_cache(&config)?,
+        },
+        Commands::Cleanup { empty_notes } => {
+            if !empty_notes {
+                return Err(eyre!("nothing to clean up").suggestion("use `--empty-notes`"));
+            }
+            cleanup_empty_notes(&config)?;
+        }
+        Commands::MigrateFormat {
+            model,
+            from,
+            to,
+            query,
+            dry_run,
+        } => migrate_format(&config, &model, from, to, query.as_deref(), dry_run)?,
+        Commands::MigrateDeckPrefix { old, new, dry_run } => {
+            migrate_deck_prefix(&config, &old, &new, dry_run)?
+        }
+        Commands::Models { command } => match command {
+            ModelsCommand::Export { out, .. } => export_models(&config, &paths, &out)?,
+            ModelsCommand::Apply { path } => apply_models(&path)?,
+        },
+        Commands::Report {
+            out,
+            deck_prefix,
+            open,
+        } => write_report(&out, deck_prefix.as_deref(), open)?,
+        Commands::Progress { query, group_by } => {
+            progress(&config, query.as_deref(), group_by, output)?
+        }
+        Commands::Recent { deck, since } => recent(&config, &deck, since, output)?,
+        Commands::Roundtrip { .. } => roundtrip(&config, &paths)?,
+        Commands::Check {
+            fix,
+            compile,
+            online,
+            fix_styling,
+            macros,
+            strict,
+            ..
+        } => match output {
+            OutputFormat::Text => {
+                check_file(&config, &paths, fix, compile, !no_backup)?;
+                if online {
+                    let mut models = BTreeSet::new();
+                    used_models(&config, &paths, &mut models)?;
+                    check_model_styling(&models, fix_styling)?;
+                }
+                if macros {
+                    let mut unknown = Vec::new();
+                    check_macros(&config, &paths, strict, &mut unknown)?;
+                    for diagnostic in &unknown {
+                        warn!(
+                            "{}: note #{}: {}",
+                            diagnostic.file.as_deref().unwrap_or_default(),
+                            diagnostic.note_index.unwrap_or_default() + 1,
+                            diagnostic.message
+                        );
+                    }
+                    let hint = "define the commands in custom.sty or add them to `known_macros` in the config";
+                    if strict && !unknown.is_empty() {
+                        return Err(eyre!("found {} unknown commands in fields", unknown.len())
+                            .suggestion(hint));
+                    } else if !unknown.is_empty() {
+                        info!("{}", hint);
+                    }
+                }
+            }
+            OutputFormat::Json => {
+                if fix || fix_styling {
+                    return Err(eyre!(
+                        "`--fix` and `--fix-styling` can't be used with `--output json`"
+                    ));
+                }
+                let mut diagnostics = Vec::new();
+                check_diagnostics(&config, &paths, compile, &mut diagnostics)?;
+                if macros {
+                    check_macros(&config, &paths, strict, &mut diagnostics)?;
+                }
+                if online {
+                    let mut models = BTreeSet::new();
+                    used_models(&config, &paths, &mut models)?;
+                    for model in &models {
+                        let missing = missing_model_css(&get_model_styling(model)?.css);
+                        if missing.is_empty() {
+                            continue;
+                        }
+                        let selectors = missing.iter().map(|(s, _)| *s).collect::<Vec<_>>();
+                        diagnostics.push(report::Diagnostic::new(
+                            report::Severity::Warning,
+                            report::Code::MissingModelStyling,
+                            format!(
+                                "the styling of model {} has no rules for {}, add:\n{}",
+                                model,
+                                selectors.join(", "),
+                                model_css_snippet(&missing)
+                            ),
+                        ));
+                    }
+                }
+                println!("{}", serde_json::to_string_pretty(&diagnostics)?);
+                let errors = diagnostics
+                    .iter()
+                    .filter(|d| d.severity == report::Severity::Error)
+                    .count();
+                if errors != 0 {
+                    return Err(eyre!("found {} errors", errors));
+                }
+            }
+        },
+        Commands::Fields { command } => match command {
+            FieldsCommand::Rename {
+                model,
+                old,
+                new,
+                rewrite,
+                ..
+            } => rename_field(&config, &paths, &model, &old, &new, rewrite, !no_backup)?,
+        },
+        Commands::Doctor { .. } => unreachable!("doctor is handled before the config is validated"),
+    }
+
+    if timings || log_level >= Level::DEBUG {
+        print_timings(output);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch_filter() -> WatchFilter {
+        WatchFilter {
+            extensions: vec![String::from("tex")],
+            ignore_dirs: GlobList::new(DEFAULT_WATCH_IGNORE_DIRS.map(String::from).to_vec())
+                .unwrap(),
+        }
+    }
+
+    fn modify_event(path: &Path) -> notify::Result<Event> {
+        Ok(
+            Event::new(EventKind::Modify(notify::event::ModifyKind::Data(
+                notify::event::DataChange::Content,
+            )))
+            .add_path(path.to_owned()),
+        )
+    }
+
+    #[test]
+    fn api_note_roundtrip() {
+        let notes = parse_file::parse_content(
+            format!(
+                "{}\\deck{{A::B}}\\model{{Basic}}\n\
+                 \\fields{{Front}}{{$x$ and {{braces}}}}\\fields{{Back}}{{b}}\\tag{{t}}\\next\n{}",
+                parse_file::HEADER,
+                parse_file::FOOTER
+            ),
+            &parse_file::ParseOptions::default(),
+        )
+        .unwrap()
+        .notes;
+        let note = &notes[0];
+        for format in [FieldFormat::Latex, FieldFormat::Mathjax, FieldFormat::Raw] {
+            let mut sent = note.clone();
+            for field in sent.fields.values_mut() {
+                *field = format.wrap(field);
+            }
+            let request = serde_json::to_value(to_api_note(&sent)).unwrap();
+            let received: anki_tex::api::Note = serde_json::from_value(request).unwrap();
+            assert_eq!(received.deck_name, note.deck);
+            assert_eq!(received.model_name, note.model);
+            assert_eq!(received.tags, note.tags);
+            for (name, value) in &received.fields {
+                assert_eq!(unfmt_content(format, value), note.fields[name]);
+            }
+        }
+    }
+
+    fn parse_notes(body: &str) -> Vec<Note> {
+        parse_file::parse_content(
+            format!("{}{}\n{}", parse_file::HEADER, body, parse_file::FOOTER),
+            &parse_file::ParseOptions {
+                allow_missing_model: true,
+                ..parse_file::ParseOptions::default()
+            },
+        )
+        .unwrap()
+        .notes
+    }
+
+    fn model_selection() -> Vec<ModelSelection> {
+        [
+            (&["Front", "Back"][..], "Basic"),
+            (&["Front", "Back", "Example"], "Vocab+Example"),
+            (&["Front", "Back", "Audio"], "Vocab+Audio"),
+        ]
+        .into_iter()
+        .map(|(fields, model)| ModelSelection {
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            model: model.to_owned(),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn model_selection_by_fields() {
+        let mut notes = parse_notes(
+            "\\deck{A}\n\
+             \\front{der Hund}\\back{the dog}\\next\n\
+             \\front{die Katze}\\back{the cat}\\example{Die Katze schläft.}\\next\n\
+             \\model{Cloze}\\fields{Text}{{{c1::x}}}\\next",
+        );
+        let deck_models = DeckModels::new(BTreeMap::new()).unwrap();
+        let sources = select_models(&deck_models, &model_selection(), &mut notes, |i| i).unwrap();
+        let models = notes.iter().map(|n| n.model.as_str()).collect::<Vec<_>>();
+        assert_eq!(models, ["Basic", "Vocab+Example", "Cloze"]);
+        assert_eq!(sources[0].as_deref(), Some("selected by fields"));
+        assert_eq!(sources[2], None);
+
+        // a deck mapping wins
+        let mut notes = parse_notes("\\deck{Vocab}\\front{a}\\back{b}\\next");
+        let deck_models = DeckModels::new(BTreeMap::from([(
+            String::from("Vocab"),
+            String::from("Mine"),
+        )]))
+        .unwrap();
+        select_models(&deck_models, &model_selection(), &mut notes, |i| i).unwrap();
+        assert_eq!(notes[0].model, "Mine");
+    }
+
+    #[test]
+    fn model_selection_errors() {
+        let deck_models = DeckModels::new(BTreeMap::new()).unwrap();
+
+        // `Front` alone is contained in all sets
+        let mut notes = parse_notes("\\deck{A}\\front{a}\\next");
+        let err =
+            select_models(&deck_models, &model_selection(), &mut notes, |i| i + 4).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("multiple models match the fields Front of note #5"));
+
+        let mut notes = parse_notes("\\deck{A}\\front{a}\\fields{Extra}{b}\\next");
+        let err = select_models(&deck_models, &model_selection(), &mut notes, |i| i).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("no model matches the fields Extra, Front"));
+
+        let mut notes = parse_notes("\\deck{A}\\front{a}\\back{b}\\next");
+        let err = select_models(&deck_models, &[], &mut notes, |i| i).unwrap_err();
+        assert!(err.to_string().contains("no entry of `deck_models`"));
+    }
+
+    fn deck_models(entries: &[(&str, &str)]) -> DeckModels {
+        DeckModels::new(
+            entries
+                .iter()
+                .map(|(pattern, model)| (pattern.to_string(), model.to_string()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn deck_models_literal_pattern_matches_subdecks() {
+        let models = deck_models(&[("Vocab", "Vocab")]);
+        assert_eq!(models.find("Vocab"), Some(("Vocab", "Vocab")));
+        assert_eq!(
+            models.find("Vocab::French::Verbs"),
+            Some(("Vocab", "Vocab"))
+        );
+        assert_eq!(models.find("Vocabulary"), None);
+        assert_eq!(models.find("Uni::Vocab"), None);
+    }
+
+    #[test]
+    fn deck_models_overlapping_patterns() {
+        let models = deck_models(&[
+            ("Uni::**", "Basic LaTeX"),
+            ("Uni::Math::*", "Math"),
+            ("Uni::Math::Analysis", "Analysis"),
+            ("Uni*", "Uni"),
+            ("*::Exercises", "Exercise"),
+        ]);
+        // the longest literal prefix wins
+        assert_eq!(
+            models.find("Uni::Physics"),
+            Some(("Uni::**", "Basic LaTeX"))
+        );
+        assert_eq!(
+            models.find("Uni::Math::Algebra"),
+            Some(("Uni::Math::*", "Math"))
+        );
+        assert_eq!(
+            models.find("Uni::Math::Analysis::Exercises"),
+            Some(("Uni::Math::Analysis", "Analysis"))
+        );
+        assert_eq!(models.find("University"), Some(("Uni*", "Uni")));
+        assert_eq!(
+            models.find("Other::Exercises"),
+            Some(("*::Exercises", "Exercise"))
+        );
+        assert_eq!(models.find("Other"), None);
+
+        // with the same literal prefix the longer pattern wins, then the first one
+        let models = deck_models(&[("A::*", "short"), ("A::*::B", "long")]);
+        assert_eq!(models.find("A::x::B"), Some(("A::*::B", "long")));
+        let models = deck_models(&[("A::?", "question"), ("A::*", "star")]);
+        assert_eq!(models.find("A::x"), Some(("A::*", "star")));
+    }
+
+    #[test]
+    fn deck_models_precedence() {
+        let models = deck_models(&[("Vocab", "Vocab")]);
+        let mut notes = parse_notes(
+            "\\deck{Vocab}\\model{Basic}\\front{a}\\back{b}\\next\n\
+             \\model{}\\front{c}\\back{d}\\next\n\
+             \\deck{Other}\\front{e}\\back{f}\\next",
+        );
+        let sources = select_models(&models, &model_selection(), &mut notes, |i| i).unwrap();
+        let models = notes.iter().map(|n| n.model.as_str()).collect::<Vec<_>>();
+        assert_eq!(models, ["Basic", "Vocab", "Basic"]);
+        assert_eq!(
+            sources,
+            [
+                None,
+                Some(String::from("deck mapping `Vocab`")),
+                Some(String::from("selected by fields")),
+            ]
+        );
+    }
+
+    #[test]
+    fn deck_from_nested_path() {
+        let template = DeckTemplate::new(String::from("Uni::Analysis::{stem_title}")).unwrap();
+        assert_eq!(
+            template.deck(Path::new("notes/ch03-integration.tex")),
+            "Uni::Analysis::Ch03 Integration"
+        );
+        let template = DeckTemplate::new(String::from("{dir}::{stem}")).unwrap();
+        assert_eq!(
+            template.deck(Path::new("/home/u/uni/analysis/ch03_integration.tex")),
+            "analysis::ch03_integration"
+        );
+        assert_eq!(template.deck(Path::new("top.tex")), "::top");
+    }
+
+    #[test]
+    fn deck_from_unicode_path() {
+        let template = DeckTemplate::new(String::from("{dir}::{stem_title}")).unwrap();
+        assert_eq!(
+            template.deck(Path::new("Übungen/ökonomie_grundlagen-éte.tex")),
+            "Übungen::Ökonomie Grundlagen Éte"
+        );
+        assert_eq!(title_case("日本語-テスト"), "日本語 テスト");
+    }
+
+    #[test]
+    fn deck_template_errors() {
+        assert!(DeckTemplate::new(String::from("Uni::{stem")).is_err());
+        let err = DeckTemplate::new(String::from("Uni::{name}")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown placeholder `{name}` in `deck_from_path`"
+        );
+        assert!(DeckTemplate::new(String::from("Plain")).is_ok());
+    }
+
+    #[test]
+    fn latex_images_of_anki_versions() {
+        // older versions
+        assert_eq!(
+            latex_images(r#"<div><img class=latex src="latex-1a2b.png"></div>"#),
+            ["latex-1a2b.png"]
+        );
+        // newer versions with more classes and svg files
+        assert_eq!(
+            latex_images(
+                r#"<img class="latex latex-display" src='latex-3c4d.svg' alt="x">
+                   <img src=latex-5e6f.png class=latex/>"#
+            ),
+            ["latex-3c4d.svg", "latex-5e6f.png"]
+        );
+        // only the file name
+        assert_eq!(
+            latex_images(r#"<img src="latex-7a8b.png">"#),
+            ["latex-7a8b.png"]
+        );
+    }
+
+    #[test]
+    fn latex_images_ignores_other_images() {
+        assert!(latex_images(r#"<img src="photo.jpg"> latex-1.png <img>"#).is_empty());
+        assert!(latex_images(r#"<img class="mathlatex" src="x.png">"#).is_empty());
+        assert_eq!(
+            latex_images(r#"<img data-src="lazy.png" src="latex-9.png"><img src="a.png">"#),
+            ["latex-9.png"]
+        );
+        assert!(latex_images(r#"<img data-class="latex" src="a.png">"#).is_empty());
+        // an unclosed tag at the end
+        assert_eq!(latex_images(r#"<img src="latex-1.png""#), ["latex-1.png"]);
+    }
+
+    #[test]
+    fn missing_fields_of_new_notes_are_empty() {
+        let mut note = Note::new("A", "Basic", &[("Front", "a")], &[]);
+        let names = ["Front", "Back", "Extra"].map(String::from);
+        complete_fields(&mut note, &names);
+        assert_eq!(note.fields["Front"], "a");
+        assert_eq!(note.fields["Back"], "");
+        assert_eq!(note.fields["Extra"], "");
+        assert_eq!(note.fields.len(), 3);
+    }
+
+    #[test]
+    fn missing_fields_are_kept_on_update() {
+        let matching = MatchOptions::default();
+        let mut stored = Note::new(
+            "A",
+            "Basic",
+            &[("Front", "a"), ("Back", "b"), ("Extra", "manual")],
+            &[],
+        );
+        stored.id = Some(1);
+        let note = Note::new("A", "Basic", &[("Front", "a"), ("Back", "changed")], &[]);
+
+        let diff = note_diff(&stored, &note, false, &matching);
+        assert_eq!(diff.fields, ["Back"]);
+        assert!(diff.cleared.is_empty());
+        assert_eq!(
+            updated_fields(&note, &diff),
+            HashMap::from([(String::from("Back"), String::from("changed"))])
+        );
+
+        // only the missing field differs
+        let note = Note::new("A", "Basic", &[("Front", "a"), ("Back", "b")], &[]);
+        assert!(note_diff(&stored, &note, false, &matching).is_empty());
+    }
+
+    #[test]
+    fn missing_fields_are_cleared_when_replacing_all() {
+        let matching = MatchOptions {
+            hash_field: Some(String::from("Hash")),
+            ..MatchOptions::default()
+        };
+        let stored = Note::new(
+            "A",
+            "Basic",
+            &[
+                ("Front", "a"),
+                ("Back", "b"),
+                ("Extra", "manual"),
+                ("Hash", "1"),
+            ],
+            &[],
+        );
+        let note = Note::new("A", "Basic", &[("Front", "a"), ("Back", "changed")], &[]);
+
+        let diff = note_diff(&stored, &note, true, &matching);
+        assert_eq!(diff.fields, ["Back"]);
+        // the hash field isn't cleared
+        assert_eq!(diff.cleared, ["Extra"]);
+        assert_eq!(
+            updated_fields(&note, &diff),
+            HashMap::from([
+                (String::from("Back"), String::from("changed")),
+                (String::from("Extra"), String::new()),
+            ])
+        );
+
+        let note = Note::new("A", "Basic", &[("Front", "a"), ("Back", "b")], &[]);
+        let diff = note_diff(&stored, &note, true, &matching);
+        assert!(diff.fields.is_empty());
+        assert_eq!(diff.cleared, ["Extra"]);
+        assert!(!diff.is_empty());
+    }
+
+    type Respond = dyn Fn(&str, &serde_json::Value) -> Vec<u8> + Send + Sync;
+
+    /// Transport answering requests with the body returned by `respond` and
+    /// recording their actions
+    struct MockAnki {
+        respond: Box<Respond>,
+        actions: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl api::Transport for MockAnki {
+        fn post(&self, body: &[u8]) -> std::result::Result<api::RawResponse, api::TransportError> {
+            let request = serde_json::from_slice::<serde_json::Value>(body).unwrap();
+            let action = request["action"].as_str().unwrap();
+            self.actions.lock().unwrap().push(action.to_owned());
+            Ok(api::RawResponse {
+                status: reqwest::StatusCode::OK,
+                content_type: Some(String::from("application/json")),
+                location: None,
+                bytes: (self.respond)(action, &request["params"]),
+            })
+        }
+
+        fn get(&self) -> Result<reqwest::StatusCode> {
+            Ok(reqwest::StatusCode::OK)
+        }
+    }
+
+    /// Runs `f` with a client whose requests are answered by `respond` and returns
+    /// its result and the sent actions.
+    fn with_mock_anki<R>(
+        respond: impl Fn(&str, &serde_json::Value) -> Vec<u8> + Send + Sync + 'static,
+        f: impl FnOnce() -> R,
+    ) -> (R, Vec<String>) {
+        let actions = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let transport = MockAnki {
+            respond: Box::new(respond),
+            actions: actions.clone(),
+        };
+        let client = api::Client::with_transport(api::ClientConfig::default(), transport);
+        let result = api::with_client(client, f);
+        let actions = actions.lock().unwrap().clone();
+        (result, actions)
+    }
+
+    /// Body of a successful response with `result`
+    fn ok(result: serde_json::Value) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({ "result": result, "error": null })).unwrap()
+    }
+
+    fn note_info(id: usize, cards: &[usize]) -> serde_json::Value {
+        serde_json::json!({
+            "noteId": id,
+            "modelName": "Basic",
+            "fields": {
+                "Front": { "value": "a", "order": 0 },
+                "Back": { "value": "b", "order": 1 },
+            },
+            "tags": [],
+            "cards": cards,
+        })
+    }
+
+    /// Responses of a collection with the note 1 with the cards 10 and 11, with the
+    /// response of `action` replaced by `body`
+    fn anki_with_malformed(
+        action: &'static str,
+        body: Vec<u8>,
+    ) -> impl Fn(&str, &serde_json::Value) -> Vec<u8> + Send + Sync + 'static {
+        move |requested, _| {
+            if requested == action {
+                return body.clone();
+            }
+            match requested {
+                "notesInfo" => ok(serde_json::json!([note_info(1, &[10, 11])])),
+                "notesModTime" => ok(serde_json::json!([{ "noteId": 1, "mod": 100 }])),
+                "getDecks" => ok(serde_json::json!({ "A": [10], "B": [11] })),
+                other => panic!("unexpected action {}", other),
+            }
+        }
+    }
+
+    /// [`get_notes_by_ids`] with [`add_mod_times`], like `get-notes`
+    fn get_printed_notes(ids: &[usize]) -> Result<Vec<Note>> {
+        let mut notes = get_notes_by_ids(ids, CardDetails::Decks)?;
+        add_mod_times(&mut notes)?;
+        Ok(notes)
+    }
+
+    #[test]
+    fn fetching_well_formed_notes() {
+        let (notes, actions) = with_mock_anki(anki_with_malformed("none", Vec::new()), || {
+            get_notes_by_ids(&[1], CardDetails::Decks)
+        });
+        let notes = notes.unwrap();
+        assert_eq!(notes[0].id, Some(1));
+        assert_eq!(notes[0].card_decks, ["A", "B"]);
+        assert_eq!(notes[0].mod_time, None);
+        assert_eq!(actions, ["notesInfo", "getDecks"]);
+
+        let (notes, actions) = with_mock_anki(anki_with_malformed("none", Vec::new()), || {
+            get_printed_notes(&[1])
+        });
+        assert_eq!(notes.unwrap()[0].mod_time, Some(100));
+        assert_eq!(actions, ["notesInfo", "getDecks", "notesModTime"]);
+    }
+
+    #[test]
+    fn questions_are_only_fetched_when_resolved() {
+        let fields = HashMap::from([(String::from("Front"), String::from("a"))]);
+        let error = |_: &str, _: &serde_json::Value| {
+            serde_json::to_vec(&serde_json::json!({ "result": null, "error": "busy" })).unwrap()
+        };
+        let (texts, actions) = with_mock_anki(error, || {
+            let question = Note::question_or_fields(Some(461_002), &fields);
+            let unresolved = format!("{:?}", question);
+            (unresolved, format!("{:?}", question.resolve()))
+        });
+        assert_eq!(texts.0, r#"{"Front": "a"}"#);
+        assert_eq!(texts.1, texts.0);
+        assert_eq!(actions, ["cardsInfo"]);
+    }
+
+    #[test]
+    fn missing_card_info_is_an_error() {
+        let (result, actions) = with_mock_anki(
+            anki_with_malformed("getDecks", ok(serde_json::json!({ "A": [10] }))),
+            || get_notes_by_ids(&[1], CardDetails::Decks),
+        );
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "anki returned no info of card 11 of note 1"
+        );
+        assert_eq!(actions, ["notesInfo", "getDecks"]);
+    }
+
+    #[test]
+    fn malformed_responses_are_errors() {
+        let malformed = [
+            ("notesInfo", ok(serde_json::json!("not a list"))),
+            ("notesInfo", ok(serde_json::json!([{ "noteId": 1 }]))),
+            ("notesInfo", ok(serde_json::json!([null]))),
+            ("notesModTime", ok(serde_json::json!({ "noteId": 1 }))),
+            ("getDecks", ok(serde_json::json!([10, 11]))),
+            ("getDecks", b"{\"result\": {\"A\": [10".to_vec()),
+            ("notesInfo", b"<html>proxy error</html>".to_vec()),
+            ("notesInfo", b"{}".to_vec()),
+            (
+                "notesInfo",
+                serde_json::to_vec(&serde_json::json!({ "result": null, "error": "boom" }))
+                    .unwrap(),
+            ),
+        ];
+        for (action, body) in malformed {
+            let text = String::from_utf8_lossy(&body).into_owned();
+            let (result, _) = with_mock_anki(anki_with_malformed(action, body), || {
+                get_printed_notes(&[1])
+            });
+            assert!(result.is_err(), "{} answered with {}", action, text);
+        }
+    }
+
+    #[test]
+    fn malformed_deck_creation_is_an_error() {
+        let result = |body: serde_json::Value| -> serde_json::Value {
+            serde_json::json!({ "result": body, "error": null })
+        };
+        let responses = [
+            // one result for two decks
+            ok(serde_json::json!([result(serde_json::json!(1))])),
+            ok(serde_json::json!([1, 2, 3])),
+            ok(serde_json::json!({ "A": 1 })),
+            ok(serde_json::json!(null)),
+        ];
+        for body in responses {
+            let text = String::from_utf8_lossy(&body).into_owned();
+            let (created, actions) = with_mock_anki(
+                move |_, _| body.clone(),
+                || api::create_decks_multi(&["A", "B"]),
+            );
+            assert!(created.is_err(), "created decks with response {}", text);
+            assert_eq!(actions, ["multi"]);
+        }
+
+        let (created, _) = with_mock_anki(
+            move |_, _| {
+                ok(serde_json::json!([
+                    result(serde_json::json!(1)),
+                    { "result": null, "error": "no" }
+                ]))
+            },
+            || api::create_decks_multi(&["A", "B"]),
+        );
+        let created = created.unwrap();
+        assert_eq!(*created[0].as_ref().unwrap(), Some(1));
+        assert!(created[1].is_err());
+    }
+
+    #[test]
+    fn config_locations_from_env() {
+        let dir = std::env::temp_dir().join(format!("anki-tex-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("sync.toml");
+        std::fs::write(&config, "").unwrap();
+
+        // works without a home directory
+        let (config_path, cache_dir) = config_locations(
+            Some(config.clone().into()),
+            Some(dir.join("cache").into()),
+            None,
+        )
+        .unwrap();
+        assert_eq!(config_path, config);
+        assert_eq!(cache_dir, dir.join("cache"));
+
+        let err = config_locations(Some(dir.join("missing.toml").into()), None, None).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("set by `ANKI_TEX_CONFIG` doesn't exist"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn config_locations_without_home() {
+        let (config_path, cache_dir) = config_locations(None, None, None).unwrap();
+        assert_eq!(config_path, Path::new("anki-tex.toml"));
+        assert_eq!(cache_dir, Path::new(".anki-tex-cache"));
+    }
+
+    #[test]
+    fn parse_warnings_by_class() {
+        use parse_file::{ParseWarning, WarningClass};
+        let unfinished = ParseWarning::UnfinishedNote {
+            fields: vec![String::from("Front")],
+            line: Some(3),
+        };
+        let warnings = [unfinished.clone(), ParseWarning::NoNotes];
+
+        assert!(handle_parse_warnings(WarningAction::Print, &HashMap::new(), &warnings).is_ok());
+        assert!(handle_parse_warnings(WarningAction::Ignore, &HashMap::new(), &warnings).is_ok());
+        let err =
+            handle_parse_warnings(WarningAction::Error, &HashMap::new(), &warnings).unwrap_err();
+        assert_eq!(err.to_string(), unfinished.to_string());
+
+        // the class overrides the default
+        let by_class = HashMap::from([(WarningClass::NoNotes, WarningAction::Error)]);
+        let err = handle_parse_warnings(WarningAction::Ignore, &by_class, &warnings).unwrap_err();
+        assert_eq!(err.to_string(), ParseWarning::NoNotes.to_string());
+        let by_class = HashMap::from([
+            (WarningClass::NoNotes, WarningAction::Ignore),
+            (WarningClass::UnfinishedNote, WarningAction::Print),
+        ]);
+        assert!(handle_parse_warnings(WarningAction::Error, &by_class, &warnings).is_ok());
+    }
+
+    #[test]
+    fn watch_filter_of_representative_paths() {
+        let paths = FilePaths::from_main(PathBuf::from("/project/anki.tex")).unwrap();
+        let filter = watch_filter();
+        let cases = [
+            ("/project/anki.tex", true),
+            ("/project/ch01.tex", true),
+            ("/project/chapters/ch02-integration.tex", true),
+            ("/project/chapters/deep/ch03.tex", true),
+            ("/project/anki.aux", false),
+            ("/project/anki.log", false),
+            ("/project/anki.synctex.gz", false),
+            ("/project/anki.pdf", false),
+            ("/project/anki.tex~", false),
+            ("/project/.anki.tex.swp", false),
+            ("/project/Makefile", false),
+            ("/project/.git/index", false),
+            ("/project/.git/notes.tex", false),
+            ("/project/build/anki.tex", false),
+            ("/project/chapters/build/ch01.tex", false),
+            ("/project/_minted-anki/default.pygstyle", false),
+            ("/project/_minted-anki/x.tex", false),
+            // only whole directory names are ignored
+            ("/project/builds/ch01.tex", true),
+            ("/project/my.git/ch01.tex", true),
+        ];
+        for (path, relevant) in cases {
+            assert_eq!(
+                filter.is_relevant(Path::new(path), &paths),
+                relevant,
+                "{}",
+                path
+            );
+        }
+        // the custom style file is always relevant
+        assert!(filter.is_relevant(&paths.custom, &paths));
+
+        let filter = WatchFilter {
+            extensions: vec![String::from("tex"), String::from("sty")],
+            ignore_dirs: GlobList::new(vec![String::from("drafts")]).unwrap(),
+        };
+        assert!(filter.is_relevant(Path::new("/project/macros.sty"), &paths));
+        assert!(filter.is_relevant(Path::new("/project/build/ch01.tex"), &paths));
+        assert!(!filter.is_relevant(Path::new("/project/drafts/ch01.tex"), &paths));
+    }
+
+    #[test]
+    fn watch_filter_of_events() {
+        let paths = FilePaths::from_main(PathBuf::from("/project/anki.tex")).unwrap();
+        let filter = watch_filter();
+        let event = |paths: &[&str]| {
+            paths.iter().fold(
+                Event::new(EventKind::Modify(notify::event::ModifyKind::Any)),
+                |event, path| event.add_path(PathBuf::from(path)),
+            )
+        };
+        assert!(filter.is_relevant_event(&event(&["/project/ch01.tex"]), &paths));
+        assert!(!filter.is_relevant_event(&event(&["/project/anki.aux"]), &paths));
+        // one relevant path is enough
+        assert!(
+            filter.is_relevant_event(&event(&["/project/anki.log", "/project/anki.tex"]), &paths)
+        );
+        // events without paths can't be filtered
+        assert!(filter.is_relevant_event(&event(&[]), &paths));
+    }
+
+    #[test]
+    fn burst_of_events_coalesces_to_one_update() {
+        let paths = FilePaths::from_main(PathBuf::from("/project/anki.tex")).unwrap();
+        let filter = watch_filter();
+        let (tx, rx) = std::sync::mpsc::channel();
+        for i in 0..50 {
+            tx.send(modify_event(Path::new(&format!("/project/ch{}.tex", i))))
+                .unwrap();
+        }
+        assert!(drain_pending_events(&rx, &filter, &paths));
+        // all events were consumed by the first pass
+        assert!(!drain_pending_events(&rx, &filter, &paths));
+
+        tx.send(modify_event(Path::new("/project/anki.aux")))
+            .unwrap();
+        tx.send(Ok(Event::new(EventKind::Access(
+            notify::event::AccessKind::Any,
+        ))
+        .add_path(PathBuf::from("/project/anki.tex"))))
+            .unwrap();
+        assert!(!drain_pending_events(&rx, &filter, &paths));
+    }
+
+    #[test]
+    fn template_variants_parse_cleanly() {
+        let options = parse_file::ParseOptions::default();
+        for variant in [
+            TemplateVariant::Minimal,
+            TemplateVariant::Standard,
+            TemplateVariant::Examples,
+        ] {
+            TemplateFiles::validate(&options, parse_file::ANKITEX, variant).unwrap();
+        }
+
+        let notes = |variant| {
+            let template = TemplateFiles::new(&options, parse_file::ANKITEX, variant);
+            parse_file::parse_content(template.main, &options)
+                .unwrap()
+                .notes
+        };
+        let standard = notes(TemplateVariant::Standard);
+        assert!(standard.iter().any(|note| note.model == "Cloze"));
+        assert!(standard.iter().any(|note| !note.tags.is_empty()));
+        let examples = notes(TemplateVariant::Examples);
+        assert!(examples.len() > standard.len() / 2);
+        let decks = examples
+            .iter()
+            .map(|note| &note.deck)
+            .collect::<BTreeSet<_>>();
+        assert!(decks.len() >= 2);
+    }
+
+    #[test]
+    fn template_variant_from_config() {
+        #[derive(serde::Deserialize)]
+        struct Variant {
+            template_variant: TemplateVariant,
+        }
+        let variant =
+            |text: &str| toml::from_str::<Variant>(text).map(|variant| variant.template_variant);
+        assert_eq!(
+            variant("template_variant = \"examples\"").unwrap(),
+            TemplateVariant::Examples
+        );
+        assert_eq!(
+            variant("template_variant = \"minimal\"").unwrap(),
+            TemplateVariant::Minimal
+        );
+        assert!(variant("template_variant = \"exam\"").is_err());
+        assert_eq!(TemplateVariant::default(), TemplateVariant::Minimal);
+    }
+
+    #[test]
+    fn tidy_field_text_collapses_blank_lines() {
+        assert_eq!(tidy_field_text("\n\n a\n\n\n\nb \n"), "a\n\nb");
+        assert_eq!(tidy_field_text("a\n \t\n\t\nb"), "a\n\nb");
+        // single line breaks and paragraph breaks stay
+        assert_eq!(tidy_field_text("a\nb\n\nc"), "a\nb\n\nc");
+        assert_eq!(tidy_field_text("  \n "), "");
+    }
+
+    #[test]
+    fn display_field_of_anki_breaks() {
+        let stored = "<br><br>first<br><br><br><br>second&nbsp;<br>";
+        assert_eq!(display_field(stored, true), "first\n\nsecond");
+        assert_eq!(display_field("a<br><br>b", true), "a\n\nb");
+        assert_eq!(display_field(stored, false), "\n\nfirst\n\n\n\nsecond \n");
+        assert_eq!(display_field("[$]x[/$]<div>y</div>", true), "$x$y");
+        assert_eq!(display_field("[latex]$x$[/latex]", true), "$x$");
+        // a literal marker is content
+        assert_eq!(
+            display_field("use [latex] for $x$", true),
+            "use [latex] for $x$"
+        );
+    }
+
+    #[test]
+    fn matching_is_stricter_than_tidying() {
+        let note = |back: &str| Note::new("A", "Basic", &[("Front", "q"), ("Back", back)], &[]);
+        let options = MatchOptions::default();
+        // tidying makes both the same, matching still tells them apart
+        let (a, b) = ("a\n\n\n\nb", "a\n\nb");
+        assert_eq!(tidy_field_text(a), tidy_field_text(b));
+        assert!(!note(a).field_eq(&note(b), "Back", &options));
+        assert!(note(b).field_eq(&note(b), "Back", &options));
+    }
+
+    #[test]
+    fn main_path_resolution_order() {
+        let file = Path::new("scratch.tex");
+        let path = Path::new("chapters/anki.tex");
+        let dir = Path::new("/notes");
+        assert_eq!(main_path(Some(file), Some(path), Some(dir)), file);
+        assert_eq!(
+            main_path(None, Some(path), Some(dir)),
+            Path::new("/notes/chapters/anki.tex")
+        );
+        assert_eq!(main_path(None, Some(path), None), path);
+        assert_eq!(
+            main_path(None, None, Some(dir)),
+            Path::new("/notes/anki.tex")
+        );
+        assert_eq!(main_path(None, None, None), Path::new("anki.tex"));
+        // absolute paths aren't joined to the config path
+        assert_eq!(
+            main_path(None, Some(Path::new("/other/a.tex")), Some(dir)),
+            Path::new("/other/a.tex")
+        );
+
+        let paths = FilePaths::from_main(main_path(
+            Some(Path::new("/scratch/chapter7.tex")),
+            Some(path),
+            Some(dir),
+        ))
+        .unwrap();
+        assert_eq!(paths.anki, Path::new("/scratch/ankitex.sty"));
+        assert_eq!(paths.custom, Path::new("/scratch/custom.sty"));
+    }
+
+    #[test]
+    fn file_option_of_subcommands() {
+        use clap::Parser;
+        let file = |args: &[&str]| {
+            let args = Args::try_parse_from([&["anki-tex"], args].concat()).unwrap();
+            args.subcommand.file().map(Path::to_owned)
+        };
+        assert_eq!(
+            file(&["create", "--file", "scratch.tex"]).as_deref(),
+            Some(Path::new("scratch.tex"))
+        );
+        assert_eq!(
+            file(&["--path", "a.tex", "check", "--file", "chapter7.tex"]).as_deref(),
+            Some(Path::new("chapter7.tex"))
+        );
+        assert_eq!(file(&["check"]), None);
+    }
+
+    #[test]
+    fn template_validation_of_frame_and_ankitex() {
+        let options = parse_file::ParseOptions::default();
+        for variant in [TemplateVariant::Minimal, TemplateVariant::Examples] {
+            TemplateFiles::validate(&options, parse_file::ANKITEX, variant).unwrap();
+        }
+        // a header and footer of the config are used for the generated main file
+        let options = parse_file::ParseOptions {
+            frame: parse_file::DocumentFrame {
+                header: format!("% custom\n{}", parse_file::HEADER),
+                footer: parse_file::FOOTER.to_owned(),
+            },
+            ..parse_file::ParseOptions::default()
+        };
+        TemplateFiles::validate(&options, parse_file::ANKITEX, TemplateVariant::Standard).unwrap();
+
+        let options = parse_file::ParseOptions::default();
+        let template = TemplateFiles::new(&options, parse_file::ANKITEX, TemplateVariant::Minimal);
+        assert!(parse_file::check_ankitex_content(
+            "ankitex.sty",
+            &template.anki,
+            parse_file::ANKITEX
+        )
+        .into_result()
+        .is_ok());
+        let outdated = parse_file::ANKITEX.replacen("\\newcommand", "\\renewcommand", 1);
+        assert!(
+            parse_file::check_ankitex_content("ankitex.sty", &outdated, parse_file::ANKITEX)
+                .into_result()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn notes_grouped_by_deck_and_tag() {
+        let notes = parse_notes(
+            "\\model{Basic}\n\
+             \\deck{Zoologie}\\front{a}\\back{b}\\tag{x}\\tag{y}\\next\n\
+             \\deck{Äpfel}\\front{c}\\back{d}\\next\n\
+             \\deck{Apfel}\\front{e}\\back{f}\\tag{x}\\next\n\
+             \\deck{Zoologie}\\front{g}\\back{h}\\next",
+        );
+        let groups = |group_by| {
+            group_notes(&notes, group_by)
+                .into_iter()
+                .map(|(group, notes)| (group, notes.len()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            groups(NotesGroup::Deck),
+            [("Apfel", 1), ("Äpfel", 1), ("Zoologie", 2)]
+        );
+        assert_eq!(
+            groups(NotesGroup::Tag),
+            [("(no tags)", 2), ("x", 2), ("y", 1)]
+        );
+        assert_eq!(groups(NotesGroup::Model), [("Basic", 4)]);
+    }
+
+    #[test]
+    fn already_wrapped_fields() {
+        let wrap = |content, double_wrap| {
+            wrap_content(
+                FieldFormat::Latex,
+                content,
+                double_wrap,
+                anki_tex::MathDelimiters::default(),
+            )
+        };
+        assert_eq!(
+            wrap("[latex]$x$[/latex]", DoubleWrap::Skip),
+            "[latex]$x$[/latex]"
+        );
+        assert_eq!(
+            wrap("[latex]$x$[/latex]", DoubleWrap::Force),
+            "[latex][latex]$x$[/latex][/latex]"
+        );
+        // a card about the marker itself isn't wrapped
+        let about = "anki shows [latex] as text without [/latex]";
+        assert_eq!(
+            wrap(about, DoubleWrap::Skip),
+            format!("[latex]{}[/latex]", about)
+        );
+        let two = "[latex]a[/latex] and [latex]b[/latex]";
+        assert_eq!(
+            wrap(two, DoubleWrap::Skip),
+            format!("[latex]{}[/latex]", two)
+        );
+        assert_eq!(wrap("\\(x\\)", DoubleWrap::Skip), "[latex]\\(x\\)[/latex]");
+        assert_eq!(
+            wrap_content(
+                FieldFormat::Mathjax,
+                "\\(x\\)",
+                DoubleWrap::Skip,
+                anki_tex::MathDelimiters::default()
+            ),
+            "\\(x\\)"
+        );
+    }
+
+    #[test]
+    fn since_durations() {
+        assert_eq!(parse_since("24h"), Ok(Duration::from_secs(24 * 3600)));
+        assert_eq!(parse_since("7d"), Ok(Duration::from_secs(7 * 24 * 3600)));
+        assert_eq!(parse_since("0h"), Ok(Duration::ZERO));
+        assert!(parse_since("24").unwrap_err().contains("no unit"));
+        assert!(parse_since("1w").is_err());
+        assert!(parse_since("-1d").unwrap_err().contains("invalid number"));
+        assert!(parse_since("h").is_err());
+        assert!(parse_since(&format!("{}d", u64::MAX / 2))
+            .unwrap_err()
+            .contains("too long"));
+    }
+
+    fn card_info(card: usize, note: usize, lapses: i32) -> serde_json::Value {
+        serde_json::json!({
+            "answer": "", "question": "", "deckName": "A", "modelName": "Basic",
+            "fieldOrder": 0, "fields": {}, "css": "", "cardId": card, "interval": 1,
+            "note": note, "ord": 0, "type": 2, "queue": 2, "due": 0, "reps": 3,
+            "lapses": lapses, "left": 0, "mod": 0,
+        })
+    }
+
+    #[test]
+    fn recent_without_reviews() {
+        let (failed, actions) = with_mock_anki(
+            |action, params| match action {
+                "getLatestReviewID" => {
+                    assert_eq!(params["deck"], "A");
+                    ok(serde_json::json!(500))
+                }
+                other => panic!("unexpected action {}", other),
+            },
+            || failed_cards("A", 1000, "generated"),
+        );
+        assert!(failed.unwrap().is_empty());
+        assert_eq!(actions, ["getLatestReviewID"]);
+
+        // reviews without again
+        let (failed, _) = with_mock_anki(
+            |action, _| match action {
+                "getLatestReviewID" => ok(serde_json::json!(2000)),
+                "cardReviews" => ok(serde_json::json!([[1500, 10, -1, 3, 4, 1, 2500, 6000, 1]])),
+                other => panic!("unexpected action {}", other),
+            },
+            || failed_cards("A", 1000, "generated"),
+        );
+        assert!(failed.unwrap().is_empty());
+    }
+
+    #[test]
+    fn recent_failed_cards_of_generated_notes() {
+        let (failed, actions) = with_mock_anki(
+            |action, params| match action {
+                "getLatestReviewID" => ok(serde_json::json!(5000)),
+                "cardReviews" => {
+                    assert_eq!(params["startID"], 1000);
+                    ok(serde_json::json!([
+                        [1100, 10, -1, 1, -600, 1, 2500, 6000, 1],
+                        [1200, 11, -1, 1, -600, 1, 2500, 6000, 1],
+                        [1300, 10, -1, 3, 1, -600, 2500, 6000, 2],
+                        [1400, 10, -1, 1, -600, 1, 2500, 6000, 1],
+                        [1500, 12, -1, 1, -600, 1, 2500, 6000, 1],
+                        [1600, 13, -1, 3, 1, 1, 2500, 6000, 1],
+                    ]))
+                }
+                "cardsInfo" => ok(serde_json::json!([
+                    card_info(10, 1, 2),
+                    card_info(11, 1, 0),
+                    card_info(12, 2, 5),
+                ])),
+                "notesInfo" => {
+                    let mut generated = note_info(1, &[10, 11]);
+                    generated["tags"] = serde_json::json!(["generated"]);
+                    ok(serde_json::json!([generated, note_info(2, &[12])]))
+                }
+                other => panic!("unexpected action {}", other),
+            },
+            || failed_cards("A", 1000, "generated"),
+        );
+        let failed = failed.unwrap();
+        assert_eq!(
+            actions,
+            ["getLatestReviewID", "cardReviews", "cardsInfo", "notesInfo"]
+        );
+        // card 12 belongs to a note which wasn't generated
+        let cards = failed
+            .iter()
+            .map(|card| (card.card_id, card.last_failed, card.again, card.lapses))
+            .collect::<Vec<_>>();
+        assert_eq!(cards, [(10, 1400, 2, 2), (11, 1200, 1, 0)]);
+        assert_eq!(failed[0].first_field, "a");
+        assert_eq!(failed[0].reviews.len(), 3);
+        let json = serde_json::to_value(&failed[1]).unwrap();
+        assert_eq!(
+            json["reviews"],
+            serde_json::json!([[1200, 11, -1, 1, -600, 1, 2500, 6000, 1]])
+        );
+    }
+
+    #[test]
+    fn date_tag_alone_sends_no_update() {
+        let respond = |action: &str, _: &serde_json::Value| match action {
+            "notesInfo" => {
+                let mut note = note_info(1, &[10]);
+                note["tags"] = serde_json::json!(["generated", "2024-03-12"]);
+                ok(serde_json::json!([note]))
+            }
+            "getDecks" => ok(serde_json::json!({ "A": [10] })),
+            "updateNote" | "updateNoteFields" => ok(serde_json::Value::Null),
+            other => panic!("unexpected action {}", other),
+        };
+        let update = |tags: &'static [&'static str]| {
+            with_mock_anki(respond, move || {
+                let mut stored = get_notes_by_ids(&[1], CardDetails::Decks)?.remove(0);
+                let mut note = Note::new("A", "Basic", &[("Front", "a"), ("Back", "b")], &[]);
+                note.tags = tags.iter().map(|tag| tag.to_string()).collect();
+                let diff = note_diff(&stored, &note, false, &MatchOptions::default());
+                apply_note_update(&mut stored, &note, &diff, TagCase::Preserve)?;
+                Ok::<_, color_eyre::Report>((diff, stored))
+            })
+        };
+
+        let (result, actions) = update(&["generated", "2024-05-01"]);
+        let (diff, stored) = result.unwrap();
+        assert!(diff.is_empty());
+        assert_eq!(actions, ["notesInfo", "getDecks"]);
+        assert_eq!(stored.tags, ["generated", "2024-03-12"]);
+
+        // other new tags are added without the date tag of today
+        let (result, actions) = update(&["generated", "2024-05-01", "new"]);
+        let (diff, stored) = result.unwrap();
+        assert_eq!(diff.missing_tags, ["new"]);
+        assert_eq!(
+            actions
+                .iter()
+                .filter(|action| action.starts_with("updateNote"))
+                .collect::<Vec<_>>(),
+            ["updateNote"]
+        );
+        assert_eq!(stored.tags, ["2024-03-12", "generated", "new"]);
+    }
+
+    /// [`Placeholders`] of `main.tex` keeping the counters in `cache_file`
+    fn placeholders(cache_file: &Path, reserve: bool) -> Placeholders {
+        let paths = FilePaths {
+            main: "/notes/main.tex".into(),
+            anki: "/notes/anki.tex".into(),
+            custom: "/notes/custom.tex".into(),
+            root: "/notes".into(),
+        };
+        Placeholders::new(&paths, cache_file, reserve)
+    }
+
+    /// Note of the model `Basic` in the deck `A` with the raw `fields`
+    fn basic_model() -> Model {
+        Model {
+            field_names: vec!["Front".to_owned(), "Back".to_owned()],
+        }
+    }
+
+    #[test]
+    fn placeholder_expansion_and_escapes() {
+        let dir =
+            std::env::temp_dir().join(format!("anki-tex-placeholders-{}", std::process::id()));
+        let mut values = placeholders(&dir.join("cache.json"), false);
+        let matching = MatchOptions::default();
+        let mut note = Note::new(
+            "A",
+            "Basic",
+            &[
+                ("Front", "Theorem {{counter:theorem}} in {{deck}}"),
+                ("Back", r"from {{file}}, \{\{today\}\}"),
+            ],
+            &[],
+        );
+        expand_placeholders(&[], &matching, &basic_model(), &mut note, &mut values).unwrap();
+        assert_eq!(note.fields["Front"], "Theorem 1 in A");
+        assert_eq!(note.fields["Back"], r"from main.tex, \{\{today\}\}");
+
+        // raw fields can't show `\{`
+        let mut note = Note::new(
+            "A",
+            "Basic",
+            &[("Front", "a"), ("Back", r"\{\{today}} {{today}}")],
+            &[],
+        );
+        note.formats.insert("Back".to_owned(), FieldFormat::Raw);
+        expand_placeholders(&[], &matching, &basic_model(), &mut note, &mut values).unwrap();
+        assert_eq!(
+            note.fields["Back"],
+            format!("{{{{today}}}} {}", values.today)
+        );
+
+        // a stored note keeps its values and doesn't use a counter
+        let stored = Note::new(
+            "A",
+            "Basic",
+            &[("Front", "Theorem 7 in A"), ("Back", "b")],
+            &[],
+        );
+        let mut note = Note::new(
+            "A",
+            "Basic",
+            &[
+                ("Front", "Theorem {{counter:theorem}} in {{deck}}"),
+                ("Back", "b"),
+            ],
+            &[],
+        );
+        expand_placeholders(&[stored], &matching, &basic_model(), &mut note, &mut values).unwrap();
+        assert_eq!(note.fields["Front"], "Theorem 7 in A");
+        assert_eq!(values.next_counter("theorem").unwrap(), 2);
+
+        let latex = Note::new(
+            "A",
+            "Basic",
+            &[("Front", r"$\frac{{a}}{b}$"), ("Back", "{{counter:}}")],
+            &[],
+        );
+        assert_eq!(unknown_placeholder(&latex), Some(("Back", "{{counter:}}")));
+        let latex = Note::new(
+            "A",
+            "Basic",
+            &[("Front", r"$\frac{{a}}{b}$"), ("Back", "b")],
+            &[],
+        );
+        assert_eq!(unknown_placeholder(&latex), Some(("Front", "{{a}}")));
+    }
+
+    #[test]
+    fn counters_are_reserved_under_the_lock() {
+        let dir = std::env::temp_dir().join(format!("anki-tex-counters-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache_file = dir.join("cache.json");
+
+        // previews neither lock nor save the counters
+        let mut preview = placeholders(&cache_file, false);
+        assert_eq!(preview.next_counter("theorem").unwrap(), 1);
+        preview.finish().unwrap();
+        assert!(!cache_file.with_extension("lock").exists());
+        assert!(CachedState::load(&cache_file).counters.is_empty());
+
+        let mut first = placeholders(&cache_file, true);
+        assert_eq!(first.next_counter("theorem").unwrap(), 1);
+        assert_eq!(first.next_counter("theorem").unwrap(), 2);
+        assert_eq!(first.next_counter("lemma").unwrap(), 1);
+        assert!(cache_file.with_extension("lock").exists());
+
+        // a concurrent run waits for the lock and continues with the saved counters
+        let second = std::thread::spawn({
+            let cache_file = cache_file.clone();
+            move || {
+                let mut second = placeholders(&cache_file, true);
+                let value = second.next_counter("theorem").unwrap();
+                second.finish().unwrap();
+                value
+            }
+        });
+        std::thread::sleep(Duration::from_millis(300));
+        first.finish().unwrap();
+        assert_eq!(second.join().unwrap(), 3);
+
+        let counters = CachedState::load(&cache_file).counters;
+        assert_eq!(counters["theorem"], 3);
+        assert_eq!(counters["lemma"], 1);
+        assert!(!cache_file.with_extension("lock").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn blank_fields_of_notes() {
+        let model = basic_model();
+        let blank = |fields: &[(&str, &str)], case| {
+            blank_fields(&model, &Note::new("A", "Basic", fields, &[]), case)
+        };
+        let strict = FieldNameCase::Strict;
+        assert_eq!(
+            blank(&[("Front", "a"), ("Back", "b")], strict),
+            BlankFields::None
+        );
+        assert_eq!(
+            blank(&[("Front", "a"), ("Back", " ")], strict),
+            BlankFields::None
+        );
+        assert_eq!(
+            blank(&[("Front", " \n\t"), ("Back", "")], strict),
+            BlankFields::All
+        );
+        assert_eq!(blank(&[], strict), BlankFields::All);
+        assert_eq!(
+            blank(&[("Front", "~"), ("Back", "")], strict),
+            BlankFields::None
+        );
+        assert_eq!(
+            blank(&[("Front", "\n"), ("Back", "b")], strict),
+            BlankFields::First("Front")
+        );
+        assert_eq!(blank(&[("Back", "b")], strict), BlankFields::First("Front"));
+
+        // the field names of the note only match the model ignoring their case
+        let fields = [("front", "a"), ("Back", "b")];
+        assert_eq!(blank(&fields, strict), BlankFields::First("Front"));
+        assert_eq!(blank(&fields, FieldNameCase::Ignore), BlankFields::None);
+
+        let empty = Model {
+            field_names: Vec::new(),
+        };
+        let note = Note::new("A", "Basic", &[("Front", "a")], &[]);
+        assert_eq!(blank_fields(&empty, &note, strict), BlankFields::None);
+    }
+
+    /// Empty directory for the cache of a test
+    fn cache_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anki-tex-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let dir = cache_dir("cache-round-trip");
+        let path = dir.join("cache.json");
+        let mut cache = CachedState::empty();
+        cache.last_sync = Some(1_700_000_000);
+        cache.deck_names = vec!["A".to_owned(), "A::B".to_owned()];
+        cache.managed_notes.insert("hash".to_owned(), vec![1, 2]);
+        cache.custom_sty_hash = Some("abc".to_owned());
+        cache.counters.insert("theorem".to_owned(), 3);
+        cache.save(&path).unwrap();
+
+        let loaded = CachedState::load(&path);
+        assert_eq!(loaded.schema_version, CACHE_SCHEMA_VERSION);
+        assert_eq!(loaded.last_sync, Some(1_700_000_000));
+        assert_eq!(loaded.deck_names, ["A", "A::B"]);
+        assert_eq!(loaded.managed_notes["hash"], [1, 2]);
+        assert_eq!(loaded.custom_sty_hash.as_deref(), Some("abc"));
+        assert_eq!(loaded.counters["theorem"], 3);
+        // only the cache is left after the atomic write
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_updates_keep_all_changes() {
+        let dir = cache_dir("cache-concurrent-updates");
+        let path = dir.join("cache.json");
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..10 {
+                        CachedState::update(path, |cache| {
+                            *cache.counters.entry(format!("c{}", i)).or_default() += 1;
+                            cache.focused_cards.push(i);
+                        })
+                        .unwrap();
+                    }
+                });
+            }
+        });
+        let cache = CachedState::load(&path);
+        assert_eq!(cache.counters.values().collect::<Vec<_>>(), [&10; 4]);
+        assert_eq!(cache.focused_cards.len(), 40);
+        // the lock is released
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_cache_is_rebuilt() {
+        let dir = cache_dir("cache-corrupt");
+        let path = dir.join("cache.json");
+        let current = format!(
+            "{{\"schema_version\": {}, \"last_sync\": 5, \"deck_names\": [\"A\"]",
+            CACHE_SCHEMA_VERSION
+        );
+        for text in [
+            String::new(),
+            String::from("not json"),
+            String::from("[1, 2]"),
+            // truncated by a crash
+            current.clone(),
+            format!(
+                "{}, \"deck_names\": 3}}",
+                &current[..current.rfind(',').unwrap()]
+            ),
+        ] {
+            std::fs::write(&path, &text).unwrap();
+            let cache = CachedState::load(&path);
+            assert_eq!(cache.schema_version, CACHE_SCHEMA_VERSION, "{}", text);
+            assert_eq!(cache.last_sync, None, "{}", text);
+            assert!(cache.deck_names.is_empty(), "{}", text);
+        }
+        // fields of newer versions are ignored
+        std::fs::write(&path, format!("{}, \"unknown\": true}}", current)).unwrap();
+        assert_eq!(CachedState::load(&path).last_sync, Some(5));
+
+        // a directory can't be read
+        let cache = CachedState::load(&dir);
+        assert!(cache.deck_names.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cache_of_other_versions_is_rebuilt() {
+        let dir = cache_dir("cache-versions");
+        let path = dir.join("cache.json");
+        for version in [
+            String::new(),
+            String::from("\"schema_version\": 0,"),
+            format!("\"schema_version\": {},", CACHE_SCHEMA_VERSION + 1),
+        ] {
+            let text = format!("{{{} \"last_sync\": 5, \"deck_names\": [\"A\"]}}", version);
+            std::fs::write(&path, &text).unwrap();
+            let cache = CachedState::load(&path);
+            assert_eq!(cache.schema_version, CACHE_SCHEMA_VERSION, "{}", text);
+            assert_eq!(cache.last_sync, None, "{}", text);
+        }
+        // even if the fields of the other version can't be read
+        let text = format!(
+            "{{\"schema_version\": {}, \"deck_names\": {{\"A\": 1}}}}",
+            CACHE_SCHEMA_VERSION + 1
+        );
+        std::fs::write(&path, text).unwrap();
+        assert!(CachedState::load(&path).deck_names.is_empty());
+
+        // the TOML cache of older versions is moved
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(
+            CachedState::legacy_path(&path),
+            "last_sync = 5\ndeck_names = [\"A\"]\n",
+        )
+        .unwrap();
+        let cache = CachedState::load(&path);
+        assert_eq!(cache.schema_version, CACHE_SCHEMA_VERSION);
+        assert_eq!(
+            (cache.last_sync, &cache.deck_names[..]),
+            (Some(5), &["A".to_owned()][..])
+        );
+        std::fs::write(CachedState::legacy_path(&path), "last_sync = \"x\"").unwrap();
+        assert_eq!(CachedState::load(&path).last_sync, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,13 +1,37 @@
-use crate::Note;
+use crate::{report::Code, FieldFormat, MatchOptions, MathDelimiters, Note, NoteOptions};
 use color_eyre::{
     eyre::{eyre, Result},
     Help,
 };
-use std::{collections::HashMap, path::Path};
-use tracing::warn;
+use serde::Deserialize;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    ops::Range,
+    path::Path,
+};
+use tracing::{debug, warn};
 
 pub const ANKITEX: &str = include_str!("../templates/ankitex.sty");
+/// CSS rules the styling of models needs for the notes created by anki-tex, with
+/// their selector.
+///
+/// Anki renders `[latex]` as `<img class="latex">`, which is black on transparent
+/// and unreadable in the night mode of anki without the inversion.
+pub const MODEL_CSS: &[(&str, &str)] = &[
+    (
+        "img.latex",
+        "img.latex {\n  vertical-align: middle;\n  max-width: 100%;\n}",
+    ),
+    (
+        ".nightMode img.latex",
+        ".nightMode img.latex {\n  filter: invert(1) hue-rotate(180deg);\n}",
+    ),
+];
 pub const CUSTOM_TEMPLATE: &str = include_str!("../templates/custom.sty");
+/// Bodies of the main file created by `anki-tex template --variant`
+pub const STANDARD_TEMPLATE_BODY: &str = include_str!("../templates/standard.tex");
+pub const EXAMPLES_TEMPLATE_BODY: &str = include_str!("../templates/examples.tex");
 
 pub const HEADER: &str = r"\documentclass{article}
 \usepackage{ankitex}
@@ -26,245 +50,4839 @@ enum Cmd {
     Field,
     Next,
     Tag,
+    /// Field applied to all following notes which don't set it, cleared by an
+    /// empty or missing value
+    Shared,
+    /// `notelist` environment ending one note per item, the arguments are the
+    /// field of the items (empty for the default), the shared text and the items
+    NoteList,
+    /// `\sep` splitting the implicit fields of a note
+    Sep,
+    /// `\implicitfields` enabling implicit fields for the rest of the file
+    ImplicitFields,
+    /// `\keep` protecting the note from changes by anki-tex
+    Keep,
 }
 
-macro_rules! reg {
-        ($name:ident = $cmd:path = $mat:literal) => {
-            lazy_static::lazy_static! {
-                static ref $name: (Cmd, regex::Regex) = ($cmd, regex::Regex::new($mat).unwrap());
-            }
-        };
-        ($($name:ident = $cmd:path = $mat:literal),*$(,)?) => {
-            $(reg!($name = $cmd = $mat);)*
+/// Argument of a custom command and the builtin command it is mapped onto
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum CommandArg {
+    Deck,
+    Model,
+    Tag,
+    /// The content of the field with the given name
+    Field(String),
+}
+
+impl TryFrom<String> for CommandArg {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        match value.as_str() {
+            "deck" => Ok(Self::Deck),
+            "model" => Ok(Self::Model),
+            "tag" => Ok(Self::Tag),
+            other => match other.strip_prefix("field:") {
+                Some(name) if !name.is_empty() => Ok(Self::Field(name.to_owned())),
+                _ => Err(format!(
+                    "unknown argument `{}`, expected one of `deck`, `model`, `tag` or `field:<name>`",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+/// A user defined LaTeX command which expands to builtin commands,
+/// e.g. `\defnote{deck}{model}`
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomCommand {
+    /// Name of the command without the leading backslash
+    pub name: String,
+    pub args: Vec<CommandArg>,
+}
+
+/// How the parser finds a [`Builtin`]
+#[derive(Debug, Clone, Copy)]
+enum Syntax {
+    /// A command matched by a regex built from its name and arity, see
+    /// [`CommandTable::new`]
+    Command(Cmd),
+    /// A command setting the field of the given name, e.g. `\front{der Hund}`
+    FieldShorthand(&'static str),
+    /// `\usetemplate`, see [`expand_templates`]
+    Template,
+    /// The `field` environment, see [`find_field_envs`]
+    FieldEnv,
+    /// The `notelist` environment, see [`find_note_lists`]
+    NoteList,
+}
+
+/// A builtin command or environment, see [`grammar`]
+struct Builtin {
+    syntax: Syntax,
+    name: &'static str,
+    /// Number of arguments in braces
+    arity: usize,
+    /// Whether an optional argument in brackets may follow the name
+    optional_arg: bool,
+    /// Whether the name must not be followed by a letter
+    boundary: bool,
+    description: &'static str,
+}
+
+impl Builtin {
+    const fn kind(&self) -> GrammarKind {
+        match self.syntax {
+            Syntax::FieldEnv | Syntax::NoteList => GrammarKind::Environment,
+            _ => GrammarKind::Command,
         }
     }
+}
 
-reg![
-    DECK = Cmd::Deck = r"\\deck\{([^\}]*)\}",
-    MODEL = Cmd::Model = r"\\model\{([^\}]*)\}",
-    TAG = Cmd::Tag = r"\\tag\{([^\}]*)\}",
-    NEXT = Cmd::Next = r"\\next",
-    FIELD = Cmd::Field = r"\\fields\{([^\}]*)\}\{([^\}]*)\}",
-    FIELD_ENV = Cmd::Field = r"\\begin\{field\}\{([^\}]*)\}([\s\S]*?)\\end\{field\}",
+const BUILTINS: &[Builtin] = &[
+    Builtin {
+        syntax: Syntax::Command(Cmd::Deck),
+        name: "deck",
+        arity: 1,
+        optional_arg: false,
+        boundary: false,
+        description: "sets the deck of the following notes",
+    },
+    Builtin {
+        syntax: Syntax::Command(Cmd::Model),
+        name: "model",
+        arity: 1,
+        optional_arg: false,
+        boundary: false,
+        description: "sets the model of the following notes",
+    },
+    Builtin {
+        syntax: Syntax::Command(Cmd::Tag),
+        name: "tag",
+        arity: 1,
+        optional_arg: false,
+        boundary: false,
+        description: "adds a tag to the current note",
+    },
+    Builtin {
+        syntax: Syntax::Command(Cmd::Next),
+        name: "next",
+        arity: 0,
+        optional_arg: false,
+        boundary: false,
+        description: "ends the current note",
+    },
+    Builtin {
+        syntax: Syntax::Command(Cmd::Field),
+        name: "fields",
+        arity: 2,
+        optional_arg: false,
+        boundary: false,
+        description: "sets the field with the name of the first argument",
+    },
+    Builtin {
+        syntax: Syntax::Command(Cmd::Shared),
+        name: "shared",
+        arity: 2,
+        optional_arg: false,
+        boundary: false,
+        description: "sets a field of all following notes which don't set it",
+    },
+    Builtin {
+        syntax: Syntax::Command(Cmd::Shared),
+        name: "clearshared",
+        arity: 1,
+        optional_arg: false,
+        boundary: false,
+        description: "clears a field set by `\\shared`",
+    },
+    Builtin {
+        syntax: Syntax::Command(Cmd::Sep),
+        name: "sep",
+        arity: 0,
+        optional_arg: false,
+        boundary: true,
+        description: "separates the implicit fields of a note",
+    },
+    Builtin {
+        syntax: Syntax::Command(Cmd::ImplicitFields),
+        name: "implicitfields",
+        arity: 0,
+        optional_arg: false,
+        boundary: true,
+        description: "enables implicit fields for the rest of the file",
+    },
+    Builtin {
+        syntax: Syntax::Command(Cmd::Keep),
+        name: "keep",
+        arity: 0,
+        optional_arg: false,
+        boundary: true,
+        description: "protects the note from changes by anki-tex",
+    },
+    Builtin {
+        syntax: Syntax::FieldShorthand("Front"),
+        name: "front",
+        arity: 1,
+        optional_arg: false,
+        boundary: false,
+        description: "sets the field of the same name",
+    },
+    Builtin {
+        syntax: Syntax::FieldShorthand("Back"),
+        name: "back",
+        arity: 1,
+        optional_arg: false,
+        boundary: false,
+        description: "sets the field of the same name",
+    },
+    Builtin {
+        syntax: Syntax::FieldShorthand("Example"),
+        name: "example",
+        arity: 1,
+        optional_arg: false,
+        boundary: false,
+        description: "sets the field of the same name",
+    },
+    Builtin {
+        syntax: Syntax::Template,
+        name: "usetemplate",
+        arity: 2,
+        optional_arg: false,
+        boundary: false,
+        description: "creates a note from a template of the config with `key=value` arguments",
+    },
+    Builtin {
+        syntax: Syntax::FieldEnv,
+        name: "field",
+        arity: 1,
+        optional_arg: true,
+        boundary: false,
+        description: "sets the field with the name of the argument to the body, the \
+                      optional argument is the format",
+    },
+    Builtin {
+        syntax: Syntax::NoteList,
+        name: "notelist",
+        arity: 1,
+        optional_arg: true,
+        boundary: false,
+        description: "creates one note per `\\item` with the argument as shared text, \
+                      the optional argument is the field of the items",
+    },
 ];
 
-fn get_all_matches(text: &str) -> Vec<(usize, Cmd, Option<regex::Captures>)> {
-    let mut locations = Vec::new();
+/// Names of all builtin commands without the backslash, custom commands can't use them
+pub const COMMANDS: &[&str] = &command_names();
 
-    for mat in NEXT.1.find_iter(text) {
-        locations.push((mat.start(), NEXT.0, None));
+const COMMAND_COUNT: usize = {
+    let mut count = 0;
+    let mut i = 0;
+    while i < BUILTINS.len() {
+        if let GrammarKind::Command = BUILTINS[i].kind() {
+            count += 1;
+        }
+        i += 1;
     }
+    count
+};
 
-    for (cmd, re) in &[&*DECK, &MODEL, &TAG, &FIELD, &FIELD_ENV] {
-        for mat in re.find_iter(text) {
-            let start = mat.start();
-            let group = re.captures(&text[start..]).unwrap();
-            locations.push((start, *cmd, Some(group)));
+const fn command_names() -> [&'static str; COMMAND_COUNT] {
+    let mut names = [""; COMMAND_COUNT];
+    let mut count = 0;
+    let mut i = 0;
+    while i < BUILTINS.len() {
+        if let GrammarKind::Command = BUILTINS[i].kind() {
+            names[count] = BUILTINS[i].name;
+            count += 1;
         }
+        i += 1;
     }
+    names
+}
 
-    locations.sort_by_cached_key(|(start, _, _)| *start);
+/// Whether an entry of [`grammar`] is a command or an environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GrammarKind {
+    /// `\name{arg}...`
+    Command,
+    /// `\begin{name}[optional]{arg}...\end{name}`
+    Environment,
+}
 
-    locations
+/// A command or environment recognized by the parser, see [`grammar`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct GrammarEntry {
+    /// Name without the backslash
+    pub name: &'static str,
+    pub kind: GrammarKind,
+    /// Number of mandatory arguments in braces
+    pub arity: usize,
+    /// Whether an optional argument in brackets may follow the name
+    pub optional_arg: bool,
+    pub description: &'static str,
 }
 
-pub fn check_ankitex_template(path: &Path) -> Result<()> {
-    let content = std::fs::read_to_string(path)
-        .with_note(|| eyre!("while reading file {}", path.to_string_lossy()))?;
+/// The builtin commands and environments recognized by the parser, e.g. for
+/// highlighting them in editors. Custom commands of the config aren't included.
+pub fn grammar() -> Vec<GrammarEntry> {
+    BUILTINS
+        .iter()
+        .map(|builtin| GrammarEntry {
+            name: builtin.name,
+            kind: builtin.kind(),
+            arity: builtin.arity,
+            optional_arg: builtin.optional_arg,
+            description: builtin.description,
+        })
+        .collect()
+}
 
-    if content != ANKITEX {
-        return Err(eyre!(
-            "invalid content of {}. Don't modify this file but `custom.sty` instead",
-            path.to_string_lossy()
-        )
-        .with_note(|| "You can generate a valid `ankitex.sty` file with `anki-tex template`"));
+const USE_TEMPLATE: &str = r"\usetemplate{";
+
+/// A note shape defined in the config and instantiated with
+/// `\usetemplate{name}{key=value, ...}`
+///
+/// `{key}` in the values is replaced by the value of `key`, `{{` and `}}` are
+/// literal braces.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NoteTemplate {
+    pub name: String,
+    pub deck: Option<String>,
+    pub model: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl NoteTemplate {
+    /// All texts with placeholders
+    fn texts(&self) -> impl Iterator<Item = &String> {
+        self.deck
+            .iter()
+            .chain(&self.model)
+            .chain(&self.tags)
+            .chain(self.fields.values())
     }
+}
 
-    Ok(())
+/// Replaces the `{placeholders}` in `text` with the values returned by `lookup`.
+///
+/// Returns the name of the first placeholder `lookup` doesn't know as error.
+fn fill_placeholders<'a>(
+    text: &str,
+    lookup: impl Fn(&str) -> Option<&'a str>,
+) -> std::result::Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find(['{', '}']) {
+        result.push_str(&rest[..i]);
+        let (c, after) = (rest.as_bytes()[i], &rest[i + 1..]);
+        if after.as_bytes().first() == Some(&c) {
+            // escaped brace
+            result.push(c as char);
+            rest = &after[1..];
+            continue;
+        }
+        if c == b'}' {
+            return Err(String::from("}"));
+        }
+        let Some((name, after)) = after.split_once('}') else {
+            return Err(String::from("{"));
+        };
+        result.push_str(lookup(name).ok_or_else(|| name.to_owned())?);
+        rest = after;
+    }
+    result.push_str(rest);
+    Ok(result)
 }
 
-fn prepare_content(content: String) -> Result<String> {
-    let content = content.trim();
-    let content = match content.strip_prefix(HEADER) {
-        Some(content) => content,
-        None => {
-            let longest_prefix = get_longest_common_prefix(content, HEADER);
-            let longest_prefix_note = match longest_prefix {
-                Some(i) => {
-                    format!(
-                        "they differ at char {}: required `{}` got `{}`",
-                        i,
-                        content.chars().nth(i).unwrap(),
-                        HEADER.chars().nth(i).unwrap(),
-                    )
+/// Returns the end of the group starting with `{` at `start`, i.e. the position
+/// after the matching `}`.
+fn group_end(text: &str, start: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut depth = 0usize;
+    let mut pos = start;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            // skip escaped characters like `\}`
+            b'\\' => pos += 1,
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(pos + 1);
                 }
-                None => {
-                    format!(
-                        "file is too short, expected min {} characters but it has {}",
-                        HEADER.len(),
-                        content.len(),
-                    )
-                }
-            };
-            let (required_line, got_line) = match longest_prefix {
-                Some(i) => (
-                    format!("required line `{}`", get_line_with_pos(HEADER, i)),
-                    format!("got line `{}`", get_line_with_pos(content, i)),
-                ),
-                None => Default::default(),
-            };
-            return Err(eyre!("file does not start with required header")
-                .with_note(|| {
-                    format!(
-                        "started instead with: {}",
-                        &content[..content.len().min(50)]
-                    )
-                })
-                .note(longest_prefix_note)
-                .note(required_line)
-                .note(got_line));
+            }
+            _ => {}
         }
-    };
-    let content = match content.strip_suffix(FOOTER) {
-        Some(content) => content,
-        None => {
-            return Err(
-                eyre!("file does not end with required footer").with_note(|| {
-                    format!(
-                        "ended instead with: {}",
-                        &content[content.len().max(50) - 50..]
+        pos += 1;
+    }
+    None
+}
+
+/// Splits `key=value, ...` at the commas outside of braces.
+///
+/// Values are trimmed and a value wrapped in braces is unwrapped, so
+/// `{a, b}` is the value `a, b`.
+fn parse_key_values(args: &str) -> std::result::Result<HashMap<&str, &str>, String> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut part_start = 0;
+    let mut escaped = false;
+    for (i, c) in args.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                parts.push(&args[part_start..i]);
+                part_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&args[part_start..]);
+
+    let mut values = HashMap::new();
+    for part in parts {
+        if part.trim().is_empty() {
+            continue;
+        }
+        let Some((key, value)) = part.split_once('=') else {
+            return Err(format!("expected `key=value` but got `{}`", part.trim()));
+        };
+        let value = value.trim();
+        let value = match value.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+            Some(inner) if group_end(value, 0) == Some(value.len()) => inner,
+            _ => value,
+        };
+        if values.insert(key.trim(), value).is_some() {
+            return Err(format!("`{}` is set multiple times", key.trim()));
+        }
+    }
+    Ok(values)
+}
+
+#[derive(Debug)]
+enum CommandKind {
+    Builtin(Cmd),
+    Custom(Vec<CommandArg>),
+}
+
+/// The commands recognized by the parser
+#[derive(Debug)]
+pub struct CommandTable {
+    commands: Vec<(CommandKind, regex::Regex)>,
+    templates: HashMap<String, NoteTemplate>,
+}
+
+impl CommandTable {
+    /// Creates the table of the builtin commands extended by `custom` and the note
+    /// templates `templates`.
+    pub fn new(custom: &[CustomCommand], templates: &[NoteTemplate]) -> Result<Self> {
+        // the other builtins are found by their own functions, see [`Syntax`]
+        let mut commands = BUILTINS
+            .iter()
+            .filter_map(|builtin| {
+                let kind = match builtin.syntax {
+                    Syntax::Command(cmd) => CommandKind::Builtin(cmd),
+                    Syntax::FieldShorthand(field) => {
+                        CommandKind::Custom(vec![CommandArg::Field(field.to_owned())])
+                    }
+                    Syntax::Template | Syntax::FieldEnv | Syntax::NoteList => return None,
+                };
+                let re = format!(
+                    r"\\{}{}{}",
+                    builtin.name,
+                    r"\{([^\}]*)\}".repeat(builtin.arity),
+                    if builtin.boundary { r"\b" } else { "" }
+                );
+                Some((kind, regex::Regex::new(&re).unwrap()))
+            })
+            .collect::<Vec<_>>();
+
+        for command in custom {
+            if command.name.is_empty() || !command.name.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(eyre!(
+                    "invalid name `{}` of custom command, only ascii letters are allowed",
+                    command.name
+                ));
+            }
+            if COMMANDS.contains(&command.name.as_str())
+                || custom.iter().filter(|c| c.name == command.name).count() > 1
+            {
+                return Err(eyre!(
+                    "custom command `{}` is defined multiple times",
+                    command.name
+                ));
+            }
+            if command.args.is_empty() {
+                return Err(eyre!("custom command `{}` has no arguments", command.name));
+            }
+            let re = format!(
+                r"\\{}{}",
+                command.name,
+                r"\{([^\}]*)\}".repeat(command.args.len())
+            );
+            commands.push((
+                CommandKind::Custom(command.args.clone()),
+                regex::Regex::new(&re)?,
+            ));
+        }
+
+        let mut template_map = HashMap::new();
+        for template in templates {
+            if template.name.is_empty() || template.name.contains(['{', '}']) {
+                return Err(eyre!("invalid name `{}` of note template", template.name));
+            }
+            for text in template.texts() {
+                if let Err(brace) = fill_placeholders(text, |_| Some("")) {
+                    return Err(eyre!(
+                        "unmatched `{}` in note template `{}`: {:?}",
+                        brace,
+                        template.name,
+                        text
                     )
-                }),
-            )
+                    .suggestion("use `{{` and `}}` for literal braces"));
+                }
+            }
+            if template_map
+                .insert(template.name.clone(), template.clone())
+                .is_some()
+            {
+                return Err(eyre!(
+                    "note template `{}` is defined multiple times",
+                    template.name
+                ));
+            }
         }
-    };
 
-    for (name, pat) in [("newcommand", NEWCOMMAND), ("renewcommand", RENEWCOMMAND)] {
-        if let Some(start) = content.find(pat) {
-            let (_, part) = content.split_at(start);
-            let part = part.lines().next().unwrap_or(part);
+        Ok(Self {
+            commands,
+            templates: template_map,
+        })
+    }
+}
+
+/// Checks that the custom commands take as many arguments as their definitions
+/// with `\newcommand` in `custom_sty`.
+pub fn check_custom_command_args(custom_sty: &str, custom: &[CustomCommand]) -> Result<()> {
+    for command in custom {
+        let re = regex::Regex::new(&format!(
+            r"\\(?:re)?newcommand\*?\{{?\\{}\}}?(?:\[(\d+)\])?",
+            command.name
+        ))?;
+        let Some(cap) = re.captures(custom_sty) else {
             warn!(
-                "{} `{}` will be ignored by anki. Move it to `custom.sty`.",
-                name, part,
+                "custom command `{}` is not defined in `custom.sty`",
+                command.name
             );
+            continue;
+        };
+        let defined = cap.get(1).map_or(Ok(0), |n| n.as_str().parse::<usize>())?;
+        if defined != command.args.len() {
+            return Err(eyre!(
+                "custom command `{}` has {} arguments in the config but {} in `custom.sty`",
+                command.name,
+                command.args.len(),
+                defined
+            ));
         }
     }
 
-    Ok(content.to_string())
+    Ok(())
+}
+
+/// Commands of LaTeX and the usual math packages which are known to anki's LaTeX
+/// setup, separated by whitespace, see [`unknown_macros`]
+pub const KNOWN_MACROS: &str = "
+    begin end item par newline linebreak noindent centering label ref eqref cite
+    footnote url href includegraphics caption hline cline multicolumn multirow hspace
+    vspace quad qquad enspace thinspace medspace thickspace negthinspace phantom
+    hphantom vphantom smash mbox text textbf textit texttt textrm textsf textsc textup
+    textnormal emph underline bf it rm tt sf sc em bfseries itshape ttfamily rmfamily
+    sffamily tiny scriptsize footnotesize small normalsize large Large LARGE huge Huge
+    color textcolor colorbox fbox boxed LaTeX TeX S P dag ddag dagger ddagger
+    textbackslash textasciitilde ldots dots dotsc dotsb cdots vdots ddots checkmark
+    ensuremath mathrm mathbf mathit mathsf mathtt mathcal mathbb mathfrak mathscr
+    boldsymbol bm operatorname displaystyle textstyle scriptstyle scriptscriptstyle
+    limits nolimits mathop mathrel mathbin mathord mathstrut tag notag nonumber frac
+    dfrac tfrac cfrac sqrt binom dbinom tbinom choose overline underline overbrace
+    underbrace overset underset stackrel hat widehat tilde widetilde bar vec dot ddot
+    mathring check breve acute grave substack xrightarrow xleftarrow cancel not left
+    right middle big Big bigg Bigg bigl bigr Bigl Bigr biggl biggr Biggl Biggr sum prod
+    coprod int iint iiint oint bigcup bigcap bigsqcup bigoplus bigotimes bigodot
+    biguplus bigvee bigwedge lim limsup liminf sup inf max min arg det dim exp log ln lg
+    sin cos tan cot sec csc arcsin arccos arctan sinh cosh tanh coth ker deg gcd hom Pr
+    mod bmod pmod pod cdot cdotp times div pm mp ast star circ bullet oplus ominus
+    otimes oslash odot cup cap sqcup sqcap uplus amalg setminus wedge vee land lor neg
+    lnot wr diamond leq geq le ge leqslant geqslant neq ne ll gg lesssim gtrsim approx
+    equiv sim simeq cong propto asymp doteq prec succ preceq succeq subset subseteq
+    subsetneq supset supseteq supsetneq in notin ni mid nmid parallel perp vdash dashv
+    models bowtie smile frown triangleleft triangleright lhd rhd unlhd unrhd colon to
+    gets mapsto longmapsto implies impliedby iff rightarrow leftarrow Rightarrow
+    Leftarrow leftrightarrow Leftrightarrow longrightarrow Longrightarrow longleftarrow
+    Longleftarrow longleftrightarrow Longleftrightarrow hookrightarrow hookleftarrow
+    uparrow downarrow Uparrow Downarrow updownarrow nearrow searrow swarrow nwarrow
+    leadsto rightharpoonup rightleftharpoons forall exists nexists emptyset varnothing
+    infty partial nabla ell hbar imath jmath Re Im aleph wp top bot prime angle triangle
+    square blacksquare Box Diamond qed therefore because complement clubsuit spadesuit
+    heartsuit diamondsuit flat sharp natural langle rangle lfloor rfloor lceil rceil
+    lvert rvert lVert rVert vert Vert lbrace rbrace lbrack rbrack backslash alpha beta
+    gamma delta epsilon varepsilon zeta eta theta vartheta iota kappa varkappa lambda mu
+    nu xi pi varpi rho varrho sigma varsigma tau upsilon phi varphi chi psi omega Gamma
+    Delta Theta Lambda Xi Pi Sigma Upsilon Phi Psi Omega
+";
+
+/// Names of the commands defined with `\newcommand`, `\renewcommand`,
+/// `\providecommand`, `\DeclareMathOperator`, `\def` or `\let` in `sty`
+pub fn defined_macros(sty: &str) -> BTreeSet<String> {
+    lazy_static::lazy_static! {
+        static ref DEFINITION: regex::Regex = regex::Regex::new(
+            r"\\(?:(?:re|provide)?newcommand|DeclareMathOperator|DeclareRobustCommand)\*?\s*\{?\s*\\([a-zA-Z]+)|\\(?:g|e|x)?def\s*\\([a-zA-Z]+)|\\let\s*\\([a-zA-Z]+)"
+        )
+        .unwrap();
+    }
+    DEFINITION
+        .captures_iter(sty)
+        .filter_map(|cap| (1..=3).find_map(|i| cap.get(i)))
+        .map(|name| name.as_str().to_owned())
+        .collect()
 }
 
-pub fn get_used_decks(content: String) -> Result<Vec<String>> {
-    let content = prepare_content(content)?;
-    // TODO use _start
-    let mut decks = Vec::new();
-    for (_start, cmd, cap) in get_all_matches(&content) {
-        match cmd {
-            Cmd::Deck => {
-                // TODO remove last unwrap
-                let new = cap.unwrap().get(1).unwrap().as_str();
-                decks.push(new.to_owned());
-            }
-            Cmd::Model | Cmd::Field | Cmd::Next | Cmd::Tag => {}
+/// Names of the commands used in `value` which are neither in [`KNOWN_MACROS`] nor
+/// in `known`, in the order of their first use
+///
+/// `\\` is a line break and not the start of a command.
+pub fn unknown_macros<'a>(value: &'a str, known: &BTreeSet<String>) -> Vec<&'a str> {
+    let mut unknown = Vec::new();
+    let mut rest = value;
+    while let Some(pos) = rest.find('\\') {
+        rest = &rest[pos + 1..];
+        if let Some(after) = rest.strip_prefix('\\') {
+            rest = after;
+            continue;
+        }
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let name = &rest[..len];
+        rest = &rest[len..];
+        if !name.is_empty()
+            && !KNOWN_MACROS.split_whitespace().any(|known| known == name)
+            && !known.contains(name)
+            && !unknown.contains(&name)
+        {
+            unknown.push(name);
         }
     }
+    unknown
+}
 
-    Ok(decks)
+impl Default for CommandTable {
+    fn default() -> Self {
+        Self::new(&[], &[]).unwrap()
+    }
 }
 
-pub fn get_content(content: String) -> Result<Vec<Note>> {
-    let content = prepare_content(content)?;
+/// What to do if a field is set multiple times in one note
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnDuplicateField {
+    /// Reject the file
+    #[default]
+    Error,
+    /// Keep the first value and warn
+    First,
+    /// Keep the last value and warn
+    Last,
+}
 
-    let mut current_deck = None;
-    let mut current_model = None;
-    let mut current_tags = Vec::new();
-    let mut current_fields = HashMap::new();
-    let mut completed_notes = Vec::new();
+/// What to do if `\deck` or `\model` changes after fields of the current note
+/// were set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMidNoteSwitch {
+    /// Reject the file
+    #[default]
+    Error,
+    /// Use the new value for the note and warn
+    Warn,
+}
 
-    // TODO use _start
-    for (_start, cmd, cap) in get_all_matches(&content) {
-        match cmd {
-            Cmd::Deck => {
-                // TODO remove last unwrap
-                let new = cap.unwrap().get(1).unwrap().as_str();
-                current_deck = Some(new.to_owned());
-            }
-            Cmd::Model => {
-                // TODO remove last unwrap
-                let new = cap.unwrap().get(1).unwrap().as_str();
-                current_model = Some(new.to_owned());
-            }
-            Cmd::Tag => {
-                // TODO remove last unwrap
-                let new = cap.unwrap().get(1).unwrap().as_str().to_owned();
-                if current_tags.contains(&new) {
-                    return Err(eyre!("Can't add tag {} multiple times", new));
-                }
-                current_tags.push(new);
-            }
-            Cmd::Field => {
-                let cap = cap.unwrap();
-                // TODO remove last unwrap
-                let name = cap.get(1).unwrap().as_str().to_owned();
-                let content = cap.get(2).unwrap().as_str().to_owned();
-                if current_fields.contains_key(&name) {
-                    return Err(eyre!("Field `{}` was already added", name));
-                }
-                current_fields.insert(name, content);
-            }
-            Cmd::Next => {
-                let Some(deck) = current_deck.clone() else {
-                    return Err(eyre!("Select a deck before ending a note"));
-                };
-                let Some(model) = current_model.clone() else {
-                    return Err(eyre!("Select a model before ending a note"));
-                };
-                if current_fields.is_empty() {
-                    return Err(eyre!("Cannot add note without fields"));
+/// Fields filled by the `notelist` environment
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct NoteListFields {
+    /// Field of the shared text given as argument of the environment
+    pub shared_field: String,
+    /// Field of the items, overridden by `\begin{notelist}[Field]`
+    pub item_field: String,
+}
+
+impl Default for NoteListFields {
+    fn default() -> Self {
+        Self {
+            shared_field: String::from("Front"),
+            item_field: String::from("Back"),
+        }
+    }
+}
+
+/// Fields set by the text of a note outside of field commands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImplicitFields {
+    /// Whether the text is used without `\implicitfields` in the file
+    pub enabled: bool,
+    /// Field of the text, or of the text before `\sep`
+    pub first: String,
+    /// Field of the text after `\sep`
+    pub second: String,
+}
+
+impl Default for ImplicitFields {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            first: String::from("Front"),
+            second: String::from("Back"),
+        }
+    }
+}
+
+/// Text expected before and after the document body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentFrame {
+    pub header: String,
+    pub footer: String,
+}
+
+impl Default for DocumentFrame {
+    fn default() -> Self {
+        Self {
+            header: HEADER.to_owned(),
+            footer: FOOTER.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ParseOptions {
+    pub commands: CommandTable,
+    pub on_duplicate_field: OnDuplicateField,
+    pub on_mid_note_switch: OnMidNoteSwitch,
+    pub frame: DocumentFrame,
+    /// Formats of fields by model and field name, overridden by the format of
+    /// a field environment
+    pub field_formats: HashMap<String, HashMap<String, FieldFormat>>,
+    /// Allow notes without model, which is then chosen by their fields.
+    /// Their model is empty.
+    pub allow_missing_model: bool,
+    /// Allow notes without deck, which is then derived from the file path.
+    /// Their deck is empty.
+    pub allow_missing_deck: bool,
+    pub note_list: NoteListFields,
+    pub implicit_fields: ImplicitFields,
+    /// Separator of subdecks in `\deck` which is replaced with the `::` of anki
+    pub deck_separator: Option<String>,
+}
+
+/// Separator of subdecks in the deck names of anki
+pub const ANKI_DECK_SEPARATOR: &str = "::";
+
+/// `deck` as written in the file with `separator` replaced by `::`, and whether it
+/// contained both separators.
+pub fn anki_deck_name(deck: &str, separator: Option<&str>) -> (String, bool) {
+    match separator.filter(|separator| deck.contains(separator)) {
+        Some(separator) => (
+            deck.split(separator)
+                .collect::<Vec<_>>()
+                .join(ANKI_DECK_SEPARATOR),
+            deck.contains(ANKI_DECK_SEPARATOR),
+        ),
+        None => (deck.to_owned(), false),
+    }
+}
+
+/// Inverse of [`anki_deck_name`], `deck` as it is written in the file
+pub fn file_deck_name(deck: &str, separator: Option<&str>) -> String {
+    match separator {
+        Some(separator) => deck.replace(ANKI_DECK_SEPARATOR, separator),
+        None => deck.to_owned(),
+    }
+}
+
+/// Class of a [`ParseWarning`], used to configure how warnings are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningClass {
+    IgnoredDefinition,
+    DuplicateField,
+    UnfinishedNote,
+    NoNotes,
+    MidNoteSwitch,
+    MixedDeckSeparators,
+    UnknownNoteOption,
+    IncludedFile,
+}
+
+/// Problem in a file which doesn't prevent parsing it
+///
+/// Lines are counted from the start of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWarning {
+    /// A `\newcommand` or `\renewcommand` in the main file, which is ignored by anki
+    IgnoredDefinition {
+        kind: &'static str,
+        definition: String,
+        line: usize,
+    },
+    /// A field was set multiple times in one note and one value was ignored
+    /// (see [`OnDuplicateField`])
+    DuplicateField {
+        name: String,
+        kept: String,
+        ignored: String,
+        line: usize,
+    },
+    /// Fields or tags after the last `\next`
+    UnfinishedNote {
+        fields: Vec<String>,
+        line: Option<usize>,
+    },
+    /// The file contains no completed notes
+    NoNotes,
+    /// `\deck` or `\model` changed after fields of the note were set
+    /// (see [`OnMidNoteSwitch`])
+    MidNoteSwitch {
+        /// The command, e.g. `\model{Cloze}`
+        switch: String,
+        line: usize,
+        /// First field of the note
+        field: String,
+        field_line: usize,
+    },
+    /// A deck written with both `deck_separator` and `::`
+    MixedDeckSeparators { deck: String, line: usize },
+    /// A `% anki:` comment sets an option which isn't in [`NoteOptions::KNOWN`]
+    UnknownNoteOption { key: String, line: usize },
+    /// `\input` or `\include` in the document body, the notes of the included file
+    /// aren't read
+    IncludedFile { file: String, line: usize },
+}
+
+impl ParseWarning {
+    pub fn class(&self) -> WarningClass {
+        match self {
+            Self::IgnoredDefinition { .. } => WarningClass::IgnoredDefinition,
+            Self::DuplicateField { .. } => WarningClass::DuplicateField,
+            Self::UnfinishedNote { .. } => WarningClass::UnfinishedNote,
+            Self::NoNotes => WarningClass::NoNotes,
+            Self::MidNoteSwitch { .. } => WarningClass::MidNoteSwitch,
+            Self::MixedDeckSeparators { .. } => WarningClass::MixedDeckSeparators,
+            Self::UnknownNoteOption { .. } => WarningClass::UnknownNoteOption,
+            Self::IncludedFile { .. } => WarningClass::IncludedFile,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IgnoredDefinition {
+                kind,
+                definition,
+                line,
+            } => write!(
+                f,
+                "{} `{}` (line {}) will be ignored by anki. Move it to `custom.sty`.",
+                kind, definition, line
+            ),
+            Self::DuplicateField {
+                name,
+                kept,
+                ignored,
+                line,
+            } => write!(
+                f,
+                "Field `{}` was set multiple times (line {}), keeping {} and ignoring {}",
+                name, line, kept, ignored
+            ),
+            Self::UnfinishedNote { fields, line } => {
+                write!(f, "dismissing unfinished note with fields {:?}", fields)?;
+                if let Some(line) = line {
+                    write!(f, " (line {})", line)?;
                 }
-                let tags = std::mem::take(&mut current_tags);
-                let fields = std::mem::take(&mut current_fields);
-                completed_notes.push(Note {
-                    id: None,
-                    deck,
-                    model,
-                    fields,
-                    tags,
-                    question: None,
-                });
+                Ok(())
             }
+            Self::NoNotes => write!(f, "no completed notes found"),
+            Self::MidNoteSwitch {
+                switch,
+                line,
+                field,
+                field_line,
+            } => write!(
+                f,
+                "`{}` (line {}) changes the note after its field `{}` (line {})",
+                switch, line, field, field_line
+            ),
+            Self::MixedDeckSeparators { deck, line } => write!(
+                f,
+                "deck `{}` (line {}) uses both `deck_separator` and `::`",
+                deck, line
+            ),
+            Self::UnknownNoteOption { key, line } => write!(
+                f,
+                "unknown note option `{}` (line {}), the known options are {}",
+                key,
+                line,
+                NoteOptions::KNOWN.join(", ")
+            ),
+            Self::IncludedFile { file, line } => write!(
+                f,
+                "`{}` (line {}) is included in the document body, its notes won't be read. \
+                 Only the preamble may include files.",
+                file, line
+            ),
         }
     }
+}
 
-    if !current_fields.is_empty() || !current_tags.is_empty() {
-        warn!(
-            "dismissing unfinished note with fields {:?}",
-            current_fields
-        );
+impl ParseWarning {
+    pub fn code(&self) -> Code {
+        match self {
+            Self::IgnoredDefinition { .. } => Code::IgnoredDefinition,
+            Self::DuplicateField { .. } => Code::DuplicateField,
+            Self::UnfinishedNote { .. } => Code::UnfinishedNote,
+            Self::NoNotes => Code::NoNotes,
+            Self::MidNoteSwitch { .. } => Code::MidNoteSwitch,
+            Self::MixedDeckSeparators { .. } => Code::MixedDeckSeparators,
+            Self::UnknownNoteOption { .. } => Code::UnknownNoteOption,
+            Self::IncludedFile { .. } => Code::IncludedFile,
+        }
     }
 
-    if completed_notes.is_empty() {
-        warn!("no completed notes found");
+    /// Line in the file, if the warning belongs to one
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Self::IgnoredDefinition { line, .. }
+            | Self::DuplicateField { line, .. }
+            | Self::MidNoteSwitch { line, .. }
+            | Self::MixedDeckSeparators { line, .. }
+            | Self::UnknownNoteOption { line, .. }
+            | Self::IncludedFile { line, .. } => Some(*line),
+            Self::UnfinishedNote { line, .. } => *line,
+            Self::NoNotes => None,
+        }
     }
+}
 
-    Ok(completed_notes)
+/// Error in a file with a stable [`Code`], found with [`find_parse_error`]
+#[derive(Debug)]
+pub struct ParseError {
+    pub code: Code,
+    /// Byte offset in the file, see [`file_position`]
+    pub pos: Option<usize>,
+    /// Index of the note the error belongs to
+    pub note_index: Option<usize>,
+    message: String,
 }
 
-fn get_longest_common_prefix(a: &str, b: &str) -> Option<usize> {
-    for (i, (c, d)) in a.chars().zip(b.chars()).enumerate() {
-        if c != d {
-            return Some(i);
+impl ParseError {
+    fn new(code: Code, pos: impl Into<Option<usize>>, message: String) -> Self {
+        Self {
+            code,
+            pos: pos.into(),
+            note_index: None,
+            message,
         }
     }
-    None
+
+    fn in_note(mut self, index: usize) -> Self {
+        self.note_index = Some(index);
+        self
+    }
 }
 
-fn get_line_with_pos(text: &str, pos: usize) -> &str {
-    let mut offset = 0;
-    for line in text.lines() {
-        offset += line.len();
-        if offset >= pos {
-            return line;
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn parse_error(code: Code, pos: impl Into<Option<usize>>, message: String) -> color_eyre::Report {
+    ParseError::new(code, pos, message).into()
+}
+
+/// Returns the [`ParseError`] causing `e`, if any.
+pub fn find_parse_error(e: &color_eyre::Report) -> Option<&ParseError> {
+    e.chain().find_map(|e| e.downcast_ref::<ParseError>())
+}
+
+/// Where a byte of a preprocessed text was copied from, see [`SourceMap`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourcePos {
+    /// Index of the file, see [`SourceMap::file_name`]
+    pub file: usize,
+    /// Byte offset in the file
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment {
+    /// Start in the preprocessed text
+    start: usize,
+    len: usize,
+    /// Origin of the first byte, `None` for text inserted by the preprocessing
+    origin: Option<SourcePos>,
+}
+
+/// Maps the byte offsets of a text produced by preprocessing back to the files it
+/// was copied from.
+///
+/// The text is a sequence of segments which are either copied from a range of a file
+/// or inserted. A preprocessing pass builds the map of its output from the map of
+/// its input with [`SourceMap::push_mapped`], so spans and error positions found in
+/// the final text can be reported in the coordinates of the files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    files: Vec<String>,
+    segments: Vec<Segment>,
+}
+
+impl SourceMap {
+    /// Map of the unchanged content of the file `name` with the length `len`
+    pub fn identity(name: &str, len: usize) -> Self {
+        let mut map = Self::default();
+        let file = map.add_file(name);
+        map.push_file(file, 0..len);
+        map
+    }
+
+    /// Index of the file `name`, which is added if it's new.
+    pub fn add_file(&mut self, name: &str) -> usize {
+        match self.files.iter().position(|file| file == name) {
+            Some(index) => index,
+            None => {
+                self.files.push(name.to_owned());
+                self.files.len() - 1
+            }
         }
     }
-    ""
+
+    /// Name of the file with the index `file`
+    pub fn file_name(&self, file: usize) -> &str {
+        &self.files[file]
+    }
+
+    /// Length of the preprocessed text
+    pub fn len(&self) -> usize {
+        self.segments.last().map_or(0, |s| s.start + s.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn push(&mut self, len: usize, origin: Option<SourcePos>) {
+        if len == 0 {
+            return;
+        }
+        let start = self.len();
+        if let Some(last) = self.segments.last_mut() {
+            let continues = match (last.origin, origin) {
+                (Some(a), Some(b)) => a.file == b.file && a.offset + last.len == b.offset,
+                (None, None) => true,
+                _ => false,
+            };
+            if continues {
+                last.len += len;
+                return;
+            }
+        }
+        self.segments.push(Segment { start, len, origin });
+    }
+
+    /// Appends text copied from `range` of the file with the index `file`.
+    pub fn push_file(&mut self, file: usize, range: Range<usize>) {
+        let origin = SourcePos {
+            file,
+            offset: range.start,
+        };
+        self.push(range.len(), Some(origin));
+    }
+
+    /// Appends `len` bytes of text which don't come from a file.
+    pub fn push_inserted(&mut self, len: usize) {
+        self.push(len, None);
+    }
+
+    /// Appends text copied from `range` of the text mapped by `other`, e.g. the input
+    /// of a preprocessing pass.
+    pub fn push_mapped(&mut self, other: &SourceMap, range: Range<usize>) {
+        let first = other
+            .segments
+            .partition_point(|s| s.start + s.len <= range.start);
+        for segment in &other.segments[first..] {
+            if segment.start >= range.end {
+                break;
+            }
+            let start = range.start.max(segment.start);
+            let end = range.end.min(segment.start + segment.len);
+            let origin = segment.origin.map(|origin| SourcePos {
+                file: self.add_file(&other.files[origin.file]),
+                offset: origin.offset + start - segment.start,
+            });
+            self.push(end - start, origin);
+        }
+    }
+
+    /// Position in the files of the byte at `pos` in the preprocessed text.
+    ///
+    /// The end of the text maps to the end of the last segment. Returns `None` for
+    /// inserted text and positions after the end.
+    pub fn to_original(&self, pos: usize) -> Option<SourcePos> {
+        let index = self.segments.partition_point(|s| s.start <= pos);
+        let segment = self.segments.get(index.checked_sub(1)?)?;
+        if pos > segment.start + segment.len {
+            return None;
+        }
+        let origin = segment.origin?;
+        Some(SourcePos {
+            file: origin.file,
+            offset: origin.offset + pos - segment.start,
+        })
+    }
+
+    /// [`SourceMap::to_original`] of both ends of `span`, if they are in the same file.
+    pub fn to_original_span(&self, span: Range<usize>) -> Option<(usize, Range<usize>)> {
+        let start = self.to_original(span.start)?;
+        let end = if span.is_empty() {
+            start
+        } else {
+            let last = self.to_original(span.end - 1)?;
+            SourcePos {
+                offset: last.offset + 1,
+                ..last
+            }
+        };
+        (start.file == end.file).then_some((start.file, start.offset..end.offset))
+    }
+}
+
+/// Name of the main file in the [`SourceMap`] of [`prepare_content`]
+const MAIN_FILE: &str = "main";
+
+/// Byte offset of the document body in `content`, see [`prepare_content`]
+fn body_offset(content: &str, frame: &DocumentFrame) -> usize {
+    let trimmed = content.trim_start();
+    let header = header_len(trimmed, frame).unwrap_or(frame.header.len());
+    (content.len() - trimmed.len() + header).min(content.len())
+}
+
+const BEGIN_DOCUMENT: &str = r"\begin{document}";
+
+lazy_static::lazy_static! {
+    /// `\input` or `\include` of another file
+    static ref INPUT: regex::Regex = regex::Regex::new(r"\\(?:input|include)\{([^}]*)\}").unwrap();
+    /// `\usepackage` with the names of the packages
+    static ref USEPACKAGE: regex::Regex =
+        regex::Regex::new(r"\\usepackage(?:\[[^\]]*\])?\{([^}]*)\}").unwrap();
+}
+
+/// Whether `pos` in `text` is in a comment, `\%` doesn't start one
+fn is_commented(text: &str, pos: usize) -> bool {
+    let line_start = text[..pos].rfind('\n').map_or(0, |i| i + 1);
+    find_unescaped(&text[line_start..pos], "%").is_some()
+}
+
+/// First groups of the matches of `re` in `text` which aren't in a comment
+fn uncommented_captures<'a>(
+    re: &'a regex::Regex,
+    text: &'a str,
+) -> impl Iterator<Item = &'a str> + 'a {
+    re.captures_iter(text)
+        .filter(|captures| !is_commented(text, captures.get(0).unwrap().start()))
+        .filter_map(|captures| captures.get(1))
+        .map(|name| name.as_str().trim())
+}
+
+/// Length of the header at the start of `content`, the header of `frame` or a
+/// preamble including other files, see [`preamble_inputs`]
+fn header_len(content: &str, frame: &DocumentFrame) -> Option<usize> {
+    if content.starts_with(&frame.header) {
+        return Some(frame.header.len());
+    }
+    preamble_inputs(content).map(|(len, _)| len)
+}
+
+/// Files included by the preamble at the start of `content`, e.g. in
+/// `\input{preamble}\begin{document}`, with the length of the preamble up to the
+/// line break after `\begin{document}`.
+///
+/// Only the preamble may include files, the notes are read from the main file and
+/// files included by the body are reported as [`ParseWarning::IncludedFile`].
+///
+/// Returns `None` if the preamble doesn't include files. Files including their
+/// header are accepted instead of the header of the config, [`check_preamble`]
+/// checks that the included files use the packages of the header.
+pub fn preamble_inputs(content: &str) -> Option<(usize, Vec<String>)> {
+    let end = content.find(BEGIN_DOCUMENT)?;
+    let inputs = uncommented_captures(&INPUT, &content[..end])
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    if inputs.is_empty() {
+        return None;
+    }
+    let len = end + BEGIN_DOCUMENT.len();
+    let rest = &content[len..];
+    let line_break = ["\r\n", "\n"]
+        .into_iter()
+        .find(|line_break| rest.starts_with(line_break))
+        .map_or(0, str::len);
+    Some((len + line_break, inputs))
+}
+
+/// Checks that the preamble of `content` and the files it includes use the packages
+/// of the header of `frame`, if the file includes its header, see
+/// [`preamble_inputs`].
+///
+/// Included files are resolved relative to `dir` like LaTeX does, `.tex` is added to
+/// names without extension.
+pub fn check_preamble(content: &str, frame: &DocumentFrame, dir: &Path) -> Result<()> {
+    let content = content.trim_start();
+    let Some((len, inputs)) = preamble_inputs(content) else {
+        return Ok(());
+    };
+    let mut text = content[..len].to_owned();
+    let mut searched = vec![String::from("the main file")];
+    let mut queue = inputs.into_iter().collect::<VecDeque<_>>();
+    let mut visited = HashSet::new();
+    while let Some(name) = queue.pop_front() {
+        let mut path = dir.join(&name);
+        if path.extension().is_none() {
+            path.set_extension("tex");
+        }
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        let included = std::fs::read_to_string(&path).map_err(|e| {
+            parse_error(
+                Code::MissingHeader,
+                None,
+                format!(
+                    "can't read {} included by the preamble: {}",
+                    path.to_string_lossy(),
+                    e
+                ),
+            )
+        })?;
+        queue.extend(uncommented_captures(&INPUT, &included).map(str::to_owned));
+        text.push_str(&included);
+        searched.push(path.to_string_lossy().into_owned());
+    }
+
+    let used = uncommented_captures(&USEPACKAGE, &text)
+        .flat_map(|names| names.split(','))
+        .map(str::trim)
+        .collect::<HashSet<_>>();
+    let missing = uncommented_captures(&USEPACKAGE, &frame.header)
+        .flat_map(|names| names.split(','))
+        .map(str::trim)
+        .filter(|name| !used.contains(name))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(parse_error(
+        Code::MissingHeader,
+        None,
+        format!(
+            "the preamble doesn't use the required packages {}, searched {}",
+            missing.join(", "),
+            searched.join(", ")
+        ),
+    ))
+}
+
+/// [`SourceMap`] of the document body of `content` with the length `len`
+fn body_map(content: &str, frame: &DocumentFrame, len: usize) -> SourceMap {
+    let start = body_offset(content, frame);
+    let mut map = SourceMap::default();
+    let file = map.add_file(MAIN_FILE);
+    map.push_file(file, start..start + len);
+    map
+}
+
+/// Offset in the main file of `pos` in the body mapped by `map`
+fn main_offset(map: &SourceMap, pos: usize) -> usize {
+    map.to_original(pos).map_or(pos, |pos| pos.offset)
+}
+
+/// Converts byte offsets in the document body, or in a part of it, to positions in
+/// the main file `file` with the [`SourceMap`] of the body
+#[derive(Debug, Clone, Copy)]
+struct FilePositions<'a> {
+    file: &'a str,
+    /// `None` if the text is the file itself
+    map: Option<&'a SourceMap>,
+    /// Start of the part in the body
+    start: usize,
+}
+
+impl<'a> FilePositions<'a> {
+    fn new(file: &'a str, map: &'a SourceMap) -> Self {
+        Self {
+            file,
+            map: Some(map),
+            start: 0,
+        }
+    }
+
+    /// Positions in `text`, which isn't taken from a file
+    fn unmapped(text: &'a str) -> Self {
+        Self {
+            file: text,
+            map: None,
+            start: 0,
+        }
+    }
+
+    /// Positions in the part of the text starting at `start`
+    fn part(self, start: usize) -> Self {
+        Self {
+            start: self.start + start,
+            ..self
+        }
+    }
+
+    /// Byte offset in the file of `pos`
+    fn offset(self, pos: usize) -> usize {
+        let pos = self.start + pos;
+        self.map
+            .map_or(pos, |map| main_offset(map, pos))
+            .min(self.file.len())
+    }
+
+    /// Line in the file of `pos`
+    fn line(self, pos: usize) -> usize {
+        line_of(self.file, self.offset(pos))
+    }
+}
+
+/// Converts the byte offset `pos` in `content`, e.g. [`ParseError::pos`], to the line
+/// and column.
+pub fn file_position(content: &str, pos: usize) -> (usize, usize) {
+    let mut pos = pos.min(content.len());
+    while !content.is_char_boundary(pos) {
+        pos -= 1;
+    }
+    let line_start = content[..pos].rfind('\n').map_or(0, |i| i + 1);
+    (
+        line_of(content, pos),
+        content[line_start..pos].chars().count() + 1,
+    )
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseOutcome {
+    pub notes: Vec<Note>,
+    pub warnings: Vec<ParseWarning>,
+}
+
+const FIELD_BEGIN: &str = r"\begin{field}";
+const FIELD_END: &str = r"\end{field}";
+const VERBATIM_BEGIN: &str = r"\begin{verbatim}";
+const VERBATIM_END: &str = r"\end{verbatim}";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldToken {
+    Begin,
+    End,
+}
+
+/// Returns the next `\begin{field}` or `\end{field}` starting at or after `pos`.
+///
+/// Comments and `verbatim` environments are skipped.
+fn next_field_token(text: &str, pos: usize) -> Option<(usize, FieldToken)> {
+    next_env_token(text, pos, FIELD_BEGIN, FIELD_END)
+}
+
+/// Returns the next `begin` or `end` starting at or after `pos`, see [`next_field_token`].
+fn next_env_token(
+    text: &str,
+    mut pos: usize,
+    begin: &str,
+    end: &str,
+) -> Option<(usize, FieldToken)> {
+    // only ascii is matched, so working on bytes always yields char boundaries
+    let bytes = text.as_bytes();
+    while pos < bytes.len() {
+        let rest = &bytes[pos..];
+        if rest.starts_with(begin.as_bytes()) {
+            return Some((pos, FieldToken::Begin));
+        }
+        if rest.starts_with(end.as_bytes()) {
+            return Some((pos, FieldToken::End));
+        }
+        if rest.starts_with(VERBATIM_BEGIN.as_bytes()) {
+            pos += text[pos..]
+                .find(VERBATIM_END)
+                .map_or(rest.len(), |end| end + VERBATIM_END.len());
+            continue;
+        }
+        match rest[0] {
+            // skip escaped characters like `\%`
+            b'\\' => pos += 2,
+            b'%' => pos += rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len()),
+            _ => pos += 1,
+        }
+    }
+    None
+}
+
+fn line_of(text: &str, pos: usize) -> usize {
+    text[..pos].matches('\n').count() + 1
+}
+
+/// Describes the syntax of the field command starting at `start`.
+fn field_syntax(text: &str, start: usize) -> &'static str {
+    if text[start..].starts_with(FIELD_BEGIN) {
+        "field environment"
+    } else {
+        r"`\fields`"
+    }
+}
+
+/// A `field` environment, e.g. `\begin{field}[raw]{Name}body\end{field}`
+struct FieldEnv<'a> {
+    span: Range<usize>,
+    /// Byte range of the name inside the braces
+    name_span: Range<usize>,
+    name: &'a str,
+    body: &'a str,
+    format: Option<FieldFormat>,
+}
+
+/// Finds all `field` environments in `text`, whose positions in the file are `positions`.
+fn find_field_envs<'a>(text: &'a str, positions: FilePositions) -> Result<Vec<FieldEnv<'a>>> {
+    let mut envs = Vec::new();
+    // start, name, format and start of the body of the currently open environment
+    let mut open: Option<(usize, &str, Option<FieldFormat>, usize)> = None;
+    let mut pos = 0;
+
+    while let Some((start, token)) = next_field_token(text, pos) {
+        match token {
+            FieldToken::Begin => {
+                if let Some((outer, outer_name, _, _)) = open {
+                    return Err(parse_error(
+                        Code::NestedFieldEnv,
+                        positions.offset(start),
+                        format!(
+                            "field environments can't be nested (line {})",
+                            positions.line(start)
+                        ),
+                    )
+                    .note(format!(
+                        "the outer field `{}` starts at line {}",
+                        outer_name,
+                        positions.line(outer)
+                    )));
+                }
+                let mut after_begin = &text[start + FIELD_BEGIN.len()..];
+                let format = match after_begin
+                    .strip_prefix('[')
+                    .and_then(|rest| rest.split_once(']'))
+                {
+                    Some((format, rest)) => {
+                        after_begin = rest;
+                        Some(format.parse::<FieldFormat>().map_err(|e| {
+                            parse_error(
+                                Code::UnknownFieldFormat,
+                                positions.offset(start),
+                                format!("{} (line {})", e, positions.line(start)),
+                            )
+                        })?)
+                    }
+                    None => None,
+                };
+                let Some((name, _)) = after_begin
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                else {
+                    return Err(parse_error(
+                        Code::FieldEnvWithoutName,
+                        positions.offset(start),
+                        format!(
+                            "field environment without name (line {})",
+                            positions.line(start)
+                        ),
+                    )
+                    .note(r"use `\begin{field}{<name>}`"));
+                };
+                let body_start = text.len() - after_begin.len() + name.len() + 2;
+                open = Some((start, name, format, body_start));
+                pos = body_start;
+            }
+            FieldToken::End => {
+                let Some((env_start, name, format, body_start)) = open.take() else {
+                    return Err(parse_error(
+                        Code::UnmatchedFieldEnd,
+                        positions.offset(start),
+                        format!(
+                            r"`\end{{field}}` without matching `\begin{{field}}` (line {})",
+                            positions.line(start)
+                        ),
+                    ));
+                };
+                pos = start + FIELD_END.len();
+                let name_start = body_start - 1 - name.len();
+                envs.push(FieldEnv {
+                    span: env_start..pos,
+                    name_span: name_start..name_start + name.len(),
+                    name,
+                    body: &text[body_start..start],
+                    format,
+                });
+            }
+        }
+    }
+
+    if let Some((start, name, _, _)) = open {
+        return Err(parse_error(
+            Code::UnclosedFieldEnv,
+            positions.offset(start),
+            format!(
+                r"field environment `{}` is never closed with `\end{{field}}` (line {})",
+                name,
+                positions.line(start)
+            ),
+        ));
+    }
+
+    Ok(envs)
+}
+
+const NOTELIST_BEGIN: &str = r"\begin{notelist}";
+const NOTELIST_END: &str = r"\end{notelist}";
+const ITEM: &str = r"\item";
+
+/// A `notelist` environment, e.g. `\begin{notelist}[Back]{Shared}\item a \item b\end{notelist}`
+struct NoteList<'a> {
+    span: Range<usize>,
+    /// Field of the items given in brackets
+    item_field: Option<&'a str>,
+    shared: &'a str,
+    items: Vec<&'a str>,
+}
+
+/// Splits the body of a `notelist` environment from `start` to `end` at the
+/// `\item`s which aren't inside of a group or another environment, so items
+/// with a nested `itemize` stay one item.
+///
+/// Returns the position of text before the first item, ignoring whitespace and
+/// comments, and the trimmed items.
+fn split_items(text: &str, start: usize, end: usize) -> (Option<usize>, Vec<&str>) {
+    let bytes = text.as_bytes();
+    let mut items = Vec::new();
+    let mut item_start = None;
+    let mut text_before = None;
+    let mut depth = 0usize;
+    let mut pos = start;
+    while pos < end {
+        let rest = &bytes[pos..end];
+        if depth == 0
+            && rest.starts_with(ITEM.as_bytes())
+            && !rest
+                .get(ITEM.len())
+                .is_some_and(|b| b.is_ascii_alphabetic())
+        {
+            if let Some(item_start) = item_start {
+                items.push(text[item_start..pos].trim());
+            }
+            pos += ITEM.len();
+            item_start = Some(pos);
+            continue;
+        }
+        if item_start.is_none()
+            && text_before.is_none()
+            && rest[0] != b'%'
+            && !rest[0].is_ascii_whitespace()
+        {
+            text_before = Some(pos);
+        }
+        if rest.starts_with(VERBATIM_BEGIN.as_bytes()) {
+            pos += text[pos..end]
+                .find(VERBATIM_END)
+                .map_or(rest.len(), |end| end + VERBATIM_END.len());
+            continue;
+        }
+        if rest.starts_with(br"\begin{") {
+            depth += 1;
+        } else if rest.starts_with(br"\end{") {
+            depth = depth.saturating_sub(1);
+        }
+        match rest[0] {
+            b'\\' => pos += 2,
+            b'{' => {
+                depth += 1;
+                pos += 1;
+            }
+            b'}' => {
+                depth = depth.saturating_sub(1);
+                pos += 1;
+            }
+            b'%' => pos += rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len()),
+            _ => pos += 1,
+        }
+    }
+    if let Some(item_start) = item_start {
+        items.push(text[item_start..end].trim());
+    }
+    (text_before, items)
+}
+
+/// Finds all `notelist` environments in `text`, see [`find_field_envs`].
+fn find_note_lists<'a>(text: &'a str, positions: FilePositions) -> Result<Vec<NoteList<'a>>> {
+    let mut lists = Vec::new();
+    let mut pos = 0;
+    let invalid = |start: usize, message: String| {
+        parse_error(
+            Code::InvalidNoteList,
+            positions.offset(start),
+            format!("{} (line {})", message, positions.line(start)),
+        )
+    };
+
+    while let Some((start, token)) = next_env_token(text, pos, NOTELIST_BEGIN, NOTELIST_END) {
+        if token == FieldToken::End {
+            return Err(invalid(
+                start,
+                String::from(r"`\end{notelist}` without matching `\begin{notelist}`"),
+            ));
+        }
+        let mut args_start = start + NOTELIST_BEGIN.len();
+        let item_field = match text[args_start..]
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once(']'))
+        {
+            Some((field, _)) => {
+                args_start += field.len() + 2;
+                Some(field.trim())
+            }
+            None => None,
+        };
+        let Some(shared_end) = text[args_start..]
+            .starts_with('{')
+            .then(|| group_end(text, args_start))
+            .flatten()
+        else {
+            return Err(invalid(start, String::from("notelist without shared text"))
+                .note(r"use `\begin{notelist}[<item field>]{<shared text>}`"));
+        };
+        let shared = &text[args_start + 1..shared_end - 1];
+
+        let end = match next_env_token(text, shared_end, NOTELIST_BEGIN, NOTELIST_END) {
+            Some((end, FieldToken::End)) => end,
+            Some((inner, FieldToken::Begin)) => {
+                return Err(
+                    invalid(inner, String::from("notelist environments can't be nested")).note(
+                        format!(
+                            "the outer notelist starts at line {}",
+                            positions.line(start)
+                        ),
+                    ),
+                )
+            }
+            None => {
+                return Err(parse_error(
+                    Code::UnclosedNoteList,
+                    positions.offset(start),
+                    format!(
+                        r"notelist is never closed with `\end{{notelist}}` (line {})",
+                        positions.line(start)
+                    ),
+                ))
+            }
+        };
+
+        let (text_before, items) = split_items(text, shared_end, end);
+        if items.iter().all(|item| item.is_empty()) {
+            return Err(parse_error(
+                Code::EmptyNoteList,
+                positions.offset(start),
+                format!(r"notelist without items (line {})", positions.line(start)),
+            )
+            .note(r"every `\item` becomes a note"));
+        }
+        if let Some(text_before) = text_before {
+            return Err(invalid(
+                text_before,
+                String::from(r"text before the first `\item` of a notelist"),
+            )
+            .note(
+                r"put text shared by all items in the argument `\begin{notelist}{<shared text>}`",
+            ));
+        }
+        if let Some(empty) = items.iter().position(|item| item.is_empty()) {
+            return Err(invalid(
+                start,
+                format!("item {} of the notelist is empty", empty + 1),
+            ));
+        }
+
+        pos = end + NOTELIST_END.len();
+        lists.push(NoteList {
+            span: start..pos,
+            item_field,
+            shared,
+            items,
+        });
+    }
+
+    Ok(lists)
+}
+
+/// Replaces the placeholders `{i}` and `{n}` of the shared text of a notelist with
+/// the number of the item `i` (starting at 1) and the number of items `n`.
+fn number_item(shared: &str, i: usize, n: usize) -> String {
+    shared
+        .replace("{i}", &i.to_string())
+        .replace("{n}", &n.to_string())
+}
+
+/// A command found in the document body
+struct CommandMatch<'a> {
+    /// Byte range of the whole command
+    span: Range<usize>,
+    cmd: Cmd,
+    args: Vec<Cow<'a, str>>,
+    /// Whether the command was written as is, i.e. isn't part of a custom command
+    /// or a field environment
+    builtin: bool,
+    /// Format given in a field environment
+    format: Option<FieldFormat>,
+    /// Byte range of the field name if it is written out in the document
+    name_span: Option<Range<usize>>,
+}
+
+/// Finds all commands of `table` in `text`, whose positions in the file are
+/// `positions`. The spans of the commands are byte ranges in `text`.
+fn get_all_commands<'a>(
+    text: &'a str,
+    table: &'a CommandTable,
+    positions: FilePositions,
+) -> Result<Vec<CommandMatch<'a>>> {
+    let mut locations = Vec::new();
+
+    for (kind, re) in &table.commands {
+        for cap in re.captures_iter(text) {
+            let span = cap.get(0).unwrap().range();
+            let args = cap.iter().skip(1).flatten().map(|m| m.as_str());
+            match kind {
+                CommandKind::Builtin(cmd) => locations.push(CommandMatch {
+                    span,
+                    cmd: *cmd,
+                    args: args.map(Cow::Borrowed).collect(),
+                    builtin: true,
+                    format: None,
+                    name_span: matches!(cmd, Cmd::Field | Cmd::Shared)
+                        .then(|| cap.get(1).unwrap().range()),
+                }),
+                CommandKind::Custom(kinds) => {
+                    for (kind, arg) in kinds.iter().zip(args) {
+                        let (cmd, args) = match kind {
+                            CommandArg::Deck => (Cmd::Deck, vec![arg]),
+                            CommandArg::Model => (Cmd::Model, vec![arg]),
+                            CommandArg::Tag => (Cmd::Tag, vec![arg]),
+                            CommandArg::Field(name) => (Cmd::Field, vec![name.as_str(), arg]),
+                        };
+                        locations.push(CommandMatch {
+                            span: span.clone(),
+                            cmd,
+                            args: args.into_iter().map(Cow::Borrowed).collect(),
+                            builtin: false,
+                            format: None,
+                            name_span: None,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    for env in find_field_envs(text, positions)? {
+        locations.push(CommandMatch {
+            span: env.span,
+            cmd: Cmd::Field,
+            args: vec![env.name.into(), env.body.into()],
+            builtin: false,
+            format: env.format,
+            name_span: Some(env.name_span),
+        });
+    }
+
+    locations.extend(expand_templates(text, &table.templates, positions)?);
+
+    // commands in a notelist are part of the shared text or an item
+    let note_lists = find_note_lists(text, positions)?;
+    locations.retain(|m| !note_lists.iter().any(|l| l.span.contains(&m.span.start)));
+    for list in note_lists {
+        let mut args = vec![list.item_field.unwrap_or_default(), list.shared];
+        args.extend(list.items);
+        locations.push(CommandMatch {
+            span: list.span,
+            cmd: Cmd::NoteList,
+            args: args.into_iter().map(Cow::Borrowed).collect(),
+            builtin: true,
+            format: None,
+            name_span: None,
+        });
+    }
+
+    // stable, so the expansions of custom commands keep their order
+    locations.sort_by_key(|m| m.span.start);
+
+    Ok(locations)
+}
+
+/// Finds all `\usetemplate{name}{key=value, ...}` and expands them to the commands
+/// setting deck, model, tags and fields of the template.
+fn expand_templates<'a>(
+    text: &'a str,
+    templates: &HashMap<String, NoteTemplate>,
+    positions: FilePositions,
+) -> Result<Vec<CommandMatch<'a>>> {
+    let mut commands = Vec::new();
+    let mut pos = 0;
+    while let Some(i) = text[pos..].find(USE_TEMPLATE) {
+        let start = pos + i;
+        let line = positions.line(start);
+        let syntax_error = || {
+            parse_error(
+                Code::InvalidTemplateUse,
+                positions.offset(start),
+                format!(r"invalid `\usetemplate` (line {})", line),
+            )
+            .note(r"use `\usetemplate{<name>}{<key>=<value>, ...}`")
+        };
+
+        let name_start = start + USE_TEMPLATE.len() - 1;
+        let name_end = group_end(text, name_start).ok_or_else(syntax_error)?;
+        let name = &text[name_start + 1..name_end - 1];
+        if !text[name_end..].starts_with('{') {
+            return Err(syntax_error());
+        }
+        let end = group_end(text, name_end).ok_or_else(syntax_error)?;
+        let args = &text[name_end + 1..end - 1];
+        pos = end;
+
+        let Some(template) = templates.get(name) else {
+            let mut known = templates.keys().map(String::as_str).collect::<Vec<_>>();
+            known.sort_unstable();
+            return Err(parse_error(
+                Code::UnknownTemplate,
+                positions.offset(start),
+                format!("unknown note template `{}` (line {})", name, line),
+            )
+            .note(format!("known templates: {}", known.join(", "))));
+        };
+        let values = parse_key_values(args).map_err(|e| {
+            parse_error(
+                Code::InvalidTemplateArgs,
+                positions.offset(start),
+                format!(
+                    "invalid arguments of note template `{}` (line {}): {}",
+                    name, line, e
+                ),
+            )
+        })?;
+        let fill = |text: &str| {
+            fill_placeholders(text, |key| values.get(key).copied()).map_err(|key| {
+                parse_error(
+                    Code::MissingTemplateValue,
+                    positions.offset(start),
+                    format!(
+                        "note template `{}` needs a value for `{}` (line {})",
+                        name, key, line
+                    ),
+                )
+            })
+        };
+
+        let mut push = |cmd, args: Vec<Cow<'a, str>>| {
+            commands.push(CommandMatch {
+                span: start..end,
+                cmd,
+                args,
+                builtin: false,
+                format: None,
+                name_span: None,
+            })
+        };
+        if let Some(deck) = &template.deck {
+            push(Cmd::Deck, vec![fill(deck)?.into()]);
+        }
+        if let Some(model) = &template.model {
+            push(Cmd::Model, vec![fill(model)?.into()]);
+        }
+        for tag in &template.tags {
+            push(Cmd::Tag, vec![fill(tag)?.into()]);
+        }
+        for (field, value) in &template.fields {
+            push(Cmd::Field, vec![field.clone().into(), fill(value)?.into()]);
+        }
+    }
+    Ok(commands)
+}
+
+/// Problem in a style file found by [`check_ankitex_template`] or
+/// [`check_custom_template`]
+///
+/// Lines are counted from the start of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StyFinding {
+    /// The content differs from the expected one
+    Modified,
+    /// No `\ProvidesPackage` with the name of the package
+    MissingProvides { package: String },
+    /// `\ProvidesPackage` with another name than the one the file is loaded as
+    WrongProvides {
+        package: String,
+        found: String,
+        line: usize,
+    },
+    /// The file loads itself or `ankitex`, which is loaded next to it
+    CircularPackage { package: String, line: usize },
+    /// `\begin` without `\end` outside of definitions
+    UnclosedEnv { env: String, line: usize },
+    /// `\end` which doesn't close the last opened environment
+    UnmatchedEnd { env: String, line: usize },
+}
+
+impl StyFinding {
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Self::Modified | Self::MissingProvides { .. } => None,
+            Self::WrongProvides { line, .. }
+            | Self::CircularPackage { line, .. }
+            | Self::UnclosedEnv { line, .. }
+            | Self::UnmatchedEnd { line, .. } => Some(*line),
+        }
+    }
+}
+
+impl std::fmt::Display for StyFinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Modified => write!(f, "the content was modified"),
+            Self::MissingProvides { package } => {
+                write!(f, "`\\ProvidesPackage{{{}}}` is missing", package)
+            }
+            Self::WrongProvides {
+                package,
+                found,
+                line,
+            } => write!(
+                f,
+                "`\\ProvidesPackage{{{}}}` (line {}) should be `\\ProvidesPackage{{{}}}`",
+                found, line, package
+            ),
+            Self::CircularPackage { package, line } => write!(
+                f,
+                "`\\usepackage{{{}}}` (line {}) loads a package of anki-tex again",
+                package, line
+            ),
+            Self::UnclosedEnv { env, line } => {
+                write!(f, "environment `{}` (line {}) is never closed", env, line)
+            }
+            Self::UnmatchedEnd { env, line } => write!(
+                f,
+                "`\\end{{{}}}` (line {}) doesn't close an open environment",
+                env, line
+            ),
+        }
+    }
+}
+
+/// Findings of the checks of a style file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StyReport {
+    /// Name of the file in messages, e.g. its path
+    pub name: String,
+    pub findings: Vec<StyFinding>,
+}
+
+impl StyReport {
+    pub fn is_ok(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Error listing the findings, for files which must be valid.
+    pub fn into_result(self) -> Result<()> {
+        if self.is_ok() {
+            return Ok(());
+        }
+        let mut error = if self.findings.contains(&StyFinding::Modified) {
+            eyre!(
+                "invalid content of {}. Don't modify this file but `custom.sty` instead",
+                self.name
+            )
+            .with_note(|| "You can generate a valid `ankitex.sty` file with `anki-tex template`")
+        } else {
+            eyre!("invalid structure of {}", self.name)
+        };
+        for finding in self.findings.iter().filter(|f| **f != StyFinding::Modified) {
+            error = error.note(finding.to_string());
+        }
+        Err(error)
+    }
+}
+
+/// Commands of a style file which are checked, see [`scan_sty`]
+#[derive(Debug, Default)]
+struct StyScan {
+    /// Names of `\ProvidesPackage` and their lines
+    provides: Vec<(String, usize)>,
+    /// Packages loaded with `\usepackage` or `\RequirePackage` and their lines
+    packages: Vec<(String, usize)>,
+    /// Problems of the environments outside of groups
+    envs: Vec<StyFinding>,
+}
+
+lazy_static::lazy_static! {
+    static ref STY_COMMAND: regex::Regex = regex::Regex::new(
+        r"\\(ProvidesPackage|usepackage|RequirePackage|begin|end)\s*(?:\[[^\]]*\]\s*)?\{([^}]*)\}"
+    )
+    .unwrap();
+}
+
+/// Finds the commands of `content` which are checked in style files.
+///
+/// Environments are only matched outside of groups, as definitions like
+/// `\newcommand{\x}{\begin{center}}` may open them without closing them.
+fn scan_sty(content: &str) -> StyScan {
+    let content = strip_comments(content);
+    let mut scan = StyScan::default();
+    let mut open: Vec<(String, usize)> = Vec::new();
+    // depth of the groups at `pos`
+    let mut depth = 0usize;
+    let mut pos = 0;
+    for cap in STY_COMMAND.captures_iter(&content) {
+        let start = cap.get(0).unwrap().start();
+        let bytes = content.as_bytes();
+        while pos < start {
+            match bytes[pos] {
+                b'\\' => pos += 1,
+                b'{' => depth += 1,
+                b'}' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            pos += 1;
+        }
+        let line = line_of(&content, start);
+        let arg = cap[2].trim();
+        match &cap[1] {
+            "ProvidesPackage" => scan.provides.push((arg.to_owned(), line)),
+            "usepackage" | "RequirePackage" => scan.packages.extend(
+                arg.split(',')
+                    .map(|package| (package.trim().to_owned(), line)),
+            ),
+            _ if depth > 0 => {}
+            "begin" => open.push((arg.to_owned(), line)),
+            _ => match open.last() {
+                Some((env, _)) if env == arg => {
+                    open.pop();
+                }
+                _ => scan.envs.push(StyFinding::UnmatchedEnd {
+                    env: arg.to_owned(),
+                    line,
+                }),
+            },
+        }
+    }
+    scan.envs.extend(
+        open.into_iter()
+            .map(|(env, line)| StyFinding::UnclosedEnv { env, line }),
+    );
+    scan.envs.sort_by_key(StyFinding::line);
+    scan
+}
+
+/// Checks that `scan` provides `package`.
+fn check_provides(scan: &StyScan, package: &str, findings: &mut Vec<StyFinding>) {
+    match scan.provides.first() {
+        None => findings.push(StyFinding::MissingProvides {
+            package: package.to_owned(),
+        }),
+        Some((found, line)) if found != package => findings.push(StyFinding::WrongProvides {
+            package: package.to_owned(),
+            found: found.clone(),
+            line: *line,
+        }),
+        Some(_) => {}
+    }
+}
+
+/// Checks that the file at `path` has the content `expected`, usually [`ANKITEX`].
+pub fn check_ankitex_template(path: &Path, expected: &str) -> Result<StyReport> {
+    let content = std::fs::read_to_string(path)
+        .with_note(|| eyre!("while reading file {}", path.to_string_lossy()))?;
+    Ok(check_ankitex_content(
+        &path.to_string_lossy(),
+        &content,
+        expected,
+    ))
+}
+
+/// Checks that `content` of the file `name` is `expected`, see [`check_ankitex_template`].
+///
+/// Modified content is checked like `custom.sty` to point out what breaks it.
+pub fn check_ankitex_content(name: &str, content: &str, expected: &str) -> StyReport {
+    let mut findings = Vec::new();
+    if content != expected {
+        findings.push(StyFinding::Modified);
+        let scan = scan_sty(content);
+        check_provides(&scan, "ankitex", &mut findings);
+        findings.extend(scan.envs);
+    }
+    StyReport {
+        name: name.to_owned(),
+        findings,
+    }
+}
+
+/// Checks the structure of the `custom.sty` at `path`: it provides the package
+/// `custom`, doesn't load the packages of anki-tex and closes its environments.
+pub fn check_custom_template(path: &Path) -> Result<StyReport> {
+    let content = std::fs::read_to_string(path)
+        .with_note(|| eyre!("while reading file {}", path.to_string_lossy()))?;
+    Ok(check_custom_content(&path.to_string_lossy(), &content))
+}
+
+/// Checks `content` of the file `name`, see [`check_custom_template`].
+pub fn check_custom_content(name: &str, content: &str) -> StyReport {
+    let scan = scan_sty(content);
+    let mut findings = Vec::new();
+    check_provides(&scan, "custom", &mut findings);
+    findings.extend(
+        scan.packages
+            .iter()
+            .filter(|(package, _)| package == "ankitex" || package == "custom")
+            .map(|(package, line)| StyFinding::CircularPackage {
+                package: package.clone(),
+                line: *line,
+            }),
+    );
+    findings.extend(scan.envs);
+    StyReport {
+        name: name.to_owned(),
+        findings,
+    }
+}
+
+/// Removes the LaTeX comments from `text`, keeping the line breaks.
+fn strip_comments(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find(['\\', '%']) {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if rest.starts_with('%') {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        } else {
+            let len = escape_len(rest).unwrap_or(1);
+            result.push_str(&rest[..len]);
+            rest = &rest[len..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Replaces typographic quotes and non-breaking spaces with their LaTeX equivalents
+/// and normalizes the text to NFC.
+///
+/// Applying it multiple times doesn't change the result.
+pub fn normalize_unicode(text: &str) -> String {
+    use unicode_normalization::UnicodeNormalization;
+
+    let mut result = String::with_capacity(text.len());
+    for c in text.nfc() {
+        match c {
+            '\u{201C}' => result.push_str("``"),
+            '\u{201D}' => result.push_str("''"),
+            '\u{201E}' => result.push_str(",,"),
+            '\u{2018}' => result.push('`'),
+            '\u{2019}' => result.push('\''),
+            '\u{00A0}' => result.push('~'),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Environments whose content is copied unchanged by [`convert_math_delimiters`]
+const VERBATIM_ENVS: [&str; 3] = ["verbatim", "lstlisting", "minted"];
+
+/// Length of the verbatim text (`\verb` or a verbatim environment) at the start of
+/// `text`.
+fn verbatim_len(text: &str) -> Option<usize> {
+    if let Some(rest) = text.strip_prefix("\\verb") {
+        let rest = rest.strip_prefix('*').unwrap_or(rest);
+        let delimiter = rest.chars().next().filter(|c| !c.is_alphabetic())?;
+        let start = text.len() - rest.len() + delimiter.len_utf8();
+        let end = text[start..]
+            .find(delimiter)
+            .map_or(text.len(), |end| start + end + delimiter.len_utf8());
+        return Some(end);
+    }
+    VERBATIM_ENVS.iter().find_map(|env| {
+        if !text.starts_with(&format!("\\begin{{{}}}", env)) {
+            return None;
+        }
+        let end = format!("\\end{{{}}}", env);
+        Some(text.find(&end).map_or(text.len(), |pos| pos + end.len()))
+    })
+}
+
+/// Length of the escape sequence at the start of `text`, e.g. 2 for `\\` and `\$`.
+fn escape_len(text: &str) -> Option<usize> {
+    let rest = text.strip_prefix('\\')?;
+    Some(1 + rest.chars().next().map_or(0, char::len_utf8))
+}
+
+/// Position of the first `pattern` in `text` which isn't part of an escape sequence
+/// or of verbatim text.
+fn find_unescaped(text: &str, pattern: &str) -> Option<usize> {
+    let mut pos = 0;
+    while pos < text.len() {
+        let rest = &text[pos..];
+        if rest.starts_with(pattern) {
+            return Some(pos);
+        }
+        pos += verbatim_len(rest)
+            .or_else(|| escape_len(rest))
+            .unwrap_or_else(|| rest.chars().next().map_or(1, char::len_utf8));
+    }
+    None
+}
+
+/// Replaces the delimiters of display math in `text` as set by `delimiters`.
+///
+/// Only pairs of delimiters are replaced. Escaped characters (like `\\[` for a line
+/// break with a length or `\$`), inline math and verbatim text are kept.
+///
+/// Applying it multiple times doesn't change the result.
+pub fn convert_math_delimiters(text: &str, delimiters: MathDelimiters) -> String {
+    let pair = match delimiters {
+        MathDelimiters::Keep => return text.to_owned(),
+        MathDelimiters::Dollars => ("\\[", "\\]", "$$", "$$"),
+        MathDelimiters::Brackets => ("$$", "$$", "\\[", "\\]"),
+    };
+    replace_math_pairs(text, &[pair])
+}
+
+/// Replaces `\[...\]` and `\(...\)` in `text` with `$$...$$` and `$...$`, e.g. for
+/// Markdown, like [`convert_math_delimiters`].
+pub fn dollar_math(text: &str) -> String {
+    replace_math_pairs(
+        text,
+        &[("\\[", "\\]", "$$", "$$"), ("\\(", "\\)", "$", "$")],
+    )
+}
+
+/// Replaces the pairs of delimiters `(open, close)` in `text` with `(new_open,
+/// new_close)`, see [`convert_math_delimiters`].
+fn replace_math_pairs(text: &str, pairs: &[(&str, &str, &str, &str)]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find(['\\', '$']) {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        let pair = pairs
+            .iter()
+            .find_map(|&(open, close, new_open, new_close)| {
+                let inner = rest.strip_prefix(open)?;
+                let end = find_unescaped(inner, close)?;
+                Some((
+                    open.len() + end + close.len(),
+                    &inner[..end],
+                    new_open,
+                    new_close,
+                ))
+            });
+        let len = if let Some((len, inner, new_open, new_close)) = pair {
+            result.push_str(new_open);
+            result.push_str(inner);
+            result.push_str(new_close);
+            len
+        } else if let Some(len) = verbatim_len(rest) {
+            result.push_str(&rest[..len]);
+            len
+        } else if let Some(inner) = rest.strip_prefix('$').filter(|i| !i.starts_with('$')) {
+            // inline math is copied as a whole, so that `$a$$b$` isn't display math
+            let len = find_unescaped(inner, "$").map_or(1, |end| end + 2);
+            result.push_str(&rest[..len]);
+            len
+        } else {
+            let len = escape_len(rest).unwrap_or(1);
+            result.push_str(&rest[..len]);
+            len
+        };
+        rest = &rest[len..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Strips header and footer from `content` and returns the document body with its
+/// [`SourceMap`].
+fn prepare_content(
+    file: &str,
+    frame: &DocumentFrame,
+    warnings: &mut Vec<ParseWarning>,
+) -> Result<(String, SourceMap)> {
+    let header = frame.header.as_str();
+    let footer = frame.footer.as_str();
+    let content = file.trim();
+    let content = match header_len(content, frame) {
+        Some(len) => &content[len..],
+        None => {
+            let longest_prefix = get_longest_common_prefix(content, header);
+            let longest_prefix_note = match longest_prefix {
+                Some(i) => {
+                    format!(
+                        "they differ at char {}: required `{}` got `{}`",
+                        i,
+                        header.chars().nth(i).unwrap(),
+                        content.chars().nth(i).unwrap(),
+                    )
+                }
+                None => {
+                    format!(
+                        "file is too short, expected min {} characters but it has {}",
+                        header.len(),
+                        content.len(),
+                    )
+                }
+            };
+            let (required_line, got_line) = match longest_prefix {
+                Some(i) => (
+                    format!("required line `{}`", get_line_with_pos(header, i)),
+                    format!("got line `{}`", get_line_with_pos(content, i)),
+                ),
+                None => Default::default(),
+            };
+            return Err(parse_error(
+                Code::MissingHeader,
+                None,
+                String::from("file does not start with required header"),
+            )
+            .with_note(|| {
+                format!(
+                    "started instead with: {}",
+                    &content[..content.len().min(50)]
+                )
+            })
+            .note(longest_prefix_note)
+            .note(required_line)
+            .note(got_line));
+        }
+    };
+    let content = match content.strip_suffix(footer) {
+        Some(content) => content,
+        None => {
+            return Err(parse_error(
+                Code::MissingFooter,
+                None,
+                String::from("file does not end with required footer"),
+            )
+            .with_note(|| {
+                format!(
+                    "ended instead with: {}",
+                    &content[content.len().max(50) - 50..]
+                )
+            }))
+        }
+    };
+
+    let map = body_map(file, frame, content.len());
+    let positions = FilePositions::new(file, &map);
+    for (name, pat) in [("newcommand", NEWCOMMAND), ("renewcommand", RENEWCOMMAND)] {
+        if let Some(start) = content.find(pat) {
+            let (_, part) = content.split_at(start);
+            let part = part.lines().next().unwrap_or(part);
+            warnings.push(ParseWarning::IgnoredDefinition {
+                kind: name,
+                definition: part.to_owned(),
+                line: positions.line(start),
+            });
+        }
+    }
+    for captures in INPUT.captures_iter(content) {
+        let start = captures.get(0).unwrap().start();
+        if !is_commented(content, start) {
+            warnings.push(ParseWarning::IncludedFile {
+                file: captures[1].trim().to_owned(),
+                line: positions.line(start),
+            });
+        }
+    }
+
+    Ok((content.to_string(), map))
+}
+
+/// A `\deck` or `\model` command setting the value which is already active
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedundantCommand {
+    /// `deck` or `model`
+    pub command: &'static str,
+    pub value: String,
+    /// Line in the file
+    pub line: usize,
+    /// Byte range in the file
+    pub span: Range<usize>,
+}
+
+/// Style problems of a file, see [`lint_content`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lint {
+    pub redundant: Vec<RedundantCommand>,
+    /// Tags set on every note of the file
+    pub common_tags: Vec<String>,
+    /// Lines in the file of the `\next` of notes without tags
+    pub untagged: Vec<usize>,
+    pub notes: usize,
+}
+
+impl Lint {
+    pub fn is_empty(&self) -> bool {
+        self.redundant.is_empty() && self.common_tags.is_empty() && self.untagged.is_empty()
+    }
+
+    /// Removes the redundant commands from `content`, which must be the linted file.
+    ///
+    /// Lines which only contained redundant commands are removed completely,
+    /// all other bytes are kept.
+    pub fn fix(&self, content: &str) -> String {
+        let mut result = String::with_capacity(content.len());
+        let mut pos = 0;
+        for removal in self.removals(content) {
+            result.push_str(&content[pos..removal.start]);
+            pos = removal.end;
+        }
+        result.push_str(&content[pos..]);
+        result
+    }
+
+    /// Sorted byte ranges of `content` removed by [`Lint::fix`]
+    pub fn removals(&self, content: &str) -> Vec<Range<usize>> {
+        let line_bounds = |span: &Range<usize>| {
+            let start = content[..span.start].rfind('\n').map_or(0, |i| i + 1);
+            let end = content[span.end..]
+                .find('\n')
+                .map_or(content.len(), |i| span.end + i + 1);
+            start..end
+        };
+
+        let mut removals = Vec::new();
+        let mut redundant = self.redundant.iter().map(|r| &r.span).peekable();
+        while let Some(first) = redundant.next() {
+            let line = line_bounds(first);
+            let mut spans = vec![first.clone()];
+            while let Some(span) = redundant.next_if(|span| span.start < line.end) {
+                spans.push(span.clone());
+            }
+
+            let mut rest = String::new();
+            let mut pos = line.start;
+            for span in &spans {
+                rest.push_str(&content[pos..span.start]);
+                pos = span.end;
+            }
+            rest.push_str(&content[pos..line.end]);
+            if rest.trim().is_empty() {
+                removals.push(line);
+            } else {
+                removals.extend(spans);
+            }
+        }
+        removals
+    }
+}
+
+/// Finds redundant commands, tags which could be set for the whole file and notes
+/// without tags in the file with the content `content`.
+pub fn lint_content(content: &str, options: &ParseOptions) -> Result<Lint> {
+    let mut warnings = Vec::new();
+    let (body, map) = prepare_content(content, &options.frame, &mut warnings)?;
+    let positions = FilePositions::new(content, &map);
+
+    let mut lint = Lint::default();
+    let mut current_deck = None;
+    let mut current_model = None;
+    let mut current_tags = Vec::new();
+    let mut note_tags: Vec<Vec<&str>> = Vec::new();
+
+    let commands = get_all_commands(&body, &options.commands, positions)?;
+    // e.g. a `\deck` inside of a field environment, removing it would change the field
+    let is_nested = |m: &CommandMatch| {
+        commands.iter().any(|other| {
+            other.span != m.span && other.span.start <= m.span.start && m.span.end <= other.span.end
+        })
+    };
+    for m in &commands {
+        let current = match m.cmd {
+            Cmd::Deck => Some(("deck", &mut current_deck)),
+            Cmd::Model => Some(("model", &mut current_model)),
+            Cmd::Tag => {
+                current_tags.push(m.args[0].as_ref());
+                None
+            }
+            Cmd::Next | Cmd::NoteList => {
+                if current_tags.is_empty() {
+                    lint.untagged.push(positions.line(m.span.start));
+                }
+                let tags = std::mem::take(&mut current_tags);
+                // a notelist ends one note per item
+                let notes = if m.cmd == Cmd::NoteList {
+                    m.args.len() - 2
+                } else {
+                    1
+                };
+                note_tags.extend(std::iter::repeat_n(tags, notes));
+                None
+            }
+            Cmd::Field | Cmd::Shared | Cmd::Sep | Cmd::ImplicitFields | Cmd::Keep => None,
+        };
+        if let Some((command, current)) = current {
+            let value = match m.cmd {
+                Cmd::Deck => anki_deck_name(&m.args[0], options.deck_separator.as_deref()).0,
+                _ => m.args[0].to_string(),
+            };
+            if m.builtin && current.as_ref() == Some(&value) && !is_nested(m) {
+                lint.redundant.push(RedundantCommand {
+                    command,
+                    value: m.args[0].to_string(),
+                    line: positions.line(m.span.start),
+                    span: positions.offset(m.span.start)..positions.offset(m.span.end),
+                });
+            }
+            *current = Some(value);
+        }
+    }
+
+    lint.notes = note_tags.len();
+    if let Some((first, rest)) = note_tags.split_first() {
+        if !rest.is_empty() {
+            lint.common_tags = first
+                .iter()
+                .filter(|tag| rest.iter().all(|tags| tags.contains(tag)))
+                .map(|tag| tag.to_string())
+                .collect();
+        }
+    }
+
+    Ok(lint)
+}
+
+/// Occurrences of a field name in a document, see [`field_name_spans`].
+#[derive(Debug, Default)]
+pub struct FieldNameSpans {
+    /// Byte ranges of the field name in the file
+    pub spans: Vec<Range<usize>>,
+    /// Lines where the field can't be renamed, because it is set without writing
+    /// out its name, e.g. by a custom command or a note template, or by a
+    /// `\shared` field which is also used by notes of other models
+    pub fixed: Vec<usize>,
+}
+
+/// Finds where the field `field` of notes with model `model` is named in `content`.
+pub fn field_name_spans(
+    content: &str,
+    options: &ParseOptions,
+    model: &str,
+    field: &str,
+) -> Result<FieldNameSpans> {
+    let mut warnings = Vec::new();
+    let (body, map) = prepare_content(content, &options.frame, &mut warnings)?;
+    let positions = FilePositions::new(content, &map);
+
+    let mut found = FieldNameSpans::default();
+    let mut current_model = None;
+    let mut current = Vec::new();
+    // `\shared` commands naming the field since the last one setting a value,
+    // whether the value is active and used by notes with and without `model`
+    let mut shared = Vec::new();
+    let mut shared_active = false;
+    let (mut shared_used, mut shared_conflict) = (false, false);
+    let flush_shared = |found: &mut FieldNameSpans,
+                        shared: &mut Vec<&CommandMatch>,
+                        used: bool,
+                        conflict: bool| {
+        for m in shared.drain(..) {
+            match &m.name_span {
+                Some(span) if used && !conflict => found
+                    .spans
+                    .push(positions.offset(span.start)..positions.offset(span.end)),
+                _ if used => found.fixed.push(positions.line(m.span.start)),
+                _ => {}
+            }
+        }
+    };
+
+    let commands = get_all_commands(&body, &options.commands, positions)?;
+    // e.g. a `\fields` inside of a field environment is part of the content
+    let is_nested = |m: &CommandMatch| {
+        commands.iter().any(|other| {
+            other.span != m.span && other.span.start <= m.span.start && m.span.end <= other.span.end
+        })
+    };
+    for m in &commands {
+        match m.cmd {
+            Cmd::Model => current_model = Some(m.args[0].as_ref()),
+            Cmd::Field if m.args[0] == field && !is_nested(m) => current.push(m),
+            Cmd::Shared if m.args[0] == field && !is_nested(m) => {
+                let value = m.args.get(1).map_or("", |v| v.as_ref());
+                if !value.is_empty() {
+                    flush_shared(&mut found, &mut shared, shared_used, shared_conflict);
+                    (shared_used, shared_conflict) = (false, false);
+                }
+                shared_active = !value.is_empty();
+                shared.push(m);
+            }
+            Cmd::Next | Cmd::NoteList => {
+                let matches = std::mem::take(&mut current);
+                if m.cmd == Cmd::NoteList && current_model == Some(model) {
+                    let item_field = match m.args[0].as_ref() {
+                        "" => options.note_list.item_field.as_str(),
+                        item_field => item_field,
+                    };
+                    if field == options.note_list.shared_field || field == item_field {
+                        found.fixed.push(positions.line(m.span.start));
+                    }
+                }
+                if shared_active && matches.is_empty() {
+                    if current_model == Some(model) {
+                        shared_used = true;
+                    } else {
+                        shared_conflict = true;
+                    }
+                }
+                if current_model != Some(model) {
+                    continue;
+                }
+                for m in matches {
+                    match &m.name_span {
+                        Some(span) => found
+                            .spans
+                            .push(positions.offset(span.start)..positions.offset(span.end)),
+                        None => found.fixed.push(positions.line(m.span.start)),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    flush_shared(&mut found, &mut shared, shared_used, shared_conflict);
+    found.spans.sort_by_key(|span| span.start);
+    found.fixed.sort_unstable();
+
+    Ok(found)
+}
+
+/// Formats `notes` as document which parses to the same notes.
+///
+/// Fields are sorted by name and written with `\fields` if possible,
+/// otherwise as field environment.
+///
+/// Decks are written with `deck_separator` of `options`, the generated tag of
+/// `matching` isn't written.
+pub fn format_notes(
+    notes: &[Note],
+    options: &ParseOptions,
+    matching: &MatchOptions,
+) -> Result<String> {
+    let frame = &options.frame;
+    let mut out = frame.header.clone();
+    let mut current_deck = None;
+    let mut current_model = None;
+
+    for note in notes {
+        out.push('\n');
+        if current_deck != Some(&note.deck) {
+            out.push_str(&format!(
+                "\\deck{{{}}}\n",
+                file_deck_name(&note.deck, options.deck_separator.as_deref())
+            ));
+            current_deck = Some(&note.deck);
+        }
+        if current_model != Some(&note.model) {
+            out.push_str(&format!("\\model{{{}}}\n", note.model));
+            current_model = Some(&note.model);
+        }
+
+        let mut fields = note.fields.iter().collect::<Vec<_>>();
+        fields.sort();
+        for (name, value) in fields {
+            if name.contains(['{', '}']) {
+                return Err(eyre!(
+                    "can't format field name `{}` containing braces",
+                    name
+                ));
+            }
+            let format = note.format(name);
+            if format == FieldFormat::Latex && !value.contains(['{', '}', '\n', '%']) {
+                out.push_str(&format!("\\fields{{{}}}{{{}}}\n", name, value));
+                continue;
+            }
+            let option = match format {
+                FieldFormat::Latex => String::new(),
+                other => format!("[{}]", other.name()),
+            };
+            let env = format!(
+                "{}{}{{{}}}{}{}",
+                FIELD_BEGIN, option, name, value, FIELD_END
+            );
+            // the value must not contain anything ending the environment early
+            match find_field_envs(&env, FilePositions::unmapped(&env)) {
+                Ok(envs) if envs.len() == 1 && envs[0].body == value => {
+                    out.push_str(&env);
+                    out.push('\n');
+                }
+                _ => {
+                    return Err(eyre!(
+                        "can't format value of field `{}` as field environment",
+                        name
+                    )
+                    .note(format!("the value is {:?}", value)))
+                }
+            }
+        }
+        for tag in note
+            .tags
+            .iter()
+            .filter(|tag| !matching.is_generated_tag(tag))
+        {
+            out.push_str(&format!("\\tag{{{}}}\n", tag));
+        }
+        out.push_str("\\next\n");
+    }
+
+    out.push('\n');
+    out.push_str(&frame.footer);
+    out.push('\n');
+    Ok(out)
+}
+
+/// Logs `warnings` with level WARN.
+fn log_warnings(warnings: &[ParseWarning]) {
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+}
+
+pub fn get_used_decks(content: String, options: &ParseOptions) -> Result<Vec<String>> {
+    let mut warnings = Vec::new();
+    let (body, map) = prepare_content(&content, &options.frame, &mut warnings)?;
+    log_warnings(&warnings);
+    let mut decks = Vec::new();
+    for CommandMatch { cmd, args, .. } in
+        get_all_commands(&body, &options.commands, FilePositions::new(&content, &map))?
+    {
+        match cmd {
+            Cmd::Deck => {
+                let separator = options.deck_separator.as_deref();
+                decks.push(anki_deck_name(&args[0], separator).0);
+            }
+            Cmd::Model
+            | Cmd::Field
+            | Cmd::Next
+            | Cmd::Tag
+            | Cmd::Shared
+            | Cmd::NoteList
+            | Cmd::Sep
+            | Cmd::ImplicitFields
+            | Cmd::Keep => {}
+        }
+    }
+
+    Ok(decks)
+}
+
+pub fn get_used_models(content: String, options: &ParseOptions) -> Result<Vec<String>> {
+    let mut warnings = Vec::new();
+    let (body, map) = prepare_content(&content, &options.frame, &mut warnings)?;
+    log_warnings(&warnings);
+    let mut models = Vec::new();
+    for CommandMatch { cmd, args, .. } in
+        get_all_commands(&body, &options.commands, FilePositions::new(&content, &map))?
+    {
+        match cmd {
+            Cmd::Model => {
+                if !models.iter().any(|m| *m == args[0]) {
+                    models.push(args[0].to_string());
+                }
+            }
+            Cmd::Deck
+            | Cmd::Field
+            | Cmd::Next
+            | Cmd::Tag
+            | Cmd::Shared
+            | Cmd::NoteList
+            | Cmd::Sep
+            | Cmd::ImplicitFields
+            | Cmd::Keep => {}
+        }
+    }
+
+    Ok(models)
+}
+
+lazy_static::lazy_static! {
+    /// `% anki: ...` comment setting [`NoteOptions`]
+    static ref NOTE_OPTIONS: regex::Regex =
+        regex::Regex::new(r"(?m)^[ \t]*%[ \t]*anki:(.*)$").unwrap();
+}
+
+/// Parses the options of a `% anki:` comment, either a JSON object or `key=value`
+/// pairs separated by commas or spaces.
+///
+/// Values of pairs are parsed as JSON if possible and are strings otherwise, a key
+/// without value is `true`.
+fn parse_note_options(text: &str) -> Result<NoteOptions, String> {
+    let text = text.trim();
+    let options = if text.starts_with('{') {
+        serde_json::from_str(text).map_err(|e| format!("invalid JSON: {}", e))?
+    } else {
+        text.split([',', ' ', '\t'])
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = match pair.split_once('=') {
+                    Some((key, value)) => (
+                        key,
+                        serde_json::from_str(value)
+                            .unwrap_or_else(|_| serde_json::Value::from(value)),
+                    ),
+                    None => (pair, serde_json::Value::Bool(true)),
+                };
+                if key.is_empty() {
+                    return Err(format!("`{}` has no key", pair));
+                }
+                Ok((key.to_owned(), value))
+            })
+            .collect::<Result<_, String>>()?
+    };
+    NoteOptions::new(options)
+}
+
+/// Like [`parse_content`] but only returns the notes and logs the warnings.
+pub fn get_content(content: String, options: &ParseOptions) -> Result<Vec<Note>> {
+    let outcome = parse_content(content, options)?;
+    log_warnings(&outcome.warnings);
+    Ok(outcome.notes)
+}
+
+/// Parses all notes of the file with the content `content`.
+pub fn parse_content(content: String, options: &ParseOptions) -> Result<ParseOutcome> {
+    let mut warnings = Vec::new();
+    let (body, map) = prepare_content(&content, &options.frame, &mut warnings)?;
+    let (notes, _) = parse_body(
+        &body,
+        0..body.len(),
+        options,
+        FilePositions::new(&content, &map),
+        CarriedState::new(options),
+        0,
+        &mut warnings,
+    )?;
+    if notes.is_empty() {
+        warnings.push(ParseWarning::NoNotes);
+    }
+    Ok(ParseOutcome { notes, warnings })
+}
+
+/// Parser state which carries over from one note to the next
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, Deserialize)]
+struct CarriedState {
+    deck: Option<String>,
+    model: Option<String>,
+    /// Fields set with `\shared`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    shared: BTreeMap<String, String>,
+    /// Whether implicit fields are enabled, by the config or `\implicitfields`
+    implicit: bool,
+}
+
+impl CarriedState {
+    fn new(options: &ParseOptions) -> Self {
+        Self {
+            deck: None,
+            model: None,
+            shared: BTreeMap::new(),
+            implicit: options.implicit_fields.enabled,
+        }
+    }
+}
+
+/// The text of the notes ended by one `\next` or `\notelist`, see [`ParseIndex`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, Deserialize)]
+struct NoteSpan {
+    /// Byte range in the document body, from the end of the previous note to the end
+    /// of the command ending the notes
+    span: Range<usize>,
+    /// Number of notes, more than one for a notelist
+    notes: usize,
+    /// Whether the text changes the state of the following notes, e.g. with `\deck`
+    stateful: bool,
+    /// Whether no command reaches past the end of the text, e.g. a `\next` in a field
+    /// ends the note, but not the field
+    clean: bool,
+    /// State at the start of the text
+    state: CarriedState,
+}
+
+/// Parses the notes in `range` of the document body `content`, starting with `state`.
+///
+/// `positions` are the positions of the body in the file. `range` has to start at the
+/// start of a note. `first_note` is the index of its first
+/// note in the file. Returns the notes with the spans of their text.
+fn parse_body(
+    content: &str,
+    range: Range<usize>,
+    options: &ParseOptions,
+    positions: FilePositions,
+    state: CarriedState,
+    first_note: usize,
+    warnings: &mut Vec<ParseWarning>,
+) -> Result<(Vec<Note>, Vec<NoteSpan>)> {
+    let CarriedState {
+        deck: mut current_deck,
+        model: mut current_model,
+        shared: mut shared_fields,
+        implicit: mut implicit_enabled,
+    } = state;
+    let mut spans = Vec::new();
+    // start and first note of the current span and whether it changed the carried state
+    let mut span_start = range.start;
+    let mut span_first = 0;
+    let mut stateful = false;
+    let mut span_state = CarriedState {
+        deck: current_deck.clone(),
+        model: current_model.clone(),
+        shared: shared_fields.clone(),
+        implicit: implicit_enabled,
+    };
+    let mut current_tags = Vec::new();
+    let mut current_fields: HashMap<String, String> = HashMap::new();
+    // start of the command which set the field
+    let mut current_field_starts: HashMap<String, usize> = HashMap::new();
+    let mut current_formats = HashMap::new();
+    let mut completed_notes = Vec::new();
+    // text of the note outside of commands, before and after `\sep`
+    let mut implicit_text = [String::new(), String::new()];
+    // start of the first text and of `\sep`
+    let mut implicit_start = None;
+    let mut sep_start = None;
+    let mut current_keep = false;
+    let mut current_options = NoteOptions::default();
+    // end of the commands so far, text before it is part of a command
+    let mut commands_end = range.start;
+
+    for CommandMatch {
+        span,
+        cmd,
+        args,
+        format,
+        ..
+    } in get_all_commands(
+        &content[range.clone()],
+        &options.commands,
+        positions.part(range.start),
+    )? {
+        let span = span.start + range.start..span.end + range.start;
+        let start = span.start;
+        let nested = start < commands_end;
+        if start > commands_end {
+            for comment in NOTE_OPTIONS.captures_iter(&content[commands_end..start]) {
+                let comment_start = commands_end + comment.get(0).unwrap().start();
+                let options = parse_note_options(&comment[1]).map_err(|e| {
+                    ParseError::new(
+                        Code::InvalidNoteOptions,
+                        positions.offset(comment_start),
+                        format!("invalid note options: {}", e),
+                    )
+                    .in_note(first_note + completed_notes.len())
+                })?;
+                for key in options.unknown() {
+                    warnings.push(ParseWarning::UnknownNoteOption {
+                        key: key.to_owned(),
+                        line: positions.line(comment_start),
+                    });
+                }
+                current_options.merge(options);
+            }
+        }
+        if implicit_enabled && start > commands_end {
+            let text = strip_comments(&content[commands_end..start]);
+            if let Some(offset) = text.find(|c: char| !c.is_whitespace()) {
+                implicit_start.get_or_insert(commands_end + offset);
+            }
+            implicit_text[usize::from(sep_start.is_some())].push_str(&text);
+        }
+        commands_end = commands_end.max(span.end);
+        if matches!(
+            cmd,
+            Cmd::Deck | Cmd::Model | Cmd::Shared | Cmd::ImplicitFields
+        ) {
+            stateful = true;
+        }
+        match cmd {
+            Cmd::Deck | Cmd::Model => {
+                let (command, current) = match cmd {
+                    Cmd::Deck => ("deck", &mut current_deck),
+                    _ => ("model", &mut current_model),
+                };
+                let value = match cmd {
+                    Cmd::Deck => {
+                        let separator = options.deck_separator.as_deref();
+                        let (deck, mixed) = anki_deck_name(&args[0], separator);
+                        if mixed {
+                            warnings.push(ParseWarning::MixedDeckSeparators {
+                                deck: args[0].to_string(),
+                                line: positions.line(start),
+                            });
+                        }
+                        deck
+                    }
+                    _ => args[0].to_string(),
+                };
+                // fields set by the same command, e.g. a note template, come first
+                let first_field = current_field_starts
+                    .iter()
+                    .filter(|(_, field_start)| **field_start < start)
+                    .min_by_key(|(_, field_start)| **field_start);
+                if let Some((field, field_start)) = first_field {
+                    if current.as_ref().is_some_and(|current| *current != value) {
+                        let warning = ParseWarning::MidNoteSwitch {
+                            switch: format!("\\{}{{{}}}", command, value),
+                            line: positions.line(start),
+                            field: field.clone(),
+                            field_line: positions.line(*field_start),
+                        };
+                        match options.on_mid_note_switch {
+                            OnMidNoteSwitch::Error => {
+                                return Err(color_eyre::Report::from(
+                                    ParseError::new(
+                                        Code::MidNoteSwitch,
+                                        positions.offset(start),
+                                        warning.to_string(),
+                                    )
+                                    .in_note(first_note + completed_notes.len()),
+                                )
+                                .note(format!(
+                                    "the {} of a note is the one set when it ends with `\\next`",
+                                    command
+                                ))
+                                .suggestion(format!(
+                                    "move `\\{}` before the fields or after `\\next`, or set `on_mid_note_switch = \"warn\"` in the config",
+                                    command
+                                )));
+                            }
+                            OnMidNoteSwitch::Warn => warnings.push(warning),
+                        }
+                    }
+                }
+                *current = Some(value);
+            }
+            Cmd::Tag => {
+                let new = args[0].to_string();
+                if current_tags.contains(&new) {
+                    return Err(ParseError::new(
+                        Code::DuplicateTag,
+                        positions.offset(start),
+                        format!("Can't add tag {} multiple times", new),
+                    )
+                    .in_note(first_note + completed_notes.len())
+                    .into());
+                }
+                current_tags.push(new);
+            }
+            Cmd::Field => {
+                let name = args[0].to_string();
+                let value = args[1].to_string();
+                if let Some(old_value) = current_fields.get(&name) {
+                    let old_start = current_field_starts[&name];
+                    let describe = |start: usize, value: &str| {
+                        format!(
+                            "{} at line {}: {:?}",
+                            field_syntax(content, start),
+                            positions.line(start),
+                            value.trim().chars().take(40).collect::<String>(),
+                        )
+                    };
+                    let first = describe(old_start, old_value);
+                    let second = describe(start, &value);
+                    match options.on_duplicate_field {
+                        OnDuplicateField::Error => {
+                            return Err(color_eyre::Report::from(
+                                ParseError::new(
+                                    Code::DuplicateField,
+                                    positions.offset(start),
+                                    format!("Field `{}` was already added", name),
+                                )
+                                .in_note(first_note + completed_notes.len()),
+                            )
+                                .note(format!("first set with {}", first))
+                                .note(format!("then set with {}", second))
+                                .suggestion(
+                                    "set `on_duplicate_field` in the config or use `--prefer-last` to keep one of the values",
+                                ));
+                        }
+                        OnDuplicateField::First => {
+                            warnings.push(ParseWarning::DuplicateField {
+                                name,
+                                kept: first,
+                                ignored: second,
+                                line: positions.line(start),
+                            });
+                            continue;
+                        }
+                        OnDuplicateField::Last => {
+                            warnings.push(ParseWarning::DuplicateField {
+                                name: name.clone(),
+                                kept: second,
+                                ignored: first,
+                                line: positions.line(start),
+                            });
+                        }
+                    }
+                }
+                current_field_starts.insert(name.clone(), start);
+                match format {
+                    Some(format) => current_formats.insert(name.clone(), format),
+                    None => current_formats.remove(&name),
+                };
+                current_fields.insert(name, value);
+            }
+            Cmd::Shared => {
+                let name = args[0].to_string();
+                match args.get(1).filter(|value| !value.is_empty()) {
+                    Some(value) => shared_fields.insert(name, value.to_string()),
+                    None => shared_fields.remove(&name),
+                };
+            }
+            Cmd::ImplicitFields => implicit_enabled = true,
+            Cmd::Keep => current_keep = true,
+            // e.g. in a field environment, where it is part of the content
+            Cmd::Sep if nested || !implicit_enabled => {}
+            Cmd::Sep => {
+                if let Some(first) = sep_start {
+                    return Err(color_eyre::Report::from(
+                        ParseError::new(
+                            Code::MisplacedSep,
+                            positions.offset(start),
+                            String::from("`\\sep` is used multiple times in the note"),
+                        )
+                        .in_note(first_note + completed_notes.len()),
+                    )
+                    .note(format!(
+                        "the first `\\sep` is at line {}",
+                        positions.line(first)
+                    )));
+                }
+                sep_start = Some(start);
+            }
+            Cmd::Next | Cmd::NoteList => {
+                let implicit =
+                    std::mem::take(&mut implicit_text).map(|text| text.trim().to_owned());
+                let text_start = implicit_start.take().or(sep_start.take());
+                sep_start = None;
+                let implicit_fields = [
+                    &options.implicit_fields.first,
+                    &options.implicit_fields.second,
+                ]
+                .into_iter()
+                .zip(implicit)
+                .filter(|(_, text)| !text.is_empty());
+                if let Some(text_start) = text_start {
+                    // field commands disable the implicit fields of the note
+                    if current_fields.is_empty() && cmd == Cmd::Next {
+                        for (name, text) in implicit_fields {
+                            current_field_starts.insert(name.clone(), text_start);
+                            current_fields.insert(name.clone(), text);
+                        }
+                    } else if let Some((name, _)) = implicit_fields
+                        .into_iter()
+                        .find(|(name, _)| current_fields.contains_key(*name))
+                    {
+                        let field_start = current_field_starts[name];
+                        return Err(color_eyre::Report::from(
+                            ParseError::new(
+                                Code::ImplicitFieldConflict,
+                                positions.offset(field_start),
+                                format!(
+                                    "Field `{}` is set by a field command and by the text of the note",
+                                    name
+                                ),
+                            )
+                            .in_note(first_note + completed_notes.len()),
+                        )
+                        .note(format!(
+                            "the field is set with {} at line {}",
+                            field_syntax(content, field_start),
+                            positions.line(field_start)
+                        ))
+                        .note(format!(
+                            "the text starts at line {}",
+                            positions.line(text_start)
+                        ))
+                        .suggestion("remove the text or move it into the field command"));
+                    }
+                }
+
+                let deck = match current_deck.clone() {
+                    Some(deck) => deck,
+                    None if options.allow_missing_deck => String::new(),
+                    None => {
+                        return Err(ParseError::new(
+                            Code::MissingDeck,
+                            positions.offset(start),
+                            String::from("Select a deck before ending a note"),
+                        )
+                        .in_note(first_note + completed_notes.len())
+                        .into())
+                    }
+                };
+                let model = match current_model.clone() {
+                    Some(model) => model,
+                    None if options.allow_missing_model => String::new(),
+                    None => {
+                        return Err(ParseError::new(
+                            Code::MissingModel,
+                            positions.offset(start),
+                            String::from("Select a model before ending a note"),
+                        )
+                        .in_note(first_note + completed_notes.len())
+                        .into())
+                    }
+                };
+                // the shared field and the field of the items of a notelist
+                let list_fields = (cmd == Cmd::NoteList).then(|| {
+                    let item_field = match args[0].as_ref() {
+                        "" => options.note_list.item_field.clone(),
+                        field => field.to_owned(),
+                    };
+                    (options.note_list.shared_field.clone(), item_field)
+                });
+                if let Some((shared_field, item_field)) = &list_fields {
+                    if let Some(name) = [shared_field, item_field]
+                        .into_iter()
+                        .find(|name| current_fields.contains_key(*name))
+                    {
+                        return Err(ParseError::new(
+                            Code::DuplicateField,
+                            positions.offset(start),
+                            format!("Field `{}` is already set before the notelist", name),
+                        )
+                        .in_note(first_note + completed_notes.len())
+                        .into());
+                    }
+                    current_fields.insert(shared_field.clone(), args[1].to_string());
+                    current_fields.insert(item_field.clone(), String::new());
+                }
+                if current_fields.is_empty() {
+                    return Err(ParseError::new(
+                        Code::EmptyNote,
+                        positions.offset(start),
+                        String::from("Cannot add note without fields"),
+                    )
+                    .in_note(first_note + completed_notes.len())
+                    .into());
+                }
+                let tags = std::mem::take(&mut current_tags);
+                let note_options = std::mem::take(&mut current_options);
+                let protected = std::mem::take(&mut current_keep) || note_options.keep();
+                let mut fields = std::mem::take(&mut current_fields);
+                for (name, value) in &shared_fields {
+                    fields.entry(name.clone()).or_insert_with(|| value.clone());
+                }
+                current_field_starts.clear();
+                let mut formats = std::mem::take(&mut current_formats);
+                for (name, format) in note_options.formats() {
+                    if fields.contains_key(&name) {
+                        formats.entry(name).or_insert(format);
+                    }
+                }
+                if let Some(model_formats) = options.field_formats.get(&model) {
+                    for name in fields.keys() {
+                        if let Some(format) = model_formats.get(name) {
+                            formats.entry(name.clone()).or_insert(*format);
+                        }
+                    }
+                }
+                formats.retain(|_, format| *format != FieldFormat::Latex);
+                let note = |fields| Note {
+                    id: None,
+                    deck: deck.clone(),
+                    model: model.clone(),
+                    fields,
+                    tags: tags.clone(),
+                    first_card: None,
+                    mod_time: None,
+                    card_ords: Vec::new(),
+                    card_decks: Vec::new(),
+                    formats: formats.clone(),
+                    protected,
+                    options: note_options.clone(),
+                };
+                match list_fields {
+                    Some((shared_field, item_field)) => {
+                        let items = &args[2..];
+                        for (i, item) in items.iter().enumerate() {
+                            let mut fields = fields.clone();
+                            fields.insert(
+                                shared_field.clone(),
+                                number_item(&args[1], i + 1, items.len()),
+                            );
+                            fields.insert(item_field.clone(), item.to_string());
+                            completed_notes.push(note(fields));
+                        }
+                    }
+                    None => completed_notes.push(note(fields)),
+                }
+                let next_state = CarriedState {
+                    deck: current_deck.clone(),
+                    model: current_model.clone(),
+                    shared: shared_fields.clone(),
+                    implicit: implicit_enabled,
+                };
+                spans.push(NoteSpan {
+                    span: span_start..span.end,
+                    notes: completed_notes.len() - span_first,
+                    stateful: std::mem::take(&mut stateful),
+                    clean: commands_end == span.end,
+                    state: std::mem::replace(&mut span_state, next_state),
+                });
+                span_start = span.end;
+                span_first = completed_notes.len();
+            }
+        }
+    }
+
+    if !current_fields.is_empty() || !current_tags.is_empty() {
+        let mut fields = current_fields.into_keys().collect::<Vec<_>>();
+        fields.sort();
+        warnings.push(ParseWarning::UnfinishedNote {
+            fields,
+            line: current_field_starts
+                .values()
+                .min()
+                .map(|start| positions.line(*start)),
+        });
+    }
+
+    Ok((completed_notes, spans))
+}
+
+/// Where the notes of a parsed file are, to parse only the changed notes of the next
+/// version with [`parse_changed`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, Deserialize)]
+pub struct ParseIndex {
+    /// Hash of the header and footer
+    frame: String,
+    /// Length of the document body in bytes
+    len: usize,
+    /// Hashes of the lines of the document body
+    lines: Vec<u64>,
+    spans: Vec<NoteSpan>,
+}
+
+impl ParseIndex {
+    /// Number of notes in the file
+    pub fn notes(&self) -> usize {
+        self.spans.iter().map(|span| span.notes).sum()
+    }
+}
+
+/// Result of [`parse_changed`]
+#[derive(Debug, Clone)]
+pub struct ChangedNotes {
+    /// The parsed notes, all notes if the file was parsed completely
+    pub outcome: ParseOutcome,
+    /// Index of the first parsed note in the file
+    pub first_note: usize,
+    /// Number of notes which weren't parsed because they didn't change
+    pub skipped: usize,
+    /// Index of the new content, for the next call
+    pub index: ParseIndex,
+}
+
+/// Whether the commands in `range` of `content` are parsed the same in the whole
+/// content, i.e. no command, group or comment reaches into or out of `range`.
+///
+/// Environments which aren't closed in `range` are errors when parsing it.
+fn is_closed_region(content: &str, range: Range<usize>) -> bool {
+    let line_start = |pos: usize| content[..pos].rfind('\n').map_or(0, |i| i + 1);
+    // commands match the first `}` after an argument and other commands the matching
+    // one, both are in `range` if all groups are closed in it
+    let balanced = |text: &str, skip_escaped: bool| {
+        let bytes = text.as_bytes();
+        let mut depth = 0usize;
+        let mut pos = 0;
+        while pos < bytes.len() {
+            match bytes[pos] {
+                b'\\' if skip_escaped => pos += 1,
+                b'{' => depth += 1,
+                b'}' => match depth.checked_sub(1) {
+                    Some(d) => depth = d,
+                    None => return false,
+                },
+                _ => {}
+            }
+            pos += 1;
+        }
+        depth == 0
+    };
+    let text = &content[range.clone()];
+    // verbatim hides the text up to its end from the environments
+    !content.contains(VERBATIM_BEGIN)
+        && !content[line_start(range.start)..range.start].contains('%')
+        && !content[line_start(range.end).max(range.start)..range.end].contains('%')
+        && content[..range.start]
+            .rfind(['{', '}'])
+            .is_none_or(|i| &content[i..=i] == "}")
+        && balanced(text, false)
+        && balanced(text, true)
+}
+
+fn frame_hash(frame: &DocumentFrame) -> String {
+    let text = format!("{}\0{}", frame.header, frame.footer);
+    format!("{:016x}", fasthash::metro::hash64(text))
+}
+
+fn line_hashes(content: &str) -> Vec<u64> {
+    content
+        .split_inclusive('\n')
+        .map(fasthash::metro::hash64)
+        .collect()
+}
+
+/// Parses the notes of the file with the content `content` which changed since it was
+/// parsed with the result `previous`.
+///
+/// The changed lines are found by comparing the line hashes with `previous`. Only the
+/// notes containing them are parsed, starting with the state `previous` recorded for
+/// the first of them. The file is parsed completely without `previous`, if the header
+/// or footer changed, if the changes are outside of the notes or if the changed notes
+/// set a state used by the following notes, like `\deck`.
+pub fn parse_changed(
+    content: String,
+    options: &ParseOptions,
+    previous: Option<&ParseIndex>,
+) -> Result<ChangedNotes> {
+    let mut warnings = Vec::new();
+    let (body, map) = prepare_content(&content, &options.frame, &mut warnings)?;
+    let positions = FilePositions::new(&content, &map);
+    let frame = frame_hash(&options.frame);
+    let lines = line_hashes(&body);
+
+    if let Some(previous) = previous.filter(|previous| previous.frame == frame) {
+        if let Some(mut changed) = parse_changed_notes(&body, &lines, options, positions, previous)
+        {
+            warnings.append(&mut changed.outcome.warnings);
+            changed.outcome.warnings = warnings;
+            return Ok(changed);
+        }
+        debug!("the structure of the file changed, parsing all notes");
+    }
+
+    let (notes, spans) = parse_body(
+        &body,
+        0..body.len(),
+        options,
+        positions,
+        CarriedState::new(options),
+        0,
+        &mut warnings,
+    )?;
+    if notes.is_empty() {
+        warnings.push(ParseWarning::NoNotes);
+    }
+    Ok(ChangedNotes {
+        outcome: ParseOutcome { notes, warnings },
+        first_note: 0,
+        skipped: 0,
+        index: ParseIndex {
+            frame,
+            len: body.len(),
+            lines,
+            spans,
+        },
+    })
+}
+
+/// Parses the notes of `content` which changed since `previous`, see [`parse_changed`].
+///
+/// Returns `None` if the file has to be parsed completely.
+fn parse_changed_notes(
+    content: &str,
+    lines: &[u64],
+    options: &ParseOptions,
+    positions: FilePositions,
+    previous: &ParseIndex,
+) -> Option<ChangedNotes> {
+    let prefix = previous
+        .lines
+        .iter()
+        .zip(lines)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = previous.lines[prefix..]
+        .iter()
+        .rev()
+        .zip(lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let line_start =
+        |line: usize| -> usize { content.split_inclusive('\n').take(line).map(str::len).sum() };
+    // the changed bytes are `start..old_end` before and `start..new_end` now
+    let start = line_start(prefix);
+    let suffix_len = content.len() - line_start(lines.len() - suffix);
+    let old_end = previous.len.checked_sub(suffix_len)?;
+    let new_end = content.len() - suffix_len;
+
+    if start == old_end && start == new_end {
+        return Some(ChangedNotes {
+            outcome: ParseOutcome::default(),
+            first_note: previous.notes(),
+            skipped: previous.notes(),
+            index: previous.clone(),
+        });
+    }
+
+    // text inserted between two notes belongs to the second one
+    let touches = |span: &&NoteSpan| {
+        if start < old_end {
+            span.span.start < old_end && start < span.span.end
+        } else {
+            span.span.contains(&start)
+        }
+    };
+    let first = previous.spans.iter().position(|span| touches(&span))?;
+    let last = previous.spans.iter().rposition(|span| touches(&span))?;
+    let changed = &previous.spans[first..=last];
+    let region_start = changed[0].span.start;
+    let old_region_end = changed[changed.len() - 1].span.end;
+    if old_end > old_region_end || changed.iter().any(|span| span.stateful) {
+        return None;
+    }
+    let region_end = (old_region_end + content.len()).checked_sub(previous.len)?;
+    let after_previous = first.checked_sub(1).map(|i| &previous.spans[i]);
+    if after_previous.is_some_and(|span| !span.clean)
+        || !is_closed_region(content, region_start..region_end)
+    {
+        return None;
+    }
+
+    let first_note = previous.spans[..first].iter().map(|span| span.notes).sum();
+    let mut warnings = Vec::new();
+    let (notes, spans) = parse_body(
+        content,
+        region_start..region_end,
+        options,
+        positions,
+        changed[0].state.clone(),
+        first_note,
+        &mut warnings,
+    )
+    .ok()?;
+    // e.g. a removed `\next` merges the last note with the following one
+    let parsed_end = spans.last().map_or(region_start, |span| span.span.end);
+    if parsed_end != region_end || spans.iter().any(|span| span.stateful) {
+        return None;
+    }
+
+    let changed_notes = changed.iter().map(|span| span.notes).sum::<usize>();
+    let skipped = previous.notes() - changed_notes;
+    debug!(
+        "parsed {} changed notes, skipped {} notes",
+        notes.len(),
+        skipped
+    );
+    let following = previous.spans[last + 1..].iter().map(|span| NoteSpan {
+        span: span.span.start + region_end - old_region_end
+            ..span.span.end + region_end - old_region_end,
+        ..span.clone()
+    });
+    let spans = previous.spans[..first]
+        .iter()
+        .cloned()
+        .chain(spans)
+        .chain(following)
+        .collect();
+    Some(ChangedNotes {
+        outcome: ParseOutcome { notes, warnings },
+        first_note,
+        skipped,
+        index: ParseIndex {
+            frame: previous.frame.clone(),
+            len: content.len(),
+            lines: lines.to_vec(),
+            spans,
+        },
+    })
+}
+
+fn get_longest_common_prefix(a: &str, b: &str) -> Option<usize> {
+    for (i, (c, d)) in a.chars().zip(b.chars()).enumerate() {
+        if c != d {
+            return Some(i);
+        }
+    }
+    None
+}
+
+fn get_line_with_pos(text: &str, pos: usize) -> &str {
+    let mut offset = 0;
+    for line in text.lines() {
+        offset += line.len();
+        if offset >= pos {
+            return line;
+        }
+    }
+    ""
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `body` with the default header and footer
+    fn document(body: &str) -> String {
+        format!("{}{}\n{}", HEADER, body, FOOTER)
+    }
+
+    fn parse(body: &str) -> Result<ParseOutcome> {
+        parse_content(document(body), &ParseOptions::default())
+    }
+
+    fn error_code<T>(result: Result<T>) -> Code {
+        let Err(e) = result else {
+            panic!("expected an error");
+        };
+        find_parse_error(&e)
+            .unwrap_or_else(|| panic!("no parse error in {:?}", e))
+            .code
+    }
+
+    fn field_envs(text: &str) -> Result<Vec<FieldEnv<'_>>> {
+        find_field_envs(text, FilePositions::unmapped(text))
+    }
+
+    fn field_bodies(text: &str) -> Vec<(&str, &str)> {
+        field_envs(text)
+            .unwrap()
+            .into_iter()
+            .map(|env| (env.name, env.body))
+            .collect()
+    }
+
+    #[test]
+    fn field_env_with_nested_environments() {
+        let align = r"\begin{align}a &= b \\ c &= d\end{align}";
+        let enumerate = "\\begin{enumerate}\n\\item a\n\\item b\n\\end{enumerate}";
+        let tikz = r"\begin{tikzpicture}\draw (0,0) -- (1,1);\end{tikzpicture}";
+        let text = format!(
+            "\\begin{{field}}{{Front}}{}\\end{{field}}\n\
+             \\begin{{field}}{{Back}}{}\\end{{field}}\n\
+             \\begin{{field}}{{Extra}}{}\\end{{field}}",
+            align, enumerate, tikz
+        );
+        assert_eq!(
+            field_bodies(&text),
+            [("Front", align), ("Back", enumerate), ("Extra", tikz)]
+        );
+    }
+
+    #[test]
+    fn field_env_with_commented_end() {
+        let body = "a\n% \\end{field} is commented out\nb\n";
+        let text = format!(
+            "\\begin{{field}}{{Front}}{}\\end{{field}}\\begin{{field}}{{Back}}c\\end{{field}}",
+            body
+        );
+        assert_eq!(field_bodies(&text), [("Front", body), ("Back", "c")]);
+    }
+
+    #[test]
+    fn field_env_with_end_in_verbatim() {
+        let body = r"\begin{verbatim}\end{field}\end{verbatim}";
+        let text = format!(r"\begin{{field}}{{Front}}{}\end{{field}}", body);
+        assert_eq!(field_bodies(&text), [("Front", body)]);
+    }
+
+    #[test]
+    fn field_env_escaped_percent_is_no_comment() {
+        let text = r"\begin{field}{Front}50\% \end{field}";
+        assert_eq!(field_bodies(text), [("Front", r"50\% ")]);
+    }
+
+    #[test]
+    fn field_env_errors() {
+        let nested = "\\begin{field}{Front}\n\\begin{field}{Back}b\\end{field}\\end{field}";
+        let e = field_envs(nested).err().unwrap();
+        let error = find_parse_error(&e).unwrap();
+        assert_eq!(error.code, Code::NestedFieldEnv);
+        assert_eq!(error.pos, Some(nested.find("\n").unwrap() + 1));
+
+        assert_eq!(
+            error_code(field_envs(r"\begin{field}{Front}a")),
+            Code::UnclosedFieldEnv
+        );
+        assert_eq!(
+            error_code(field_envs(r"a\end{field}")),
+            Code::UnmatchedFieldEnd
+        );
+        assert_eq!(
+            error_code(field_envs(r"\begin{field}a\end{field}")),
+            Code::FieldEnvWithoutName
+        );
+    }
+
+    #[test]
+    fn field_env_bodies_in_notes() {
+        let outcome = parse(
+            "\\deck{A}\\model{Basic}\n\
+             \\begin{field}{Front}\\begin{align}x\\end{align}\\end{field}\n\
+             \\begin{field}{Back}% \\end{field}\ny\\end{field}\n\
+             \\next",
+        )
+        .unwrap();
+        assert_eq!(outcome.notes.len(), 1);
+        let fields = &outcome.notes[0].fields;
+        assert_eq!(fields["Front"], r"\begin{align}x\end{align}");
+        assert_eq!(fields["Back"], "% \\end{field}\ny");
+    }
+
+    #[test]
+    fn unfinished_note_warning() {
+        let outcome = parse(
+            "\\deck{A}\\model{Basic}\n\
+             \\fields{Front}{x}\\fields{Back}{y}\\next\n\
+             \\fields{Front}{z}\n\
+             \\tag{t}",
+        )
+        .unwrap();
+        assert_eq!(outcome.notes.len(), 1);
+        assert_eq!(
+            outcome.warnings,
+            vec![ParseWarning::UnfinishedNote {
+                fields: vec![String::from("Front")],
+                line: Some(8),
+            }]
+        );
+        assert_eq!(outcome.warnings[0].class(), WarningClass::UnfinishedNote);
+    }
+
+    #[test]
+    fn unfinished_note_with_only_tags_has_no_line() {
+        let outcome = parse("\\deck{A}\\model{Basic}\n\\tag{t}").unwrap();
+        assert_eq!(
+            outcome.warnings,
+            vec![
+                ParseWarning::UnfinishedNote {
+                    fields: Vec::new(),
+                    line: None,
+                },
+                ParseWarning::NoNotes,
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_body_warning() {
+        let outcome = parse("").unwrap();
+        assert!(outcome.notes.is_empty());
+        assert_eq!(outcome.warnings, vec![ParseWarning::NoNotes]);
+        assert_eq!(outcome.warnings[0].class(), WarningClass::NoNotes);
+        // the compatibility wrapper only returns the notes
+        assert!(get_content(document(""), &ParseOptions::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Whether both files give the same notes
+    fn same_notes(a: &str, b: &str) {
+        let a = parse_content(a.to_owned(), &ParseOptions::default()).unwrap();
+        let b = parse_content(b.to_owned(), &ParseOptions::default()).unwrap();
+        assert_eq!(a.notes.len(), b.notes.len());
+        for (a, b) in a.notes.iter().zip(&b.notes) {
+            assert_eq!(
+                (&a.deck, &a.model, &a.fields, &a.tags),
+                (&b.deck, &b.model, &b.fields, &b.tags)
+            );
+        }
+    }
+
+    const LINT_BODY: &str = "\\deck{A}\\model{Basic}\n\
+                             \\fields{Front}{a}\\fields{Back}{b}\\tag{x}\\next\n\
+                             \\deck{A}\n\
+                             \\fields{Front}{c}\\fields{Back}{d}\\tag{x}\\tag{y}\\next\n\
+                             \\deck{A} \\model{Basic} % same as before\n\
+                             \\fields{Front}{e}\\fields{Back}{f}\\tag{x}\\next\n\
+                             \\deck{B}\\model{Basic}\n\
+                             \\fields{Front}{g}\\fields{Back}{h}\\next\n";
+
+    #[test]
+    fn lint_finds_redundant_commands() {
+        let content = document(LINT_BODY);
+        let lint = lint_content(&content, &ParseOptions::default()).unwrap();
+        let redundant = lint
+            .redundant
+            .iter()
+            .map(|r| (r.command, r.value.as_str(), r.line))
+            .collect::<Vec<_>>();
+        // the body starts in line 6
+        assert_eq!(
+            redundant,
+            [
+                ("deck", "A", 8),
+                ("deck", "A", 10),
+                ("model", "Basic", 10),
+                ("model", "Basic", 12),
+            ]
+        );
+        for r in &lint.redundant {
+            assert!(content[r.span.clone()].starts_with(&format!("\\{}{{", r.command)));
+        }
+        assert_eq!(lint.notes, 4);
+        assert!(lint.common_tags.is_empty());
+        assert_eq!(lint.untagged, [13]);
+    }
+
+    #[test]
+    fn lint_finds_common_tags() {
+        let content = document(
+            "\\deck{A}\\model{Basic}\n\
+             \\fields{Front}{a}\\fields{Back}{b}\\tag{x}\\tag{y}\\next\n\
+             \\fields{Front}{c}\\fields{Back}{d}\\tag{y}\\tag{x}\\next\n",
+        );
+        let lint = lint_content(&content, &ParseOptions::default()).unwrap();
+        assert_eq!(lint.common_tags, ["x", "y"]);
+        assert!(lint.redundant.is_empty());
+        assert!(lint.untagged.is_empty());
+    }
+
+    #[test]
+    fn lint_fix_keeps_other_bytes_and_notes() {
+        let content = document(LINT_BODY);
+        let lint = lint_content(&content, &ParseOptions::default()).unwrap();
+        let fixed = lint.fix(&content);
+        assert_eq!(
+            fixed,
+            document(
+                "\\deck{A}\\model{Basic}\n\
+                 \\fields{Front}{a}\\fields{Back}{b}\\tag{x}\\next\n\
+                 \\fields{Front}{c}\\fields{Back}{d}\\tag{x}\\tag{y}\\next\n\
+                 \x20 % same as before\n\
+                 \\fields{Front}{e}\\fields{Back}{f}\\tag{x}\\next\n\
+                 \\deck{B}\n\
+                 \\fields{Front}{g}\\fields{Back}{h}\\next\n"
+            )
+        );
+        same_notes(&content, &fixed);
+        // fixing again changes nothing
+        let lint = lint_content(&fixed, &ParseOptions::default()).unwrap();
+        assert!(lint.redundant.is_empty());
+        assert_eq!(lint.fix(&fixed), fixed);
+    }
+
+    #[test]
+    fn lint_keeps_commands_in_fields() {
+        let content = document(
+            "\\deck{A}\\model{Basic}\n\
+             \\fields{Front}{a}\n\
+             \\begin{field}{Back}\n\\deck{A}\n\\end{field}\\next\n",
+        );
+        let lint = lint_content(&content, &ParseOptions::default()).unwrap();
+        assert!(lint.redundant.is_empty());
+        assert_eq!(lint.fix(&content), content);
+    }
+
+    /// Parses `content`, formats the notes and parses them again.
+    fn roundtrip(content: &str, options: &ParseOptions, matching: &MatchOptions) {
+        let notes = parse_content(content.to_owned(), options).unwrap().notes;
+        assert!(!notes.is_empty());
+        let formatted = format_notes(&notes, options, matching).unwrap();
+        let reparsed = parse_content(formatted.clone(), options).unwrap().notes;
+        assert_eq!(notes.len(), reparsed.len(), "formatted:\n{}", formatted);
+        for (a, b) in notes.iter().zip(&reparsed) {
+            assert!(
+                a.matches(b, matching),
+                "{:?}\nchanged to\n{:?}\nformatted:\n{}",
+                a,
+                b,
+                formatted
+            );
+        }
+    }
+
+    #[test]
+    fn format_notes_roundtrip() {
+        let content = document(
+            "\\deck{A}\\model{Basic}\n\
+             \\fields{Front}{plain}\\fields{Back}{$x^2$}\\tag{x}\\next\n\
+             \\fields{Front}{braces {a} and \\% percent}\n\
+             \\begin{field}{Back}\n\\begin{align}\n  a &= b\n\\end{align}\n\\end{field}\n\
+             \\tag{x}\\tag{y}\\next\n\
+             \\deck{B::C}\\model{Cloze}\n\
+             \\fields{Text}{{{c1::a}}}\\fields{Back Extra}{}\\next\n\
+             \\begin{field}{Text}% \\end{field}\n{{c1::b}}\\end{field}\\next\n",
+        );
+        roundtrip(&content, &ParseOptions::default(), &MatchOptions::default());
+    }
+
+    #[test]
+    fn format_notes_roundtrip_with_options() {
+        let options = ParseOptions {
+            deck_separator: Some(String::from("/")),
+            ..ParseOptions::default()
+        };
+        let matching = MatchOptions {
+            generated_tag: Some(String::from("generated")),
+            ..MatchOptions::default()
+        };
+        let content = document(
+            "\\deck{A/B}\\model{Basic}\n\
+             \\fields{Front}{a}\\fields{Back}{b}\\tag{generated}\\tag{x}\\next\n",
+        );
+        roundtrip(&content, &options, &matching);
+
+        let notes = parse_content(content, &options).unwrap().notes;
+        assert_eq!(notes[0].deck, "A::B");
+        let formatted = format_notes(&notes, &options, &matching).unwrap();
+        assert!(formatted.contains("\\deck{A/B}"));
+        assert!(!formatted.contains("generated"));
+    }
+
+    #[test]
+    fn format_notes_rejects_end_of_field_in_value() {
+        let mut notes = parse("\\deck{A}\\model{Basic}\\fields{Front}{a}\\next")
+            .unwrap()
+            .notes;
+        notes[0]
+            .fields
+            .insert(String::from("Back"), String::from("x\\end{field}y"));
+        assert!(format_notes(&notes, &ParseOptions::default(), &MatchOptions::default()).is_err());
+    }
+
+    const OCCLUSION: &str =
+        r#"{"shapes": [{"x": 1, "y": 2.5}], "label": "a\\b <i>&amp;</i> [latex]x[/latex]"}"#;
+
+    #[test]
+    fn raw_field_is_kept_byte_for_byte() {
+        let outcome = parse(&format!(
+            "\\deck{{A}}\\model{{Image Occlusion}}\n\
+             \\fields{{Header}}{{x}}\n\
+             \\begin{{field}}[raw]{{Occlusion}}{}\\end{{field}}\\next",
+            OCCLUSION
+        ))
+        .unwrap();
+        let note = &outcome.notes[0];
+        assert_eq!(note.fields["Occlusion"], OCCLUSION);
+        assert_eq!(note.format("Occlusion"), FieldFormat::Raw);
+        assert_eq!(note.format("Header"), FieldFormat::Latex);
+
+        let mut normalized = note.clone();
+        normalized.normalize_unicode();
+        assert_eq!(normalized.fields["Occlusion"], OCCLUSION);
+
+        let formatted = format_notes(
+            &outcome.notes,
+            &ParseOptions::default(),
+            &MatchOptions::default(),
+        )
+        .unwrap();
+        let reparsed = parse_content(formatted, &ParseOptions::default())
+            .unwrap()
+            .notes;
+        assert_eq!(reparsed[0].fields["Occlusion"], OCCLUSION);
+        assert_eq!(reparsed[0].formats, note.formats);
+    }
+
+    #[test]
+    fn raw_field_format_from_options() {
+        let options = ParseOptions {
+            field_formats: HashMap::from([(
+                String::from("Image Occlusion"),
+                HashMap::from([(String::from("Occlusion"), FieldFormat::Raw)]),
+            )]),
+            ..ParseOptions::default()
+        };
+        let notes = parse_content(
+            document(&format!(
+                "\\deck{{A}}\\model{{Image Occlusion}}\n\
+                 \\begin{{field}}{{Occlusion}}{}\\end{{field}}\\next\n\
+                 \\model{{Basic}}\\begin{{field}}{{Occlusion}}{}\\end{{field}}\\next",
+                OCCLUSION, OCCLUSION
+            )),
+            &options,
+        )
+        .unwrap()
+        .notes;
+        assert_eq!(notes[0].format("Occlusion"), FieldFormat::Raw);
+        assert_eq!(notes[1].format("Occlusion"), FieldFormat::Latex);
+    }
+
+    fn template_options() -> ParseOptions {
+        let template = NoteTemplate {
+            name: String::from("theorem"),
+            deck: Some(String::from("Uni::{course}")),
+            model: Some(String::from("Basic")),
+            tags: vec![String::from("theorem")],
+            fields: BTreeMap::from([(
+                String::from("Front"),
+                String::from("State the theorem: {title}"),
+            )]),
+        };
+        ParseOptions {
+            commands: CommandTable::new(&[], &[template]).unwrap(),
+            ..ParseOptions::default()
+        }
+    }
+
+    #[test]
+    fn usetemplate_instantiates_template() {
+        let notes = parse_content(
+            document(
+                "\\usetemplate{theorem}{title=Green's theorem, course=Analysis}\n\
+                 \\fields{Back}{proof}\\tag{exam}\\next\n\
+                 \\usetemplate{theorem}{course=Algebra, title={Lagrange, groups}}\n\
+                 \\fields{Back}{b}\\next",
+            ),
+            &template_options(),
+        )
+        .unwrap()
+        .notes;
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].deck, "Uni::Analysis");
+        assert_eq!(notes[0].model, "Basic");
+        assert_eq!(
+            notes[0].fields["Front"],
+            "State the theorem: Green's theorem"
+        );
+        assert_eq!(notes[0].fields["Back"], "proof");
+        assert_eq!(notes[0].tags, ["theorem", "exam"]);
+        assert_eq!(notes[1].deck, "Uni::Algebra");
+        assert_eq!(
+            notes[1].fields["Front"],
+            "State the theorem: Lagrange, groups"
+        );
+    }
+
+    #[test]
+    fn usetemplate_errors() {
+        let options = template_options();
+        let err = parse_content(
+            document("\n\\usetemplate{theorem}{title=x}\\next"),
+            &options,
+        )
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("`theorem`") && message.contains("`course`"));
+        // the body starts in line 6 of the file
+        assert!(message.contains("line 7"));
+        assert_eq!(
+            find_parse_error(&err).unwrap().code,
+            Code::MissingTemplateValue
+        );
+
+        let result = parse_content(document("\\usetemplate{lemma}{}\\next"), &options);
+        assert_eq!(error_code(result), Code::UnknownTemplate);
+        let result = parse_content(
+            document("\\usetemplate{theorem}{title=a, title=b}\\next"),
+            &options,
+        );
+        assert_eq!(error_code(result), Code::InvalidTemplateArgs);
+        let result = parse_content(document("\\usetemplate{theorem}{course}\\next"), &options);
+        assert_eq!(error_code(result), Code::InvalidTemplateArgs);
+        let result = parse_content(document("\\usetemplate{theorem}\\next"), &options);
+        assert_eq!(error_code(result), Code::InvalidTemplateUse);
+    }
+
+    #[test]
+    fn template_placeholder_escapes() {
+        let values = HashMap::from([("x", "v"), ("braces", "{a}")]);
+        let fill = |text| fill_placeholders(text, |key| values.get(key).copied());
+        assert_eq!(fill("{x} and {{x}}").unwrap(), "v and {x}");
+        assert_eq!(
+            fill("$\\frac{{1}}{{2}}$ {braces}").unwrap(),
+            "$\\frac{1}{2}$ {a}"
+        );
+        assert_eq!(fill("{y}").unwrap_err(), "y");
+        assert_eq!(fill("a } b").unwrap_err(), "}");
+        assert_eq!(fill("a { b").unwrap_err(), "{");
+
+        let values = parse_key_values(r"a={x, y}, b = \{z\}, c={{1}}, d=").unwrap();
+        assert_eq!(values["a"], "x, y");
+        assert_eq!(values["b"], r"\{z\}");
+        assert_eq!(values["c"], "{1}");
+        assert_eq!(values["d"], "");
+        // only a single group is unwrapped
+        assert_eq!(parse_key_values("a={x}{y}").unwrap()["a"], "{x}{y}");
+    }
+
+    #[test]
+    fn shared_fields() {
+        let outcome = parse(
+            "\\deck{A}\\model{Basic}\n\
+             \\shared{Back}{context}\\shared{Extra}{more}\n\
+             \\fields{Front}{1}\\next\n\
+             \\fields{Front}{2}\\fields{Back}{own}\\next\n\
+             \\fields{Front}{3}\\next\n\
+             \\clearshared{Back}\n\
+             \\fields{Front}{4}\\next\n\
+             \\shared{Extra}{}\n\
+             \\fields{Front}{5}\\next\n\
+             \\shared{Back}{again}\n",
+        )
+        .unwrap();
+        let fields = outcome
+            .notes
+            .iter()
+            .map(|note| {
+                let get = |name| note.fields.get(name).map(String::as_str);
+                (get("Front").unwrap(), get("Back"), get("Extra"))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                ("1", Some("context"), Some("more")),
+                // the explicit value overrides the shared one for this note only
+                ("2", Some("own"), Some("more")),
+                ("3", Some("context"), Some("more")),
+                ("4", None, Some("more")),
+                ("5", None, None),
+            ]
+        );
+        // shared values at the end are no unfinished note
+        assert!(outcome.warnings.is_empty(), "{:?}", outcome.warnings);
+    }
+
+    const MID_NOTE_SWITCH: &str = "\\deck{A}\\model{Basic}\n\
+                                   \\fields{Front}{a}\n\
+                                   \\model{Cloze}\\fields{Back}{b}\\next";
+
+    #[test]
+    fn mid_note_switch_is_an_error() {
+        let err = parse(MID_NOTE_SWITCH).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`\\model{Cloze}` (line 8) changes the note after its field `Front` (line 7)"
+        );
+        assert_eq!(find_parse_error(&err).unwrap().code, Code::MidNoteSwitch);
+        assert_eq!(
+            error_code(parse(
+                "\\deck{A}\\model{Basic}\\fields{Front}{a}\\deck{B}\\next"
+            )),
+            Code::MidNoteSwitch
+        );
+    }
+
+    #[test]
+    fn mid_note_switch_warning() {
+        let options = ParseOptions {
+            on_mid_note_switch: OnMidNoteSwitch::Warn,
+            ..ParseOptions::default()
+        };
+        let outcome = parse_content(document(MID_NOTE_SWITCH), &options).unwrap();
+        assert_eq!(
+            outcome.warnings,
+            [ParseWarning::MidNoteSwitch {
+                switch: String::from("\\model{Cloze}"),
+                line: 8,
+                field: String::from("Front"),
+                field_line: 7,
+            }]
+        );
+        // the new value is used
+        assert_eq!(outcome.notes[0].model, "Cloze");
+    }
+
+    #[test]
+    fn switch_between_notes_is_silent() {
+        let outcome = parse(
+            "\\deck{A}\\model{Basic}\n\
+             \\fields{Front}{a}\\fields{Back}{b}\\next\n\
+             \\deck{B}\\model{Cloze}\\fields{Text}{{{c1::x}}}\\next\n\
+             \\tag{t}\\deck{C}\\model{Basic}\\deck{D}\\fields{Front}{c}\\next\n\
+             \\deck{D}\\fields{Front}{d}\\deck{D}\\next",
+        )
+        .unwrap();
+        assert!(outcome.warnings.is_empty(), "{:?}", outcome.warnings);
+        let decks = outcome
+            .notes
+            .iter()
+            .map(|n| (n.deck.as_str(), n.model.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decks,
+            [
+                ("A", "Basic"),
+                ("B", "Cloze"),
+                ("D", "Basic"),
+                ("D", "Basic")
+            ]
+        );
+    }
+
+    const MIXED_MATH: &str = r"Let $a$ and $b$, then \[a^2 + b^2\] and $c$\[x\]";
+
+    #[test]
+    fn math_delimiters_in_mixed_field() {
+        assert_eq!(
+            convert_math_delimiters(MIXED_MATH, MathDelimiters::Keep),
+            MIXED_MATH
+        );
+        let dollars = convert_math_delimiters(MIXED_MATH, MathDelimiters::Dollars);
+        assert_eq!(dollars, r"Let $a$ and $b$, then $$a^2 + b^2$$ and $c$$$x$$");
+        // the inline math before `$$` isn't mistaken for display math
+        let brackets = convert_math_delimiters(&dollars, MathDelimiters::Brackets);
+        assert_eq!(brackets, MIXED_MATH);
+    }
+
+    #[test]
+    fn math_delimiters_are_stable() {
+        for delimiters in [
+            MathDelimiters::Keep,
+            MathDelimiters::Dollars,
+            MathDelimiters::Brackets,
+        ] {
+            let once = convert_math_delimiters(MIXED_MATH, delimiters);
+            assert_eq!(convert_math_delimiters(&once, delimiters), once);
+            // back and forth gives the same text
+            let there = convert_math_delimiters(&once, MathDelimiters::Dollars);
+            let back = convert_math_delimiters(&there, MathDelimiters::Brackets);
+            assert_eq!(
+                convert_math_delimiters(&back, MathDelimiters::Dollars),
+                there
+            );
+        }
+    }
+
+    #[test]
+    fn dollar_math_for_markdown() {
+        assert_eq!(
+            dollar_math(r"\(a\) and \[b\] with $c$"),
+            r"$a$ and $$b$$ with $c$"
+        );
+        let kept = r"a\\[2pt] b \\(c) \verb|\(x\)| \(unpaired";
+        assert_eq!(dollar_math(kept), kept);
+    }
+
+    #[test]
+    fn math_delimiters_keep_escapes_and_verbatim() {
+        let text = r"a\\[2pt] b \$\[ \$ \verb|\[x\]| \begin{verbatim}\[y\]\end{verbatim}";
+        assert_eq!(convert_math_delimiters(text, MathDelimiters::Dollars), text);
+        // unpaired delimiters are kept
+        assert_eq!(
+            convert_math_delimiters(r"\[x", MathDelimiters::Dollars),
+            r"\[x"
+        );
+        assert_eq!(
+            convert_math_delimiters("$$x", MathDelimiters::Brackets),
+            "$$x"
+        );
+        assert_eq!(
+            convert_math_delimiters(r"\[ \\[1pt] x \]", MathDelimiters::Dollars),
+            r"$$ \\[1pt] x $$"
+        );
+    }
+
+    #[test]
+    fn normalize_unicode_quotes() {
+        let mixed = "\u{201C}smart\u{201D} and \"straight\", it\u{2019}s \u{2018}x\u{2019} 'y'";
+        assert_eq!(
+            normalize_unicode(mixed),
+            "``smart'' and \"straight\", it's `x' 'y'"
+        );
+        assert_eq!(normalize_unicode("\u{201E}unten\u{201C}"), ",,unten``");
+    }
+
+    #[test]
+    fn normalize_unicode_spaces_and_nfc() {
+        assert_eq!(normalize_unicode("a\u{00A0}b"), "a~b");
+        // `e` with a combining acute accent
+        assert_eq!(normalize_unicode("caf\u{0065}\u{0301}"), "caf\u{00E9}");
+    }
+
+    #[test]
+    fn normalize_unicode_is_idempotent() {
+        for text in [
+            "\u{201C}a\u{201D} \"b\" \u{2018}c\u{2019} 'd'",
+            "x\u{00A0}=\u{00A0}\u{0065}\u{0301}",
+            "``already'' ascii",
+            "",
+        ] {
+            let once = normalize_unicode(text);
+            assert_eq!(normalize_unicode(&once), once);
+        }
+    }
+
+    /// Removes the `%` comments of the text mapped by `input` like a preprocessing
+    /// pass, keeping the line breaks.
+    fn strip_comments_mapped(text: &str, input: &SourceMap) -> (String, SourceMap) {
+        let mut out = String::new();
+        let mut map = SourceMap::default();
+        let mut pos = 0;
+        for line in text.split_inclusive('\n') {
+            let kept = line.find('%').map_or(line.len(), |i| i);
+            out.push_str(&line[..kept]);
+            map.push_mapped(input, pos..pos + kept);
+            if kept < line.len() && line.ends_with('\n') {
+                out.push('\n');
+                map.push_inserted(1);
+            }
+            pos += line.len();
+        }
+        (out, map)
+    }
+
+    #[test]
+    fn source_map_round_trips_offsets() {
+        use rand::{Rng, SeedableRng};
+
+        let main = "\\deck{A} % deck\n\\input{a}\nend % of main\n";
+        let included = "% included\n\\fields{Front}{x}\n";
+        // expands `\input{a}` with a marker around the included file
+        let input = "\\input{a}";
+        let at = main.find(input).unwrap();
+        let mut expanded = String::new();
+        let mut map = SourceMap::default();
+        let main_file = map.add_file("main.tex");
+        let included_file = map.add_file("a.tex");
+        expanded.push_str(&main[..at]);
+        map.push_file(main_file, 0..at);
+        expanded.push_str("<<");
+        map.push_inserted(2);
+        expanded.push_str(included);
+        map.push_file(included_file, 0..included.len());
+        expanded.push_str(">>");
+        map.push_inserted(2);
+        expanded.push_str(&main[at + input.len()..]);
+        map.push_file(main_file, at + input.len()..main.len());
+        assert_eq!(map.len(), expanded.len());
+
+        let (stripped, stripped_map) = strip_comments_mapped(&expanded, &map);
+        assert_eq!(stripped, "\\deck{A} \n<<\n\\fields{Front}{x}\n>>\nend \n");
+        assert_eq!(stripped_map.len(), stripped.len());
+
+        let files = [main, included];
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(451);
+        for (text, map) in [(&expanded, &map), (&stripped, &stripped_map)] {
+            for _ in 0..200 {
+                let pos = rng.gen_range(0..text.len());
+                match map.to_original(pos) {
+                    Some(origin) => {
+                        let file = files[origin.file];
+                        assert_eq!(
+                            file.as_bytes()[origin.offset],
+                            text.as_bytes()[pos],
+                            "{} of {:?}",
+                            pos,
+                            text
+                        );
+                        assert_eq!(
+                            map.file_name(origin.file),
+                            ["main.tex", "a.tex"][origin.file]
+                        );
+                    }
+                    // the markers and the line breaks of the removed comments
+                    None => assert!(b"<>\n".contains(&text.as_bytes()[pos])),
+                }
+            }
+        }
+
+        // every byte of a mapped span comes from the same file
+        let fields = stripped.find("\\fields").unwrap();
+        let span = fields..fields + "\\fields{Front}{x}".len();
+        let (file, range) = stripped_map.to_original_span(span.clone()).unwrap();
+        assert_eq!(&files[file][range], &stripped[span]);
+        let across = stripped.find("A}").unwrap()..stripped.find("Front").unwrap();
+        assert_eq!(stripped_map.to_original_span(across), None);
+        // the end of the text maps to the end of the last segment
+        assert_eq!(
+            map.to_original(expanded.len()),
+            Some(SourcePos {
+                file: main_file,
+                offset: main.len()
+            })
+        );
+        assert_eq!(map.to_original(expanded.len() + 1), None);
+        // the last segment of the stripped text is the inserted line break
+        assert_eq!(stripped_map.to_original(stripped.len()), None);
+    }
+
+    #[test]
+    fn identity_source_map() {
+        let map = SourceMap::identity("main", 10);
+        assert_eq!(map.len(), 10);
+        for pos in 0..=10 {
+            assert_eq!(
+                map.to_original(pos),
+                Some(SourcePos {
+                    file: 0,
+                    offset: pos
+                })
+            );
+        }
+        assert_eq!(map.to_original_span(2..5), Some((0, 2..5)));
+        assert_eq!(map.to_original_span(3..3), Some((0, 3..3)));
+        assert!(SourceMap::default().is_empty());
+        assert_eq!(SourceMap::default().to_original(0), None);
+    }
+
+    #[test]
+    fn positions_are_in_the_file() {
+        let body = "\\deck{A}\\model{Basic}\n\\fields{Front}{a}\\tag{t}\\tag{t}\\next";
+        // leading whitespace before the header is part of the file
+        let content = format!("\n\n{}", document(body));
+        let err = parse_content(content.clone(), &ParseOptions::default()).unwrap_err();
+        let error = find_parse_error(&err).unwrap();
+        assert_eq!(error.code, Code::DuplicateTag);
+        let pos = error.pos.unwrap();
+        assert!(content[pos..].starts_with("\\tag{t}\\next"));
+        assert_eq!(file_position(&content, pos), (9, 25));
+
+        let mut warnings = Vec::new();
+        let (body, map) =
+            prepare_content(&content, &ParseOptions::default().frame, &mut warnings).unwrap();
+        let positions = FilePositions::new(&content, &map);
+        for pos in 0..body.len() {
+            let offset = positions.offset(pos);
+            assert_eq!(content.get(offset..offset + 1), body.get(pos..pos + 1));
+            assert_eq!(positions.part(pos).offset(0), offset);
+        }
+        assert_eq!(positions.line(0), 8);
+        let end = positions.offset(body.len());
+        assert!(content[end..].starts_with(&ParseOptions::default().frame.footer));
+    }
+
+    #[test]
+    fn warnings_of_changed_notes_are_in_file_lines() {
+        let options = ParseOptions {
+            on_duplicate_field: OnDuplicateField::First,
+            ..ParseOptions::default()
+        };
+        let notes = |second: &str| {
+            document(&format!(
+                "\\deck{{A}}\\model{{Basic}}\n\\fields{{Front}}{{a}}\\next\n{}\\next\n\\fields{{Front}}{{d}}\\next",
+                second
+            ))
+        };
+        let index = parse_changed(notes(r"\fields{Front}{b}"), &options, None)
+            .unwrap()
+            .index;
+        let second = notes(r"\fields{Front}{b}\fields{Front}{c}");
+        let full = parse_content(second.clone(), &options).unwrap();
+        let changed = parse_changed(second, &options, Some(&index)).unwrap();
+        // the changed line ends in the text of the last note
+        assert_eq!(changed.skipped, 1);
+        assert_eq!(changed.outcome.warnings, full.warnings);
+        // the body starts in line 6
+        assert_eq!(full.warnings[0].line(), Some(8));
+    }
+
+    const CHANGED_BASE: &str = "\\deck{A}\\model{Basic}\n\
+                                \\fields{Front}{1}\\fields{Back}{a}\\next\n\
+                                \\begin{field}{Front}\n2\n\\end{field}\\fields{Back}{b}\\next\n\
+                                \\fields{Front}{3}\\fields{Back}{c}\\tag{t}\\next\n\
+                                \\fields{Front}{4}\\fields{Back}{d}\\next\n\
+                                % end of the notes\n";
+
+    /// Notes of the file with the body `after` like `--changed-only` finds them, the
+    /// notes of the file with the body `before` with the changed notes replaced, and
+    /// the number of skipped notes
+    fn spliced_notes(before: &str, after: &str) -> (Vec<Note>, usize) {
+        let options = ParseOptions::default();
+        let previous = parse_changed(document(before), &options, None).unwrap();
+        let changed = parse_changed(document(after), &options, Some(&previous.index)).unwrap();
+        let replaced = previous.outcome.notes.len() - changed.skipped;
+        let mut notes = previous.outcome.notes;
+        notes.splice(
+            changed.first_note..changed.first_note + replaced,
+            changed.outcome.notes,
+        );
+        (notes, changed.skipped)
+    }
+
+    /// The decks, models, sorted fields and tags of `notes`
+    fn note_contents(notes: &[Note]) -> Vec<String> {
+        notes
+            .iter()
+            .map(|note| {
+                let mut fields = note.fields.iter().collect::<Vec<_>>();
+                fields.sort();
+                format!("{} {} {:?} {:?}", note.deck, note.model, fields, note.tags)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn changed_notes_match_a_full_parse() {
+        let edits = [
+            // (edit, skipped notes)
+            // the first note shares its span with the state commands
+            (CHANGED_BASE.replace("{a}", "{changed}"), 0),
+            (CHANGED_BASE.replace("\n2\n", "\n2 and more\n"), 3),
+            (CHANGED_BASE.replace("{3}", "{three}"), 2),
+            // changes reaching the end of the body parse everything
+            (CHANGED_BASE.replace("{d}", "{dd}"), 0),
+            // a new note between two others
+            (
+                CHANGED_BASE.replace(
+                    "\\fields{Front}{3}",
+                    "\\fields{Front}{new}\\next\n\\fields{Front}{3}",
+                ),
+                3,
+            ),
+            // a removed note
+            (
+                CHANGED_BASE.replace("\\fields{Front}{3}\\fields{Back}{c}\\tag{t}\\next\n", ""),
+                2,
+            ),
+            // two merged notes
+            (
+                CHANGED_BASE.replace("{b}\\next\n\\fields{Front}{3}\\fields{Back}{c}", "{b}\n"),
+                1,
+            ),
+            // a state change for the following notes
+            (
+                CHANGED_BASE.replace("\\fields{Front}{3}", "\\deck{B}\\fields{Front}{3}"),
+                0,
+            ),
+            (CHANGED_BASE.replace("\\tag{t}", ""), 2),
+            (CHANGED_BASE.to_owned(), 4),
+        ];
+        for (after, skipped) in edits {
+            let full = parse(&after).unwrap();
+            let (notes, actual_skipped) = spliced_notes(CHANGED_BASE, &after);
+            assert_eq!(
+                note_contents(&notes),
+                note_contents(&full.notes),
+                "{}",
+                after
+            );
+            assert_eq!(actual_skipped, skipped, "{}", after);
+        }
+    }
+
+    #[test]
+    fn changed_frame_parses_all_notes() {
+        let options = ParseOptions::default();
+        let other = ParseOptions {
+            frame: DocumentFrame {
+                header: format!("% other\n{}", HEADER),
+                footer: FOOTER.to_owned(),
+            },
+            ..ParseOptions::default()
+        };
+        let previous =
+            parse_changed(format!("% other\n{}", document(CHANGED_BASE)), &other, None).unwrap();
+        let changed =
+            parse_changed(document(CHANGED_BASE), &options, Some(&previous.index)).unwrap();
+        assert_eq!(changed.skipped, 0);
+        assert_eq!(changed.outcome.notes.len(), 4);
+
+        // an edit in the following notes after an unchanged file
+        let unchanged =
+            parse_changed(document(CHANGED_BASE), &options, Some(&changed.index)).unwrap();
+        assert_eq!((unchanged.skipped, unchanged.outcome.notes.len()), (4, 0));
+        let (notes, _) = spliced_notes(CHANGED_BASE, &CHANGED_BASE.replace("{4}", "{four}"));
+        assert_eq!(notes[3].fields["Front"], "four");
+        assert_eq!(notes[2].tags, ["t"]);
+    }
+
+    const CUSTOM_STY: &str = "\\NeedsTeXFormat{LaTeX2e}\n\
+                              \\ProvidesPackage{custom}[2024/01/01 my commands]\n\
+                              \\RequirePackage[utf8]{inputenc}\n\
+                              \\usepackage{amsmath, amssymb}\n\
+                              % \\usepackage{ankitex} is loaded by the document\n\
+                              \\newcommand{\\startproof}{\\begin{proof}}\n\
+                              \\newenvironment{note}{\\begin{center}}{\\end{center}}\n\
+                              \\newcommand{\\brace}{\\{\\begin{x}}\n\
+                              \\begin{document}\\end{document}\n";
+
+    #[test]
+    fn sty_scan_of_commands() {
+        let scan = scan_sty(CUSTOM_STY);
+        assert_eq!(scan.provides, [("custom".to_owned(), 2)]);
+        let packages = scan
+            .packages
+            .iter()
+            .map(|(p, l)| (p.as_str(), *l))
+            .collect::<Vec<_>>();
+        assert_eq!(packages, [("inputenc", 3), ("amsmath", 4), ("amssymb", 4)]);
+        // environments in definitions aren't matched, also after an escaped brace
+        assert_eq!(scan.envs, []);
+        assert!(check_custom_content("custom.sty", CUSTOM_STY).is_ok());
+    }
+
+    #[test]
+    fn custom_sty_findings() {
+        let findings = |content: &str| check_custom_content("custom.sty", content).findings;
+        assert_eq!(
+            findings("\\usepackage{amsmath}\n"),
+            [StyFinding::MissingProvides {
+                package: "custom".to_owned()
+            }]
+        );
+        assert_eq!(
+            findings("\n\\ProvidesPackage{mine}\n"),
+            [StyFinding::WrongProvides {
+                package: "custom".to_owned(),
+                found: "mine".to_owned(),
+                line: 2
+            }]
+        );
+        assert_eq!(
+            findings("\\ProvidesPackage{custom}\n\\RequirePackage[final]{amsmath,ankitex}\n\\usepackage{custom}"),
+            [
+                StyFinding::CircularPackage {
+                    package: "ankitex".to_owned(),
+                    line: 2
+                },
+                StyFinding::CircularPackage {
+                    package: "custom".to_owned(),
+                    line: 3
+                }
+            ]
+        );
+        // ordered by line
+        assert_eq!(
+            findings("\\ProvidesPackage{custom}\n\\begin{center}\n\\begin{x}\n\\end{center}\n"),
+            [
+                StyFinding::UnclosedEnv {
+                    env: "center".to_owned(),
+                    line: 2
+                },
+                StyFinding::UnclosedEnv {
+                    env: "x".to_owned(),
+                    line: 3
+                },
+                StyFinding::UnmatchedEnd {
+                    env: "center".to_owned(),
+                    line: 4
+                },
+            ]
+        );
+        let report = check_custom_content("custom.sty", "\\usepackage{ankitex}");
+        let err = report.into_result().unwrap_err();
+        assert_eq!(err.to_string(), "invalid structure of custom.sty");
+    }
+
+    #[test]
+    fn ankitex_sty_findings() {
+        let report = check_ankitex_content("ankitex.sty", ANKITEX, ANKITEX);
+        assert!(report.is_ok());
+        report.into_result().unwrap();
+
+        let modified = ANKITEX.replacen('\n', "\n\\newcommand{\\mine}{x}\n", 1);
+        let report = check_ankitex_content("ankitex.sty", &modified, ANKITEX);
+        assert_eq!(report.findings, [StyFinding::Modified]);
+        let err = report.into_result().unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid content of ankitex.sty"));
+
+        let renamed = ANKITEX.replace("\\ProvidesPackage{ankitex}", "\\ProvidesPackage{anki}");
+        let findings = check_ankitex_content("ankitex.sty", &renamed, ANKITEX).findings;
+        assert!(matches!(
+            &findings[..],
+            [StyFinding::Modified, StyFinding::WrongProvides { found, .. }] if found == "anki"
+        ));
+        assert_eq!(
+            findings[1].line(),
+            scan_sty(ANKITEX).provides.first().map(|(_, l)| *l)
+        );
+
+        assert!(check_custom_template(Path::new("/nonexistent/custom.sty")).is_err());
+    }
+
+    /// Directory with the files `files` for a test of includes
+    fn include_dir(test: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("anki-tex-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    const INCLUDED_NOTE: &str = "\\deck{A}\\model{Basic}\\fields{Front}{a}\\fields{Back}{b}\\next";
+
+    #[test]
+    fn escaped_percent_is_no_comment() {
+        let text =
+            "50\\% \\usepackage{a}\n% \\usepackage{b}\n\\\\% \\usepackage{c}\n\\usepackage{d} % e";
+        let names = uncommented_captures(&USEPACKAGE, text).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "d"]);
+        assert!(!is_commented("\\%\\input{x}", 2));
+        assert!(is_commented("a % \\input{x}", 4));
+        assert!(!is_commented("% comment\n\\input{x}", 10));
+    }
+
+    #[test]
+    fn preamble_in_include() {
+        let dir = include_dir(
+            "preamble-include",
+            &[
+                (
+                    "preamble.tex",
+                    "\\documentclass{article}\n\\usepackage{ankitex}\n\\input{packages}\n",
+                ),
+                (
+                    "packages.tex",
+                    "% \\usepackage{other}\n\\usepackage[final]{custom}\n",
+                ),
+            ],
+        );
+        let content = format!(
+            "\\input{{preamble}} % 100\\% shared\n\\begin{{document}}\n{}\n{}",
+            INCLUDED_NOTE, FOOTER
+        );
+        let frame = DocumentFrame::default();
+        check_preamble(&content, &frame, &dir).unwrap();
+        let outcome = parse_content(content.clone(), &ParseOptions::default()).unwrap();
+        assert_eq!(outcome.notes.len(), 1);
+        assert_eq!(outcome.notes[0].fields["Back"], "b");
+
+        // the packages aren't used anywhere
+        std::fs::write(dir.join("packages.tex"), "% \\usepackage{custom}\n").unwrap();
+        let err = check_preamble(&content, &frame, &dir).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("required packages custom, searched the main file"),
+            "{}",
+            message
+        );
+        assert!(message.contains(&dir.join("preamble.tex").to_string_lossy().into_owned()));
+        assert!(message.contains(&dir.join("packages.tex").to_string_lossy().into_owned()));
+        // the header of the config is no include
+        check_preamble(&document(INCLUDED_NOTE), &frame, &dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn body_in_include() {
+        let outcome = parse(&format!(
+            "{}\n\\input{{chapter1}}\n% \\input{{chapter2}}\n100\\% \\include{{chapter3}}",
+            INCLUDED_NOTE
+        ))
+        .unwrap();
+        assert_eq!(outcome.notes.len(), 1);
+        assert_eq!(
+            outcome.warnings,
+            [
+                ParseWarning::IncludedFile {
+                    file: "chapter1".to_owned(),
+                    line: 7
+                },
+                ParseWarning::IncludedFile {
+                    file: "chapter3".to_owned(),
+                    line: 9
+                }
+            ]
+        );
+        assert_eq!(outcome.warnings[0].class(), WarningClass::IncludedFile);
+    }
+
+    #[test]
+    fn missing_preamble_file() {
+        let dir = include_dir("missing-preamble", &[]);
+        let content = format!(
+            "\\input{{preamble}}\n\\begin{{document}}\n{}\n{}",
+            INCLUDED_NOTE, FOOTER
+        );
+        let err = check_preamble(&content, &DocumentFrame::default(), &dir).unwrap_err();
+        assert_eq!(find_parse_error(&err).unwrap().code, Code::MissingHeader);
+        let missing = dir.join("preamble.tex").to_string_lossy().into_owned();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("can't read {} included by the preamble", missing)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// `\name{Front}{Back=x}...` for an entry of [`grammar`], environments with an
+    /// optional argument, a valid format or field name, and an item in their body
+    fn grammar_example(entry: &GrammarEntry) -> String {
+        let args = (0..entry.arity)
+            .map(|i| if i == 0 { "{Front}" } else { "{Back=x}" })
+            .collect::<String>();
+        let optional = if entry.optional_arg { "[raw]" } else { "" };
+        match entry.kind {
+            GrammarKind::Command => format!("\\{}{}{}", entry.name, optional, args),
+            GrammarKind::Environment => format!(
+                "\\begin{{{0}}}{1}{2}\\item x\\end{{{0}}}",
+                entry.name, optional, args
+            ),
+        }
+    }
+
+    #[test]
+    fn grammar_entries_are_matched() {
+        let entries = grammar();
+        let template = NoteTemplate {
+            name: "Front".to_owned(),
+            deck: None,
+            model: None,
+            tags: Vec::new(),
+            fields: BTreeMap::from([("Front".to_owned(), "{Back}".to_owned())]),
+        };
+        let table = CommandTable::new(&[], &[template]).unwrap();
+        let mut text = String::new();
+        let mut spans = Vec::new();
+        for entry in &entries {
+            let example = grammar_example(entry);
+            spans.push(text.len()..text.len() + example.len());
+            text.push_str(&example);
+            text.push('\n');
+        }
+        let matches = get_all_commands(&text, &table, FilePositions::unmapped(&text)).unwrap();
+        for span in spans {
+            assert!(
+                matches.iter().any(|m| m.span == span),
+                "`{}` isn't matched",
+                &text[span]
+            );
+        }
+
+        let commands = entries
+            .iter()
+            .filter(|entry| entry.kind == GrammarKind::Command)
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>();
+        assert_eq!(COMMANDS, commands);
+        let envs = entries
+            .iter()
+            .filter(|entry| entry.kind == GrammarKind::Environment);
+        assert!(envs.clone().all(|entry| entry.optional_arg));
+        assert_eq!(
+            envs.map(|entry| entry.name).collect::<Vec<_>>(),
+            ["field", "notelist"]
+        );
+    }
 }
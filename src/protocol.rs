@@ -0,0 +1,229 @@
+//! Requests to and responses from AnkiConnect.
+//!
+//! Every request is a JSON object with the action, its parameters and the
+//! [`VERSION`] of the protocol. The response contains either the result or an error,
+//! older versions of AnkiConnect return the result directly and aren't supported.
+//!
+//! Use [`crate::api::Client::call_action`] to send actions which aren't wrapped by
+//! [`crate::api`].
+
+use color_eyre::{eyre::eyre, Help, Report, Result};
+use std::borrow::Cow;
+
+use serde::{de::DeserializeOwned, de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tracing::debug;
+
+/// Version of the AnkiConnect protocol sent with every request
+pub const VERSION: i32 = 6;
+
+/// Body of a request of `action` with `params`
+#[derive(Serialize)]
+pub struct Request<'a, T> {
+    action: Cow<'a, str>,
+    params: &'a T,
+    version: i32,
+}
+
+impl<'a, T: Serialize> Request<'a, T> {
+    pub fn new(action: impl Into<Cow<'a, str>>, params: &'a T) -> Self {
+        Self {
+            action: action.into(),
+            params,
+            version: VERSION,
+        }
+    }
+}
+
+/// Parameters of actions without parameters, serialized as `{}`
+pub fn empty() -> impl Serialize {
+    serde_json::map::Map::new()
+}
+
+/// Response to a request with `result` and `error`
+///
+/// AnkiConnect sets exactly one of them, forks are known to omit `error` or to set
+/// both. A missing field counts as `null` and unknown fields are ignored. Responses
+/// without both fields are in the format of versions before [`VERSION`] 6.
+#[derive(Debug)]
+pub enum ReqResult<T> {
+    /// Only `result` is set
+    Ok(T),
+    /// `error` is set, the `result` next to it is only logged
+    Err {
+        error: String,
+        result: Option<Value>,
+    },
+    /// Both `result` and `error` are `null`
+    Null,
+    /// The result without the surrounding object, as sent by old versions
+    Legacy,
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for ReqResult<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let Value::Object(mut response) = Value::deserialize(deserializer)? else {
+            return Ok(Self::Legacy);
+        };
+        if !response.contains_key("result") && !response.contains_key("error") {
+            return Ok(Self::Legacy);
+        }
+        let result = response.remove("result").filter(|r| !r.is_null());
+        match response.remove("error").unwrap_or(Value::Null) {
+            Value::Null => match result {
+                Some(result) => serde_json::from_value(result)
+                    .map(Self::Ok)
+                    .map_err(|e| D::Error::custom(format!("invalid result: {}", e))),
+                None => Ok(Self::Null),
+            },
+            Value::String(error) => Ok(Self::Err { error, result }),
+            error => Err(D::Error::custom(format!(
+                "invalid error, expected a string or null, got {}",
+                error
+            ))),
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> ReqResult<T> {
+    /// Returns the result or the error returned by anki.
+    pub fn get(self) -> Result<T> {
+        match self {
+            Self::Ok(result) => Ok(result),
+            Self::Err { error, result } => Err(anki_error(error, result)),
+            Self::Null => Err(eyre!("malformed response: both fields null")
+                .note("AnkiConnect returned neither a result nor an error")),
+            Self::Legacy => Err(too_old()),
+        }
+    }
+
+    /// Like [`Self::get`] but for actions which return `null` on success.
+    pub fn get_void(self) -> Result<()> {
+        match self {
+            Self::Ok(_) | Self::Null => Ok(()),
+            Self::Err { error, result } => Err(anki_error(error, result)),
+            Self::Legacy => Err(too_old()),
+        }
+    }
+}
+
+fn anki_error(error: String, result: Option<Value>) -> Report {
+    if let Some(result) = result {
+        debug!("discarding result {} of response with error", result);
+    }
+    eyre!("anki returned an error: {}", error)
+}
+
+fn too_old() -> Report {
+    eyre!("AnkiConnect too old, the response has no `result` and `error` fields")
+        .note(format!(
+            "anki-tex requires version {} of the protocol",
+            VERSION
+        ))
+        .suggestion("update the AnkiConnect add-on in anki")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response(value: Value) -> ReqResult<Vec<u64>> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn error_of(value: Value) -> String {
+        response(value).get().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn result_or_error() {
+        assert_eq!(
+            response(json!({"result": [1, 2], "error": null}))
+                .get()
+                .unwrap(),
+            [1, 2]
+        );
+        // forks omitting `error`
+        assert_eq!(response(json!({"result": [3]})).get().unwrap(), [3]);
+        assert_eq!(
+            error_of(json!({"result": null, "error": "deck was not found"})),
+            "anki returned an error: deck was not found"
+        );
+        assert_eq!(
+            error_of(json!({"error": "model was not found"})),
+            "anki returned an error: model was not found"
+        );
+    }
+
+    #[test]
+    fn both_fields_set_prefer_the_error() {
+        let both = response(json!({"result": [1], "error": "cannot create note"}));
+        assert!(matches!(
+            &both,
+            ReqResult::Err {
+                result: Some(_),
+                ..
+            }
+        ));
+        assert_eq!(
+            both.get().unwrap_err().to_string(),
+            "anki returned an error: cannot create note"
+        );
+        let both: ReqResult<()> =
+            serde_json::from_value(json!({"result": 1, "error": "e"})).unwrap();
+        assert!(both.get_void().is_err());
+    }
+
+    #[test]
+    fn both_fields_null() {
+        assert_eq!(
+            error_of(json!({"result": null, "error": null})),
+            "malformed response: both fields null"
+        );
+        assert_eq!(
+            error_of(json!({"result": null})),
+            "malformed response: both fields null"
+        );
+        // actions like `sync` return `null` on success
+        let null: ReqResult<()> =
+            serde_json::from_value(json!({"result": null, "error": null})).unwrap();
+        null.get_void().unwrap();
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let extra = json!({"result": [1], "error": null, "version": 6, "debug": {"ms": 3}});
+        assert_eq!(response(extra).get().unwrap(), [1]);
+        let extra = json!({"error": "e", "trace": []});
+        assert_eq!(error_of(extra), "anki returned an error: e");
+    }
+
+    #[test]
+    fn legacy_responses_are_too_old() {
+        for legacy in [
+            json!([1, 2]),
+            json!(5),
+            json!("text"),
+            json!({"notes": [1]}),
+            json!(null),
+        ] {
+            let result = response(legacy.clone());
+            assert!(matches!(result, ReqResult::Legacy), "{}", legacy);
+            assert!(error_of(legacy).starts_with("AnkiConnect too old"),);
+        }
+        let legacy: ReqResult<()> = serde_json::from_value(json!([])).unwrap();
+        assert!(legacy.get_void().is_err());
+    }
+
+    #[test]
+    fn invalid_fields() {
+        let err = serde_json::from_value::<ReqResult<Vec<u64>>>(json!({"result": 1, "error": 2}))
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid error"), "{}", err);
+        let err =
+            serde_json::from_value::<ReqResult<Vec<u64>>>(json!({"result": "a", "error": null}))
+                .unwrap_err();
+        assert!(err.to_string().contains("invalid result"), "{}", err);
+    }
+}
@@ -0,0 +1,794 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Whether colored output should be written to stdout.
+///
+/// Respects https://no-color.org
+pub fn use_color() -> bool {
+    std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+/// How names of decks, models and tags are sorted in the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Collation {
+    /// Ignore case and accents first, so `Äpfel` is sorted next to `Apfel` and the
+    /// subdecks of a deck follow it directly
+    #[default]
+    Unicode,
+    /// Compare the bytes of the names
+    Bytes,
+}
+
+static COLLATION: OnceLock<Collation> = OnceLock::new();
+
+/// Sets the collation used by [`collate`], only the first call has an effect.
+pub fn set_collation(collation: Collation) {
+    let _ = COLLATION.set(collation);
+}
+
+/// Compares the names `a` and `b` with the collation set by [`set_collation`].
+pub fn collate(a: &str, b: &str) -> Ordering {
+    COLLATION.get().copied().unwrap_or_default().compare(a, b)
+}
+
+impl Collation {
+    /// Compares the names `a` and `b`.
+    ///
+    /// With [`Collation::Unicode`] the parts separated by `::` are compared without
+    /// case and accents, ties are broken by the bytes so the order is total.
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            Self::Bytes => a.cmp(b),
+            Self::Unicode => a
+                .split("::")
+                .map(fold)
+                .cmp(b.split("::").map(fold))
+                .then_with(|| a.cmp(b)),
+        }
+    }
+}
+
+/// `text` in lowercase without accents, with `ß` as `ss`
+fn fold(text: &str) -> String {
+    use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .replace('ß', "ss")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone)]
+struct Cell {
+    text: String,
+    color: Option<&'static str>,
+}
+
+/// A simple table with aligned columns and an optional totals row
+#[derive(Debug, Clone)]
+pub struct Table {
+    header: Vec<String>,
+    align: Vec<Align>,
+    rows: Vec<Vec<Cell>>,
+    totals: Option<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn new(header: &[&str]) -> Self {
+        Self {
+            header: header.iter().map(|h| h.to_string()).collect(),
+            align: vec![Align::Left; header.len()],
+            rows: Vec::new(),
+            totals: None,
+        }
+    }
+
+    pub fn align(mut self, column: usize, align: Align) -> Self {
+        self.align[column] = align;
+        self
+    }
+
+    pub fn row(&mut self, row: Vec<String>) {
+        self.rows.push(row.into_iter().map(Self::cell).collect());
+    }
+
+    /// Adds a row whose cells are colored with ANSI escape codes when rendered with color.
+    fn colored_row(&mut self, row: Vec<(String, Option<&'static str>)>) {
+        self.rows.push(
+            row.into_iter()
+                .map(|(text, color)| Cell { text, color })
+                .collect(),
+        );
+    }
+
+    pub fn totals(&mut self, row: Vec<String>) {
+        self.totals = Some(row.into_iter().map(Self::cell).collect());
+    }
+
+    fn cell(text: String) -> Cell {
+        Cell { text, color: None }
+    }
+
+    pub fn render(&self, color: bool) -> String {
+        let mut widths = self
+            .header
+            .iter()
+            .map(|h| h.chars().count())
+            .collect::<Vec<_>>();
+        for row in self.rows.iter().chain(&self.totals) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.text.chars().count());
+            }
+        }
+
+        let header = self
+            .header
+            .iter()
+            .cloned()
+            .map(Self::cell)
+            .collect::<Vec<_>>();
+        let mut out = String::new();
+        self.render_row(&mut out, &header, &widths, color, true);
+        out.push_str(&self.separator(&widths));
+        for row in &self.rows {
+            self.render_row(&mut out, row, &widths, color, false);
+        }
+        if let Some(totals) = &self.totals {
+            out.push_str(&self.separator(&widths));
+            self.render_row(&mut out, totals, &widths, color, true);
+        }
+
+        out
+    }
+
+    fn separator(&self, widths: &[usize]) -> String {
+        let mut line = widths
+            .iter()
+            .map(|w| "-".repeat(*w))
+            .collect::<Vec<_>>()
+            .join("-+-");
+        line.push('\n');
+        line
+    }
+
+    fn render_row(
+        &self,
+        out: &mut String,
+        row: &[Cell],
+        widths: &[usize],
+        color: bool,
+        bold: bool,
+    ) {
+        let cells = row
+            .iter()
+            .zip(widths)
+            .zip(&self.align)
+            .map(|((cell, width), align)| {
+                let padding = " ".repeat(width - cell.text.chars().count());
+                let text = match cell.color {
+                    Some(cell_color) if color => format!("{}{}{}", cell_color, cell.text, RESET),
+                    _ => cell.text.clone(),
+                };
+                match align {
+                    Align::Left => format!("{}{}", text, padding),
+                    Align::Right => format!("{}{}", padding, text),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" | ");
+        if color && bold {
+            out.push_str(&format!("{}{}{}", BOLD, cells.trim_end(), RESET));
+        } else {
+            out.push_str(cells.trim_end());
+        }
+        out.push('\n');
+    }
+}
+
+/// Results of a run for a single deck
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct DeckSummary {
+    pub deck: String,
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub duplicates: usize,
+    /// Notes which differ from the file, but are protected from changes
+    pub protected: usize,
+    pub errors: usize,
+}
+
+impl DeckSummary {
+    fn add(&mut self, other: &Self) {
+        self.created += other.created;
+        self.updated += other.updated;
+        self.unchanged += other.unchanged;
+        self.duplicates += other.duplicates;
+        self.protected += other.protected;
+        self.errors += other.errors;
+    }
+}
+
+/// Number of requests sent to anki and their total duration
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RequestTotals {
+    pub requests: usize,
+    pub total_ms: u64,
+}
+
+/// Results of a run of `create` or `diff`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct RunSummary {
+    /// Profile open in anki during the run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub decks: Vec<DeckSummary>,
+    /// Order in which new notes were sent to anki
+    pub order: Option<String>,
+    /// Status of the steps after creating the notes, e.g. `render`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<(String, String)>,
+    /// Problems found while checking the notes
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+    /// Number of notes left out by the deck filters or budgets, by the filter, e.g.
+    /// `skip-deck Uni::Old*`, `only-deck` or `budget of Uni::Analysis`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub filtered: BTreeMap<String, usize>,
+    /// Number of notes in the decks matching each `only-deck` pattern
+    #[serde(skip)]
+    pub only_deck_matches: BTreeMap<String, usize>,
+    /// Requests sent to anki since the start of anki-tex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests: Option<RequestTotals>,
+}
+
+impl RunSummary {
+    pub fn deck_mut(&mut self, deck: &str) -> &mut DeckSummary {
+        let idx = match self.decks.iter().position(|d| d.deck == deck) {
+            Some(idx) => idx,
+            None => {
+                self.decks.push(DeckSummary {
+                    deck: deck.to_owned(),
+                    ..Default::default()
+                });
+                self.decks.len() - 1
+            }
+        };
+        &mut self.decks[idx]
+    }
+
+    /// Adds the results of `other` to `self`.
+    pub fn merge(&mut self, other: Self) {
+        for deck in other.decks {
+            self.deck_mut(&deck.deck.clone()).add(&deck);
+        }
+        if self.order.is_none() {
+            self.order = other.order;
+        }
+        if self.profile.is_none() {
+            self.profile = other.profile;
+        }
+        self.diagnostics.extend(other.diagnostics);
+        for (filter, count) in other.filtered {
+            *self.filtered.entry(filter).or_default() += count;
+        }
+        for (pattern, count) in other.only_deck_matches {
+            *self.only_deck_matches.entry(pattern).or_default() += count;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decks.is_empty()
+    }
+
+    /// Whether anything was (or would be) changed in anki
+    pub fn has_changes(&self) -> bool {
+        self.decks.iter().any(|d| d.created != 0 || d.updated != 0)
+    }
+
+    /// Whether notes failed or a note had an error
+    pub fn has_errors(&self) -> bool {
+        self.decks.iter().any(|d| d.errors != 0)
+            || self
+                .diagnostics
+                .iter()
+                .any(|d| d.severity == Severity::Error)
+    }
+
+    pub fn totals(&self) -> DeckSummary {
+        let mut totals = DeckSummary {
+            deck: String::from("total"),
+            ..Default::default()
+        };
+        for deck in &self.decks {
+            totals.add(deck);
+        }
+        totals
+    }
+
+    pub fn to_table(&self) -> Table {
+        let mut table = Table::new(&[
+            "deck",
+            "created",
+            "updated",
+            "unchanged",
+            "duplicates",
+            "protected",
+            "errors",
+        ]);
+        for column in 1..=6 {
+            table = table.align(column, Align::Right);
+        }
+
+        let mut decks = self.decks.iter().collect::<Vec<_>>();
+        decks.sort_by(|a, b| collate(&a.deck, &b.deck));
+        let colored = |n: usize, color| (n.to_string(), (n != 0).then_some(color));
+        for deck in decks {
+            table.colored_row(vec![
+                (deck.deck.clone(), None),
+                colored(deck.created, GREEN),
+                colored(deck.updated, YELLOW),
+                (deck.unchanged.to_string(), None),
+                (deck.duplicates.to_string(), None),
+                (deck.protected.to_string(), None),
+                colored(deck.errors, RED),
+            ]);
+        }
+
+        let totals = self.totals();
+        table.totals(vec![
+            totals.deck,
+            totals.created.to_string(),
+            totals.updated.to_string(),
+            totals.unchanged.to_string(),
+            totals.duplicates.to_string(),
+            totals.protected.to_string(),
+            totals.errors.to_string(),
+        ]);
+
+        table
+    }
+}
+
+/// Interval in days from which anki considers a card mature
+pub const MATURE_INTERVAL: i32 = 21;
+
+/// Learning progress of the cards of a group of notes, see `anki-tex progress`
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Progress {
+    /// Deck or generation date of the notes
+    pub group: String,
+    pub notes: usize,
+    pub cards: usize,
+    pub new: usize,
+    /// Cards in learning or relearning
+    pub learning: usize,
+    /// Review cards with an interval below [`MATURE_INTERVAL`]
+    pub young: usize,
+    pub mature: usize,
+    /// Share of mature cards of all cards, `None` without cards
+    pub mature_percent: Option<f64>,
+    /// Mean interval of the review cards in days, `None` without review cards
+    pub mean_interval: Option<f64>,
+    pub lapses: usize,
+    /// Cards due today, including overdue ones
+    pub due: usize,
+    /// Sum of the intervals of the review cards in days
+    #[serde(skip)]
+    interval_sum: u64,
+}
+
+impl Progress {
+    pub fn new(group: String) -> Self {
+        Self {
+            group,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_card(&mut self, card: &crate::api::CardInfo, due: bool) {
+        self.cards += 1;
+        match card.r#type {
+            0 => self.new += 1,
+            2 if card.interval >= MATURE_INTERVAL => self.mature += 1,
+            2 => self.young += 1,
+            _ => self.learning += 1,
+        }
+        if card.r#type == 2 {
+            self.interval_sum += card.interval.max(0) as u64;
+        }
+        self.lapses += card.lapses.max(0) as usize;
+        self.due += usize::from(due);
+        self.update_ratios();
+    }
+
+    /// Adds the counts of `other` to `self`.
+    pub fn add(&mut self, other: &Self) {
+        self.notes += other.notes;
+        self.cards += other.cards;
+        self.new += other.new;
+        self.learning += other.learning;
+        self.young += other.young;
+        self.mature += other.mature;
+        self.lapses += other.lapses;
+        self.due += other.due;
+        self.interval_sum += other.interval_sum;
+        self.update_ratios();
+    }
+
+    fn update_ratios(&mut self) {
+        let reviewed = self.young + self.mature;
+        self.mature_percent =
+            (self.cards != 0).then(|| self.mature as f64 * 100.0 / self.cards as f64);
+        self.mean_interval = (reviewed != 0).then(|| self.interval_sum as f64 / reviewed as f64);
+    }
+}
+
+/// Table of the groups of `progress` with their totals
+pub fn progress_table(groups: &[Progress]) -> Table {
+    let mut table = Table::new(&[
+        "group", "notes", "cards", "new", "learning", "young", "mature", "mature %", "mean ivl",
+        "lapses", "due",
+    ]);
+    for column in 1..=10 {
+        table = table.align(column, Align::Right);
+    }
+    let row = |p: &Progress| {
+        let ratio = |value: Option<f64>| value.map_or(String::from("-"), |v| format!("{:.1}", v));
+        vec![
+            p.group.clone(),
+            p.notes.to_string(),
+            p.cards.to_string(),
+            p.new.to_string(),
+            p.learning.to_string(),
+            p.young.to_string(),
+            p.mature.to_string(),
+            ratio(p.mature_percent),
+            ratio(p.mean_interval),
+            p.lapses.to_string(),
+            p.due.to_string(),
+        ]
+    };
+    let mut totals = Progress::new(String::from("total"));
+    for group in groups {
+        table.row(row(group));
+        totals.add(group);
+    }
+    table.totals(row(&totals));
+    table
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+    /// Not run because an earlier check failed
+    Skip,
+}
+
+impl CheckStatus {
+    fn label(self) -> (&'static str, Option<&'static str>) {
+        match self {
+            Self::Pass => ("ok", Some(GREEN)),
+            Self::Warn => ("warn", Some(YELLOW)),
+            Self::Fail => ("FAIL", Some(RED)),
+            Self::Skip => ("skip", None),
+        }
+    }
+}
+
+/// Result of a single check of `doctor`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+    /// How to fix the problem
+    pub hint: Option<String>,
+}
+
+impl Check {
+    pub fn new(name: &str, status: CheckStatus, message: impl Into<String>) -> Self {
+        Self {
+            name: name.to_owned(),
+            status,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    pub fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Renders the checks as one line each, followed by the hint if there is one.
+pub fn render_checks(checks: &[Check], color: bool) -> String {
+    let width = checks
+        .iter()
+        .map(|c| c.name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    for check in checks {
+        let (label, label_color) = check.status.label();
+        let label = format!("{:<4}", label);
+        let label = match label_color {
+            Some(label_color) if color => format!("{}{}{}", label_color, label, RESET),
+            _ => label,
+        };
+        out.push_str(&format!(
+            "[{}] {:<width$}  {}\n",
+            label,
+            check.name,
+            check.message,
+            width = width
+        ));
+        if let Some(hint) = &check.hint {
+            out.push_str(&format!(
+                "       {:<width$}  hint: {}\n",
+                "",
+                hint,
+                width = width
+            ));
+        }
+    }
+    out
+}
+
+/// Severity of a [`Diagnostic`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Stable code of a [`Diagnostic`], serialized in kebab case, e.g. `unknown-field`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Code {
+    /// The file doesn't start with the configured header
+    MissingHeader,
+    /// The file doesn't end with the configured footer
+    MissingFooter,
+    /// A field environment inside of another one
+    NestedFieldEnv,
+    /// `\begin{field}` without a name
+    FieldEnvWithoutName,
+    /// `\end{field}` without `\begin{field}`
+    UnmatchedFieldEnd,
+    /// `\begin{field}` without `\end{field}`
+    UnclosedFieldEnv,
+    /// Unknown format in `\begin{field}[format]`
+    UnknownFieldFormat,
+    /// A `notelist` without items
+    EmptyNoteList,
+    /// `\begin{notelist}` without `\end{notelist}`
+    UnclosedNoteList,
+    /// A `notelist` with invalid syntax, e.g. text before the first `\item`
+    InvalidNoteList,
+    /// `\usetemplate` with invalid syntax
+    InvalidTemplateUse,
+    /// `\usetemplate` of a template which isn't configured
+    UnknownTemplate,
+    /// Invalid `key=value` arguments of `\usetemplate`
+    InvalidTemplateArgs,
+    /// A placeholder of a note template without value
+    MissingTemplateValue,
+    /// A tag added multiple times to one note
+    DuplicateTag,
+    /// A field set multiple times in one note
+    DuplicateField,
+    /// A note ended before a deck was selected
+    MissingDeck,
+    /// A note ended before a model was selected
+    MissingModel,
+    /// A note without fields
+    EmptyNote,
+    /// `\newcommand` or `\renewcommand` in the file, which is ignored by anki
+    IgnoredDefinition,
+    /// Fields or tags after the last `\next`
+    UnfinishedNote,
+    /// `\deck` or `\model` changed after fields of the note were set
+    MidNoteSwitch,
+    /// A deck written with both `deck_separator` and `::`
+    MixedDeckSeparators,
+    /// A field is set by a field command and by the text of the note, see
+    /// `implicit_fields`
+    ImplicitFieldConflict,
+    /// `\sep` is used multiple times in a note
+    MisplacedSep,
+    /// A `% anki:` comment with invalid options
+    InvalidNoteOptions,
+    /// A `% anki:` comment sets an option which anki-tex doesn't know
+    UnknownNoteOption,
+    /// `\input` or `\include` in the document body
+    IncludedFile,
+    /// The file contains no notes
+    NoNotes,
+    /// `\deck` or `\model` with the value which is already set
+    RedundantCommand,
+    /// A note without tags
+    UntaggedNote,
+    /// No model could be chosen for a note without `\model`
+    NoModel,
+    /// The model of a note doesn't exist in anki
+    UnknownModel,
+    /// The model of a note doesn't have a field of the note
+    UnknownField,
+    /// The model of a note has no fields in anki
+    ModelWithoutFields,
+    /// All fields of a note are empty or whitespace
+    BlankNote,
+    /// The first field of a note is empty, but other fields aren't
+    EmptyFirstField,
+    /// The file sets a tag matching `protected_tags`
+    ProtectedTag,
+    /// The styling of a used model lacks rules of the CSS anki-tex relies on
+    MissingModelStyling,
+    /// A style file with a structural problem, e.g. without `\ProvidesPackage`
+    InvalidStyFile,
+    /// A field is already wrapped in `[latex]` with `double_wrap = "error"`
+    DoubleWrap,
+    /// A field contains a placeholder like `{{name}}` which doesn't exist
+    UnknownPlaceholder,
+    /// A field is larger than `warn_field_bytes`
+    LargeField,
+    /// A field is larger than `max_field_bytes`
+    FieldTooLarge,
+    /// An error reported by LaTeX with `check --compile`
+    LatexError,
+    /// A command in a field which is neither standard LaTeX nor defined, see
+    /// `check --macros`
+    UnknownMacro,
+    /// Any other error
+    Other,
+}
+
+/// An error or warning about a file for editor integrations
+///
+/// Lines and columns start at 1, `note_index` at 0.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub note_index: Option<usize>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: Code, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            code,
+            message: message.into(),
+            file: None,
+            line: None,
+            column: None,
+            note_index: None,
+        }
+    }
+
+    pub fn file(mut self, file: impl Into<String>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn position(mut self, line: usize, column: Option<usize>) -> Self {
+        self.line = Some(line);
+        self.column = column;
+        self
+    }
+
+    pub fn note_index(mut self, index: Option<usize>) -> Self {
+        self.note_index = index;
+        self
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Allows returning a diagnostic as error while keeping its code
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(collation: Collation, names: &[&str]) -> Vec<String> {
+        let mut names = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        names.sort_by(|a, b| collation.compare(a, b));
+        names
+    }
+
+    #[test]
+    fn german_deck_names() {
+        let decks = [
+            "Zoologie",
+            "Äpfel",
+            "Apfel",
+            "apfel",
+            "Österreich",
+            "Ostern",
+            "Straße",
+        ];
+        assert_eq!(
+            sorted(Collation::Unicode, &decks),
+            [
+                "Apfel",
+                "apfel",
+                "Äpfel",
+                "Ostern",
+                "Österreich",
+                "Straße",
+                "Zoologie"
+            ]
+        );
+        assert_eq!(
+            sorted(Collation::Bytes, &decks),
+            [
+                "Apfel",
+                "Ostern",
+                "Straße",
+                "Zoologie",
+                "apfel",
+                "Äpfel",
+                "Österreich"
+            ]
+        );
+    }
+
+    #[test]
+    fn subdecks_follow_their_deck() {
+        let decks = ["Café::Z", "Cafe Noir", "Café", "Cafe::A"];
+        assert_eq!(
+            sorted(Collation::Unicode, &decks),
+            ["Café", "Cafe::A", "Café::Z", "Cafe Noir"]
+        );
+    }
+
+    #[test]
+    fn unicode_collation_is_deterministic() {
+        let names = ["é", "e", "E", "É", "e\u{301}"];
+        let expected = sorted(Collation::Unicode, &names);
+        for permutation in [[4, 3, 2, 1, 0], [2, 0, 4, 1, 3]] {
+            let names = permutation.map(|i| names[i]);
+            assert_eq!(sorted(Collation::Unicode, &names), expected);
+        }
+        // only names which are the same compare equal
+        for (i, a) in expected.iter().enumerate() {
+            for b in &expected[i + 1..] {
+                assert_eq!(Collation::Unicode.compare(a, b), Ordering::Less);
+            }
+        }
+    }
+}